@@ -13,6 +13,11 @@
 //!   - Log Index
 //!   - Log Data
 //!   - Anchor Index
+//!   - Sequence Number State
+//!   - Timestamp Index
+//!   - Caller Index
+//!   - Operation Index
+//!   - Secondary Index State
 //! ----------------------------------------
 //! Unallocated space
 //! ```
@@ -36,6 +41,30 @@
 //! - prefix scan with anchor to retrieve entries by anchor
 //! - prefix scan with (anchor, timestamp) to narrow down on the time period for a specific anchor
 //! - prefix scan with (anchor, timestamp, log index) to do pagination (with the key of the first entry not included in the previous set)
+//!
+//! ### Sequence Number State
+//! II pushes batches of entries together with their sequence numbers (see [append_entries]). The
+//! highest sequence number stored is kept in a [StableCell] so that entries of a batch that is
+//! retried by II are not stored twice.
+//!
+//! Sequence numbers that have been skipped (e.g. because II dropped entries) are recorded as gaps
+//! in the same cell. If a missing entry is delivered later, it is stored and the gap is narrowed
+//! accordingly. At most [MAX_RECORDED_GAPS] gaps are recorded: further gaps are only counted as
+//! missing entries, and their entries are not stored if they are delivered later. Entries written
+//! using the deprecated [write_entry] are not tracked.
+//!
+//! ### Secondary Indices
+//! In order to query entries across anchors (see [get_filtered_entries]) there are three additional
+//! [StableBTreeMap] indices with the following keys:
+//! - (timestamp, log index)
+//! - (caller, timestamp, log index)
+//! - (operation type, timestamp, log index)
+//!
+//! Since the caller and the operation are only known after decoding the entry, the secondary
+//! indices are built from the log (rather than on write) and entries that cannot be decoded are not
+//! indexed. The number of log entries processed so far is kept in the secondary index state. Each
+//! write indexes at most [MAX_INDEXED_ENTRIES_PER_CALL] entries, so that entries written before
+//! the indices existed are indexed gradually.
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::stable::stable64_size;
 use ic_cdk::api::time;
@@ -54,6 +83,10 @@ use std::cell::RefCell;
 
 #[cfg(test)]
 mod anchor_index_key_tests;
+#[cfg(test)]
+mod secondary_index_key_tests;
+#[cfg(test)]
+mod sequence_state_tests;
 
 /// We use restricted memory in order to ensure the separation between non-managed config memory (first page)
 /// and the managed memory for the archived data & indices.
@@ -63,6 +96,11 @@ type ConfigCell = StableCell<ConfigState, Memory>;
 /// Type of the index to efficiently retrieve entries by anchor.
 type LogIndex = u64;
 type AnchorIndex = StableBTreeMap<VirtualMemory<Memory>, AnchorIndexKey, ()>;
+type SequenceStateCell = StableCell<SequenceState, VirtualMemory<Memory>>;
+type TimestampIndex = StableBTreeMap<VirtualMemory<Memory>, TimestampIndexKey, ()>;
+type CallerIndex = StableBTreeMap<VirtualMemory<Memory>, CallerIndexKey, ()>;
+type OperationIndex = StableBTreeMap<VirtualMemory<Memory>, OperationIndexKey, ()>;
+type SecondaryIndexStateCell = StableCell<SecondaryIndexState, VirtualMemory<Memory>>;
 
 const GIB: u64 = 1 << 30;
 const WASM_PAGE_SIZE: u64 = 65536;
@@ -74,6 +112,22 @@ const MAX_WASM_PAGES: u64 = MAX_STABLE_MEMORY_SIZE / WASM_PAGE_SIZE;
 const LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(0);
 const LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(1);
 const ANCHOR_ACCESS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(2);
+const SEQUENCE_STATE_MEMORY_ID: MemoryId = MemoryId::new(3);
+const TIMESTAMP_INDEX_MEMORY_ID: MemoryId = MemoryId::new(4);
+const CALLER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(5);
+const OPERATION_INDEX_MEMORY_ID: MemoryId = MemoryId::new(6);
+const SECONDARY_INDEX_STATE_MEMORY_ID: MemoryId = MemoryId::new(7);
+
+/// The maximum number of log entries added to the secondary indices per write call.
+const MAX_INDEXED_ENTRIES_PER_CALL: u64 = 1000;
+/// The maximum number of index entries inspected by a single call to [get_filtered_entries].
+const MAX_SCANNED_INDEX_ENTRIES: usize = 1000;
+/// Maximum length of a principal in bytes.
+const MAX_PRINCIPAL_LEN: usize = 29;
+
+/// The maximum number of gaps recorded individually. If there are more gaps, the new ones are only
+/// counted (see [UnrecordedGaps]).
+const MAX_RECORDED_GAPS: usize = 1000;
 
 thread_local! {
     /// Static configuration of the archive set by init() or post_upgrade().
@@ -91,6 +145,21 @@ thread_local! {
     static ANCHOR_INDEX: RefCell<AnchorIndex> = with_memory_manager(|memory_manager| {
         RefCell::new(StableBTreeMap::init(memory_manager.get(ANCHOR_ACCESS_INDEX_MEMORY_ID), std::mem::size_of::<AnchorIndexKey>() as u32, 0))
     });
+
+    /// Information about the sequence numbers of the entries stored.
+    static SEQUENCE_STATE: RefCell<SequenceStateCell> = with_memory_manager(|memory_manager| {
+        RefCell::new(SequenceStateCell::init(memory_manager.get(SEQUENCE_STATE_MEMORY_ID), SequenceState::default()).expect("failed to initialize sequence state"))
+    });
+
+    /// Indices to efficiently retrieve entries by timestamp, caller or operation.
+    static SECONDARY_INDEXES: RefCell<SecondaryIndexes> = with_memory_manager(|memory_manager| {
+        RefCell::new(SecondaryIndexes {
+            timestamp: StableBTreeMap::init(memory_manager.get(TIMESTAMP_INDEX_MEMORY_ID), std::mem::size_of::<TimestampIndexKey>() as u32, 0),
+            caller: StableBTreeMap::init(memory_manager.get(CALLER_INDEX_MEMORY_ID), CallerIndexKey::SIZE as u32, 0),
+            operation: StableBTreeMap::init(memory_manager.get(OPERATION_INDEX_MEMORY_ID), OperationIndexKey::SIZE as u32, 0),
+            state: SecondaryIndexStateCell::init(memory_manager.get(SECONDARY_INDEX_STATE_MEMORY_ID), SecondaryIndexState::default()).expect("failed to initialize secondary index state"),
+        })
+    });
 }
 
 /// Reserve the first stable memory page for the configuration stable cell.
@@ -123,6 +192,33 @@ fn with_anchor_index_mut<R>(f: impl FnOnce(&mut AnchorIndex) -> R) -> R {
     ANCHOR_INDEX.with(|cell| f(&mut *cell.borrow_mut()))
 }
 
+/// A helper function to access the sequence number state.
+fn with_sequence_state<R>(f: impl FnOnce(&SequenceState) -> R) -> R {
+    SEQUENCE_STATE.with(|cell| f(cell.borrow().get()))
+}
+
+/// A helper function to modify the sequence number state.
+fn with_sequence_state_mut<R>(f: impl FnOnce(&mut SequenceState) -> R) -> R {
+    SEQUENCE_STATE.with(|cell| {
+        let mut state = cell.borrow().get().clone();
+        let result = f(&mut state);
+        cell.borrow_mut()
+            .set(state)
+            .expect("failed to write sequence state");
+        result
+    })
+}
+
+/// A helper function to access the secondary indices.
+fn with_secondary_indexes<R>(f: impl FnOnce(&SecondaryIndexes) -> R) -> R {
+    SECONDARY_INDEXES.with(|cell| f(&*cell.borrow()))
+}
+
+/// A helper function to modify the secondary indices.
+fn with_secondary_indexes_mut<R>(f: impl FnOnce(&mut SecondaryIndexes) -> R) -> R {
+    SECONDARY_INDEXES.with(|cell| f(&mut *cell.borrow_mut()))
+}
+
 /// Configuration state of the archive.
 enum ConfigState {
     Uninitialized, // This state is only used between wasm module initialization and init().
@@ -170,6 +266,157 @@ impl Storable for ConfigState {
     }
 }
 
+/// Sequence numbers of the entries pushed by II using [append_entries].
+#[derive(Clone, Debug, Default, Eq, PartialEq, CandidType, Deserialize)]
+struct SequenceState {
+    /// The highest sequence number stored, if any.
+    highest_sequence_number: Option<u64>,
+    /// The sequence number from which on entries are tracked. This is 0 unless entries had already
+    /// been written using [write_entry] when the first entry was appended.
+    first_tracked_sequence_number: u64,
+    /// Ranges of missing sequence numbers below the highest sequence number, ordered ascending and
+    /// non-overlapping.
+    gaps: Vec<SequenceNumberGap>,
+    /// Gaps that were not recorded because [MAX_RECORDED_GAPS] gaps were recorded already.
+    unrecorded_gaps: Option<UnrecordedGaps>,
+}
+
+/// Missing sequence numbers that are not part of the recorded gaps.
+/// Since they are not known individually, entries with these sequence numbers are not stored if
+/// they are delivered later (they cannot be told apart from duplicates).
+#[derive(Clone, Debug, Eq, PartialEq, CandidType, Deserialize)]
+struct UnrecordedGaps {
+    /// The lowest sequence number of the unrecorded gaps.
+    start: u64,
+    /// The number of sequence numbers covered by the unrecorded gaps.
+    missing_entries: u64,
+}
+
+impl SequenceState {
+    /// Records the given sequence number and returns whether the corresponding entry needs to be
+    /// stored, i.e. it is neither a duplicate nor older than the first tracked sequence number.
+    /// `log_empty` is used to determine where tracking starts on the first entry.
+    fn record(&mut self, sequence_number: u64, log_empty: bool) -> bool {
+        let highest = match self.highest_sequence_number {
+            None => {
+                if log_empty {
+                    self.add_gap(0, sequence_number);
+                } else {
+                    self.first_tracked_sequence_number = sequence_number;
+                }
+                self.highest_sequence_number = Some(sequence_number);
+                return true;
+            }
+            Some(highest) => highest,
+        };
+
+        if sequence_number > highest {
+            self.add_gap(highest + 1, sequence_number);
+            self.highest_sequence_number = Some(sequence_number);
+            return true;
+        }
+        self.fill_gap(sequence_number)
+    }
+
+    /// Records the sequence numbers in the range [start, end) as missing.
+    fn add_gap(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+        if self.gaps.len() >= MAX_RECORDED_GAPS {
+            let unrecorded = self.unrecorded_gaps.get_or_insert(UnrecordedGaps {
+                start,
+                missing_entries: 0,
+            });
+            unrecorded.missing_entries += end - start;
+            return;
+        }
+        self.gaps.push(SequenceNumberGap {
+            start,
+            end: end - 1,
+        });
+    }
+
+    /// Removes the given sequence number from the recorded gaps.
+    /// Returns false if the sequence number was not missing.
+    fn fill_gap(&mut self, sequence_number: u64) -> bool {
+        let Some(idx) = self
+            .gaps
+            .iter()
+            .position(|gap| gap.start <= sequence_number && sequence_number <= gap.end)
+        else {
+            return false;
+        };
+        let gap = self.gaps[idx].clone();
+        match (gap.start == sequence_number, gap.end == sequence_number) {
+            (true, true) => {
+                self.gaps.remove(idx);
+            }
+            (true, false) => self.gaps[idx].start = sequence_number + 1,
+            (false, true) => self.gaps[idx].end = sequence_number - 1,
+            (false, false) => {
+                self.gaps[idx].end = sequence_number - 1;
+                self.gaps.insert(
+                    idx + 1,
+                    SequenceNumberGap {
+                        start: sequence_number + 1,
+                        end: gap.end,
+                    },
+                );
+            }
+        }
+        true
+    }
+
+    /// The highest sequence number such that all entries from the first tracked sequence number up
+    /// to it have been stored.
+    fn highest_contiguous_sequence_number(&self) -> Option<u64> {
+        let first_missing = self
+            .gaps
+            .first()
+            .map(|gap| gap.start)
+            .into_iter()
+            .chain(self.unrecorded_gaps.as_ref().map(|gaps| gaps.start))
+            .min();
+        match first_missing {
+            None => self.highest_sequence_number,
+            Some(start) => start.checked_sub(1),
+        }
+    }
+
+    fn missing_entries(&self) -> u64 {
+        let unrecorded = self
+            .unrecorded_gaps
+            .as_ref()
+            .map_or(0, |gaps| gaps.missing_entries);
+        self.gaps
+            .iter()
+            .map(|gap| gap.end - gap.start + 1)
+            .sum::<u64>()
+            + unrecorded
+    }
+
+    fn health(&self) -> ArchiveHealth {
+        ArchiveHealth {
+            highest_sequence_number: self.highest_sequence_number,
+            highest_contiguous_sequence_number: self.highest_contiguous_sequence_number(),
+            first_tracked_sequence_number: self.first_tracked_sequence_number,
+            missing_entries: self.missing_entries(),
+            gaps: self.gaps.clone(),
+        }
+    }
+}
+
+impl Storable for SequenceState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode sequence state"))
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode sequence state")
+    }
+}
+
 /// Index key for the anchor index.
 /// Changing the (serialized) size of this value requires a stable memory migration.
 #[derive(Eq, PartialEq, Debug)]
@@ -218,8 +465,192 @@ impl Storable for AnchorIndexKey {
     }
 }
 
+/// Indices to retrieve entries across anchors.
+struct SecondaryIndexes {
+    timestamp: TimestampIndex,
+    caller: CallerIndex,
+    operation: OperationIndex,
+    state: SecondaryIndexStateCell,
+}
+
+/// Progress of building the secondary indices from the log.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct SecondaryIndexState {
+    /// Number of log entries (starting from the first) processed by the secondary indices.
+    indexed_entries: u64,
+}
+
+impl Storable for SecondaryIndexState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode secondary index state"))
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode secondary index state")
+    }
+}
+
+/// Index key for the timestamp index. The serialized key is also used as the next_token returned
+/// by [get_filtered_entries] and as the offset for range scans on all secondary indices.
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug)]
+struct TimestampIndexKey {
+    timestamp: Timestamp,
+    log_index: LogIndex,
+}
+
+impl Storable for TimestampIndexKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(std::mem::size_of::<TimestampIndexKey>());
+        buf.extend(&self.timestamp.to_be_bytes());
+        buf.extend(&self.log_index.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        TimestampIndexKey {
+            timestamp: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[0..8]).expect("failed to read timestamp"),
+            ),
+            log_index: u64::from_be_bytes(
+                TryFrom::try_from(&bytes[8..16]).expect("failed to read log_index"),
+            ),
+        }
+    }
+}
+
+/// Index key for the caller index.
+/// The caller is stored as a length byte followed by the principal padded to [MAX_PRINCIPAL_LEN].
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug)]
+struct CallerIndexKey {
+    caller: Principal,
+    key: TimestampIndexKey,
+}
+
+impl CallerIndexKey {
+    const SIZE: usize = 1 + MAX_PRINCIPAL_LEN + std::mem::size_of::<TimestampIndexKey>();
+
+    fn prefix(caller: &Principal) -> Vec<u8> {
+        let bytes = caller.as_slice();
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.push(bytes.len() as u8);
+        buf.extend(bytes);
+        buf.resize(1 + MAX_PRINCIPAL_LEN, 0);
+        buf
+    }
+}
+
+impl Storable for CallerIndexKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Self::prefix(&self.caller);
+        buf.extend(self.key.to_bytes().as_ref());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        let len = bytes[0] as usize;
+        CallerIndexKey {
+            caller: Principal::from_slice(&bytes[1..1 + len]),
+            key: TimestampIndexKey::from_bytes(bytes[1 + MAX_PRINCIPAL_LEN..].to_vec()),
+        }
+    }
+}
+
+/// Index key for the operation index.
+/// Changing the (serialized) size of this value requires a stable memory migration.
+#[derive(Eq, PartialEq, Debug)]
+struct OperationIndexKey {
+    operation: OperationType,
+    key: TimestampIndexKey,
+}
+
+impl OperationIndexKey {
+    const SIZE: usize = 1 + std::mem::size_of::<TimestampIndexKey>();
+}
+
+impl Storable for OperationIndexKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.push(operation_tag(&self.operation));
+        buf.extend(self.key.to_bytes().as_ref());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        OperationIndexKey {
+            operation: operation_from_tag(bytes[0]),
+            key: TimestampIndexKey::from_bytes(bytes[1..].to_vec()),
+        }
+    }
+}
+
+/// Stable representation of the operation type in the operation index.
+/// Existing values must never be changed.
+fn operation_tag(operation: &OperationType) -> u8 {
+    match operation {
+        OperationType::RegisterAnchor => 0,
+        OperationType::AddDevice => 1,
+        OperationType::UpdateDevice => 2,
+        OperationType::RemoveDevice => 3,
+        OperationType::DeleteAnchor => 4,
+        OperationType::QueueRecoveryOperation => 5,
+        OperationType::ExecuteRecoveryOperation => 6,
+        OperationType::CancelRecoveryOperation => 7,
+        OperationType::SetGuardians => 8,
+        OperationType::ApproveGuardianRecovery => 9,
+    }
+}
+
+fn operation_from_tag(tag: u8) -> OperationType {
+    match tag {
+        0 => OperationType::RegisterAnchor,
+        1 => OperationType::AddDevice,
+        2 => OperationType::UpdateDevice,
+        3 => OperationType::RemoveDevice,
+        4 => OperationType::DeleteAnchor,
+        5 => OperationType::QueueRecoveryOperation,
+        6 => OperationType::ExecuteRecoveryOperation,
+        7 => OperationType::CancelRecoveryOperation,
+        8 => OperationType::SetGuardians,
+        9 => OperationType::ApproveGuardianRecovery,
+        _ => trap(&format!("unknown operation tag {}", tag)),
+    }
+}
+
 #[update]
 fn write_entry(anchor: Anchor, timestamp: Timestamp, entry: ByteBuf) {
+    check_caller();
+    append_entry(anchor, timestamp, entry);
+    update_secondary_indexes();
+}
+
+/// Stores a batch of entries pushed by II and returns the highest sequence number stored.
+/// Entries that have already been stored by a previous call are skipped. Sequence numbers skipped
+/// by II are recorded as gaps (see [get_archive_health]).
+#[update]
+fn append_entries(entries: Vec<BufferedEntry>) -> u64 {
+    check_caller();
+    if entries.is_empty() {
+        trap("no entries to append");
+    }
+
+    with_sequence_state_mut(|state| {
+        for entry in entries {
+            let log_empty = with_log(|log| log.len() == 0);
+            if !state.record(entry.sequence_number, log_empty) {
+                continue;
+            }
+            append_entry(entry.anchor, entry.timestamp, entry.entry);
+        }
+        update_secondary_indexes();
+        state
+            .highest_sequence_number
+            .expect("bug: no sequence number after appending entries")
+    })
+}
+
+fn check_caller() {
     with_config(|config| {
         if config.ii_canister != caller() {
             trap(&format!(
@@ -228,6 +659,9 @@ fn write_entry(anchor: Anchor, timestamp: Timestamp, entry: ByteBuf) {
             ))
         }
     });
+}
+
+fn append_entry(anchor: Anchor, timestamp: Timestamp, entry: ByteBuf) {
     let idx = with_log(|log| {
         log.append(entry.as_ref())
             .expect("failed to append log entry")
@@ -246,6 +680,64 @@ fn write_entry(anchor: Anchor, timestamp: Timestamp, entry: ByteBuf) {
     })
 }
 
+/// Adds the log entries not yet processed to the secondary indices (at most
+/// [MAX_INDEXED_ENTRIES_PER_CALL] many).
+fn update_secondary_indexes() {
+    with_secondary_indexes_mut(|indexes| {
+        let start = indexes.state.get().indexed_entries;
+        let end = with_log(|log| log.len() as u64).min(start + MAX_INDEXED_ENTRIES_PER_CALL);
+
+        for log_index in start..end {
+            let bytes = with_log(|log| log.get(log_index as usize))
+                .expect("bug: index to non-existing entry");
+            // Entries that cannot be decoded are still returned by get_entries and
+            // get_anchor_entries but cannot be filtered.
+            let Ok(entry) = candid::decode_one::<Entry>(&bytes) else {
+                continue;
+            };
+            let key = || TimestampIndexKey {
+                timestamp: entry.timestamp,
+                log_index,
+            };
+
+            // the only way these expects can trigger is when the key size is wrong.
+            indexes
+                .timestamp
+                .insert(key(), ())
+                .expect("bug: key size mismatch");
+            indexes
+                .caller
+                .insert(
+                    CallerIndexKey {
+                        caller: entry.caller,
+                        key: key(),
+                    },
+                    (),
+                )
+                .expect("bug: key size mismatch");
+            indexes
+                .operation
+                .insert(
+                    OperationIndexKey {
+                        operation: OperationType::from(&entry.operation),
+                        key: key(),
+                    },
+                    (),
+                )
+                .expect("bug: key size mismatch");
+        }
+
+        if end > start {
+            indexes
+                .state
+                .set(SecondaryIndexState {
+                    indexed_entries: end,
+                })
+                .expect("failed to write secondary index state");
+        }
+    })
+}
+
 #[query]
 fn get_entries(index: Option<u64>, limit: Option<u16>) -> Entries {
     let limit = limit_or_default(limit);
@@ -346,6 +838,98 @@ fn get_anchor_entries(anchor: Anchor, cursor: Option<Cursor>, limit: Option<u16>
     })
 }
 
+/// Returns information about the completeness of the entries pushed by II.
+#[query]
+fn get_archive_health() -> ArchiveHealth {
+    with_sequence_state(|state| state.health())
+}
+
+/// Returns the entries matching all the criteria of the given filter, ordered by timestamp.
+#[query]
+fn get_filtered_entries(
+    filter: EntryFilter,
+    cursor: Option<Cursor>,
+    limit: Option<u16>,
+) -> FilteredEntries {
+    let limit = limit_or_default(limit);
+    let from_timestamp = filter.from_timestamp.unwrap_or(0);
+    let to_timestamp = filter.to_timestamp.unwrap_or(u64::MAX);
+
+    // All secondary index keys end with (timestamp, log index). Depending on the filter, the most
+    // selective index is scanned starting from that offset and the remaining criteria are checked
+    // on the decoded entries.
+    let offset = match cursor {
+        None => from_timestamp.to_be_bytes().to_vec(),
+        Some(Cursor::Timestamp { timestamp }) => {
+            timestamp.max(from_timestamp).to_be_bytes().to_vec()
+        }
+        Some(Cursor::NextToken { next_token }) => {
+            TimestampIndexKey::from_bytes(next_token.into_vec())
+                .to_bytes()
+                .to_vec()
+        }
+    };
+
+    // Take one too many keys to determine whether there are more keys to be scanned.
+    let keys: Vec<TimestampIndexKey> =
+        with_secondary_indexes(|indexes| match (&filter.caller, &filter.operation) {
+            (Some(caller), _) => indexes
+                .caller
+                .range(CallerIndexKey::prefix(caller), Some(offset))
+                .map(|(key, _)| key.key)
+                .take_while(|key| key.timestamp < to_timestamp)
+                .take(MAX_SCANNED_INDEX_ENTRIES + 1)
+                .collect(),
+            (None, Some(operation)) => indexes
+                .operation
+                .range(vec![operation_tag(operation)], Some(offset))
+                .map(|(key, _)| key.key)
+                .take_while(|key| key.timestamp < to_timestamp)
+                .take(MAX_SCANNED_INDEX_ENTRIES + 1)
+                .collect(),
+            (None, None) => indexes
+                .timestamp
+                .range(vec![], Some(offset))
+                .map(|(key, _)| key)
+                .take_while(|key| key.timestamp < to_timestamp)
+                .take(MAX_SCANNED_INDEX_ENTRIES + 1)
+                .collect(),
+        });
+
+    let mut entries = Vec::with_capacity(limit);
+    let mut cursor = None;
+    with_log(|log| {
+        for (scanned, key) in keys.into_iter().enumerate() {
+            if entries.len() == limit || scanned == MAX_SCANNED_INDEX_ENTRIES {
+                cursor = Some(Cursor::NextToken {
+                    next_token: ByteBuf::from(key.to_bytes()),
+                });
+                break;
+            }
+            let bytes = log
+                .get(key.log_index as usize)
+                .expect("bug: index to non-existing entry");
+            let entry: Entry = candid::decode_one(&bytes).expect("failed to decode log entry");
+            if matches_filter(&filter, &entry) {
+                entries.push(Some(entry));
+            }
+        }
+    });
+
+    FilteredEntries { entries, cursor }
+}
+
+fn matches_filter(filter: &EntryFilter, entry: &Entry) -> bool {
+    filter
+        .from_timestamp
+        .map_or(true, |from| entry.timestamp >= from)
+        && filter.to_timestamp.map_or(true, |to| entry.timestamp < to)
+        && filter.caller.map_or(true, |caller| entry.caller == caller)
+        && filter.operation.as_ref().map_or(true, |operation| {
+            *operation == OperationType::from(&entry.operation)
+        })
+}
+
 fn limit_or_default(limit: Option<u16>) -> usize {
     with_config(|config| {
         limit
@@ -439,6 +1023,32 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             "Total size of the log data in bytes.",
         )
     })?;
+    with_sequence_state(|state| {
+        if let Some(sequence_number) = state.highest_sequence_number {
+            w.encode_gauge(
+                "ii_archive_highest_sequence_number",
+                sequence_number as f64,
+                "Highest sequence number of the entries pushed by II.",
+            )?;
+        }
+        if let Some(sequence_number) = state.highest_contiguous_sequence_number() {
+            w.encode_gauge(
+                "ii_archive_highest_contiguous_sequence_number",
+                sequence_number as f64,
+                "Highest sequence number up to which all entries pushed by II have been stored.",
+            )?;
+        }
+        w.encode_gauge(
+            "ii_archive_missing_entries_count",
+            state.missing_entries() as f64,
+            "Number of entries missing below the highest sequence number.",
+        )?;
+        w.encode_gauge(
+            "ii_archive_sequence_number_gaps_count",
+            state.gaps.len() as f64,
+            "Number of recorded ranges of missing sequence numbers.",
+        )
+    })?;
     with_anchor_index_mut(|index| {
         w.encode_gauge(
             "ii_archive_anchor_index_entries_count",
@@ -446,6 +1056,29 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             "Number of entries in the anchor index.",
         )
     })?;
+    with_secondary_indexes(|indexes| {
+        w.encode_gauge(
+            "ii_archive_timestamp_index_entries_count",
+            indexes.timestamp.len() as f64,
+            "Number of entries in the timestamp index.",
+        )?;
+        w.encode_gauge(
+            "ii_archive_caller_index_entries_count",
+            indexes.caller.len() as f64,
+            "Number of entries in the caller index.",
+        )?;
+        w.encode_gauge(
+            "ii_archive_operation_index_entries_count",
+            indexes.operation.len() as f64,
+            "Number of entries in the operation index.",
+        )?;
+        w.encode_gauge(
+            "ii_archive_unindexed_entries_count",
+            with_log(|log| log.len() as u64).saturating_sub(indexes.state.get().indexed_entries)
+                as f64,
+            "Number of log entries not yet processed by the secondary indices.",
+        )
+    })?;
     MEMORY_MANAGER.with(|cell| {
         let manager = cell.borrow();
         w.encode_gauge(
@@ -462,6 +1095,21 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             "ii_archive_anchor_index_virtual_memory_size",
             manager.get(ANCHOR_ACCESS_INDEX_MEMORY_ID).size() as f64,
             "Number of stable memory pages allocated to the anchor index virtual memory.",
+        )?;
+        w.encode_gauge(
+            "ii_archive_timestamp_index_virtual_memory_size",
+            manager.get(TIMESTAMP_INDEX_MEMORY_ID).size() as f64,
+            "Number of stable memory pages allocated to the timestamp index virtual memory.",
+        )?;
+        w.encode_gauge(
+            "ii_archive_caller_index_virtual_memory_size",
+            manager.get(CALLER_INDEX_MEMORY_ID).size() as f64,
+            "Number of stable memory pages allocated to the caller index virtual memory.",
+        )?;
+        w.encode_gauge(
+            "ii_archive_operation_index_virtual_memory_size",
+            manager.get(OPERATION_INDEX_MEMORY_ID).size() as f64,
+            "Number of stable memory pages allocated to the operation index virtual memory.",
         )
     })?;
     w.encode_gauge(
@@ -0,0 +1,56 @@
+use crate::{CallerIndexKey, OperationIndexKey, TimestampIndexKey};
+use candid::Principal;
+use ic_stable_structures::Storable;
+use internet_identity_interface::OperationType;
+
+#[test]
+fn should_serialize_timestamp_index_key() {
+    let index_key = TimestampIndexKey {
+        timestamp: 5678,
+        log_index: 23,
+    };
+    let bytes = index_key.to_bytes();
+    assert_eq!(
+        bytes,
+        hex::decode("000000000000162e0000000000000017").unwrap()
+    );
+    assert_eq!(TimestampIndexKey::from_bytes(bytes.to_vec()), index_key);
+}
+
+#[test]
+fn should_serialize_caller_index_key_with_fixed_size() {
+    for caller in [
+        Principal::anonymous(),
+        Principal::management_canister(),
+        Principal::self_authenticating([1; 32]),
+    ] {
+        let index_key = CallerIndexKey {
+            caller,
+            key: TimestampIndexKey {
+                timestamp: 5678,
+                log_index: 23,
+            },
+        };
+        let bytes = index_key.to_bytes();
+        assert_eq!(bytes.len(), CallerIndexKey::SIZE);
+        assert!(bytes.starts_with(&CallerIndexKey::prefix(&caller)));
+        assert_eq!(CallerIndexKey::from_bytes(bytes.to_vec()), index_key);
+    }
+}
+
+#[test]
+fn should_serialize_operation_index_key() {
+    let index_key = OperationIndexKey {
+        operation: OperationType::UpdateDevice,
+        key: TimestampIndexKey {
+            timestamp: 5678,
+            log_index: 23,
+        },
+    };
+    let bytes = index_key.to_bytes();
+    assert_eq!(
+        bytes,
+        hex::decode("02000000000000162e0000000000000017").unwrap()
+    );
+    assert_eq!(OperationIndexKey::from_bytes(bytes.to_vec()), index_key);
+}
@@ -0,0 +1,109 @@
+use crate::{SequenceState, MAX_RECORDED_GAPS};
+use internet_identity_interface::SequenceNumberGap;
+
+#[test]
+fn should_track_contiguous_sequence_numbers() {
+    let mut state = SequenceState::default();
+
+    assert!(state.record(0, true));
+    assert!(state.record(1, false));
+    assert!(state.record(2, false));
+
+    assert_eq!(state.highest_contiguous_sequence_number(), Some(2));
+    assert!(state.gaps.is_empty());
+}
+
+#[test]
+fn should_record_missing_first_entries() {
+    let mut state = SequenceState::default();
+
+    assert!(state.record(3, true));
+
+    assert_eq!(state.highest_contiguous_sequence_number(), None);
+    assert_eq!(state.gaps, vec![SequenceNumberGap { start: 0, end: 2 }]);
+    assert_eq!(state.missing_entries(), 3);
+}
+
+#[test]
+fn should_skip_duplicates() {
+    let mut state = SequenceState::default();
+
+    assert!(state.record(0, true));
+    assert!(state.record(5, false));
+
+    assert!(!state.record(0, false));
+    assert!(!state.record(5, false));
+    assert_eq!(state.missing_entries(), 4);
+}
+
+#[test]
+fn should_split_gap_when_filling_the_middle() {
+    let mut state = SequenceState::default();
+    state.record(0, true);
+    state.record(10, false);
+
+    assert!(state.record(5, false));
+
+    assert_eq!(
+        state.gaps,
+        vec![
+            SequenceNumberGap { start: 1, end: 4 },
+            SequenceNumberGap { start: 6, end: 9 }
+        ]
+    );
+    assert_eq!(state.highest_contiguous_sequence_number(), Some(0));
+
+    assert!(state.record(1, false));
+    assert!(state.record(9, false));
+    assert_eq!(
+        state.gaps,
+        vec![
+            SequenceNumberGap { start: 2, end: 4 },
+            SequenceNumberGap { start: 6, end: 8 }
+        ]
+    );
+    assert_eq!(state.highest_contiguous_sequence_number(), Some(1));
+}
+
+/// Records the gaps 1, 3, ..., 2 * MAX_RECORDED_GAPS + 1, so that the last one is not recorded.
+fn state_with_too_many_gaps() -> SequenceState {
+    let mut state = SequenceState::default();
+    state.record(0, true);
+    for i in 1..=MAX_RECORDED_GAPS as u64 + 1 {
+        state.record(2 * i, false);
+    }
+    state
+}
+
+#[test]
+fn should_only_count_gaps_when_full() {
+    let state = state_with_too_many_gaps();
+
+    assert_eq!(state.gaps.len(), MAX_RECORDED_GAPS);
+    assert_eq!(state.gaps[0], SequenceNumberGap { start: 1, end: 1 });
+    assert_eq!(state.missing_entries(), MAX_RECORDED_GAPS as u64 + 1);
+    assert_eq!(state.highest_contiguous_sequence_number(), Some(0));
+}
+
+#[test]
+fn should_skip_duplicates_when_gaps_are_full() {
+    let mut state = state_with_too_many_gaps();
+
+    for i in 0..=MAX_RECORDED_GAPS as u64 + 1 {
+        assert!(!state.record(2 * i, false));
+    }
+    // the entries of unrecorded gaps cannot be told apart from duplicates
+    assert!(!state.record(2 * MAX_RECORDED_GAPS as u64 + 1, false));
+    assert_eq!(state.missing_entries(), MAX_RECORDED_GAPS as u64 + 1);
+
+    // the entries of recorded gaps are still stored
+    for i in 0..MAX_RECORDED_GAPS as u64 {
+        assert!(state.record(2 * i + 1, false));
+    }
+    assert!(state.gaps.is_empty());
+    assert_eq!(state.missing_entries(), 1);
+    assert_eq!(
+        state.highest_contiguous_sequence_number(),
+        Some(2 * MAX_RECORDED_GAPS as u64)
+    );
+}
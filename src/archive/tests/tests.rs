@@ -4,8 +4,9 @@ use canister_tests::framework::*;
 use ic_state_machine_tests::ErrorCode::CanisterCalledTrap;
 use ic_state_machine_tests::{CanisterId, StateMachine};
 use internet_identity_interface::{
-    ArchiveInit, Cursor, DeviceDataUpdate, DeviceDataWithoutAlias, DeviceProtection, Entry,
-    HttpRequest, KeyType, Operation, Purpose, Timestamp, UserNumber,
+    ArchiveHealth, ArchiveInit, Cursor, DeviceDataUpdate, DeviceDataWithoutAlias, DeviceProtection,
+    Entry, EntryFilter, FilteredEntries, HttpRequest, KeyType, Operation, OperationType, Purpose,
+    SequenceNumberGap, Timestamp, UserNumber,
 };
 use regex::Regex;
 use serde_bytes::ByteBuf;
@@ -109,6 +110,437 @@ mod write_tests {
     }
 }
 
+/// Verifies the batch write functionality used by II.
+#[cfg(test)]
+mod append_tests {
+    use super::*;
+
+    /// Verifies that a batch of entries can be written and the highest sequence number is returned.
+    #[test]
+    fn should_append_entries() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        let highest_sequence_number = api::append_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![buffered_entry(log_entry_1()), buffered_entry(log_entry_2())],
+        )?;
+        assert_eq!(highest_sequence_number, 1);
+
+        let logs = api::get_entries(&env, canister_id, None, None)?;
+        assert_eq!(logs.entries.len(), 2);
+        assert_eq!(
+            logs.entries.get(0).unwrap().as_ref().unwrap(),
+            &log_entry_1()
+        );
+        assert_eq!(
+            logs.entries.get(1).unwrap().as_ref().unwrap(),
+            &log_entry_2()
+        );
+        let user_logs = api::get_anchor_entries(&env, canister_id, USER_NUMBER_2, None, None)?;
+        assert_eq!(user_logs.entries.len(), 1);
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_highest_sequence_number",
+            1,
+        );
+        Ok(())
+    }
+
+    /// Verifies that entries of a retried batch are not stored twice.
+    #[test]
+    fn should_skip_already_stored_entries() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        api::append_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![
+                buffered_entry(log_entry(0, TIMESTAMP_1, USER_NUMBER_1)),
+                buffered_entry(log_entry(1, TIMESTAMP_1, USER_NUMBER_1)),
+            ],
+        )?;
+        let highest_sequence_number = api::append_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![
+                buffered_entry(log_entry(1, TIMESTAMP_1, USER_NUMBER_1)),
+                buffered_entry(log_entry(2, TIMESTAMP_1, USER_NUMBER_1)),
+            ],
+        )?;
+        assert_eq!(highest_sequence_number, 2);
+
+        let logs = api::get_entries(&env, canister_id, None, None)?;
+        assert_eq!(logs.entries.len(), 3);
+        assert_eq!(
+            logs.entries.get(2).unwrap().as_ref().unwrap(),
+            &log_entry(2, TIMESTAMP_1, USER_NUMBER_1)
+        );
+        Ok(())
+    }
+
+    /// Verifies that the highest sequence number is kept across upgrades.
+    #[test]
+    fn should_keep_highest_sequence_number_across_upgrades() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        api::append_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![buffered_entry(log_entry(5, TIMESTAMP_1, USER_NUMBER_1))],
+        )?;
+        upgrade_archive_canister(&env, canister_id, ARCHIVE_WASM.clone());
+
+        let highest_sequence_number = api::append_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![buffered_entry(log_entry(5, TIMESTAMP_1, USER_NUMBER_1))],
+        )?;
+        assert_eq!(highest_sequence_number, 5);
+        let logs = api::get_entries(&env, canister_id, None, None)?;
+        assert_eq!(logs.entries.len(), 1);
+        Ok(())
+    }
+
+    /// Verifies that only the configured ii_canister principal can append entries.
+    #[test]
+    fn should_reject_append_by_wrong_principal() {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        let result = api::append_entries(
+            &env,
+            canister_id,
+            principal_2(),
+            vec![buffered_entry(log_entry_1())],
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Only [\\w-]+ is allowed to write entries\\.").unwrap(),
+        );
+    }
+}
+
+/// Verifies the detection of missing entries.
+#[cfg(test)]
+mod health_tests {
+    use super::*;
+
+    /// Verifies that skipped sequence numbers are reported as gaps.
+    #[test]
+    fn should_report_gaps() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        api::append_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![
+                buffered_entry(log_entry(0, TIMESTAMP_1, USER_NUMBER_1)),
+                buffered_entry(log_entry(1, TIMESTAMP_1, USER_NUMBER_1)),
+                buffered_entry(log_entry(4, TIMESTAMP_1, USER_NUMBER_1)),
+                buffered_entry(log_entry(7, TIMESTAMP_1, USER_NUMBER_1)),
+            ],
+        )?;
+
+        let health = api::get_archive_health(&env, canister_id)?;
+        assert_eq!(
+            health,
+            ArchiveHealth {
+                highest_sequence_number: Some(7),
+                highest_contiguous_sequence_number: Some(1),
+                first_tracked_sequence_number: 0,
+                missing_entries: 4,
+                gaps: vec![
+                    SequenceNumberGap { start: 2, end: 3 },
+                    SequenceNumberGap { start: 5, end: 6 },
+                ],
+            }
+        );
+        let metrics = get_metrics(&env, canister_id);
+        assert_metric(&metrics, "ii_archive_highest_contiguous_sequence_number", 1);
+        assert_metric(&metrics, "ii_archive_missing_entries_count", 4);
+        assert_metric(&metrics, "ii_archive_sequence_number_gaps_count", 2);
+        Ok(())
+    }
+
+    /// Verifies that entries delivered late are stored and close the corresponding gap.
+    #[test]
+    fn should_fill_gaps_with_late_entries() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        api::append_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![
+                buffered_entry(log_entry(0, TIMESTAMP_1, USER_NUMBER_1)),
+                buffered_entry(log_entry(3, TIMESTAMP_1, USER_NUMBER_1)),
+            ],
+        )?;
+        upgrade_archive_canister(&env, canister_id, ARCHIVE_WASM.clone());
+        let highest_sequence_number = api::append_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![
+                buffered_entry(log_entry(1, TIMESTAMP_1, USER_NUMBER_1)),
+                buffered_entry(log_entry(2, TIMESTAMP_1, USER_NUMBER_1)),
+            ],
+        )?;
+        assert_eq!(highest_sequence_number, 3);
+
+        let health = api::get_archive_health(&env, canister_id)?;
+        assert_eq!(health.highest_contiguous_sequence_number, Some(3));
+        assert_eq!(health.missing_entries, 0);
+        assert!(health.gaps.is_empty());
+        let logs = api::get_entries(&env, canister_id, None, None)?;
+        assert_eq!(logs.entries.len(), 4);
+        Ok(())
+    }
+
+    /// Verifies that entries written before sequence numbers were tracked are not reported as missing.
+    #[test]
+    fn should_start_tracking_at_first_appended_entry() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            USER_NUMBER_1,
+            TIMESTAMP_1,
+            candid::encode_one(log_entry(0, TIMESTAMP_1, USER_NUMBER_1))
+                .expect("failed to encode entry"),
+        )?;
+        api::append_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![buffered_entry(log_entry(1, TIMESTAMP_1, USER_NUMBER_1))],
+        )?;
+
+        let health = api::get_archive_health(&env, canister_id)?;
+        assert_eq!(health.first_tracked_sequence_number, 1);
+        assert_eq!(health.highest_contiguous_sequence_number, Some(1));
+        assert!(health.gaps.is_empty());
+        Ok(())
+    }
+}
+
+/// Verifies the filtered queries across anchors.
+#[cfg(test)]
+mod filter_tests {
+    use super::*;
+
+    fn append_entries(env: &StateMachine, canister_id: CanisterId, entries: Vec<Entry>) {
+        api::append_entries(
+            env,
+            canister_id,
+            principal_1(),
+            entries.into_iter().map(buffered_entry).collect(),
+        )
+        .expect("failed to append entries");
+    }
+
+    fn sequence_numbers(entries: &FilteredEntries) -> Vec<u64> {
+        entries
+            .entries
+            .iter()
+            .map(|entry| entry.as_ref().unwrap().sequence_number)
+            .collect()
+    }
+
+    /// Verifies that entries of all anchors can be retrieved by timestamp range.
+    #[test]
+    fn should_filter_by_timestamp_range() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        append_entries(
+            &env,
+            canister_id,
+            (0..10).map(|n| log_entry(n, 100 + n, n % 3)).collect(),
+        );
+
+        let entries = api::get_filtered_entries(
+            &env,
+            canister_id,
+            EntryFilter {
+                from_timestamp: Some(103),
+                to_timestamp: Some(107),
+                ..EntryFilter::default()
+            },
+            None,
+            None,
+        )?;
+        assert_eq!(sequence_numbers(&entries), vec![3, 4, 5, 6]);
+        assert!(entries.cursor.is_none());
+        Ok(())
+    }
+
+    /// Verifies that entries can be retrieved by caller.
+    #[test]
+    fn should_filter_by_caller() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        append_entries(
+            &env,
+            canister_id,
+            vec![
+                log_entry(0, TIMESTAMP_1, USER_NUMBER_1),
+                log_entry(1, TIMESTAMP_1, USER_NUMBER_2),
+                log_entry(2, TIMESTAMP_2, USER_NUMBER_3),
+            ],
+        );
+
+        let caller = log_entry(1, TIMESTAMP_1, USER_NUMBER_2).caller;
+        let entries = api::get_filtered_entries(
+            &env,
+            canister_id,
+            EntryFilter {
+                caller: Some(caller),
+                ..EntryFilter::default()
+            },
+            None,
+            None,
+        )?;
+        assert_eq!(sequence_numbers(&entries), vec![1]);
+        assert_eq!(entries.entries[0].as_ref().unwrap().caller, caller);
+        Ok(())
+    }
+
+    /// Verifies that entries can be retrieved by operation type, combined with other criteria.
+    #[test]
+    fn should_filter_by_operation_and_caller() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        let register_entry = log_entry_1();
+        let add_device_entry = log_entry_2();
+        append_entries(
+            &env,
+            canister_id,
+            vec![
+                register_entry.clone(),
+                add_device_entry.clone(),
+                log_entry(2, TIMESTAMP_2, USER_NUMBER_1),
+            ],
+        );
+
+        let entries = api::get_filtered_entries(
+            &env,
+            canister_id,
+            EntryFilter {
+                operation: Some(OperationType::AddDevice),
+                ..EntryFilter::default()
+            },
+            None,
+            None,
+        )?;
+        assert_eq!(entries.entries, vec![Some(add_device_entry)]);
+
+        let entries = api::get_filtered_entries(
+            &env,
+            canister_id,
+            EntryFilter {
+                operation: Some(OperationType::RegisterAnchor),
+                caller: Some(principal_1().0),
+                ..EntryFilter::default()
+            },
+            None,
+            None,
+        )?;
+        assert_eq!(entries.entries, vec![Some(register_entry)]);
+
+        let entries = api::get_filtered_entries(
+            &env,
+            canister_id,
+            EntryFilter {
+                operation: Some(OperationType::RemoveDevice),
+                ..EntryFilter::default()
+            },
+            None,
+            None,
+        )?;
+        assert!(entries.entries.is_empty());
+        Ok(())
+    }
+
+    /// Verifies that all matching entries can be retrieved using the cursor.
+    #[test]
+    fn should_paginate_filtered_entries() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        append_entries(
+            &env,
+            canister_id,
+            (0..25).map(|n| log_entry(n, 100 + n / 2, n % 2)).collect(),
+        );
+        let filter = EntryFilter {
+            from_timestamp: Some(101),
+            operation: Some(OperationType::UpdateDevice),
+            ..EntryFilter::default()
+        };
+
+        let mut cursor = None;
+        let mut sequence_nrs = vec![];
+        loop {
+            let entries =
+                api::get_filtered_entries(&env, canister_id, filter.clone(), cursor, Some(5))?;
+            assert!(entries.entries.len() <= 5);
+            sequence_nrs.extend(sequence_numbers(&entries));
+            cursor = entries.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(sequence_nrs, (2..25).collect::<Vec<u64>>());
+        Ok(())
+    }
+
+    /// Verifies that entries written using write_entry are indexed as well, unless they cannot be decoded.
+    #[test]
+    fn should_index_written_entries() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            USER_NUMBER_1,
+            TIMESTAMP_1,
+            vec![1, 2, 3, 4], // not candid
+        )?;
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            USER_NUMBER_1,
+            TIMESTAMP_1,
+            candid::encode_one(log_entry_1()).expect("failed to encode entry"),
+        )?;
+
+        let entries =
+            api::get_filtered_entries(&env, canister_id, EntryFilter::default(), None, None)?;
+        assert_eq!(entries.entries, vec![Some(log_entry_1())]);
+        let metrics = get_metrics(&env, canister_id);
+        assert_metric(&metrics, "ii_archive_timestamp_index_entries_count", 1);
+        assert_metric(&metrics, "ii_archive_unindexed_entries_count", 0);
+        Ok(())
+    }
+}
+
 /// Verifies the read functionality of the archive canister.
 #[cfg(test)]
 mod read_tests {
@@ -660,6 +1092,7 @@ mod stable_memory_tests {
                     purpose: Purpose::Authentication,
                     key_type: KeyType::Unknown,
                     protection: DeviceProtection::Unprotected,
+                    protection_policy: None,
                 },
             },
             timestamp: TIMESTAMP,
@@ -695,6 +1128,7 @@ mod stable_memory_tests {
                     purpose: Some(Purpose::Recovery),
                     key_type: None,
                     protection: None,
+                    protection_policy: None,
                 },
             },
             timestamp: TIMESTAMP,
@@ -1,2 +1,3 @@
 pub mod archive;
 pub mod internet_identity;
+pub mod test_app;
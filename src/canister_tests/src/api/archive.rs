@@ -20,6 +20,15 @@ pub fn add_entry(
     )
 }
 
+pub fn append_entries(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    entries: Vec<types::BufferedEntry>,
+) -> Result<u64, CallError> {
+    framework::call_candid_as(env, canister_id, sender, "append_entries", (entries,)).map(|(x,)| x)
+}
+
 pub fn get_entries(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -45,6 +54,29 @@ pub fn get_anchor_entries(
     .map(|(x,)| x)
 }
 
+pub fn get_filtered_entries(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    filter: types::EntryFilter,
+    cursor: Option<types::Cursor>,
+    limit: Option<u16>,
+) -> Result<types::FilteredEntries, CallError> {
+    framework::query_candid(
+        env,
+        canister_id,
+        "get_filtered_entries",
+        (filter, cursor, limit),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn get_archive_health(
+    env: &StateMachine,
+    canister_id: CanisterId,
+) -> Result<types::ArchiveHealth, CallError> {
+    framework::query_candid(env, canister_id, "get_archive_health", ()).map(|(x,)| x)
+}
+
 pub fn http_request(
     env: &StateMachine,
     canister_id: CanisterId,
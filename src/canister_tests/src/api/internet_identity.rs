@@ -4,6 +4,7 @@ use candid::Principal;
 use ic_state_machine_tests::{CanisterId, PrincipalId, StateMachine};
 use internet_identity_interface as types;
 use serde_bytes::ByteBuf;
+use std::collections::HashMap;
 
 /** The functions here are derived (manually) from Internet Identity's Candid file */
 
@@ -56,6 +57,8 @@ pub fn prepare_delegation(
     frontend_hostname: types::FrontendHostname,
     session_key: types::SessionKey,
     max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    derivation_origin: Option<types::DerivationOrigin>,
 ) -> Result<(types::UserKey, types::Timestamp), CallError> {
     framework::call_candid_as(
         env,
@@ -67,6 +70,8 @@ pub fn prepare_delegation(
             frontend_hostname,
             session_key,
             max_time_to_live,
+            targets,
+            derivation_origin,
         ),
     )
 }
@@ -79,13 +84,22 @@ pub fn get_delegation(
     frontend_hostname: types::FrontendHostname,
     session_key: types::SessionKey,
     timestamp: u64,
+    targets: Option<Vec<Principal>>,
+    derivation_origin: Option<types::FrontendHostname>,
 ) -> Result<types::GetDelegationResponse, CallError> {
     framework::query_candid_as(
         env,
         canister_id,
         sender,
         "get_delegation",
-        (user_number, frontend_hostname, session_key, timestamp),
+        (
+            user_number,
+            frontend_hostname,
+            session_key,
+            timestamp,
+            targets,
+            derivation_origin,
+        ),
     )
     .map(|(x,)| x)
 }
@@ -96,17 +110,89 @@ pub fn get_principal(
     sender: PrincipalId,
     user_number: types::UserNumber,
     frontend_hostname: types::FrontendHostname,
+    derivation_origin: Option<types::DerivationOrigin>,
 ) -> Result<Principal, CallError> {
     framework::query_candid_as(
         env,
         canister_id,
         sender,
         "get_principal",
+        (user_number, frontend_hostname, derivation_origin),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn revoke_delegations(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    frontend_hostname: types::FrontendHostname,
+) -> Result<types::Timestamp, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "revoke_delegations",
         (user_number, frontend_hostname),
     )
     .map(|(x,)| x)
 }
 
+pub fn get_delegations_not_before(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    principal: Principal,
+) -> Result<types::DelegationsNotBefore, CallError> {
+    framework::query_candid(env, canister_id, "get_delegations_not_before", (principal,))
+        .map(|(x,)| x)
+}
+
+pub fn prepare_attestation(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    frontend_hostname: types::FrontendHostname,
+    claim: types::AttestationClaim,
+    derivation_origin: Option<types::DerivationOrigin>,
+) -> Result<types::PrepareAttestationResponse, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "prepare_attestation",
+        (user_number, frontend_hostname, claim, derivation_origin),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn get_attestation(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    frontend_hostname: types::FrontendHostname,
+    claim: types::AttestationClaim,
+    issued_at: types::Timestamp,
+    derivation_origin: Option<types::FrontendHostname>,
+) -> Result<types::GetAttestationResponse, CallError> {
+    framework::query_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_attestation",
+        (
+            user_number,
+            frontend_hostname,
+            claim,
+            issued_at,
+            derivation_origin,
+        ),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn lookup(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -115,6 +201,14 @@ pub fn lookup(
     framework::query_candid(env, canister_id, "lookup", (user_number,)).map(|(x,)| x)
 }
 
+pub fn lookup_anchors(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    device: types::DeviceIdentifier,
+) -> Result<Vec<types::UserNumber>, CallError> {
+    framework::query_candid(env, canister_id, "lookup_anchors", (device,)).map(|(x,)| x)
+}
+
 pub fn add(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -158,6 +252,15 @@ pub fn remove(
     )
 }
 
+pub fn delete_anchor(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<(), CallError> {
+    framework::call_candid_as(env, canister_id, sender, "delete_anchor", (user_number,))
+}
+
 pub fn get_anchor_info(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -168,6 +271,176 @@ pub fn get_anchor_info(
         .map(|(x,)| x)
 }
 
+pub fn get_anchor_metadata(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<HashMap<String, types::MetadataEntry>, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_anchor_metadata",
+        (user_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn set_anchor_metadata(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    metadata: HashMap<String, types::MetadataEntry>,
+) -> Result<(), CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "set_anchor_metadata",
+        (user_number, metadata),
+    )
+}
+
+pub fn get_delegation_lifetime_limits(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<types::DelegationLifetimeLimits, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_delegation_lifetime_limits",
+        (user_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn set_delegation_lifetime_limits(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    limits: types::DelegationLifetimeLimits,
+) -> Result<(), CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "set_delegation_lifetime_limits",
+        (user_number, limits),
+    )
+}
+
+pub fn get_recovery_delay(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<Option<u64>, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_recovery_delay",
+        (user_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn set_recovery_delay(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    recovery_delay: Option<u64>,
+) -> Result<(), CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "set_recovery_delay",
+        (user_number, recovery_delay),
+    )
+}
+
+pub fn get_pending_recovery_operations(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<Vec<types::PendingRecoveryOperation>, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_pending_recovery_operations",
+        (user_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn cancel_pending_recovery_operations(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<(), CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "cancel_pending_recovery_operations",
+        (user_number,),
+    )
+}
+
+pub fn get_sessions(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<Vec<types::Session>, CallError> {
+    framework::call_candid_as(env, canister_id, sender, "get_sessions", (user_number,))
+        .map(|(x,)| x)
+}
+
+pub fn create_webauthn_challenge(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<types::WebAuthnChallenge, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "create_webauthn_challenge",
+        (user_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn verify_webauthn_assertion(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    assertion: types::WebAuthnAssertion,
+) -> Result<types::VerifyWebAuthnAssertionResponse, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "verify_webauthn_assertion",
+        (user_number, assertion),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn enter_device_registration_mode(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -233,6 +506,84 @@ pub fn verify_tentative_device(
     .map(|(x,)| x)
 }
 
+pub fn get_guardians(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+) -> Result<Option<types::Guardians>, CallError> {
+    framework::call_candid_as(env, canister_id, sender, "get_guardians", (user_number,))
+        .map(|(x,)| x)
+}
+
+pub fn set_guardians(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    guardians: Option<types::Guardians>,
+) -> Result<(), CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "set_guardians",
+        (user_number, guardians),
+    )
+}
+
+pub fn start_guardian_recovery(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    user_number: types::UserNumber,
+    device_data: types::DeviceData,
+) -> Result<types::StartGuardianRecoveryResponse, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "start_guardian_recovery",
+        (user_number, device_data),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn get_guardian_recovery(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    guardian_number: types::UserNumber,
+    user_number: types::UserNumber,
+) -> Result<Option<types::GuardianRecoveryInfo>, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_guardian_recovery",
+        (guardian_number, user_number),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn approve_guardian_recovery(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: PrincipalId,
+    guardian_number: types::UserNumber,
+    user_number: types::UserNumber,
+    device_key: types::DeviceKey,
+) -> Result<types::ApproveGuardianRecoveryResponse, CallError> {
+    framework::call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "approve_guardian_recovery",
+        (guardian_number, user_number, device_key),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn deploy_archive(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -0,0 +1,35 @@
+use crate::framework;
+use crate::framework::CallError;
+use candid::CandidType;
+use ic_state_machine_tests::{CanisterId, StateMachine};
+use internet_identity_interface as types;
+
+/// Behaviour of the /.well-known/ii-alternative-origins asset of the test app.
+#[derive(Clone, Debug, CandidType)]
+pub enum AlternativeOriginsMode {
+    CertifiedContent,
+    UncertifiedContent,
+    Redirect { location: String },
+}
+
+pub fn update_alternative_origins(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    content: &str,
+    mode: AlternativeOriginsMode,
+) -> Result<(), CallError> {
+    framework::call_candid(
+        env,
+        canister_id,
+        "update_alternative_origins",
+        (content, mode),
+    )
+}
+
+pub fn http_request(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    http_request: types::HttpRequest,
+) -> Result<types::HttpResponse, CallError> {
+    framework::query_candid(env, canister_id, "http_request", (http_request,)).map(|(x,)| x)
+}
@@ -6,7 +6,7 @@ use crate::certificate_validation::ValidationError::{
 };
 use flate2::read::GzDecoder;
 use ic_certification::{verify_certificate, CertificateValidationError};
-use ic_sdk_types::hash_tree::LookupResult;
+use ic_sdk_types::hash_tree::{Label, LookupResult};
 use ic_sdk_types::HashTree;
 use ic_state_machine_tests::{CanisterId, ThresholdSigPublicKey, Time};
 use regex::Regex;
@@ -26,6 +26,7 @@ pub enum ValidationError {
     CertificateExpired,
     AssetPathLookupFailed,
     AssetHashMismatch,
+    LookupFailed,
 }
 
 /// Validates asset certification according to the HTTP gateway specification:
@@ -91,6 +92,42 @@ pub fn validate_certification(
     Ok(())
 }
 
+/// Validates a certified lookup (a CBOR encoded map with the certificate and the hash tree, i.e. the
+/// format of canister signatures) and returns the value at the given path, or None if the tree
+/// proves that the path is absent.
+pub fn validate_certified_lookup(
+    certified_lookup: &[u8],
+    canister_id: CanisterId,
+    path: &[&[u8]],
+    root_key: ThresholdSigPublicKey,
+) -> Result<Option<Vec<u8>>, ValidationError> {
+    #[derive(Deserialize)]
+    struct CertifiedLookup {
+        #[serde(with = "serde_bytes")]
+        certificate: Vec<u8>,
+        tree: HashTree,
+    }
+
+    let lookup: CertifiedLookup =
+        serde_cbor::from_slice(certified_lookup).map_err(|err| MalformedCertificate {
+            message: format!("failed to decode cbor value: {:?}", err),
+        })?;
+    verify_certificate(
+        &lookup.certificate,
+        &canister_id,
+        &root_key,
+        &lookup.tree.digest(),
+    )
+    .map_err(|err| ValidationError::CertificateValidationFailed { inner: err })?;
+
+    let path: Vec<Label> = path.iter().map(|label| (*label).into()).collect();
+    match lookup.tree.lookup_path(&path) {
+        LookupResult::Found(value) => Ok(Some(value.to_vec())),
+        LookupResult::Absent => Ok(None),
+        _ => Err(ValidationError::LookupFailed),
+    }
+}
+
 fn parse_header(ic_certificate: &str) -> Result<(&str, &str), ValidationError> {
     let captures = Regex::new("^certificate=:([^:]*):,\\s*tree=:([^:]*):$")
         .unwrap()
@@ -1,9 +1,12 @@
 use crate::api::internet_identity::{create_challenge, http_request, register};
+use crate::api::test_app;
 use crate::framework::{device_data_1, principal_1};
 use ic_state_machine_tests::{CanisterId, PrincipalId, StateMachine};
 use internet_identity_interface::{
-    ChallengeAttempt, DeviceData, HttpRequest, RegisterResponse, UserNumber,
+    CertifiedAlternativeOrigins, ChallengeAttempt, DerivationOrigin, DeviceData, HttpRequest,
+    RegisterResponse, UserNumber,
 };
+use regex::Regex;
 use serde_bytes::ByteBuf;
 
 pub fn register_anchor(env: &StateMachine, canister_id: CanisterId) -> UserNumber {
@@ -47,3 +50,44 @@ pub fn get_metrics(env: &StateMachine, canister_id: CanisterId) -> String {
     .expect("HTTP request to /metrics failed");
     String::from_utf8_lossy(&*response.body).to_string()
 }
+
+/// Fetches the alternative origins of the given test app canister including the certificate and
+/// tree of the IC-Certificate header.
+pub fn certified_alternative_origins(
+    env: &StateMachine,
+    test_app: CanisterId,
+) -> CertifiedAlternativeOrigins {
+    let response = test_app::http_request(
+        &env,
+        test_app,
+        HttpRequest {
+            method: "GET".to_string(),
+            url: "/.well-known/ii-alternative-origins".to_string(),
+            headers: vec![],
+            body: ByteBuf::new(),
+        },
+    )
+    .expect("HTTP request to /.well-known/ii-alternative-origins failed");
+    let (_, ic_certificate) = response
+        .headers
+        .iter()
+        .find(|(name, _)| name == "IC-Certificate")
+        .expect("IC-Certificate header missing");
+    let captures = Regex::new("^certificate=:([^:]*):,\\s*tree=:([^:]*):$")
+        .unwrap()
+        .captures(ic_certificate)
+        .expect("malformed IC-Certificate header");
+    CertifiedAlternativeOrigins {
+        body: ByteBuf::from(response.body.to_vec()),
+        certificate: ByteBuf::from(base64::decode(&captures[1]).unwrap()),
+        tree: ByteBuf::from(base64::decode(&captures[2]).unwrap()),
+    }
+}
+
+/// Derivation origin served by the given test app canister.
+pub fn derivation_origin(env: &StateMachine, test_app: CanisterId) -> DerivationOrigin {
+    DerivationOrigin {
+        origin: format!("https://{}.ic0.app", test_app.get()),
+        alternative_origins: certified_alternative_origins(env, test_app),
+    }
+}
@@ -69,6 +69,20 @@ lazy_static! {
         get_wasm_path("II_WASM".to_string(), &def_path).expect(&err)
     };
 
+    /** The Wasm module of the test app, which is used to serve the alternative origins of derivation origins */
+    pub static ref TEST_APP_WASM: Vec<u8> = {
+        let def_path = path::PathBuf::from("..").join("..").join("demos").join("test-app").join("test_app.wasm");
+        let err = format!("
+        Could not find test app Wasm module.
+
+        I will look for it at {:?}, and you can specify another path with the environment variable TEST_APP_WASM (note that I run from {:?}).
+
+        In order to build the Wasm module, please run the following command:
+            ./demos/test-app/build.sh
+        ", &def_path, &std::env::current_dir().map(|x| x.display().to_string()).unwrap_or("an unknown directory".to_string()));
+        get_wasm_path("TEST_APP_WASM".to_string(), &def_path).expect(&err)
+    };
+
     /** Empty WASM module (without any pre- and post-upgrade hooks. Useful to initialize a canister before loading a stable memory backup. */
     pub static ref EMPTY_WASM: Vec<u8> = vec![0, 0x61, 0x73, 0x6D, 1, 0, 0, 0];
 }
@@ -128,9 +142,91 @@ pub fn arg_with_wasm_hash(wasm: Vec<u8>) -> Option<types::InternetIdentityInit>
         assigned_user_number_range: None,
         archive_module_hash: Some(archive_wasm_hash(&wasm)),
         canister_creation_cycles_cost: Some(0),
+        layout_migration_batch_size: None,
+        ic_root_key_der: None,
+        webauthn_verification: None,
+        register_rate_limit: None,
+        captcha_type: None,
+    })
+}
+
+/// Init args configuring the root key of the state machine, so that II can verify certificates.
+pub fn arg_with_root_key(env: &StateMachine) -> Option<types::InternetIdentityInit> {
+    Some(types::InternetIdentityInit {
+        assigned_user_number_range: None,
+        archive_module_hash: None,
+        canister_creation_cycles_cost: None,
+        layout_migration_batch_size: None,
+        ic_root_key_der: Some(root_key_der(env)),
+        webauthn_verification: None,
+        register_rate_limit: None,
+        captcha_type: None,
     })
 }
 
+/// Init args enabling the canister-side verification of WebAuthn assertions for recovery devices.
+pub fn arg_with_webauthn_verification() -> Option<types::InternetIdentityInit> {
+    Some(types::InternetIdentityInit {
+        assigned_user_number_range: None,
+        archive_module_hash: None,
+        canister_creation_cycles_cost: None,
+        layout_migration_batch_size: None,
+        ic_root_key_der: None,
+        webauthn_verification: Some(types::WebAuthnVerificationConfig {
+            allowed_origins: vec![WEBAUTHN_ORIGIN.to_string()],
+            require_user_verification: true,
+            required_for_recovery_devices: true,
+        }),
+        register_rate_limit: None,
+        captcha_type: None,
+    })
+}
+
+/// Init args configuring the type of captchas issued by create_challenge.
+pub fn arg_with_captcha_type(
+    captcha_type: types::CaptchaType,
+) -> Option<types::InternetIdentityInit> {
+    Some(types::InternetIdentityInit {
+        assigned_user_number_range: None,
+        archive_module_hash: None,
+        canister_creation_cycles_cost: None,
+        layout_migration_batch_size: None,
+        ic_root_key_der: None,
+        webauthn_verification: None,
+        register_rate_limit: None,
+        captcha_type: Some(captcha_type),
+    })
+}
+
+/// Init args configuring the rate limit of captcha challenges and registrations.
+pub fn arg_with_rate_limit(config: types::RateLimitConfig) -> Option<types::InternetIdentityInit> {
+    Some(types::InternetIdentityInit {
+        assigned_user_number_range: None,
+        archive_module_hash: None,
+        canister_creation_cycles_cost: None,
+        layout_migration_batch_size: None,
+        ic_root_key_der: None,
+        webauthn_verification: None,
+        register_rate_limit: Some(config),
+        captcha_type: None,
+    })
+}
+
+pub fn install_test_app_canister(env: &StateMachine) -> CanisterId {
+    env.install_canister(TEST_APP_WASM.clone(), vec![], None)
+        .unwrap()
+}
+
+/// The DER encoded root key of the state machine, to be used as `ic_root_key_der` in the II init args.
+pub fn root_key_der(env: &StateMachine) -> ByteBuf {
+    // DER prefix of BLS12-381 public keys
+    let mut der =
+        hex::decode("308182301d060d2b0601040182dc7c0503010201060c2b0601040182dc7c05030201036100")
+            .unwrap();
+    der.extend(env.root_key().into_bytes());
+    ByteBuf::from(der)
+}
+
 pub fn archive_wasm_hash(wasm: &Vec<u8>) -> [u8; 32] {
     let mut hasher = Sha256::new();
     hasher.update(&wasm);
@@ -208,6 +304,9 @@ pub fn device_data_1() -> types::DeviceData {
         purpose: types::Purpose::Authentication,
         key_type: types::KeyType::Unknown,
         protection: types::DeviceProtection::Unprotected,
+        created_at: None,
+        last_used: None,
+        protection_policy: None,
     }
 }
 
@@ -219,6 +318,9 @@ pub fn device_data_2() -> types::DeviceData {
         purpose: types::Purpose::Authentication,
         key_type: types::KeyType::Unknown,
         protection: types::DeviceProtection::Unprotected,
+        created_at: None,
+        last_used: None,
+        protection_policy: None,
     }
 }
 
@@ -230,6 +332,9 @@ pub fn recovery_device_data_1() -> types::DeviceData {
         purpose: types::Purpose::Recovery,
         key_type: types::KeyType::SeedPhrase,
         protection: types::DeviceProtection::Unprotected,
+        created_at: None,
+        last_used: None,
+        protection_policy: None,
     }
 }
 
@@ -241,6 +346,87 @@ pub fn recovery_device_data_2() -> types::DeviceData {
         purpose: types::Purpose::Recovery,
         key_type: types::KeyType::SeedPhrase,
         protection: types::DeviceProtection::Unprotected,
+        created_at: None,
+        last_used: None,
+        protection_policy: None,
+    }
+}
+
+pub const WEBAUTHN_ORIGIN: &str = "https://identity.ic0.app";
+pub const WEBAUTHN_CREDENTIAL_ID: [u8; 4] = [1, 2, 3, 4];
+
+/// ES256 key of the WebAuthn device returned by `webauthn_device_data`.
+pub fn webauthn_signing_key() -> p256::ecdsa::SigningKey {
+    p256::ecdsa::SigningKey::from_bytes(&[42; 32]).unwrap()
+}
+
+pub fn principal_webauthn() -> PrincipalId {
+    PrincipalId(Principal::self_authenticating(
+        webauthn_device_data().pubkey,
+    ))
+}
+
+/// A device with a DER-wrapped COSE (ES256) public key as created by a WebAuthn authenticator.
+pub fn webauthn_device_data() -> types::DeviceData {
+    let point = webauthn_signing_key()
+        .verifying_key()
+        .to_encoded_point(false);
+    let cose_key = serde_cbor::to_vec(&serde_cbor::Value::Map(
+        [
+            (1, serde_cbor::Value::Integer(2)),  // kty: EC2
+            (3, serde_cbor::Value::Integer(-7)), // alg: ES256
+            (-1, serde_cbor::Value::Integer(1)), // crv: P-256
+            (-2, serde_cbor::Value::Bytes(point.x().unwrap().to_vec())),
+            (-3, serde_cbor::Value::Bytes(point.y().unwrap().to_vec())),
+        ]
+        .into_iter()
+        .map(|(label, value)| (serde_cbor::Value::Integer(label), value))
+        .collect(),
+    ))
+    .unwrap();
+    // SEQUENCE { SEQUENCE { OID 1.3.6.1.4.1.56387.1.1 }, BIT STRING <COSE key> }
+    let mut bit_string = vec![0x03, cose_key.len() as u8 + 1, 0x00];
+    bit_string.extend(cose_key);
+    let mut key_info = hex::decode("300c060a2b0601040183b8430101").unwrap();
+    key_info.extend(bit_string);
+    let mut pubkey = vec![0x30, key_info.len() as u8];
+    pubkey.extend(key_info);
+
+    types::DeviceData {
+        pubkey: ByteBuf::from(pubkey),
+        alias: "My WebAuthn device".to_string(),
+        credential_id: Some(ByteBuf::from(WEBAUTHN_CREDENTIAL_ID.to_vec())),
+        purpose: types::Purpose::Authentication,
+        key_type: types::KeyType::CrossPlatform,
+        protection: types::DeviceProtection::Unprotected,
+        created_at: None,
+        last_used: None,
+        protection_policy: None,
+    }
+}
+
+/// Signs the challenge like a WebAuthn authenticator with user presence and user verification.
+pub fn webauthn_assertion(challenge: &[u8]) -> types::WebAuthnAssertion {
+    use p256::ecdsa::signature::Signer;
+
+    let client_data_json = format!(
+        r#"{{"type":"webauthn.get","challenge":"{}","origin":"{}"}}"#,
+        base64::encode_config(challenge, base64::URL_SAFE_NO_PAD),
+        WEBAUTHN_ORIGIN
+    );
+    let mut authenticator_data = Sha256::digest("identity.ic0.app").to_vec();
+    authenticator_data.push(0x05); // user present and user verified
+    authenticator_data.extend_from_slice(&1u32.to_be_bytes());
+
+    let mut message = authenticator_data.clone();
+    message.extend(Sha256::digest(&client_data_json));
+    let signature: p256::ecdsa::Signature = webauthn_signing_key().sign(&message);
+
+    types::WebAuthnAssertion {
+        credential_id: ByteBuf::from(WEBAUTHN_CREDENTIAL_ID.to_vec()),
+        authenticator_data: ByteBuf::from(authenticator_data),
+        client_data_json: ByteBuf::from(client_data_json),
+        signature: ByteBuf::from(signature.to_der().as_bytes().to_vec()),
     }
 }
 
@@ -455,7 +641,7 @@ frame-ancestors 'none';$"
 }
 
 pub fn parse_metric(body: &str, metric: &str) -> (u64, SystemTime) {
-    let metric_capture = Regex::new(&format!("(?m)^{} (\\d+) (\\d+)$", metric))
+    let metric_capture = Regex::new(&format!("(?m)^{} (\\d+) (\\d+)$", regex::escape(metric)))
         .unwrap()
         .captures(body)
         .expect(&format!("metric {} not found", metric));
@@ -478,10 +664,19 @@ pub fn verify_delegation(
 ) {
     // transform delegation into ic typed delegation so that we have access to the signature domain separator
     // (via as_signed_bytes)
-    let delegation = Delegation::new(
-        signed_delegation.delegation.pubkey.clone().into_vec(),
-        Time::from_nanos_since_unix_epoch(signed_delegation.delegation.expiration),
-    );
+    let pubkey = signed_delegation.delegation.pubkey.clone().into_vec();
+    let expiration = Time::from_nanos_since_unix_epoch(signed_delegation.delegation.expiration);
+    let delegation = match signed_delegation.delegation.targets.clone() {
+        None => Delegation::new(pubkey, expiration),
+        Some(targets) => Delegation::new_with_targets(
+            pubkey,
+            expiration,
+            targets
+                .into_iter()
+                .map(canister_id_from_principal)
+                .collect(),
+        ),
+    };
 
     // this requires imports of internal crypto infrastructure
     // -> extend state-machine-tests to offer the functionality instead (see L2-739)
@@ -494,6 +689,63 @@ pub fn verify_delegation(
     .expect("signature invalid");
 }
 
+/// Verifies the canister signature of an attestation issued by II. The signed message is the
+/// representation independent hash of the attestation, prefixed with the domain separator.
+pub fn verify_attestation(
+    env: &StateMachine,
+    user_key: types::UserKey,
+    signed_attestation: &types::SignedAttestation,
+) {
+    const DOMAIN_SEPARATOR: &[u8] = b"ic-ii-anchor-attestation";
+
+    let attestation = &signed_attestation.attestation;
+    let mut fields: Vec<(&str, Vec<u8>)> = vec![("issued_at", leb128(attestation.issued_at))];
+    match attestation.claim {
+        types::AttestationClaim::CreatedBefore(timestamp) => {
+            fields.push(("claim", b"created_before".to_vec()));
+            fields.push(("created_before", leb128(timestamp)));
+        }
+        types::AttestationClaim::HasRecoveryDevice => {
+            fields.push(("claim", b"has_recovery_device".to_vec()));
+        }
+    }
+    let mut field_hashes: Vec<Vec<u8>> = fields
+        .iter()
+        .map(|(key, value)| {
+            let mut field_hash = Sha256::digest(key.as_bytes()).to_vec();
+            field_hash.extend_from_slice(&Sha256::digest(value));
+            field_hash
+        })
+        .collect();
+    field_hashes.sort();
+    let map_hash = Sha256::digest(field_hashes.concat());
+
+    let mut message = vec![DOMAIN_SEPARATOR.len() as u8];
+    message.extend_from_slice(DOMAIN_SEPARATOR);
+    message.extend_from_slice(&map_hash);
+
+    verify(
+        &message,
+        SignatureBytes(signed_attestation.signature.clone().into_vec()),
+        public_key_bytes_from_der(user_key.as_ref()).unwrap(),
+        &env.root_key(),
+    )
+    .expect("signature invalid");
+}
+
+fn leb128(mut value: u64) -> Vec<u8> {
+    let mut buf = vec![];
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return buf;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
 pub fn deploy_archive_via_ii(env: &StateMachine, ii_canister: CanisterId) -> CanisterId {
     match api::internet_identity::deploy_archive(
         &env,
@@ -551,6 +803,7 @@ pub fn log_entry_1() -> types::Entry {
                 purpose: types::Purpose::Authentication,
                 key_type: types::KeyType::Unknown,
                 protection: types::DeviceProtection::Unprotected,
+                protection_policy: None,
             },
         },
         sequence_number: 0,
@@ -569,12 +822,23 @@ pub fn log_entry_2() -> types::Entry {
                 purpose: types::Purpose::Authentication,
                 key_type: types::KeyType::Unknown,
                 protection: types::DeviceProtection::Unprotected,
+                protection_policy: None,
             },
         },
         sequence_number: 1,
     }
 }
 
+/// Wraps the entry the same way II does when pushing entries to the archive.
+pub fn buffered_entry(entry: types::Entry) -> types::BufferedEntry {
+    types::BufferedEntry {
+        anchor: entry.anchor,
+        timestamp: entry.timestamp,
+        sequence_number: entry.sequence_number,
+        entry: ByteBuf::from(candid::encode_one(entry).expect("failed to encode entry")),
+    }
+}
+
 pub fn log_entry(idx: u64, timestamp: u64, anchor: types::Anchor) -> types::Entry {
     types::Entry {
         timestamp,
@@ -588,6 +852,7 @@ pub fn log_entry(idx: u64, timestamp: u64, anchor: types::Anchor) -> types::Entr
                 purpose: Some(types::Purpose::Authentication),
                 key_type: None,
                 protection: Some(types::DeviceProtection::Unprotected),
+                protection_policy: None,
             },
         },
         sequence_number: idx,
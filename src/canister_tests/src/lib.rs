@@ -1,5 +1,5 @@
 //! Here you'll find modules related to testing the canisters:
-//!  * `api`: Rust-bindings for the II and archive canisters
+//!  * `api`: Rust-bindings for the II, archive and test app canisters
 //!  * `certificate_validation`: Validation logic for asset certification
 //!  * `flows`: Reusable flows consisting of multiple canister interactions
 //!  * `framework`: Helpers of various kinds for writing tests.
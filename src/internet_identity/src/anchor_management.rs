@@ -1,15 +1,22 @@
 use crate::archive::{archive_operation, device_diff};
-use crate::state::RegistrationState::DeviceTentativelyAdded;
+use crate::state::RegistrationState::{DeviceTentativelyAdded, GuardianRecoveryRequested};
 use crate::state::{Anchor, DeviceDataInternal, TentativeDeviceRegistration};
-use crate::{delegation, state, trap_if_not_authenticated};
+use crate::{delegation, secs_to_nanos, state, trap_if_not_authenticated, webauthn};
 use candid::Principal;
 use ic_cdk::api::time;
 use ic_cdk::{caller, trap};
 use internet_identity_interface::*;
+use std::collections::HashMap;
 
+pub mod guardian_recovery;
+pub mod recovery_delay;
 pub mod registration;
 pub mod tentative_device_registration;
 
+const MAX_ENTRIES_PER_USER: usize = 10;
+// 1 day
+const DEVICE_USAGE_GRANULARITY_NS: u64 = secs_to_nanos(24 * 60 * 60);
+
 pub fn get_anchor_info(user_number: UserNumber) -> IdentityAnchorInfo {
     let anchor = state::anchor(user_number);
     trap_if_not_authenticated(&anchor);
@@ -17,111 +24,147 @@ pub fn get_anchor_info(user_number: UserNumber) -> IdentityAnchorInfo {
     let devices = anchor.devices.into_iter().map(DeviceData::from).collect();
     let now = time();
 
-    state::tentative_device_registrations(|tentative_device_registrations| {
-        match tentative_device_registrations.get(&user_number) {
-            Some(TentativeDeviceRegistration {
+    match state::storage(|storage| storage.tentative_device_registration(user_number)) {
+        Some(TentativeDeviceRegistration {
+            expiration,
+            state:
+                DeviceTentativelyAdded {
+                    tentative_device, ..
+                }
+                | GuardianRecoveryRequested {
+                    tentative_device, ..
+                },
+        }) if expiration > now => IdentityAnchorInfo {
+            devices,
+            device_registration: Some(DeviceRegistrationInfo {
                 expiration,
-                state:
-                    DeviceTentativelyAdded {
-                        tentative_device, ..
-                    },
-            }) if *expiration > now => IdentityAnchorInfo {
+                tentative_device: Some(tentative_device),
+            }),
+        },
+        Some(TentativeDeviceRegistration { expiration, .. }) if expiration > now => {
+            IdentityAnchorInfo {
                 devices,
                 device_registration: Some(DeviceRegistrationInfo {
-                    expiration: *expiration,
-                    tentative_device: Some(tentative_device.clone()),
+                    expiration,
+                    tentative_device: None,
                 }),
-            },
-            Some(TentativeDeviceRegistration { expiration, .. }) if *expiration > now => {
-                IdentityAnchorInfo {
-                    devices,
-                    device_registration: Some(DeviceRegistrationInfo {
-                        expiration: *expiration,
-                        tentative_device: None,
-                    }),
-                }
             }
-            None | Some(_) => IdentityAnchorInfo {
-                devices,
-                device_registration: None,
-            },
         }
-    })
+        None | Some(_) => IdentityAnchorInfo {
+            devices,
+            device_registration: None,
+        },
+    }
 }
 
 pub async fn add(user_number: UserNumber, device_data: DeviceData) {
-    const MAX_ENTRIES_PER_USER: usize = 10;
-
     let mut anchor = state::anchor(user_number);
     // must be called before the first await because it requires caller()
     trap_if_not_authenticated(&anchor);
     let caller = caller(); // caller is only available before await
     state::ensure_salt_set().await;
 
-    check_device(&device_data, &anchor.devices);
+    check_entry_limits(&device_data);
+
+    let recovery_delay = recovery_delay::applicable_delay(&anchor, caller);
+    let operation =
+        add_device(&mut anchor.devices, device_data.clone()).unwrap_or_else(|err| trap(&err));
+    if let Some(delay) = recovery_delay {
+        recovery_delay::queue(
+            user_number,
+            delay,
+            caller,
+            RecoveryDeviceChange::Add(device_data),
+            operation,
+        );
+        return;
+    }
 
-    if anchor
-        .devices
-        .iter()
-        .find(|e| e.pubkey == device_data.pubkey)
-        .is_some()
-    {
-        trap("Device already added.");
+    record_device_usage(&mut anchor, caller);
+    write_anchor_data(user_number, anchor);
+    state::usage_metrics_mut(|metrics| {
+        metrics.add_device_counter += 1;
+    });
+
+    delegation::prune_expired_signatures();
+
+    archive_operation(user_number, caller, operation);
+}
+
+/// Adds a new device to the given devices and returns the operation to archive.
+/// Fails if the device violates the invariants checked by [check_device], is already added or if
+/// there are too many devices.
+fn add_device(
+    entries: &mut Vec<DeviceDataInternal>,
+    device_data: DeviceData,
+) -> Result<Operation, String> {
+    check_device_invariants(&device_data, entries)?;
+
+    if entries.iter().any(|e| e.pubkey == device_data.pubkey) {
+        return Err("Device already added.".to_string());
     }
 
-    if anchor.devices.len() >= MAX_ENTRIES_PER_USER {
-        trap(&format!(
+    if entries.len() >= MAX_ENTRIES_PER_USER {
+        return Err(format!(
             "at most {} authentication information entries are allowed per user",
             MAX_ENTRIES_PER_USER,
         ));
     }
 
-    anchor
-        .devices
-        .push(DeviceDataInternal::from(device_data.clone()));
-    write_anchor_data(user_number, anchor);
-
-    delegation::prune_expired_signatures();
-
-    archive_operation(
-        user_number,
-        caller,
-        Operation::AddDevice {
-            device: DeviceDataWithoutAlias::from(device_data),
-        },
-    );
+    entries.push(DeviceDataInternal {
+        created_at: Some(time()),
+        ..DeviceDataInternal::from(device_data.clone())
+    });
+    Ok(Operation::AddDevice {
+        device: DeviceDataWithoutAlias::from(device_data),
+    })
 }
 
 /// Replace or remove an existing device.
 ///
-/// NOTE: all mutable operations should call this function because it handles device protection
+/// NOTE: all mutable operations should call this function (or [mutate_device]) because it handles
+/// device protection
 fn mutate_device_or_trap(
+    user_number: UserNumber,
     entries: &mut Vec<DeviceDataInternal>,
     device_key: DeviceKey,
     new_value: Option<DeviceData>,
 ) -> Operation {
-    let index = match entries.iter().position(|e| e.pubkey == device_key) {
-        None => trap("Could not find device to mutate, check device key"),
-        Some(index) => index,
-    };
+    // Recovery devices may additionally require a canister-verified WebAuthn assertion
+    if entries
+        .iter()
+        .any(|e| e.pubkey == device_key && e.purpose == Some(Purpose::Recovery))
+    {
+        webauthn::trap_if_no_verified_assertion(user_number, entries);
+    }
 
-    let device = entries.get_mut(index).unwrap();
+    mutate_device(entries, device_key, new_value, caller()).unwrap_or_else(|err| trap(&err))
+}
 
-    // Run appropriate checks for protected devices
-    match device.protection {
-        None => (),
-        Some(DeviceProtection::Unprotected) => (),
-        Some(DeviceProtection::Protected) => {
-            // If the call is not authenticated with the device to mutate, abort
-            if caller() != Principal::self_authenticating(&device.pubkey) {
-                trap("Device is protected. Must be authenticated with this device to mutate");
-            }
-        }
-    };
+/// Replaces or removes an existing device on behalf of `principal`, which must be the principal
+/// of the device if the change is restricted by its protection policy (see [check_protection]).
+/// Returns the operation to archive.
+fn mutate_device(
+    entries: &mut Vec<DeviceDataInternal>,
+    device_key: DeviceKey,
+    new_value: Option<DeviceData>,
+    principal: Principal,
+) -> Result<Operation, String> {
+    let index = entries
+        .iter()
+        .position(|e| e.pubkey == device_key)
+        .ok_or_else(|| "Could not find device to mutate, check device key".to_string())?;
+
+    let device = entries.get_mut(index).unwrap();
+    let new_value = new_value.map(|device_data| DeviceDataInternal {
+        created_at: device.created_at,
+        last_used: device.last_used,
+        ..DeviceDataInternal::from(device_data)
+    });
+    check_protection(device, new_value.as_ref(), principal)?;
 
-    match new_value {
-        Some(device_data) => {
-            let internal_device = device_data.into();
+    let operation = match new_value {
+        Some(internal_device) => {
             let diff = device_diff(device, &internal_device);
             *device = internal_device;
             Operation::UpdateDevice {
@@ -135,7 +178,8 @@ fn mutate_device_or_trap(
             entries.remove(index);
             Operation::RemoveDevice { device: device_key }
         }
-    }
+    };
+    Ok(operation)
 }
 
 pub async fn update(user_number: UserNumber, device_key: DeviceKey, device_data: DeviceData) {
@@ -147,9 +191,29 @@ pub async fn update(user_number: UserNumber, device_key: DeviceKey, device_data:
     trap_if_not_authenticated(&anchor);
     check_device(&device_data, &anchor.devices);
 
-    let operation = mutate_device_or_trap(&mut anchor.devices, device_key, Some(device_data));
+    let recovery_delay = recovery_delay::applicable_delay(&anchor, caller());
+    let operation = mutate_device_or_trap(
+        user_number,
+        &mut anchor.devices,
+        device_key,
+        Some(device_data.clone()),
+    );
+    if let Some(delay) = recovery_delay {
+        recovery_delay::queue(
+            user_number,
+            delay,
+            caller(),
+            RecoveryDeviceChange::Update(device_data),
+            operation,
+        );
+        return;
+    }
 
+    record_device_usage(&mut anchor, caller());
     write_anchor_data(user_number, anchor);
+    state::usage_metrics_mut(|metrics| {
+        metrics.update_device_counter += 1;
+    });
 
     delegation::prune_expired_signatures();
 
@@ -165,12 +229,238 @@ pub async fn remove(user_number: UserNumber, device_key: DeviceKey) {
     state::ensure_salt_set().await;
     delegation::prune_expired_signatures();
 
-    let operation = mutate_device_or_trap(&mut anchor.devices, device_key, None);
+    let recovery_delay = recovery_delay::applicable_delay(&anchor, caller);
+    let operation =
+        mutate_device_or_trap(user_number, &mut anchor.devices, device_key.clone(), None);
+    if let Some(delay) = recovery_delay {
+        recovery_delay::queue(
+            user_number,
+            delay,
+            caller,
+            RecoveryDeviceChange::Remove(device_key),
+            operation,
+        );
+        return;
+    }
+
+    record_device_usage(&mut anchor, caller);
     write_anchor_data(user_number, anchor);
+    state::usage_metrics_mut(|metrics| {
+        metrics.remove_device_counter += 1;
+    });
 
     archive_operation(user_number, caller, operation);
 }
 
+/// Deletes the anchor with all of its devices and invalidates all outstanding delegation
+/// signatures of the anchor. The anchor number is never assigned again.
+pub fn delete_anchor(user_number: UserNumber) {
+    let anchor = state::anchor(user_number);
+    trap_if_not_authenticated(&anchor);
+
+    // A recovery device could otherwise bypass the recovery delay.
+    if recovery_delay::applicable_delay(&anchor, caller()).is_some() {
+        trap("Anchor cannot be deleted with a recovery device while the recovery delay is enabled");
+    }
+
+    // Deleting the anchor removes all of its devices, so the checks for removing protected and
+    // recovery devices apply as well.
+    for device in &anchor.devices {
+        check_protection(device, None, caller()).unwrap_or_else(|err| trap(&err));
+    }
+    if anchor
+        .devices
+        .iter()
+        .any(|device| device.purpose == Some(Purpose::Recovery))
+    {
+        webauthn::trap_if_no_verified_assertion(user_number, &anchor.devices);
+    }
+
+    let pending_recovery_operations = state::storage_mut(|storage| {
+        storage.delete(user_number).unwrap_or_else(|err| {
+            trap(&format!("failed to delete anchor {}: {}", user_number, err))
+        });
+        storage.remove_tentative_device_registration(user_number);
+        storage.remove_sessions(user_number, |_| true);
+        recovery_delay::remove_pending_operations(storage, user_number)
+    });
+    state::webauthn_challenges_mut(|challenges| challenges.remove(&user_number));
+    state::verified_assertions_mut(|verified_assertions| verified_assertions.remove(&user_number));
+
+    delegation::invalidate_signatures(user_number);
+    delegation::prune_expired_signatures();
+
+    state::usage_metrics_mut(|metrics| {
+        metrics.anchor_operation_counter += 1;
+        metrics.delete_anchor_counter += 1;
+    });
+    for operation in pending_recovery_operations {
+        archive_operation(
+            user_number,
+            caller(),
+            Operation::CancelRecoveryOperation { id: operation.id },
+        );
+    }
+    archive_operation(user_number, caller(), Operation::DeleteAnchor);
+}
+
+/// Fails if the protection policy of the device restricts the change (the removal of the device
+/// if `new_value` is `None`) and `principal` is not the principal of the device itself.
+///
+/// Changing the policy of a device with a non-empty policy is always restricted, as the
+/// restrictions could otherwise be lifted before making the actual change.
+fn check_protection(
+    device: &DeviceDataInternal,
+    new_value: Option<&DeviceDataInternal>,
+    principal: Principal,
+) -> Result<(), String> {
+    if principal == Principal::self_authenticating(&device.pubkey) {
+        return Ok(());
+    }
+
+    let policy = device.effective_protection_policy();
+    let restricted = policy.requires_self_authentication
+        || match new_value {
+            None => policy.protected_from_removal,
+            Some(new_value) => {
+                (policy.protected_from_purpose_change && purpose(device) != purpose(new_value))
+                    || (policy != DeviceProtectionPolicy::default()
+                        && new_value.effective_protection_policy() != policy)
+            }
+        };
+    if restricted {
+        return Err(
+            "Device is protected. Must be authenticated with this device to mutate".to_string(),
+        );
+    }
+    Ok(())
+}
+
+fn purpose(device: &DeviceDataInternal) -> Purpose {
+    device.purpose.clone().unwrap_or(Purpose::Authentication)
+}
+
+/// Returns the limits for the lifetime of the delegations issued for the anchor.
+pub fn get_delegation_lifetime_limits(user_number: UserNumber) -> DelegationLifetimeLimits {
+    let anchor = state::anchor(user_number);
+    trap_if_not_authenticated(&anchor);
+    anchor.delegation_lifetime_limits.unwrap_or_default()
+}
+
+/// Sets the limits for the lifetime of the delegations issued for the anchor, which are enforced
+/// by `prepare_delegation`.
+pub fn set_delegation_lifetime_limits(user_number: UserNumber, limits: DelegationLifetimeLimits) {
+    const MAX_FRONTEND_OVERRIDES: usize = 10;
+
+    let mut anchor = state::anchor(user_number);
+    trap_if_not_authenticated(&anchor);
+
+    if limits.frontend_overrides.len() > MAX_FRONTEND_OVERRIDES {
+        trap(&format!(
+            "at most {} frontend overrides are allowed per user",
+            MAX_FRONTEND_OVERRIDES
+        ));
+    }
+    for (i, (frontend, _)) in limits.frontend_overrides.iter().enumerate() {
+        delegation::check_frontend_length(frontend);
+        if limits.frontend_overrides[..i]
+            .iter()
+            .any(|(other, _)| other == frontend)
+        {
+            trap(&format!("duplicate frontend override for {}", frontend));
+        }
+    }
+
+    anchor.delegation_lifetime_limits = Some(limits);
+    record_device_usage(&mut anchor, caller());
+    write_anchor_data(user_number, anchor);
+}
+
+/// Returns the metadata of the anchor.
+pub fn get_anchor_metadata(user_number: UserNumber) -> HashMap<String, MetadataEntry> {
+    let anchor = state::anchor(user_number);
+    trap_if_not_authenticated(&anchor);
+    anchor.metadata.unwrap_or_default()
+}
+
+/// Replaces the metadata of the anchor.
+pub fn set_anchor_metadata(user_number: UserNumber, metadata: HashMap<String, MetadataEntry>) {
+    let mut anchor = state::anchor(user_number);
+    trap_if_not_authenticated(&anchor);
+
+    check_metadata_limits(&metadata);
+    anchor.metadata = Some(metadata);
+    record_device_usage(&mut anchor, caller());
+    write_anchor_data(user_number, anchor);
+}
+
+/// Checks that the metadata fits into its share of the anchor record size and that the keys are
+/// not too long.
+fn check_metadata_limits(metadata: &HashMap<String, MetadataEntry>) {
+    const KEY_LEN_LIMIT: usize = 64;
+    const METADATA_SIZE_LIMIT: usize = 2048;
+
+    for key in metadata.keys() {
+        if key.len() > KEY_LEN_LIMIT {
+            trap(&format!(
+                "metadata key length {} exceeds the limit of {} bytes",
+                key.len(),
+                KEY_LEN_LIMIT,
+            ));
+        }
+    }
+
+    let n = candid::encode_one(metadata)
+        .expect("failed to encode metadata")
+        .len();
+    if n > METADATA_SIZE_LIMIT {
+        trap(&format!(
+            "metadata size {} exceeds the limit of {} bytes",
+            n, METADATA_SIZE_LIMIT,
+        ));
+    }
+}
+
+/// Records that the device the call is authenticated with was used now.
+///
+/// To limit the number of writes to stable memory, the usage is only recorded if the previously
+/// recorded usage is at least [DEVICE_USAGE_GRANULARITY_NS] old. Returns true if the anchor was
+/// changed.
+fn record_device_usage(anchor: &mut Anchor, caller: Principal) -> bool {
+    let now = time();
+    let device = anchor
+        .devices
+        .iter_mut()
+        .find(|device| caller == Principal::self_authenticating(&device.pubkey));
+    match device {
+        Some(device)
+            if device.last_used.map_or(true, |last_used| {
+                now.saturating_sub(last_used) >= DEVICE_USAGE_GRANULARITY_NS
+            }) =>
+        {
+            device.last_used = Some(now);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Records the usage of the device the call is authenticated with for calls that do not write the
+/// anchor otherwise (see [record_device_usage]).
+pub fn update_device_usage(user_number: UserNumber, caller: Principal) {
+    let mut anchor = state::anchor(user_number);
+    if record_device_usage(&mut anchor, caller) {
+        state::storage_mut(|storage| {
+            storage.write(user_number, anchor).unwrap_or_else(|err| {
+                trap(&format!(
+                    "failed to write data of anchor {}: {}",
+                    user_number, err
+                ))
+            });
+        });
+    }
+}
+
 /// Writes the supplied entries to stable memory and updates the anchor operation metric.
 fn write_anchor_data(user_number: UserNumber, anchor: Anchor) {
     state::storage_mut(|storage| {
@@ -189,25 +479,21 @@ fn write_anchor_data(user_number: UserNumber, anchor: Anchor) {
 
 /// This checks some device invariants, in particular:
 ///   * Sizes of various fields do not exceed limits
-///   * Only recovery phrases can be protected
 ///   * There can only be one recovery phrase
 ///
 ///  Otherwise, trap.
-///
-///  NOTE: while in the future we may lift this restriction, for now we do ensure that
-///  protected devices are limited to recovery phrases, which the webapp expects.
 fn check_device(device_data: &DeviceData, existing_devices: &[DeviceDataInternal]) {
     check_entry_limits(device_data);
+    check_device_invariants(device_data, existing_devices).unwrap_or_else(|err| trap(&err));
+}
 
-    if device_data.protection == DeviceProtection::Protected
-        && device_data.key_type != KeyType::SeedPhrase
-    {
-        trap(&format!(
-            "Only recovery phrases can be protected but key type is {:?}",
-            device_data.key_type
-        ));
-    }
-
+/// Checks the invariants of [check_device] that do not concern the sizes of the fields. Unlike
+/// the sizes, they depend on the other devices of the anchor and must therefore be checked again
+/// when a delayed change is applied (see [recovery_delay]).
+fn check_device_invariants(
+    device_data: &DeviceData,
+    existing_devices: &[DeviceDataInternal],
+) -> Result<(), String> {
     // if the device is a recovery phrase, check if a different recovery phrase already exists
     if device_data.key_type == KeyType::SeedPhrase
         && existing_devices.iter().any(|existing_device| {
@@ -215,8 +501,9 @@ fn check_device(device_data: &DeviceData, existing_devices: &[DeviceDataInternal
                 && existing_device.key_type == Some(KeyType::SeedPhrase)
         })
     {
-        trap("There is already a recovery phrase and only one is allowed.");
+        return Err("There is already a recovery phrase and only one is allowed.".to_string());
     }
+    Ok(())
 }
 
 fn check_entry_limits(device_data: &DeviceData) {
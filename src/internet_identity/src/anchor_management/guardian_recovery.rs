@@ -0,0 +1,298 @@
+//! Social recovery: other anchors (guardians) approve adding a device to a locked-out anchor.
+//!
+//! The user designates guardian anchors and a threshold with `set_guardians`. If they lose access
+//! to all of their devices, they request the recovery from a new device with
+//! `start_guardian_recovery`, which puts the anchor into the same (expiring) tentative device
+//! registration state as the two step device add flow. The guardians then approve the new device
+//! using `approve_guardian_recovery`, authenticated with their own anchors, and the device is
+//! added once `threshold` guardians approved it. The approvals expire together with the tentative
+//! registration.
+//!
+//! Since `start_guardian_recovery` is not authenticated with the anchor, a request that has not
+//! been approved by any guardian yet is replaced by a new one, so that nobody can block the
+//! recovery of an anchor by requesting it first. Guardian recoveries are rate limited and limited
+//! in number separately from the device registrations of the two step device add flow.
+//!
+//! If the recovery delay of the anchor is enabled, the approved device is not added immediately
+//! but queued like a change made with a recovery device (see [recovery_delay]), so that a user
+//! who still has access to the anchor can cancel a recovery approved by colluding guardians.
+//!
+//! The approvals are recorded in the archive, followed by the addition (or queueing) of the
+//! device.
+use crate::anchor_management::registration::rate_limit;
+use crate::anchor_management::tentative_device_registration::prune_expired_tentative_device_registrations;
+use crate::anchor_management::{
+    add_device, check_entry_limits, record_device_usage, recovery_delay, write_anchor_data,
+};
+use crate::archive::archive_operation;
+use crate::state::RegistrationState::GuardianRecoveryRequested;
+use crate::state::TentativeDeviceRegistration;
+use crate::{secs_to_nanos, state, trap_if_not_authenticated};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_cdk::{caller, trap};
+use internet_identity_interface::*;
+
+// 3 days, so that the guardians have some time to react
+const GUARDIAN_RECOVERY_DURATION: u64 = secs_to_nanos(3 * 24 * 60 * 60);
+const MAX_GUARDIANS: usize = 10;
+// How many guardian recoveries can be in progress simultaneously
+const MAX_GUARDIAN_RECOVERIES: u64 = 1_000;
+
+pub fn get_guardians(user_number: UserNumber) -> Option<Guardians> {
+    let anchor = state::anchor(user_number);
+    trap_if_not_authenticated(&anchor);
+    anchor.guardians
+}
+
+/// Replaces the guardians of the anchor (or removes them with `None`).
+pub fn set_guardians(user_number: UserNumber, guardians: Option<Guardians>) {
+    let mut anchor = state::anchor(user_number);
+    trap_if_not_authenticated(&anchor);
+
+    // A recovery device could otherwise appoint guardians to bypass the recovery delay.
+    if recovery_delay::applicable_delay(&anchor, caller()).is_some() {
+        trap("Guardians cannot be set with a recovery device while the recovery delay is enabled");
+    }
+    if let Some(ref guardians) = guardians {
+        check_guardians(user_number, guardians);
+    }
+
+    anchor.guardians = guardians.clone();
+    record_device_usage(&mut anchor, caller());
+    write_anchor_data(user_number, anchor);
+
+    archive_operation(user_number, caller(), Operation::SetGuardians { guardians });
+}
+
+/// Requests adding the device to the anchor with the approval of its guardians. Must be called
+/// with the new device. Replaces a previous request that no guardian has approved yet.
+pub fn start_guardian_recovery(
+    user_number: UserNumber,
+    device_data: DeviceData,
+) -> StartGuardianRecoveryResponse {
+    let caller = caller();
+    if caller != Principal::self_authenticating(device_data.pubkey.clone()) {
+        trap(&format!(
+            "{} could not be authenticated against {:?}",
+            caller, device_data.pubkey
+        ));
+    }
+    // the tentative device is persisted, so it must not exceed the limits of anchor devices
+    check_entry_limits(&device_data);
+    if let Err(()) = rate_limit::check_guardian_recovery_rate_limit() {
+        trap("rate limit reached, try again later");
+    }
+
+    let anchor = state::anchor(user_number);
+    let guardians = match anchor.guardians {
+        Some(guardians) => guardians,
+        None => return StartGuardianRecoveryResponse::NoGuardians,
+    };
+    if anchor
+        .devices
+        .iter()
+        .any(|e| e.pubkey == device_data.pubkey)
+    {
+        trap("Device already added.");
+    }
+
+    state::storage_mut(|storage| {
+        prune_expired_tentative_device_registrations(storage);
+        match storage.tentative_device_registration(user_number) {
+            // nobody vouched for the previous request yet, so it is replaced
+            Some(TentativeDeviceRegistration {
+                state: GuardianRecoveryRequested { approvals, .. },
+                ..
+            }) if current_approvals(approvals, &guardians).is_empty() => {}
+            Some(_) => return StartGuardianRecoveryResponse::RegistrationInProgress,
+            None => {
+                if storage.guardian_recoveries_len() >= MAX_GUARDIAN_RECOVERIES {
+                    trap("too many guardian recoveries in progress");
+                }
+            }
+        }
+
+        let expiration = time() + GUARDIAN_RECOVERY_DURATION;
+        storage.insert_tentative_device_registration(
+            user_number,
+            TentativeDeviceRegistration {
+                expiration,
+                state: GuardianRecoveryRequested {
+                    tentative_device: device_data,
+                    approvals: vec![],
+                },
+            },
+        );
+        StartGuardianRecoveryResponse::Started { expiration }
+    })
+}
+
+/// Returns the guardian recovery of the anchor that is in progress (if any). Must be called by
+/// one of its guardians.
+pub fn get_guardian_recovery(
+    guardian_number: UserNumber,
+    user_number: UserNumber,
+) -> Option<GuardianRecoveryInfo> {
+    trap_if_not_authenticated(&state::anchor(guardian_number));
+    let guardians = match state::anchor(user_number).guardians {
+        Some(guardians) if guardians.anchors.contains(&guardian_number) => guardians,
+        _ => trap(&format!(
+            "{} is not a guardian of {}",
+            guardian_number, user_number
+        )),
+    };
+
+    state::storage_mut(|storage| {
+        prune_expired_tentative_device_registrations(storage);
+        match storage.tentative_device_registration(user_number) {
+            Some(TentativeDeviceRegistration {
+                expiration,
+                state:
+                    GuardianRecoveryRequested {
+                        tentative_device,
+                        approvals,
+                    },
+            }) => Some(GuardianRecoveryInfo {
+                tentative_device,
+                approvals: current_approvals(approvals, &guardians),
+                threshold: guardians.threshold,
+                expiration,
+            }),
+            _ => None,
+        }
+    })
+}
+
+/// Approves adding the device with the given key to the anchor on behalf of the guardian anchor.
+/// The device is added once the threshold of the guardians is reached (or queued if the recovery
+/// delay is enabled).
+pub fn approve_guardian_recovery(
+    guardian_number: UserNumber,
+    user_number: UserNumber,
+    device_key: DeviceKey,
+) -> ApproveGuardianRecoveryResponse {
+    trap_if_not_authenticated(&state::anchor(guardian_number));
+    let caller = caller();
+
+    let mut anchor = state::anchor(user_number);
+    let guardians = match anchor.guardians {
+        Some(ref guardians) if guardians.anchors.contains(&guardian_number) => guardians.clone(),
+        _ => return ApproveGuardianRecoveryResponse::NotAGuardian,
+    };
+
+    let approved = state::storage_mut(|storage| {
+        prune_expired_tentative_device_registrations(storage);
+        let (expiration, tentative_device, mut approvals) =
+            match storage.tentative_device_registration(user_number) {
+                Some(TentativeDeviceRegistration {
+                    expiration,
+                    state:
+                        GuardianRecoveryRequested {
+                            tentative_device,
+                            approvals,
+                        },
+                }) if tentative_device.pubkey == device_key => {
+                    (expiration, tentative_device, approvals)
+                }
+                _ => return None,
+            };
+
+        if !approvals.contains(&guardian_number) {
+            approvals.push(guardian_number);
+        }
+        // guardians that were removed in the meantime no longer count
+        let approvals = current_approvals(approvals, &guardians);
+        if approvals.len() >= guardians.threshold as usize {
+            storage.remove_tentative_device_registration(user_number);
+        } else {
+            storage.insert_tentative_device_registration(
+                user_number,
+                TentativeDeviceRegistration {
+                    expiration,
+                    state: GuardianRecoveryRequested {
+                        tentative_device: tentative_device.clone(),
+                        approvals: approvals.clone(),
+                    },
+                },
+            );
+        }
+        Some((tentative_device, approvals.len() as u16))
+    });
+    let (tentative_device, approvals) = match approved {
+        Some(approved) => approved,
+        None => return ApproveGuardianRecoveryResponse::NoRecoveryInProgress,
+    };
+
+    archive_operation(
+        user_number,
+        caller,
+        Operation::ApproveGuardianRecovery {
+            guardian: guardian_number,
+            device: device_key,
+        },
+    );
+    if approvals < guardians.threshold {
+        return ApproveGuardianRecoveryResponse::Approved {
+            approvals,
+            threshold: guardians.threshold,
+        };
+    }
+
+    let operation =
+        add_device(&mut anchor.devices, tentative_device.clone()).unwrap_or_else(|err| trap(&err));
+    if let Some(delay) = anchor.recovery_delay {
+        // queued on behalf of the new device, which is not a device of the anchor yet
+        recovery_delay::queue(
+            user_number,
+            delay,
+            Principal::self_authenticating(&tentative_device.pubkey),
+            RecoveryDeviceChange::Add(tentative_device),
+            operation,
+        );
+        return ApproveGuardianRecoveryResponse::DeviceAdded;
+    }
+    write_anchor_data(user_number, anchor);
+    state::usage_metrics_mut(|metrics| {
+        metrics.add_device_counter += 1;
+    });
+    archive_operation(user_number, caller, operation);
+    ApproveGuardianRecoveryResponse::DeviceAdded
+}
+
+/// Returns the approvals of anchors that are (still) guardians.
+fn current_approvals(approvals: Vec<UserNumber>, guardians: &Guardians) -> Vec<UserNumber> {
+    approvals
+        .into_iter()
+        .filter(|approval| guardians.anchors.contains(approval))
+        .collect()
+}
+
+/// Checks that the guardians are existing anchors other than the anchor itself and that the
+/// threshold can be reached. Otherwise, trap.
+fn check_guardians(user_number: UserNumber, guardians: &Guardians) {
+    if guardians.anchors.len() > MAX_GUARDIANS {
+        trap(&format!(
+            "at most {} guardians are allowed per user",
+            MAX_GUARDIANS
+        ));
+    }
+    if guardians.threshold == 0 || guardians.threshold as usize > guardians.anchors.len() {
+        trap(&format!(
+            "threshold {} must be between 1 and the number of guardians ({})",
+            guardians.threshold,
+            guardians.anchors.len()
+        ));
+    }
+    for (i, guardian) in guardians.anchors.iter().enumerate() {
+        if *guardian == user_number {
+            trap("an anchor cannot be its own guardian");
+        }
+        if guardians.anchors[..i].contains(guardian) {
+            trap(&format!("duplicate guardian {}", guardian));
+        }
+        if let Err(err) = state::storage(|storage| storage.read(*guardian)) {
+            trap(&format!("invalid guardian {}: {}", guardian, err));
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Opt-in protection against stolen recovery devices (e.g. a leaked recovery phrase).
+//!
+//! If the recovery delay of an anchor is enabled, device changes (`add`, `update` and `remove`)
+//! made with a recovery device do not take effect immediately. Instead, they are queued as
+//! [PendingRecoveryOperation]s which are executed by the heartbeat once the delay has passed.
+//! Until then, the pending operations can be cancelled with any authentication device of the
+//! anchor, so a user who still has access to one of their devices can stop a takeover.
+//!
+//! Devices approved by guardians are queued the same way, on behalf of the device to add (see
+//! [crate::anchor_management::guardian_recovery]).
+//!
+//! Queueing, executing and cancelling an operation are recorded in the archive.
+use crate::anchor_management::{
+    add_device, check_device_invariants, mutate_device, record_device_usage, write_anchor_data,
+};
+use crate::archive::archive_operation;
+use crate::state::Anchor;
+use crate::{secs_to_nanos, state, trap_if_not_authenticated, Storage};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_cdk::{caller, trap};
+use ic_stable_structures::DefaultMemoryImpl;
+use internet_identity_interface::*;
+
+// 1 hour
+const MIN_RECOVERY_DELAY: u64 = secs_to_nanos(60 * 60);
+// 30 days
+const MAX_RECOVERY_DELAY: u64 = secs_to_nanos(30 * 24 * 60 * 60);
+// How many device changes of an anchor can be pending at the same time
+const MAX_PENDING_OPERATIONS_PER_ANCHOR: usize = 5;
+// How many due operations are executed per heartbeat
+const MAX_OPERATIONS_PER_HEARTBEAT: usize = 10;
+
+pub fn get_recovery_delay(user_number: UserNumber) -> Option<u64> {
+    let anchor = state::anchor(user_number);
+    trap_if_not_authenticated(&anchor);
+    anchor.recovery_delay
+}
+
+/// Enables (or disables with `None`) the recovery delay of the anchor. Operations that are
+/// already pending are not affected.
+pub fn set_recovery_delay(user_number: UserNumber, recovery_delay: Option<u64>) {
+    let mut anchor = state::anchor(user_number);
+    trap_if_not_authenticated_with_authentication_device(&anchor);
+
+    if let Some(delay) = recovery_delay {
+        if !(MIN_RECOVERY_DELAY..=MAX_RECOVERY_DELAY).contains(&delay) {
+            trap(&format!(
+                "recovery delay {} is not within the allowed range [{}, {}]",
+                delay, MIN_RECOVERY_DELAY, MAX_RECOVERY_DELAY
+            ));
+        }
+    }
+
+    anchor.recovery_delay = recovery_delay;
+    record_device_usage(&mut anchor, caller());
+    write_anchor_data(user_number, anchor);
+}
+
+pub fn get_pending_recovery_operations(user_number: UserNumber) -> Vec<PendingRecoveryOperation> {
+    trap_if_not_authenticated(&state::anchor(user_number));
+    state::storage(|storage| storage.pending_recovery_operations(user_number))
+}
+
+/// Cancels all pending recovery operations of the anchor.
+pub fn cancel_pending_recovery_operations(user_number: UserNumber) {
+    trap_if_not_authenticated_with_authentication_device(&state::anchor(user_number));
+
+    let cancelled = state::storage_mut(|storage| remove_pending_operations(storage, user_number));
+    for operation in cancelled {
+        archive_operation(
+            user_number,
+            caller(),
+            Operation::CancelRecoveryOperation { id: operation.id },
+        );
+    }
+}
+
+/// Returns the delay that applies to device changes made by `caller`, i.e. the recovery delay of
+/// the anchor if it is enabled and `caller` is a recovery device of the anchor.
+pub fn applicable_delay(anchor: &Anchor, caller: Principal) -> Option<u64> {
+    anchor
+        .recovery_delay
+        .filter(|_| is_recovery_device(anchor, caller))
+}
+
+/// Queues a device change made by `caller` instead of applying it. `operation` is the archive
+/// operation of the change (as if it was applied now).
+pub fn queue(
+    user_number: UserNumber,
+    delay: u64,
+    caller: Principal,
+    change: RecoveryDeviceChange,
+    operation: Operation,
+) {
+    let now = time();
+    let effective_at = now + delay;
+    let pending = state::storage(|storage| storage.pending_recovery_operations(user_number));
+    if pending.len() >= MAX_PENDING_OPERATIONS_PER_ANCHOR {
+        trap(&format!(
+            "at most {} pending recovery operations are allowed per user",
+            MAX_PENDING_OPERATIONS_PER_ANCHOR
+        ));
+    }
+
+    // The ids are never reused (across all anchors), so that the archive entries of an operation
+    // can be told apart from the ones of operations that were queued before.
+    let id = state::persistent_state_mut(|persistent_state| {
+        let id = persistent_state.last_recovery_operation_id.unwrap_or(0) + 1;
+        persistent_state.last_recovery_operation_id = Some(id);
+        id
+    });
+    state::storage_mut(|storage| {
+        storage.insert_pending_recovery_operation(
+            user_number,
+            PendingRecoveryOperation {
+                id,
+                change,
+                queued_by: caller,
+                queued_at: now,
+                effective_at,
+            },
+        );
+    });
+
+    archive_operation(
+        user_number,
+        caller,
+        Operation::QueueRecoveryOperation {
+            id,
+            effective_at,
+            operation: Box::new(operation),
+        },
+    );
+}
+
+/// Removes all pending recovery operations of the anchor and returns them.
+pub fn remove_pending_operations(
+    storage: &mut Storage<DefaultMemoryImpl>,
+    user_number: UserNumber,
+) -> Vec<PendingRecoveryOperation> {
+    let pending = storage.pending_recovery_operations(user_number);
+    for operation in &pending {
+        storage.remove_pending_recovery_operation(user_number, operation.id);
+    }
+    pending
+}
+
+/// Executes the next batch of pending recovery operations that became effective.
+/// Operations that can no longer be applied (e.g. because the device to remove was removed in the
+/// meantime) are cancelled instead.
+pub fn execute_due_operations() {
+    let due = state::storage_mut(|storage| {
+        storage.pop_due_recovery_operations(time(), MAX_OPERATIONS_PER_HEARTBEAT)
+    });
+    for (user_number, pending) in due {
+        match execute(user_number, &pending) {
+            Ok(operation) => archive_operation(
+                user_number,
+                pending.queued_by,
+                Operation::ExecuteRecoveryOperation {
+                    id: pending.id,
+                    operation: Box::new(operation),
+                },
+            ),
+            Err(_) => archive_operation(
+                user_number,
+                ic_cdk::id(),
+                Operation::CancelRecoveryOperation { id: pending.id },
+            ),
+        }
+    }
+}
+
+/// Applies the pending device change on behalf of the recovery device that made it.
+///
+/// NOTE: this must not trap because it is called from the heartbeat: the operation would be
+/// retried on every heartbeat.
+fn execute(
+    user_number: UserNumber,
+    pending: &PendingRecoveryOperation,
+) -> Result<Operation, String> {
+    let mut anchor =
+        state::storage(|storage| storage.read(user_number)).map_err(|err| err.to_string())?;
+
+    // the recovery device might have been removed (from a different device) in the meantime,
+    // which does not apply to a device that was queued on its own behalf by a guardian recovery
+    let adds_itself = matches!(
+        pending.change,
+        RecoveryDeviceChange::Add(ref device_data)
+            if Principal::self_authenticating(&device_data.pubkey) == pending.queued_by
+    );
+    if !adds_itself
+        && !anchor
+            .devices
+            .iter()
+            .any(|device| Principal::self_authenticating(&device.pubkey) == pending.queued_by)
+    {
+        return Err("the device that made the change is no longer registered".to_string());
+    }
+
+    let operation = match pending.change.clone() {
+        RecoveryDeviceChange::Add(device_data) => add_device(&mut anchor.devices, device_data)?,
+        RecoveryDeviceChange::Update(device_data) => {
+            check_device_invariants(&device_data, &anchor.devices)?;
+            mutate_device(
+                &mut anchor.devices,
+                device_data.pubkey.clone(),
+                Some(device_data),
+                pending.queued_by,
+            )?
+        }
+        RecoveryDeviceChange::Remove(device_key) => {
+            mutate_device(&mut anchor.devices, device_key, None, pending.queued_by)?
+        }
+    };
+
+    state::storage_mut(|storage| storage.write(user_number, anchor))
+        .map_err(|err| err.to_string())?;
+    state::usage_metrics_mut(|metrics| {
+        metrics.anchor_operation_counter += 1;
+    });
+    Ok(operation)
+}
+
+/// Traps if the call is not authenticated with a device of the anchor that is not a recovery
+/// device.
+fn trap_if_not_authenticated_with_authentication_device(anchor: &Anchor) {
+    trap_if_not_authenticated(anchor);
+    if is_recovery_device(anchor, caller()) {
+        trap(&format!(
+            "{} is a recovery device, an authentication device is required.",
+            caller()
+        ));
+    }
+}
+
+fn is_recovery_device(anchor: &Anchor, principal: Principal) -> bool {
+    anchor.devices.iter().any(|device| {
+        device.purpose == Some(Purpose::Recovery)
+            && principal == Principal::self_authenticating(&device.pubkey)
+    })
+}
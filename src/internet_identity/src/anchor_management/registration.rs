@@ -1,3 +1,4 @@
+use crate::anchor_management::registration::captcha::{captcha_provider, configured_captcha_type};
 use crate::anchor_management::{check_device, write_anchor_data};
 use crate::archive::archive_operation;
 use crate::state::{Anchor, ChallengeInfo, DeviceDataInternal};
@@ -9,28 +10,32 @@ use ic_cdk::{call, caller, trap};
 use internet_identity_interface::*;
 use rand_core::{RngCore, SeedableRng};
 
-#[cfg(not(feature = "dummy_captcha"))]
-use captcha::filters::Wave;
+pub mod captcha;
+pub mod rate_limit;
 
 // 5 mins
 const CAPTCHA_CHALLENGE_LIFETIME: u64 = secs_to_nanos(300);
-// How many captcha challenges we keep in memory (at most)
-const MAX_INFLIGHT_CHALLENGES: usize = 500;
+// How many captcha challenges we keep in stable memory (at most)
+const MAX_INFLIGHT_CHALLENGES: u64 = 500;
 
 pub async fn create_challenge() -> Challenge {
+    if let Err(()) = rate_limit::check_challenge_rate_limit() {
+        trap("rate limit reached, try again later");
+    }
+
     let mut rng = make_rng().await;
 
     delegation::prune_expired_signatures();
 
-    state::inflight_challenges_mut(|inflight_challenges| {
+    state::storage_mut(|storage| {
         let now = time() as u64;
 
         // Prune old challenges. This drops all challenges that are older than
         // CAPTCHA_CHALLENGE_LIFETIME
-        inflight_challenges.retain(|_, v| v.created > now - CAPTCHA_CHALLENGE_LIFETIME);
+        storage.prune_expired(now);
 
         // Error out if there are too many inflight challenges
-        if inflight_challenges.len() >= MAX_INFLIGHT_CHALLENGES {
+        if storage.inflight_challenges_len() >= MAX_INFLIGHT_CHALLENGES {
             trap("too many inflight captchas");
         }
 
@@ -42,22 +47,26 @@ pub async fn create_challenge() -> Challenge {
 
         for _ in 0..MAX_TRIES {
             let challenge_key = random_string(&mut rng, 10);
-            if !inflight_challenges.contains_key(&challenge_key) {
-                // Then we create the CAPTCHA
-                let (Base64(png_base64), chars) = create_captcha(rng);
+            if !storage.contains_inflight_challenge(&challenge_key) {
+                // Then we create the CAPTCHA of the configured type
+                let captcha_type = configured_captcha_type();
+                let captcha = captcha_provider(&captcha_type).create_captcha(&mut rng);
 
                 // Finally insert
-                inflight_challenges.insert(
+                storage.insert_inflight_challenge(
                     challenge_key.clone(),
                     ChallengeInfo {
-                        created: now,
-                        chars,
+                        expiration: now + CAPTCHA_CHALLENGE_LIFETIME,
+                        chars: captcha.chars,
+                        captcha_type: Some(captcha_type),
+                        pow_difficulty: captcha.pow_difficulty,
                     },
                 );
 
                 return Challenge {
-                    png_base64,
+                    png_base64: captcha.png_base64,
                     challenge_key,
+                    captcha: captcha.challenge,
                 };
             }
         }
@@ -70,7 +79,7 @@ pub async fn create_challenge() -> Challenge {
 }
 
 // Get a random number generator based on 'raw_rand'
-async fn make_rng() -> rand_chacha::ChaCha20Rng {
+pub async fn make_rng() -> rand_chacha::ChaCha20Rng {
     let raw_rand: Vec<u8> = match call(Principal::management_canister(), "raw_rand", ()).await {
         Ok((res,)) => res,
         Err((_, err)) => trap(&format!("failed to get seed: {}", err)),
@@ -108,42 +117,14 @@ fn random_string<T: RngCore>(rng: &mut T, n: usize) -> String {
     return String::from_utf8_lossy(&chars).to_string();
 }
 
-#[cfg(feature = "dummy_captcha")]
-fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
-    let mut captcha = captcha::RngCaptcha::from_rng(rng);
-    let captcha = captcha.set_chars(&vec!['a']).add_chars(1).view(96, 48);
-
-    let resp = match captcha.as_base64() {
-        Some(png_base64) => Base64(png_base64),
-        None => trap("Could not get base64 of captcha"),
-    };
-
-    return (resp, captcha.chars_as_string());
-}
-
-#[cfg(not(feature = "dummy_captcha"))]
-fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
-    let mut captcha = captcha::RngCaptcha::from_rng(rng);
-    let captcha = captcha
-        .add_chars(5)
-        .apply_filter(Wave::new(2.0, 20.0).horizontal())
-        .apply_filter(Wave::new(2.0, 20.0).vertical())
-        .view(220, 120);
-
-    let resp = match captcha.as_base64() {
-        Some(png_base64) => Base64(png_base64),
-        None => trap("Could not get base64 of captcha"),
-    };
-
-    return (resp, captcha.chars_as_string());
-}
-
 // Check whether the CAPTCHA challenge was solved
 fn check_challenge(res: ChallengeAttempt) -> Result<(), ()> {
-    state::inflight_challenges_mut(|inflight_challenges| {
-        match inflight_challenges.remove(&res.key) {
+    state::storage_mut(|storage| {
+        storage.prune_expired(time());
+        match storage.remove_inflight_challenge(&res.key) {
             Some(challenge) => {
-                if res.chars != challenge.chars {
+                let captcha_type = challenge.captcha_type.clone().unwrap_or(CaptchaType::Image);
+                if !captcha_provider(&captcha_type).check_solution(&challenge, &res.chars) {
                     return Err(());
                 }
                 return Ok(());
@@ -157,9 +138,16 @@ pub async fn register(
     device_data: DeviceData,
     challenge_result: ChallengeAttempt,
 ) -> RegisterResponse {
+    // The rate limit is checked first so that the captcha is not used up if the registration is
+    // rate limited.
+    if let Err(()) = rate_limit::check_registration_rate_limit() {
+        return RegisterResponse::RateLimited;
+    }
+
     if let Err(()) = check_challenge(challenge_result) {
         return RegisterResponse::BadChallenge;
     }
+    rate_limit::consume_registration_token();
 
     check_device(&device_data, &vec![]);
 
@@ -180,9 +168,22 @@ pub async fn register(
             write_anchor_data(
                 user_number,
                 Anchor {
-                    devices: vec![DeviceDataInternal::from(device_data.clone())],
+                    devices: vec![DeviceDataInternal {
+                        created_at: Some(time()),
+                        last_used: Some(time()),
+                        ..DeviceDataInternal::from(device_data.clone())
+                    }],
+                    created_at: Some(time()),
+                    delegation_lifetime_limits: None,
+                    metadata: None,
+                    recovery_delay: None,
+                    guardians: None,
                 },
             );
+            state::usage_metrics_mut(|metrics| {
+                metrics.register_counter += 1;
+            });
+            state::registration_load_mut(|load| load.record_registration(time()));
             archive_operation(
                 user_number,
                 caller,
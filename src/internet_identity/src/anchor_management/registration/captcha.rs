@@ -0,0 +1,267 @@
+//! Captchas protecting the registration of new anchors.
+//!
+//! The kind of captcha issued by `create_challenge` is configured using the init args (see
+//! [CaptchaType]). Each kind is implemented by a [CaptchaProvider] which creates the captchas and
+//! checks the solutions submitted by the users:
+//! * [ImageCaptcha]: distorted characters rendered to a PNG image
+//! * [TextCaptcha]: a simple arithmetic question, suitable for screen readers
+//! * [ProofOfWorkCaptcha]: a hash preimage search, solved by the frontend without user interaction
+//!
+//! The difficulty of the proof of work adapts to the number of recent registrations (see
+//! [RegistrationLoad]), so that bots have to pay more compute the more registrations they attempt.
+//! The load is not persisted: after an upgrade, the difficulty starts at the minimum again and
+//! increases with the registrations made after the upgrade. We accept this because upgrades are
+//! rare and the registrations remain rate limited (see [super::rate_limit]) in the meantime.
+use crate::anchor_management::registration::random_string;
+use crate::secs_to_nanos;
+use crate::state::{self, ChallengeInfo};
+use ic_cdk::api::time;
+use ic_cdk::trap;
+use internet_identity_interface::{CaptchaChallenge, CaptchaType, Timestamp};
+use rand_chacha::ChaCha20Rng;
+use rand_core::RngCore;
+use sha2::{Digest, Sha256};
+
+#[cfg(not(feature = "dummy_captcha"))]
+use captcha::filters::Wave;
+
+#[cfg(test)]
+mod tests;
+
+/// Number of leading zero bits required for the hash of a proof of work solution under normal load.
+const MIN_POW_DIFFICULTY: u8 = 16;
+/// Upper bound of the proof of work difficulty, regardless of the load.
+const MAX_POW_DIFFICULTY: u8 = 24;
+/// Number of recent registrations up to which the minimum difficulty is used. Every doubling of
+/// the recent registrations above this threshold adds one bit to the difficulty.
+const POW_LOAD_THRESHOLD: u64 = 100;
+/// Time window in which registrations count as recent.
+const REGISTRATION_LOAD_WINDOW: u64 = secs_to_nanos(60 * 60);
+/// Length of the nonce of a proof of work challenge.
+const POW_NONCE_LENGTH: usize = 16;
+/// Maximum length of a proof of work solution, so that callers cannot make us hash large inputs.
+const MAX_POW_SOLUTION_LENGTH: usize = 64;
+
+/// A freshly created captcha.
+pub struct Captcha {
+    // base64 encoded PNG image (empty unless this is an image captcha)
+    pub png_base64: String,
+    // what is presented to the user
+    pub challenge: CaptchaChallenge,
+    // what is required to check the solution later on (stored in the [ChallengeInfo])
+    pub chars: String,
+    pub pow_difficulty: Option<u8>,
+}
+
+pub trait CaptchaProvider {
+    /// Creates a new captcha using the given source of randomness.
+    fn create_captcha(&self, rng: &mut ChaCha20Rng) -> Captcha;
+
+    /// Checks the solution submitted by the user against the stored challenge.
+    fn check_solution(&self, challenge: &ChallengeInfo, solution: &str) -> bool;
+}
+
+/// Returns the captcha type configured using the init args (or the image captcha by default).
+pub fn configured_captcha_type() -> CaptchaType {
+    state::persistent_state(|persistent_state| persistent_state.captcha_type.clone())
+        .unwrap_or(CaptchaType::Image)
+}
+
+/// Returns the provider of the given captcha type.
+pub fn captcha_provider(captcha_type: &CaptchaType) -> &'static dyn CaptchaProvider {
+    match captcha_type {
+        CaptchaType::Image => &ImageCaptcha,
+        CaptchaType::Text => &TextCaptcha,
+        CaptchaType::ProofOfWork => &ProofOfWorkCaptcha,
+    }
+}
+
+pub struct ImageCaptcha;
+
+impl CaptchaProvider for ImageCaptcha {
+    fn create_captcha(&self, rng: &mut ChaCha20Rng) -> Captcha {
+        let (png_base64, chars) = create_image_captcha(rng);
+        Captcha {
+            png_base64,
+            challenge: CaptchaChallenge::Image,
+            chars,
+            pow_difficulty: None,
+        }
+    }
+
+    fn check_solution(&self, challenge: &ChallengeInfo, solution: &str) -> bool {
+        challenge.chars == solution
+    }
+}
+
+#[cfg(feature = "dummy_captcha")]
+fn create_image_captcha<T: RngCore>(rng: T) -> (String, String) {
+    let mut captcha = captcha::RngCaptcha::from_rng(rng);
+    let captcha = captcha.set_chars(&vec!['a']).add_chars(1).view(96, 48);
+
+    let resp = match captcha.as_base64() {
+        Some(png_base64) => png_base64,
+        None => trap("Could not get base64 of captcha"),
+    };
+
+    return (resp, captcha.chars_as_string());
+}
+
+#[cfg(not(feature = "dummy_captcha"))]
+fn create_image_captcha<T: RngCore>(rng: T) -> (String, String) {
+    let mut captcha = captcha::RngCaptcha::from_rng(rng);
+    let captcha = captcha
+        .add_chars(5)
+        .apply_filter(Wave::new(2.0, 20.0).horizontal())
+        .apply_filter(Wave::new(2.0, 20.0).vertical())
+        .view(220, 120);
+
+    let resp = match captcha.as_base64() {
+        Some(png_base64) => png_base64,
+        None => trap("Could not get base64 of captcha"),
+    };
+
+    return (resp, captcha.chars_as_string());
+}
+
+/// Arithmetic questions (e.g. "What is 7 plus 12?") answered with a number.
+///
+/// The answers are easier to guess than the characters of an image captcha, which is why the
+/// registrations are rate limited in addition to the captcha.
+pub struct TextCaptcha;
+
+impl CaptchaProvider for TextCaptcha {
+    fn create_captcha(&self, rng: &mut ChaCha20Rng) -> Captcha {
+        let (question, answer) = match rng.next_u32() % 3 {
+            0 => {
+                let (a, b) = (rng.next_u32() % 50 + 1, rng.next_u32() % 50 + 1);
+                (format!("What is {} plus {}?", a, b), a + b)
+            }
+            1 => {
+                let (a, b) = (rng.next_u32() % 50 + 1, rng.next_u32() % 50 + 1);
+                let (a, b) = (a.max(b), a.min(b));
+                (format!("What is {} minus {}?", a, b), a - b)
+            }
+            _ => {
+                let (a, b) = (rng.next_u32() % 12 + 1, rng.next_u32() % 12 + 1);
+                (format!("What is {} times {}?", a, b), a * b)
+            }
+        };
+        Captcha {
+            png_base64: String::new(),
+            challenge: CaptchaChallenge::Text { question },
+            chars: answer.to_string(),
+            pow_difficulty: None,
+        }
+    }
+
+    fn check_solution(&self, challenge: &ChallengeInfo, solution: &str) -> bool {
+        challenge.chars == solution.trim()
+    }
+}
+
+/// Proof of work: the solution `s` must be such that `sha256(nonce · s)` starts with (at least)
+/// `difficulty` zero bits.
+pub struct ProofOfWorkCaptcha;
+
+impl CaptchaProvider for ProofOfWorkCaptcha {
+    fn create_captcha(&self, rng: &mut ChaCha20Rng) -> Captcha {
+        let nonce = random_string(rng, POW_NONCE_LENGTH);
+        let difficulty = current_pow_difficulty();
+        Captcha {
+            png_base64: String::new(),
+            challenge: CaptchaChallenge::ProofOfWork {
+                nonce: nonce.clone(),
+                difficulty,
+            },
+            chars: nonce,
+            pow_difficulty: Some(difficulty),
+        }
+    }
+
+    fn check_solution(&self, challenge: &ChallengeInfo, solution: &str) -> bool {
+        let difficulty = match challenge.pow_difficulty {
+            Some(difficulty) => difficulty,
+            None => return false,
+        };
+        if solution.len() > MAX_POW_SOLUTION_LENGTH {
+            return false;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(challenge.chars.as_bytes());
+        hasher.update(solution.as_bytes());
+        leading_zero_bits(&hasher.finalize()) >= difficulty as u32
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+/// Returns the difficulty of new proof of work challenges given the current registration load.
+pub fn current_pow_difficulty() -> u8 {
+    pow_difficulty(state::registration_load(|load| {
+        load.recent_registrations(time())
+    }))
+}
+
+/// Returns the proof of work difficulty for the given number of recent registrations.
+fn pow_difficulty(recent_registrations: u64) -> u8 {
+    let mut difficulty = MIN_POW_DIFFICULTY;
+    let mut threshold = POW_LOAD_THRESHOLD;
+    while recent_registrations > threshold && difficulty < MAX_POW_DIFFICULTY {
+        difficulty += 1;
+        threshold = threshold.saturating_mul(2);
+    }
+    difficulty
+}
+
+/// Number of registrations in the current and the previous (fixed) time window, used to estimate
+/// the number of registrations within the last [REGISTRATION_LOAD_WINDOW].
+/// NOT persisted across upgrades.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RegistrationLoad {
+    // index of the current window (i.e. time / REGISTRATION_LOAD_WINDOW)
+    window: u64,
+    current_window: u64,
+    previous_window: u64,
+}
+
+impl RegistrationLoad {
+    pub fn record_registration(&mut self, now: Timestamp) {
+        self.advance(now);
+        self.current_window += 1;
+    }
+
+    /// Returns the estimated number of registrations within the last [REGISTRATION_LOAD_WINDOW]
+    /// assuming that the registrations of the previous window were evenly distributed.
+    pub fn recent_registrations(&self, now: Timestamp) -> u64 {
+        let mut load = self.clone();
+        load.advance(now);
+        let remaining = REGISTRATION_LOAD_WINDOW - now % REGISTRATION_LOAD_WINDOW;
+        let previous =
+            load.previous_window as u128 * remaining as u128 / REGISTRATION_LOAD_WINDOW as u128;
+        load.current_window + previous as u64
+    }
+
+    fn advance(&mut self, now: Timestamp) {
+        let window = now / REGISTRATION_LOAD_WINDOW;
+        if window == self.window {
+            return;
+        }
+        self.previous_window = if window == self.window + 1 {
+            self.current_window
+        } else {
+            0
+        };
+        self.current_window = 0;
+        self.window = window;
+    }
+}
@@ -0,0 +1,138 @@
+use crate::anchor_management::registration::captcha::{
+    captcha_provider, leading_zero_bits, pow_difficulty, Captcha, RegistrationLoad,
+    MAX_POW_SOLUTION_LENGTH,
+};
+use crate::state::ChallengeInfo;
+use internet_identity_interface::{CaptchaChallenge, CaptchaType};
+use rand_chacha::ChaCha20Rng;
+use rand_core::SeedableRng;
+use sha2::{Digest, Sha256};
+
+#[test]
+fn should_check_text_captcha_answer() {
+    let captcha = create_captcha(&CaptchaType::Text);
+    let question = match captcha.challenge {
+        CaptchaChallenge::Text { ref question } => question.clone(),
+        ref challenge => panic!("unexpected challenge {:?}", challenge),
+    };
+    let answer = solve_arithmetic_question(&question);
+    let info = challenge_info(&captcha, CaptchaType::Text);
+
+    let provider = captcha_provider(&CaptchaType::Text);
+    assert!(provider.check_solution(&info, &answer.to_string()));
+    assert!(provider.check_solution(&info, &format!(" {} ", answer)));
+    assert!(!provider.check_solution(&info, &(answer + 1).to_string()));
+}
+
+#[test]
+fn should_check_proof_of_work_solution() {
+    // proof of work captchas are not created here because their difficulty depends on the time
+    let info = ChallengeInfo {
+        expiration: 0,
+        chars: "abcdefghijklmnop".to_string(),
+        captcha_type: Some(CaptchaType::ProofOfWork),
+        pow_difficulty: Some(12),
+    };
+    let provider = captcha_provider(&CaptchaType::ProofOfWork);
+
+    let (solution, non_solution) = search_proof_of_work(&info.chars, 12);
+    assert!(provider.check_solution(&info, &solution));
+    assert!(!provider.check_solution(&info, &non_solution));
+}
+
+#[test]
+fn should_reject_too_long_proof_of_work_solution() {
+    // every solution has at least zero leading zero bits
+    let info = ChallengeInfo {
+        expiration: 0,
+        chars: "abcdefghijklmnop".to_string(),
+        captcha_type: Some(CaptchaType::ProofOfWork),
+        pow_difficulty: Some(0),
+    };
+    let provider = captcha_provider(&CaptchaType::ProofOfWork);
+
+    assert!(provider.check_solution(&info, &"a".repeat(MAX_POW_SOLUTION_LENGTH)));
+    assert!(!provider.check_solution(&info, &"a".repeat(MAX_POW_SOLUTION_LENGTH + 1)));
+}
+
+#[test]
+fn should_increase_pow_difficulty_with_load() {
+    assert_eq!(pow_difficulty(0), 16);
+    assert_eq!(pow_difficulty(100), 16);
+    assert_eq!(pow_difficulty(101), 17);
+    assert_eq!(pow_difficulty(200), 17);
+    assert_eq!(pow_difficulty(201), 18);
+    assert_eq!(pow_difficulty(u64::MAX), 24);
+}
+
+#[test]
+fn should_estimate_recent_registrations() {
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+    let mut load = RegistrationLoad::default();
+    let start = 100 * HOUR;
+    for _ in 0..10 {
+        load.record_registration(start);
+    }
+    assert_eq!(load.recent_registrations(start), 10);
+    assert_eq!(load.recent_registrations(start + HOUR / 2), 10);
+
+    // the registrations of the previous window are weighted by the overlap with the last hour
+    load.record_registration(start + HOUR + HOUR / 2);
+    assert_eq!(load.recent_registrations(start + HOUR + HOUR / 2), 6);
+    assert_eq!(load.recent_registrations(start + 2 * HOUR), 1);
+    assert_eq!(load.recent_registrations(start + 3 * HOUR), 0);
+}
+
+#[test]
+fn should_count_leading_zero_bits() {
+    assert_eq!(leading_zero_bits(&[0xff, 0x00]), 0);
+    assert_eq!(leading_zero_bits(&[0x00, 0x10, 0x00]), 11);
+    assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+}
+
+fn create_captcha(captcha_type: &CaptchaType) -> Captcha {
+    let mut rng = ChaCha20Rng::from_seed([42; 32]);
+    captcha_provider(captcha_type).create_captcha(&mut rng)
+}
+
+fn challenge_info(captcha: &Captcha, captcha_type: CaptchaType) -> ChallengeInfo {
+    ChallengeInfo {
+        expiration: 0,
+        chars: captcha.chars.clone(),
+        captcha_type: Some(captcha_type),
+        pow_difficulty: captcha.pow_difficulty,
+    }
+}
+
+fn solve_arithmetic_question(question: &str) -> u32 {
+    let words: Vec<&str> = question.trim_end_matches('?').split(' ').collect();
+    let (a, b): (u32, u32) = (words[2].parse().unwrap(), words[4].parse().unwrap());
+    match words[3] {
+        "plus" => a + b,
+        "minus" => a - b,
+        "times" => a * b,
+        operator => panic!("unexpected operator {}", operator),
+    }
+}
+
+/// Returns a solution and a non-solution of the proof of work challenge.
+fn search_proof_of_work(nonce: &str, difficulty: u8) -> (String, String) {
+    let mut solution = None;
+    let mut non_solution = None;
+    for i in 0u64.. {
+        let candidate = i.to_string();
+        let hash = Sha256::new()
+            .chain_update(nonce.as_bytes())
+            .chain_update(candidate.as_bytes())
+            .finalize();
+        if leading_zero_bits(&hash) >= difficulty as u32 {
+            solution.get_or_insert(candidate);
+        } else {
+            non_solution.get_or_insert(candidate);
+        }
+        if let (Some(solution), Some(non_solution)) = (&solution, &non_solution) {
+            return (solution.clone(), non_solution.clone());
+        }
+    }
+    unreachable!()
+}
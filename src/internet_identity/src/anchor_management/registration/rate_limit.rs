@@ -0,0 +1,140 @@
+//! Token bucket rate limiting of captcha challenges, registrations and guardian recoveries.
+//!
+//! Creating a challenge, registering an anchor and starting a guardian recovery each consume a
+//! token from their own bucket.
+//! The buckets are refilled with one token every `time_per_token_ns` (up to `max_tokens`), so
+//! that a single client cannot exhaust the inflight challenges (or the anchor range) for everyone
+//! else. The buckets are kept on the heap and start out full after an upgrade.
+use crate::anchor_management::registration::MAX_INFLIGHT_CHALLENGES;
+use crate::{secs_to_nanos, state};
+use ic_cdk::api::time;
+use internet_identity_interface::{RateLimitConfig, Timestamp};
+
+#[cfg(test)]
+mod tests;
+
+/// Rate limit used unless configured otherwise using the init args.
+/// The burst is limited to the number of inflight challenges, so that a single client cannot fill
+/// them up in one go.
+pub const DEFAULT_RATE_LIMIT_CONFIG: RateLimitConfig = RateLimitConfig {
+    time_per_token_ns: secs_to_nanos(10),
+    max_tokens: MAX_INFLIGHT_CHALLENGES,
+};
+
+/// Token buckets of the captcha challenges, registrations and guardian recoveries, NOT persisted
+/// across upgrades.
+#[derive(Default)]
+pub struct RegistrationRateLimits {
+    pub challenges: TokenBucket,
+    pub registrations: TokenBucket,
+    pub guardian_recoveries: TokenBucket,
+    // number of registrations rejected because the rate limit was reached
+    pub rate_limited_registrations: u64,
+}
+
+/// Token bucket that is initialized (full) on first use.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TokenBucket {
+    tokens: u64,
+    // time at which the last token was added (None if the bucket has not been used yet)
+    last_refill: Option<Timestamp>,
+}
+
+impl TokenBucket {
+    /// Returns the number of tokens available at the given time.
+    pub fn available_tokens(&self, config: &RateLimitConfig, now: Timestamp) -> u64 {
+        let mut bucket = self.clone();
+        bucket.refill(config, now);
+        bucket.tokens
+    }
+
+    /// Consumes a token if one is available at the given time.
+    pub fn try_consume(&mut self, config: &RateLimitConfig, now: Timestamp) -> bool {
+        self.refill(config, now);
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+
+    fn refill(&mut self, config: &RateLimitConfig, now: Timestamp) {
+        let last_refill = match self.last_refill {
+            None => {
+                self.tokens = config.max_tokens;
+                self.last_refill = Some(now);
+                return;
+            }
+            Some(last_refill) => last_refill,
+        };
+
+        let new_tokens = now
+            .saturating_sub(last_refill)
+            .checked_div(config.time_per_token_ns)
+            .unwrap_or(u64::MAX);
+        if new_tokens == 0 {
+            return;
+        }
+        self.tokens = self.tokens.saturating_add(new_tokens);
+        if self.tokens >= config.max_tokens {
+            // the time spent with a full bucket must not be credited later on
+            self.tokens = config.max_tokens;
+            self.last_refill = Some(now);
+        } else {
+            self.last_refill = Some(last_refill + new_tokens * config.time_per_token_ns);
+        }
+    }
+}
+
+/// Returns the configured rate limit (or the default if none was configured).
+pub fn rate_limit_config() -> RateLimitConfig {
+    state::persistent_state(|persistent_state| persistent_state.register_rate_limit.clone())
+        .unwrap_or(DEFAULT_RATE_LIMIT_CONFIG)
+}
+
+/// Consumes a token of the challenge bucket, fails if the rate limit is reached.
+pub fn check_challenge_rate_limit() -> Result<(), ()> {
+    let config = rate_limit_config();
+    state::registration_rate_limits_mut(|limits| {
+        if limits.challenges.try_consume(&config, time()) {
+            Ok(())
+        } else {
+            Err(())
+        }
+    })
+}
+
+/// Consumes a token of the guardian recovery bucket, fails if the rate limit is reached.
+pub fn check_guardian_recovery_rate_limit() -> Result<(), ()> {
+    let config = rate_limit_config();
+    state::registration_rate_limits_mut(|limits| {
+        if limits.guardian_recoveries.try_consume(&config, time()) {
+            Ok(())
+        } else {
+            Err(())
+        }
+    })
+}
+
+/// Fails if the rate limit of registrations is reached. Does not consume a token (see
+/// [consume_registration_token]).
+pub fn check_registration_rate_limit() -> Result<(), ()> {
+    let config = rate_limit_config();
+    state::registration_rate_limits_mut(|limits| {
+        if limits.registrations.available_tokens(&config, time()) > 0 {
+            Ok(())
+        } else {
+            limits.rate_limited_registrations += 1;
+            Err(())
+        }
+    })
+}
+
+/// Consumes a token of the registration bucket, which must have been checked to be available
+/// using [check_registration_rate_limit] in the same message.
+pub fn consume_registration_token() {
+    let config = rate_limit_config();
+    state::registration_rate_limits_mut(|limits| {
+        limits.registrations.try_consume(&config, time());
+    })
+}
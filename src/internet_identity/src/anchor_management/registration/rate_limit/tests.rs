@@ -0,0 +1,60 @@
+use crate::anchor_management::registration::rate_limit::TokenBucket;
+use internet_identity_interface::RateLimitConfig;
+
+const CONFIG: RateLimitConfig = RateLimitConfig {
+    time_per_token_ns: 10,
+    max_tokens: 3,
+};
+
+#[test]
+fn should_start_with_full_bucket() {
+    let mut bucket = TokenBucket::default();
+    assert_eq!(bucket.available_tokens(&CONFIG, 1_000), 3);
+
+    for _ in 0..3 {
+        assert!(bucket.try_consume(&CONFIG, 1_000));
+    }
+    assert!(!bucket.try_consume(&CONFIG, 1_000));
+    assert_eq!(bucket.available_tokens(&CONFIG, 1_000), 0);
+}
+
+#[test]
+fn should_refill_one_token_per_time_period() {
+    let mut bucket = TokenBucket::default();
+    for _ in 0..3 {
+        assert!(bucket.try_consume(&CONFIG, 1_000));
+    }
+
+    assert_eq!(bucket.available_tokens(&CONFIG, 1_009), 0);
+    assert_eq!(bucket.available_tokens(&CONFIG, 1_010), 1);
+    assert_eq!(bucket.available_tokens(&CONFIG, 1_025), 2);
+
+    // the remainder of a partially elapsed period is not lost when consuming
+    assert!(bucket.try_consume(&CONFIG, 1_015));
+    assert!(!bucket.try_consume(&CONFIG, 1_019));
+    assert!(bucket.try_consume(&CONFIG, 1_020));
+}
+
+#[test]
+fn should_not_exceed_max_tokens() {
+    let mut bucket = TokenBucket::default();
+    assert!(bucket.try_consume(&CONFIG, 1_000));
+
+    assert_eq!(bucket.available_tokens(&CONFIG, 1_000_000), 3);
+    for _ in 0..3 {
+        assert!(bucket.try_consume(&CONFIG, 1_000_000));
+    }
+    assert!(!bucket.try_consume(&CONFIG, 1_000_000));
+}
+
+#[test]
+fn should_not_limit_with_zero_time_per_token() {
+    let config = RateLimitConfig {
+        time_per_token_ns: 0,
+        max_tokens: 1,
+    };
+    let mut bucket = TokenBucket::default();
+    for _ in 0..10 {
+        assert!(bucket.try_consume(&config, 1_000));
+    }
+}
@@ -1,20 +1,23 @@
-use crate::state::RegistrationState::{DeviceRegistrationModeActive, DeviceTentativelyAdded};
+use crate::anchor_management::check_entry_limits;
+use crate::state::RegistrationState::{
+    DeviceRegistrationModeActive, DeviceTentativelyAdded, GuardianRecoveryRequested,
+};
 use crate::state::TentativeDeviceRegistration;
-use crate::{add, secs_to_nanos, state, trap_if_not_authenticated};
+use crate::{add, secs_to_nanos, state, trap_if_not_authenticated, Storage};
 use candid::Principal;
 use ic_cdk::api::time;
 use ic_cdk::{call, trap};
+use ic_stable_structures::DefaultMemoryImpl;
 use internet_identity_interface::AddTentativeDeviceResponse::{
     AddedTentatively, AnotherDeviceTentativelyAdded,
 };
 use internet_identity_interface::VerifyTentativeDeviceResponse::{NoDeviceToVerify, WrongCode};
 use internet_identity_interface::*;
-use std::collections::HashMap;
 
 // 15 mins
 const REGISTRATION_MODE_DURATION: u64 = secs_to_nanos(900);
 // How many users can be in registration mode simultaneously
-const MAX_USERS_IN_REGISTRATION_MODE: usize = 10_000;
+const MAX_USERS_IN_REGISTRATION_MODE: u64 = 10_000;
 // How many verification attempts are given for a tentative device
 const MAX_DEVICE_REGISTRATION_ATTEMPTS: u8 = 3;
 
@@ -23,17 +26,20 @@ const MAX_DEVICE_REGISTRATION_ATTEMPTS: u8 = 3;
 pub fn enter_device_registration_mode(user_number: UserNumber) -> Timestamp {
     trap_if_not_authenticated(&state::anchor(user_number));
 
-    state::tentative_device_registrations_mut(|registrations| {
-        prune_expired_tentative_device_registrations(registrations);
-        if registrations.len() >= MAX_USERS_IN_REGISTRATION_MODE {
+    state::storage_mut(|storage| {
+        prune_expired_tentative_device_registrations(storage);
+        // guardian recoveries are limited separately (see guardian_recovery)
+        if storage.tentative_device_registrations_len() - storage.guardian_recoveries_len()
+            >= MAX_USERS_IN_REGISTRATION_MODE
+        {
             trap("too many users in device registration mode");
         }
 
-        match registrations.get(&user_number) {
-            Some(TentativeDeviceRegistration { expiration, .. }) => *expiration, // already enabled, just return the existing expiration
+        match storage.tentative_device_registration(user_number) {
+            Some(TentativeDeviceRegistration { expiration, .. }) => expiration, // already enabled, just return the existing expiration
             None => {
                 let expiration = time() + REGISTRATION_MODE_DURATION;
-                registrations.insert(
+                storage.insert_tentative_device_registration(
                     user_number,
                     TentativeDeviceRegistration {
                         expiration,
@@ -49,9 +55,9 @@ pub fn enter_device_registration_mode(user_number: UserNumber) -> Timestamp {
 pub fn exit_device_registration_mode(user_number: UserNumber) {
     trap_if_not_authenticated(&state::anchor(user_number));
 
-    state::tentative_device_registrations_mut(|registrations| {
-        prune_expired_tentative_device_registrations(registrations);
-        registrations.remove(&user_number)
+    state::storage_mut(|storage| {
+        prune_expired_tentative_device_registrations(storage);
+        storage.remove_tentative_device_registration(user_number)
     });
 }
 
@@ -59,19 +65,21 @@ pub async fn add_tentative_device(
     user_number: UserNumber,
     device_data: DeviceData,
 ) -> AddTentativeDeviceResponse {
+    // the tentative device is persisted, so it must not exceed the limits of anchor devices
+    check_entry_limits(&device_data);
     let verification_code = new_verification_code().await;
     let now = time();
 
-    state::tentative_device_registrations_mut(|registrations| {
-        prune_expired_tentative_device_registrations(registrations);
+    state::storage_mut(|storage| {
+        prune_expired_tentative_device_registrations(storage);
 
-        match registrations.get_mut(&user_number) {
+        match storage.tentative_device_registration(user_number) {
             None => AddTentativeDeviceResponse::DeviceRegistrationModeOff,
-            Some(TentativeDeviceRegistration { expiration, .. }) if *expiration <= now => {
+            Some(TentativeDeviceRegistration { expiration, .. }) if expiration <= now => {
                 AddTentativeDeviceResponse::DeviceRegistrationModeOff
             }
             Some(TentativeDeviceRegistration {
-                state: DeviceTentativelyAdded { .. },
+                state: DeviceTentativelyAdded { .. } | GuardianRecoveryRequested { .. },
                 ..
             }) => AnotherDeviceTentativelyAdded,
             Some(mut registration) => {
@@ -80,8 +88,13 @@ pub async fn add_tentative_device(
                     failed_attempts: 0,
                     verification_code: verification_code.clone(),
                 };
+                let device_registration_timeout = registration.expiration;
+                storage.insert_tentative_device_registration(user_number, registration);
+                state::usage_metrics_mut(|metrics| {
+                    metrics.add_tentative_device_counter += 1;
+                });
                 AddedTentatively {
-                    device_registration_timeout: registration.expiration,
+                    device_registration_timeout,
                     verification_code,
                 }
             }
@@ -96,6 +109,9 @@ pub async fn verify_tentative_device(
     match get_verified_device(user_number, user_verification_code) {
         Ok(device) => {
             add(user_number, device).await;
+            state::usage_metrics_mut(|metrics| {
+                metrics.verify_tentative_device_counter += 1;
+            });
             VerifyTentativeDeviceResponse::Verified
         }
         Err(err) => err,
@@ -111,15 +127,26 @@ fn get_verified_device(
 ) -> Result<DeviceData, VerifyTentativeDeviceResponse> {
     trap_if_not_authenticated(&state::anchor(user_number));
 
-    state::tentative_device_registrations_mut(|registrations| {
-        prune_expired_tentative_device_registrations(registrations);
+    state::storage_mut(|storage| {
+        prune_expired_tentative_device_registrations(storage);
 
-        let mut tentative_registration = registrations
-            .remove(&user_number)
+        let mut tentative_registration = storage
+            .remove_tentative_device_registration(user_number)
             .ok_or(VerifyTentativeDeviceResponse::DeviceRegistrationModeOff)?;
 
         match tentative_registration.state {
             DeviceRegistrationModeActive => Err(NoDeviceToVerify),
+            state @ GuardianRecoveryRequested { .. } => {
+                // the device is added once enough guardians approved it, keep the recovery going
+                storage.insert_tentative_device_registration(
+                    user_number,
+                    TentativeDeviceRegistration {
+                        expiration: tentative_registration.expiration,
+                        state,
+                    },
+                );
+                Err(NoDeviceToVerify)
+            }
             DeviceTentativelyAdded {
                 failed_attempts,
                 verification_code,
@@ -137,7 +164,8 @@ fn get_verified_device(
                         verification_code,
                     };
                     // reinsert because retries are allowed
-                    registrations.insert(user_number, tentative_registration);
+                    storage
+                        .insert_tentative_device_registration(user_number, tentative_registration);
                 }
                 return Err(WrongCode {
                     retries_left: (MAX_DEVICE_REGISTRATION_ATTEMPTS - failed_attempts),
@@ -164,13 +192,8 @@ async fn new_verification_code() -> DeviceVerificationCode {
     format!("{:06}", (rand % 1_000_000))
 }
 
-/// Removes __all__ expired device registrations -> there is no need to check expiration immediately after pruning.
-fn prune_expired_tentative_device_registrations(
-    registrations: &mut HashMap<UserNumber, TentativeDeviceRegistration>,
-) {
-    let now = time();
-
-    registrations.retain(|_, value| match &value {
-        TentativeDeviceRegistration { expiration, .. } => expiration > &now,
-    })
+/// Removes __all__ expired device registrations (and captcha challenges) -> there is no need to
+/// check expiration immediately after pruning.
+pub fn prune_expired_tentative_device_registrations(storage: &mut Storage<DefaultMemoryImpl>) {
+    storage.prune_expired(time());
 }
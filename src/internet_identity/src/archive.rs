@@ -1,17 +1,17 @@
 use crate::state;
 use crate::state::DeviceDataInternal;
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::api::call::{call_with_payment, CallResult};
+use ic_cdk::api::call::{call, call_with_payment, CallResult};
 use ic_cdk::api::management_canister::main::{
     canister_status, install_code, CanisterIdRecord, CanisterInstallMode,
     CanisterInstallMode::Install, CanisterStatusResponse, CreateCanisterArgument,
     InstallCodeArgument,
 };
 use ic_cdk::api::time;
-use ic_cdk::{id, notify};
+use ic_cdk::{id, spawn};
 use internet_identity_interface::{
-    ArchiveInit, DeployArchiveResult, DeviceDataUpdate, Entry, Operation, Private, Timestamp,
-    UserNumber,
+    ArchiveBufferInfo, ArchiveInit, BufferedEntry, DeployArchiveResult, DeviceDataUpdate, Entry,
+    Operation, Private, Timestamp, UserNumber,
 };
 use serde_bytes::ByteBuf;
 use sha2::Digest;
@@ -20,6 +20,20 @@ use std::time::Duration;
 use ArchiveState::{Created, CreationInProgress, NotCreated};
 use CanisterInstallMode::Upgrade;
 
+#[cfg(test)]
+mod tests;
+
+/// Maximum number of entries buffered until they are acknowledged by the archive.
+/// If the buffer is full, entries are dropped which results in a gap of sequence numbers.
+const MAX_BUFFERED_ENTRIES: u64 = 10_000;
+/// Maximum number of entries sent to the archive in a single call.
+const MAX_ENTRIES_PER_PUSH: usize = 100;
+/// Time to wait before pushing entries to the archive again after a failed attempt.
+const PUSH_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Time after which a push that has not completed is considered lost, e.g. because its callback
+/// trapped, so that the entries are pushed again. The archive skips entries it already stored.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
 #[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
 pub struct ArchiveInfo {
     pub expected_module_hash: Option<[u8; 32]>,
@@ -43,8 +57,8 @@ impl Default for ArchiveState {
 /// Management metadata about the archive.
 #[derive(Eq, PartialEq, Clone, CandidType, Debug, Deserialize)]
 pub struct ArchiveData {
-    // Sequence number of anchor operations. Using this sequence number missing entries / reliability
-    // can be assessed without having explicit error handling on the II side.
+    // Sequence number of the next anchor operation. Using this sequence number missing entries /
+    // reliability can be assessed by the archive.
     pub sequence_number: u64,
     // Canister id of the archive canister
     pub archive_canister: Principal,
@@ -59,6 +73,54 @@ pub struct ArchiveStatusCache {
     pub status: CanisterStatusResponse,
 }
 
+/// Status of the delivery of buffered entries to the archive.
+#[derive(Clone, Debug, Default)]
+pub struct ArchivePushStatus {
+    // timestamp of the call to the archive that is in flight (if any)
+    pub push_started_at: Option<Timestamp>,
+    // timestamp of the last failed push, cleared on success
+    pub last_failure: Option<Timestamp>,
+    pub failed_pushes: u64,
+    pub dropped_entries: u64,
+}
+
+impl ArchivePushStatus {
+    /// Returns true if the next push can be started at `now`, i.e. no push is in flight (or the
+    /// push in flight timed out) and the last push did not fail less than [PUSH_RETRY_DELAY] ago.
+    fn is_push_due(&self, now: Timestamp) -> bool {
+        let in_progress = self.push_started_at.map_or(false, |started_at| {
+            now.saturating_sub(started_at) < PUSH_TIMEOUT.as_nanos() as u64
+        });
+        !in_progress
+            && self.last_failure.map_or(true, |failure| {
+                now.saturating_sub(failure) >= PUSH_RETRY_DELAY.as_nanos() as u64
+            })
+    }
+
+    /// Records the start of a push at `now`. A previous push that is still recorded as in flight
+    /// timed out and is counted as failed.
+    fn start_push(&mut self, now: Timestamp) {
+        if self.push_started_at.is_some() {
+            self.failed_pushes += 1;
+        }
+        self.push_started_at = Some(now);
+    }
+
+    /// Records the completion of the push started at `started_at`. Completions of pushes that
+    /// timed out (and were superseded by a later push) do not affect the push in flight.
+    fn complete_push(&mut self, started_at: Timestamp, success: bool, now: Timestamp) {
+        if self.push_started_at == Some(started_at) {
+            self.push_started_at = None;
+        }
+        if success {
+            self.last_failure = None;
+        } else {
+            self.last_failure = Some(now);
+            self.failed_pushes += 1;
+        }
+    }
+}
+
 struct VerifiedWasm(Vec<u8>);
 
 pub async fn deploy_archive(wasm: ByteBuf) -> DeployArchiveResult {
@@ -254,6 +316,8 @@ async fn archive_status(archive_canister: Principal) -> CanisterStatusResponse {
     }
 }
 
+/// Adds an entry for the given anchor operation to the buffer of entries to be sent to the archive.
+/// The entries are pushed to the archive in batches by [push_entries_if_due].
 pub fn archive_operation(anchor: UserNumber, caller: Principal, operation: Operation) {
     let archive_data = match state::archive_data() {
         Some(data) => data,
@@ -269,17 +333,75 @@ pub fn archive_operation(anchor: UserNumber, caller: Principal, operation: Opera
     };
     let encoded_entry = candid::encode_one(entry).expect("failed to encode archive entry");
 
-    // Notify can still trap if the message cannot be enqueued rolling back the anchor operation.
-    // Therefore we only increment the sequence number after notifying successfully.
-    let () = notify(
-        archive_data.archive_canister,
-        "write_entry",
-        (anchor, timestamp, encoded_entry),
-    )
-    .expect("failed to send archive entry notification");
+    let buffered = state::storage_mut(|storage| {
+        if storage.archive_buffer_len() >= MAX_BUFFERED_ENTRIES {
+            return false;
+        }
+        storage.buffer_archive_entry(BufferedEntry {
+            anchor,
+            timestamp,
+            sequence_number: archive_data.sequence_number,
+            entry: ByteBuf::from(encoded_entry),
+        });
+        true
+    });
+    if !buffered {
+        state::archive_push_status_mut(|status| status.dropped_entries += 1);
+    }
+
+    // The sequence number is incremented even if the entry was dropped so that the archive can
+    // detect the missing entry.
     state::increment_archive_seq_nr();
 }
 
+/// Sends the next batch of buffered entries to the archive unless a push is already in progress or
+/// the last push failed less than [PUSH_RETRY_DELAY] ago.
+pub fn push_entries_if_due() {
+    let archive_data = match state::archive_data() {
+        Some(data) => data,
+        None => return,
+    };
+    let now = time();
+    if !state::archive_push_status(|status| status.is_push_due(now)) {
+        return;
+    }
+
+    let entries = state::storage(|storage| storage.archive_buffer_entries(MAX_ENTRIES_PER_PUSH));
+    if entries.is_empty() {
+        return;
+    }
+
+    state::archive_push_status_mut(|status| status.start_push(now));
+    spawn(push_entries(archive_data.archive_canister, entries, now));
+}
+
+/// NOTE: if the callback traps (e.g. because the reply cannot be decoded), the push remains
+/// recorded as in flight until it times out (see [PUSH_TIMEOUT]).
+async fn push_entries(
+    archive_canister: Principal,
+    entries: Vec<BufferedEntry>,
+    started_at: Timestamp,
+) {
+    // the archive replies with the highest sequence number it has stored
+    let result: CallResult<(u64,)> = call(archive_canister, "append_entries", (entries,)).await;
+
+    if let Ok((highest_sequence_number,)) = result {
+        state::storage_mut(|storage| storage.acknowledge_archive_entries(highest_sequence_number));
+    }
+    state::archive_push_status_mut(|status| {
+        status.complete_push(started_at, result.is_ok(), time())
+    });
+}
+
+pub fn archive_buffer_info() -> ArchiveBufferInfo {
+    let buffered_entries = state::storage(|storage| storage.archive_buffer_len());
+    state::archive_push_status(|status| ArchiveBufferInfo {
+        buffered_entries,
+        dropped_entries: status.dropped_entries,
+        failed_pushes: status.failed_pushes,
+    })
+}
+
 pub fn device_diff(old: &DeviceDataInternal, new: &DeviceDataInternal) -> DeviceDataUpdate {
     DeviceDataUpdate {
         alias: if old.alias == new.alias {
@@ -307,5 +429,10 @@ pub fn device_diff(old: &DeviceDataInternal, new: &DeviceDataInternal) -> Device
         } else {
             new.protection.clone()
         },
+        protection_policy: if old.protection_policy == new.protection_policy {
+            None
+        } else {
+            Some(new.protection_policy.clone().unwrap_or_default())
+        },
     }
 }
@@ -0,0 +1,57 @@
+use crate::archive::{ArchivePushStatus, PUSH_RETRY_DELAY, PUSH_TIMEOUT};
+
+const NOW: u64 = 1_620_328_630_000_000_000;
+
+#[test]
+fn should_not_push_while_push_is_in_flight() {
+    let mut status = ArchivePushStatus::default();
+    assert!(status.is_push_due(NOW));
+
+    status.start_push(NOW);
+    assert!(!status.is_push_due(NOW + 1));
+
+    status.complete_push(NOW, true, NOW + 1);
+    assert!(status.is_push_due(NOW + 1));
+    assert_eq!(status.failed_pushes, 0);
+}
+
+#[test]
+fn should_wait_for_retry_delay_after_failed_push() {
+    let mut status = ArchivePushStatus::default();
+    status.start_push(NOW);
+    status.complete_push(NOW, false, NOW);
+
+    assert!(!status.is_push_due(NOW + PUSH_RETRY_DELAY.as_nanos() as u64 - 1));
+    assert!(status.is_push_due(NOW + PUSH_RETRY_DELAY.as_nanos() as u64));
+    assert_eq!(status.failed_pushes, 1);
+}
+
+/// The callback of a push that trapped never completes the push.
+#[test]
+fn should_push_again_after_push_timed_out() {
+    let mut status = ArchivePushStatus::default();
+    status.start_push(NOW);
+
+    let timed_out = NOW + PUSH_TIMEOUT.as_nanos() as u64;
+    assert!(!status.is_push_due(timed_out - 1));
+    assert!(status.is_push_due(timed_out));
+
+    status.start_push(timed_out);
+    assert_eq!(status.failed_pushes, 1);
+    assert!(!status.is_push_due(timed_out + 1));
+}
+
+#[test]
+fn should_not_complete_newer_push_when_timed_out_push_completes() {
+    let mut status = ArchivePushStatus::default();
+    status.start_push(NOW);
+    let timed_out = NOW + PUSH_TIMEOUT.as_nanos() as u64;
+    status.start_push(timed_out);
+
+    // the timed out push completes late
+    status.complete_push(NOW, true, timed_out + 1);
+    assert!(!status.is_push_due(timed_out + 1));
+
+    status.complete_push(timed_out, true, timed_out + 2);
+    assert!(status.is_push_due(timed_out + 2));
+}
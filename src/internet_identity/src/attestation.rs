@@ -0,0 +1,122 @@
+//! Canister-signed attestations about anchors.
+//!
+//! An authenticated anchor can ask II to attest a claim about itself (see [AttestationClaim]) to a
+//! relying party. The attestation is signed with the canister signature of the anchor's principal
+//! for the relying party (i.e. the same key as the delegations issued to that relying party), so
+//! the relying party can verify that the claim was made by II about the principal it knows,
+//! without trusting the frontend.
+//!
+//! Like delegations, attestations are signed in two steps: `prepare_attestation` checks the claim
+//! and adds the signature to the signature map (certified under the `sig` label), and
+//! `get_attestation` returns the signature together with the certificate.
+use crate::delegation::{
+    add_signature, calculate_seed, check_frontend_length, der_encode_canister_sig_key,
+    get_signature, prune_expired_signatures,
+};
+use crate::state::Anchor;
+use crate::{derivation_origin, hash, state, trap_if_not_authenticated};
+use ic_cdk::api::time;
+use ic_certified_map::Hash;
+use internet_identity_interface::*;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+
+pub async fn prepare_attestation(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    claim: AttestationClaim,
+    derivation_origin: Option<DerivationOrigin>,
+) -> PrepareAttestationResponse {
+    // must be called before the first await because it requires caller()
+    let anchor = state::anchor(user_number);
+    trap_if_not_authenticated(&anchor);
+
+    state::ensure_salt_set().await;
+    prune_expired_signatures();
+    check_frontend_length(&frontend);
+    let origin = derivation_origin::effective_origin(&frontend, derivation_origin);
+    check_frontend_length(&origin);
+
+    if !is_satisfied(&claim, &anchor) {
+        return PrepareAttestationResponse::ClaimNotSatisfied;
+    }
+
+    let issued_at = time();
+    let seed = calculate_seed(user_number, &origin);
+    add_signature(
+        user_number,
+        seed,
+        attestation_signature_msg_hash(&Attestation { claim, issued_at }),
+    );
+
+    PrepareAttestationResponse::Prepared {
+        user_key: ByteBuf::from(der_encode_canister_sig_key(seed.to_vec())),
+        issued_at,
+    }
+}
+
+pub fn get_attestation(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    claim: AttestationClaim,
+    issued_at: Timestamp,
+    derivation_origin: Option<FrontendHostname>,
+) -> GetAttestationResponse {
+    check_frontend_length(&frontend);
+    trap_if_not_authenticated(&state::anchor(user_number));
+
+    // No need to verify the derivation origin again: the signature only exists if the derivation
+    // origin was verified by prepare_attestation.
+    let origin = derivation_origin.unwrap_or(frontend);
+    check_frontend_length(&origin);
+
+    let attestation = Attestation { claim, issued_at };
+    let msg_hash = attestation_signature_msg_hash(&attestation);
+    match get_signature(calculate_seed(user_number, &origin), msg_hash) {
+        Some(signature) => GetAttestationResponse::SignedAttestation(SignedAttestation {
+            attestation,
+            signature: ByteBuf::from(signature),
+        }),
+        None => GetAttestationResponse::NoSuchAttestation,
+    }
+}
+
+/// Checks whether the anchor satisfies the claim.
+fn is_satisfied(claim: &AttestationClaim, anchor: &Anchor) -> bool {
+    match claim {
+        AttestationClaim::CreatedBefore(timestamp) => {
+            // anchors without registration time were registered before it was recorded
+            let created_at = anchor.created_at.or_else(|| {
+                state::persistent_state(|persistent_state| {
+                    persistent_state.anchor_creation_recorded_since
+                })
+            });
+            match created_at {
+                Some(created_at) => created_at < *timestamp,
+                None => false,
+            }
+        }
+        AttestationClaim::HasRecoveryDevice => anchor
+            .devices
+            .iter()
+            .any(|device| device.purpose == Some(Purpose::Recovery)),
+    }
+}
+
+fn attestation_signature_msg_hash(attestation: &Attestation) -> Hash {
+    use hash::Value;
+
+    let mut m = HashMap::new();
+    match attestation.claim {
+        AttestationClaim::CreatedBefore(timestamp) => {
+            m.insert("claim", Value::String("created_before"));
+            m.insert("created_before", Value::U64(timestamp));
+        }
+        AttestationClaim::HasRecoveryDevice => {
+            m.insert("claim", Value::String("has_recovery_device"));
+        }
+    }
+    m.insert("issued_at", Value::U64(attestation.issued_at));
+    let map_hash = hash::hash_of_map(m);
+    hash::hash_with_domain(b"ic-ii-anchor-attestation", &map_hash)
+}
@@ -1,11 +1,11 @@
-use crate::state::AssetHashes;
+use crate::state::{Anchor, AssetHashes};
 use crate::{
-    hash, secs_to_nanos, state, trap_if_not_authenticated, update_root_hash, LABEL_ASSETS,
-    LABEL_SIG,
+    anchor_management, derivation_origin, hash, secs_to_nanos, state, trap_if_not_authenticated,
+    update_root_hash, LABEL_ASSETS, LABEL_DELEGATIONS_NOT_BEFORE, LABEL_SIG,
 };
 use candid::Principal;
 use ic_cdk::api::{data_certificate, time};
-use ic_cdk::{id, trap};
+use ic_cdk::{caller, id, trap};
 use ic_certified_map::AsHashTree;
 use ic_certified_map::{Hash, HashTree};
 use internet_identity::signature_map::SignatureMap;
@@ -20,31 +20,55 @@ const DEFAULT_EXPIRATION_PERIOD_NS: u64 = secs_to_nanos(30 * 60);
 const MAX_EXPIRATION_PERIOD_NS: u64 = secs_to_nanos(30 * 24 * 60 * 60);
 // 1 min
 const DEFAULT_SIGNATURE_EXPIRATION_PERIOD_NS: u64 = secs_to_nanos(60);
+// the maximum number of targets of a delegation accepted by the IC
+const MAX_DELEGATION_TARGETS: usize = 1000;
 
 pub async fn prepare_delegation(
     user_number: UserNumber,
     frontend: FrontendHostname,
     session_key: SessionKey,
     max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    derivation_origin: Option<DerivationOrigin>,
 ) -> (UserKey, Timestamp) {
+    let anchor = state::anchor(user_number);
     // must be called before the first await because it requires caller()
-    trap_if_not_authenticated(&state::anchor(user_number));
+    trap_if_not_authenticated(&anchor);
+    let caller = caller(); // caller is only available before await
 
     state::ensure_salt_set().await;
     prune_expired_signatures();
     check_frontend_length(&frontend);
+    check_targets_length(&targets);
+    let origin = derivation_origin::effective_origin(&frontend, derivation_origin);
+    check_frontend_length(&origin);
 
     let delta = u64::min(
         max_time_to_live.unwrap_or(DEFAULT_EXPIRATION_PERIOD_NS),
-        MAX_EXPIRATION_PERIOD_NS,
+        max_expiration_period(&anchor, &origin),
     );
-    let expiration = (time() as u64).saturating_add(delta);
-    let seed = calculate_seed(user_number, &frontend);
+    let now = time();
+    let expiration = now.saturating_add(delta);
+    let seed = calculate_seed(user_number, &origin);
+    let session = Session {
+        session_key_hash: ByteBuf::from(hash::hash_bytes(&session_key).to_vec()),
+        derivation_origin: (origin != frontend).then(|| origin.clone()),
+        frontend,
+        issued_at: now,
+        expiration,
+    };
 
-    state::signature_map_mut(|sigs| {
-        add_signature(sigs, session_key, seed, expiration);
+    let msg_hash = delegation_signature_msg_hash(&Delegation {
+        pubkey: session_key,
+        expiration,
+        targets,
     });
-    update_root_hash();
+    add_signature(user_number, seed, msg_hash);
+    state::storage_mut(|storage| {
+        storage.prune_expired(now);
+        storage.record_session(user_number, session);
+    });
+    anchor_management::update_device_usage(user_number, caller);
 
     state::usage_metrics_mut(|metrics| {
         metrics.delegation_counter += 1;
@@ -55,47 +79,74 @@ pub async fn prepare_delegation(
     )
 }
 
+/// Returns the maximum lifetime of delegations of the anchor for the given origin, i.e. the limit
+/// set by the user (if any) capped at [MAX_EXPIRATION_PERIOD_NS].
+fn max_expiration_period(anchor: &Anchor, origin: &FrontendHostname) -> u64 {
+    let user_limit = anchor
+        .delegation_lifetime_limits
+        .as_ref()
+        .and_then(|limits| {
+            limits
+                .frontend_overrides
+                .iter()
+                .find(|(frontend, _)| frontend == origin)
+                .map(|(_, max_time_to_live)| *max_time_to_live)
+                .or(limits.max_time_to_live)
+        });
+    user_limit.map_or(MAX_EXPIRATION_PERIOD_NS, |limit| {
+        u64::min(limit, MAX_EXPIRATION_PERIOD_NS)
+    })
+}
+
 pub fn get_delegation(
     user_number: UserNumber,
     frontend: FrontendHostname,
     session_key: SessionKey,
     expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
+    derivation_origin: Option<FrontendHostname>,
 ) -> GetDelegationResponse {
     check_frontend_length(&frontend);
+    check_targets_length(&targets);
     trap_if_not_authenticated(&state::anchor(user_number));
 
-    state::asset_hashes_and_sigs(|asset_hashes, sigs| {
-        match get_signature(
-            asset_hashes,
-            sigs,
-            session_key.clone(),
-            calculate_seed(user_number, &frontend),
-            expiration,
-        ) {
-            Some(signature) => GetDelegationResponse::SignedDelegation(SignedDelegation {
-                delegation: Delegation {
-                    pubkey: session_key,
-                    expiration,
-                    targets: None,
-                },
-                signature: ByteBuf::from(signature),
-            }),
-            None => GetDelegationResponse::NoSuchDelegation,
-        }
-    })
+    // No need to verify the derivation origin again: the signature only exists if the derivation
+    // origin was verified by prepare_delegation.
+    let origin = derivation_origin.unwrap_or(frontend);
+    check_frontend_length(&origin);
+
+    let delegation = Delegation {
+        pubkey: session_key,
+        expiration,
+        targets,
+    };
+    let msg_hash = delegation_signature_msg_hash(&delegation);
+    match get_signature(calculate_seed(user_number, &origin), msg_hash) {
+        Some(signature) => GetDelegationResponse::SignedDelegation(SignedDelegation {
+            delegation,
+            signature: ByteBuf::from(signature),
+        }),
+        None => GetDelegationResponse::NoSuchDelegation,
+    }
 }
 
-pub fn get_principal(user_number: UserNumber, frontend: FrontendHostname) -> Principal {
+pub fn get_principal(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    derivation_origin: Option<DerivationOrigin>,
+) -> Principal {
     check_frontend_length(&frontend);
 
     trap_if_not_authenticated(&state::anchor(user_number));
 
-    let seed = calculate_seed(user_number, &frontend);
+    let origin = derivation_origin::effective_origin(&frontend, derivation_origin);
+    check_frontend_length(&origin);
+    let seed = calculate_seed(user_number, &origin);
     let public_key = der_encode_canister_sig_key(seed.to_vec());
     Principal::self_authenticating(&public_key)
 }
 
-fn calculate_seed(user_number: UserNumber, frontend: &FrontendHostname) -> Hash {
+pub fn calculate_seed(user_number: UserNumber, frontend: &FrontendHostname) -> Hash {
     let salt = state::salt();
 
     let mut blob: Vec<u8> = vec![];
@@ -113,7 +164,7 @@ fn calculate_seed(user_number: UserNumber, frontend: &FrontendHostname) -> Hash
     hash::hash_bytes(blob)
 }
 
-fn der_encode_canister_sig_key(seed: Vec<u8>) -> Vec<u8> {
+pub fn der_encode_canister_sig_key(seed: Vec<u8>) -> Vec<u8> {
     let my_canister_id: Vec<u8> = id().as_ref().to_vec();
 
     let mut bitstring: Vec<u8> = vec![];
@@ -155,21 +206,26 @@ fn delegation_signature_msg_hash(d: &Delegation) -> Hash {
     hash::hash_with_domain(b"ic-request-auth-delegation", &map_hash)
 }
 
-fn get_signature(
+/// Returns the canister signature of the given message hash (signed with the key derived from the
+/// given seed), if it has been added to the signature map.
+pub fn get_signature(seed: Hash, msg_hash: Hash) -> Option<Vec<u8>> {
+    let revocations_root_hash =
+        state::delegation_revocations(|revocations| revocations.root_hash());
+    state::asset_hashes_and_sigs(|asset_hashes, sigs| {
+        signature_from_map(revocations_root_hash, asset_hashes, sigs, seed, msg_hash)
+    })
+}
+
+fn signature_from_map(
+    revocations_root_hash: Hash,
     asset_hashes: &AssetHashes,
     sigs: &SignatureMap,
-    pk: PublicKey,
     seed: Hash,
-    expiration: Timestamp,
+    msg_hash: Hash,
 ) -> Option<Vec<u8>> {
     let certificate = data_certificate().unwrap_or_else(|| {
         trap("data certificate is only available in query calls");
     });
-    let msg_hash = delegation_signature_msg_hash(&Delegation {
-        pubkey: pk,
-        expiration,
-        targets: None,
-    });
     let witness = sigs.witness(hash::hash_bytes(seed), msg_hash)?;
 
     let witness_hash = witness.reconstruct();
@@ -184,12 +240,23 @@ fn get_signature(
 
     let tree = ic_certified_map::fork(
         HashTree::Pruned(ic_certified_map::labeled_hash(
-            LABEL_ASSETS,
-            &asset_hashes.root_hash(),
+            LABEL_DELEGATIONS_NOT_BEFORE,
+            &revocations_root_hash,
         )),
-        ic_certified_map::labeled(&LABEL_SIG[..], witness),
+        ic_certified_map::fork(
+            HashTree::Pruned(ic_certified_map::labeled_hash(
+                LABEL_ASSETS,
+                &asset_hashes.root_hash(),
+            )),
+            ic_certified_map::labeled(&LABEL_SIG[..], witness),
+        ),
     );
 
+    Some(certified_cbor(certificate, tree))
+}
+
+/// CBOR encodes the certificate together with the hash tree (the format of canister signatures).
+fn certified_cbor(certificate: Vec<u8>, tree: HashTree) -> Vec<u8> {
     #[derive(Serialize)]
     struct Sig<'a> {
         certificate: ByteBuf,
@@ -204,17 +271,140 @@ fn get_signature(
     let mut cbor = serde_cbor::ser::Serializer::new(Vec::new());
     cbor.self_describe().unwrap();
     sig.serialize(&mut cbor).unwrap();
-    Some(cbor.into_inner())
+    cbor.into_inner()
 }
 
-fn add_signature(sigs: &mut SignatureMap, pk: PublicKey, seed: Hash, expiration: Timestamp) {
-    let msg_hash = delegation_signature_msg_hash(&Delegation {
-        pubkey: pk,
-        expiration,
-        targets: None,
-    });
+/// Adds the signature of the given message hash (signed with the key derived from the given seed)
+/// to the signature map and updates the certified data.
+pub fn add_signature(user_number: UserNumber, seed: Hash, msg_hash: Hash) {
     let expires_at = (time() as u64).saturating_add(DEFAULT_SIGNATURE_EXPIRATION_PERIOD_NS);
-    sigs.put(hash::hash_bytes(seed), msg_hash, expires_at);
+    state::signature_map_mut(|sigs| {
+        sigs.put(hash::hash_bytes(seed), msg_hash, expires_at);
+    });
+    track_signature_seed(user_number, hash::hash_bytes(seed), expires_at);
+    update_root_hash();
+}
+
+/// Remembers the seed of a signature of the given anchor so that the signature can be invalidated
+/// if the anchor is deleted. Seeds whose signatures have all expired are forgotten.
+fn track_signature_seed(user_number: UserNumber, seed_hash: Hash, expires_at: Timestamp) {
+    let now = time() as u64;
+    state::signature_seeds_mut(|signature_seeds| {
+        signature_seeds.retain(|_, seeds| {
+            seeds.retain(|_, seed_expires_at| *seed_expires_at > now);
+            !seeds.is_empty()
+        });
+        let seed_expires_at = signature_seeds
+            .entry(user_number)
+            .or_default()
+            .entry(seed_hash)
+            .or_default();
+        *seed_expires_at = u64::max(*seed_expires_at, expires_at);
+    });
+}
+
+/// Removes all signatures of the given anchor from the signature map.
+pub fn invalidate_signatures(user_number: UserNumber) {
+    let seeds = state::signature_seeds_mut(|signature_seeds| signature_seeds.remove(&user_number));
+    let Some(seeds) = seeds else {
+        return;
+    };
+    state::signature_map_mut(|sigs| {
+        for seed_hash in seeds.keys() {
+            sigs.delete_seed(*seed_hash);
+        }
+    });
+    update_root_hash();
+}
+
+/// Revokes the delegations of the given anchor for the given frontend (or derivation origin):
+/// signatures that have not been fetched yet are dropped, the sessions are removed and the current
+/// time is recorded as the time from which on delegations of the principal are valid (see
+/// [get_delegations_not_before]).
+pub async fn revoke_delegations(user_number: UserNumber, frontend: FrontendHostname) -> Timestamp {
+    // must be called before the first await because it requires caller()
+    trap_if_not_authenticated(&state::anchor(user_number));
+
+    state::ensure_salt_set().await;
+    check_frontend_length(&frontend);
+
+    let now = time();
+    let seed = calculate_seed(user_number, &frontend);
+    let seed_hash = hash::hash_bytes(seed);
+    state::signature_map_mut(|sigs| sigs.delete_seed(seed_hash));
+    state::signature_seeds_mut(|signature_seeds| {
+        if let Some(seeds) = signature_seeds.get_mut(&user_number) {
+            seeds.remove(&seed_hash);
+        }
+    });
+
+    // delegations issued before the revocation expire within MAX_EXPIRATION_PERIOD_NS
+    let principal = Principal::self_authenticating(der_encode_canister_sig_key(seed.to_vec()));
+    let expired = state::storage_mut(|storage| {
+        storage.remove_sessions(user_number, |session| {
+            let origin = session
+                .derivation_origin
+                .as_ref()
+                .unwrap_or(&session.frontend);
+            origin == &frontend
+        });
+        let expired = storage.prune_expired_revocations(now);
+        storage.insert_delegation_revocation(
+            principal.as_slice(),
+            now,
+            now.saturating_add(MAX_EXPIRATION_PERIOD_NS),
+        );
+        expired
+    });
+    state::delegation_revocations_mut(|revocations| {
+        for expired_principal in expired {
+            revocations.delete(&expired_principal);
+        }
+        revocations.insert(principal.as_slice().to_vec(), now.to_be_bytes().to_vec());
+    });
+    update_root_hash();
+    now
+}
+
+/// Returns the time from which on delegations of the given principal are valid, i.e. delegations
+/// issued before were revoked. In query calls, the response includes a certified lookup of the
+/// timestamp under `["delegations_not_before", principal]` (or the proof of its absence).
+pub fn get_delegations_not_before(principal: Principal) -> DelegationsNotBefore {
+    let not_before = state::storage(|storage| storage.delegations_not_before(principal.as_slice()));
+    let certified_lookup = data_certificate().map(|certificate| {
+        state::delegation_revocations(|revocations| {
+            state::asset_hashes_and_sigs(|asset_hashes, sigs| {
+                let tree = ic_certified_map::fork(
+                    ic_certified_map::labeled(
+                        LABEL_DELEGATIONS_NOT_BEFORE,
+                        revocations.witness(principal.as_slice()),
+                    ),
+                    HashTree::Pruned(ic_certified_map::fork_hash(
+                        &ic_certified_map::labeled_hash(LABEL_ASSETS, &asset_hashes.root_hash()),
+                        &ic_certified_map::labeled_hash(LABEL_SIG, &sigs.root_hash()),
+                    )),
+                );
+                ByteBuf::from(certified_cbor(certificate, tree))
+            })
+        })
+    });
+    DelegationsNotBefore {
+        not_before,
+        certified_lookup,
+    }
+}
+
+/// Returns the sessions of the given anchor that have not expired yet, most recently issued first.
+pub fn get_sessions(user_number: UserNumber) -> Vec<Session> {
+    trap_if_not_authenticated(&state::anchor(user_number));
+
+    let now = time();
+    let mut sessions: Vec<Session> = state::storage(|storage| storage.sessions(user_number))
+        .into_iter()
+        .filter(|session| session.expiration > now)
+        .collect();
+    sessions.sort_by(|a, b| b.issued_at.cmp(&a.issued_at));
+    sessions
 }
 
 /// Removes a batch of expired signatures from the signature map.
@@ -231,7 +421,7 @@ pub fn prune_expired_signatures() {
     }
 }
 
-fn check_frontend_length(frontend: &FrontendHostname) {
+pub fn check_frontend_length(frontend: &FrontendHostname) {
     const FRONTEND_HOSTNAME_LIMIT: usize = 255;
 
     let n = frontend.len();
@@ -242,3 +432,15 @@ fn check_frontend_length(frontend: &FrontendHostname) {
         ));
     }
 }
+
+fn check_targets_length(targets: &Option<Vec<Principal>>) {
+    if let Some(targets) = targets {
+        if targets.len() > MAX_DELEGATION_TARGETS {
+            trap(&format!(
+                "delegation targets {} exceeds the limit of {} targets",
+                targets.len(),
+                MAX_DELEGATION_TARGETS,
+            ));
+        }
+    }
+}
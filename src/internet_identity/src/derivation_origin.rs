@@ -0,0 +1,290 @@
+//! Verification of derivation origins.
+//!
+//! A client application frontend can ask II to derive the principal from another origin (the
+//! derivation origin) than its own. This is only allowed if the derivation origin lists the
+//! frontend in its `/.well-known/ii-alternative-origins` document. Since II cannot make HTTP
+//! requests, the caller supplies the document together with the `IC-Certificate` header that
+//! the derivation origin canister serves it with. II then checks that:
+//! 1. the certificate is signed by the IC (possibly using a subnet delegation) and is recent
+//! 2. the certificate certifies the data of the canister hosting the derivation origin
+//! 3. the certified data is the root hash of the supplied tree, which contains the hash of the
+//!    supplied document under ["http_assets", "/.well-known/ii-alternative-origins"]
+//! 4. the document lists the frontend as an alternative origin
+use crate::{hash, secs_to_nanos, state, LABEL_ASSETS};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_cdk::trap;
+use ic_certified_map::{fork, labeled, Hash, HashTree};
+use internet_identity_interface::*;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use std::borrow::Cow;
+
+#[cfg(test)]
+mod tests;
+
+const ALTERNATIVE_ORIGINS_PATH: &str = "/.well-known/ii-alternative-origins";
+// the same limits are enforced by the II frontend
+const MAX_ALTERNATIVE_ORIGINS: usize = 10;
+const MAX_ALTERNATIVE_ORIGINS_SIZE: usize = 10 * 1024;
+// 5 mins, also used by the service worker
+const MAX_CERTIFICATE_AGE_NS: u64 = secs_to_nanos(5 * 60);
+const DERIVATION_ORIGIN_DOMAINS: [&str; 2] = [".ic0.app", ".icp0.io"];
+
+const IC_STATE_ROOT_DOMAIN_SEPARATOR: &[u8] = b"ic-state-root";
+/// DER prefix of a BLS12-381 public key as used by the IC.
+const BLS_KEY_DER_PREFIX: &str =
+    "308182301d060d2b0601040182dc7c0503010201060c2b0601040182dc7c05030201036100";
+/// DER encoded root key of the IC mainnet.
+const IC_ROOT_KEY_DER: &str = "308182301d060d2b0601040182dc7c0503010201060c2b0601040182dc7c05030201036100814c0e6ec71fab583b08bd81373c255c3c371b2e84863c98a4f1e08b74235d14fb5d9c0cd546d9685f913a0c0b2cc5341583bf4b4392e467db96d65b9bb4cb717112f8472e0d5a4d14505ffd7484b01291091c5f87b98883463f98091a0baaae";
+
+#[derive(Deserialize)]
+struct Certificate {
+    tree: Value,
+    signature: ByteBuf,
+    delegation: Option<CertificateDelegation>,
+}
+
+#[derive(Deserialize)]
+struct CertificateDelegation {
+    subnet_id: ByteBuf,
+    certificate: ByteBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AlternativeOrigins {
+    alternative_origins: Vec<String>,
+}
+
+/// Returns the origin the principal of the given frontend is derived from.
+/// Traps if the derivation origin is given but cannot be verified.
+pub fn effective_origin(
+    frontend: &FrontendHostname,
+    derivation_origin: Option<DerivationOrigin>,
+) -> FrontendHostname {
+    match derivation_origin {
+        None => frontend.clone(),
+        Some(derivation_origin) if derivation_origin.origin == *frontend => frontend.clone(),
+        Some(derivation_origin) => {
+            if let Err(err) = verify_derivation_origin(frontend, &derivation_origin, time()) {
+                trap(&format!(
+                    "{} is not a valid derivation origin for {}: {}",
+                    derivation_origin.origin, frontend, err
+                ));
+            }
+            derivation_origin.origin
+        }
+    }
+}
+
+fn verify_derivation_origin(
+    frontend: &FrontendHostname,
+    derivation_origin: &DerivationOrigin,
+    now: Timestamp,
+) -> Result<(), String> {
+    let canister_id = canister_id_from_origin(&derivation_origin.origin)?;
+    let alternative_origins = &derivation_origin.alternative_origins;
+    if alternative_origins.body.len() > MAX_ALTERNATIVE_ORIGINS_SIZE {
+        return Err(format!(
+            "alternative origins exceed the limit of {} bytes",
+            MAX_ALTERNATIVE_ORIGINS_SIZE
+        ));
+    }
+
+    let certified_data = verify_certificate(&alternative_origins.certificate, canister_id, now)?;
+
+    let tree_value: Value = serde_cbor::from_slice(&alternative_origins.tree)
+        .map_err(|err| format!("failed to decode tree: {}", err))?;
+    let tree = parse_tree(&tree_value)?;
+    if tree.reconstruct().as_slice() != certified_data.as_slice() {
+        return Err("tree does not match the certified data".to_string());
+    }
+    match lookup(&tree, &[LABEL_ASSETS, ALTERNATIVE_ORIGINS_PATH.as_bytes()]) {
+        Some(asset_hash)
+            if asset_hash == hash::hash_bytes(&alternative_origins.body).as_slice() => {}
+        Some(_) => return Err("alternative origins hash mismatch".to_string()),
+        None => return Err("alternative origins are not certified".to_string()),
+    }
+
+    let alternative_origins: AlternativeOrigins = serde_json::from_slice(&alternative_origins.body)
+        .map_err(|err| format!("failed to parse alternative origins: {}", err))?;
+    if alternative_origins.alternative_origins.len() > MAX_ALTERNATIVE_ORIGINS {
+        return Err(format!(
+            "more than {} alternative origins",
+            MAX_ALTERNATIVE_ORIGINS
+        ));
+    }
+    if !alternative_origins.alternative_origins.contains(frontend) {
+        return Err(format!("{} is not listed as alternative origin", frontend));
+    }
+    Ok(())
+}
+
+/// Derivation origins must be served by a canister, i.e. have the form
+/// `https://<canister id>.ic0.app` or `https://<canister id>.icp0.io`.
+fn canister_id_from_origin(origin: &str) -> Result<Principal, String> {
+    let host = origin
+        .strip_prefix("https://")
+        .ok_or_else(|| "derivation origin must use https".to_string())?;
+    let canister_id = DERIVATION_ORIGIN_DOMAINS
+        .iter()
+        .find_map(|domain| host.strip_suffix(domain))
+        .ok_or_else(|| "derivation origin must be a canister origin".to_string())?;
+    Principal::from_text(canister_id).map_err(|err| format!("invalid canister id: {}", err))
+}
+
+/// Verifies the certificate and returns the certified data of the given canister.
+fn verify_certificate(
+    certificate: &[u8],
+    canister_id: Principal,
+    now: Timestamp,
+) -> Result<Vec<u8>, String> {
+    let certificate: Certificate = serde_cbor::from_slice(certificate)
+        .map_err(|err| format!("failed to decode certificate: {}", err))?;
+    let tree = parse_tree(&certificate.tree)?;
+
+    let public_key = match certificate.delegation {
+        None => root_key()?,
+        Some(delegation) => verify_delegation(&delegation, canister_id)?,
+    };
+    verify_signature(&tree, &certificate.signature, &public_key)?;
+
+    let time = lookup(&tree, &[&b"time"[..]])
+        .ok_or_else(|| "certificate does not contain the time".to_string())
+        .and_then(decode_leb128)?;
+    if time.saturating_add(MAX_CERTIFICATE_AGE_NS) < now
+        || now.saturating_add(MAX_CERTIFICATE_AGE_NS) < time
+    {
+        return Err("certificate time is not recent".to_string());
+    }
+
+    lookup(
+        &tree,
+        &[
+            &b"canister"[..],
+            canister_id.as_slice(),
+            &b"certified_data"[..],
+        ],
+    )
+    .map(|data| data.to_vec())
+    .ok_or_else(|| "certificate does not contain the certified data".to_string())
+}
+
+/// Verifies the subnet delegation and returns the (raw) public key of the subnet.
+fn verify_delegation(
+    delegation: &CertificateDelegation,
+    canister_id: Principal,
+) -> Result<Vec<u8>, String> {
+    let certificate: Certificate = serde_cbor::from_slice(&delegation.certificate)
+        .map_err(|err| format!("failed to decode delegation certificate: {}", err))?;
+    if certificate.delegation.is_some() {
+        return Err("nested delegations are not allowed".to_string());
+    }
+    let tree = parse_tree(&certificate.tree)?;
+    verify_signature(&tree, &certificate.signature, &root_key()?)?;
+
+    let subnet_id = delegation.subnet_id.as_slice();
+    let ranges = lookup(&tree, &[&b"subnet"[..], subnet_id, &b"canister_ranges"[..]])
+        .ok_or_else(|| "delegation does not contain the canister ranges".to_string())?;
+    let ranges: Vec<(ByteBuf, ByteBuf)> = serde_cbor::from_slice(ranges)
+        .map_err(|err| format!("failed to decode canister ranges: {}", err))?;
+    let canister_id = canister_id.as_slice();
+    if !ranges
+        .iter()
+        .any(|(low, high)| low.as_slice() <= canister_id && canister_id <= high.as_slice())
+    {
+        return Err("delegation is not valid for the canister".to_string());
+    }
+
+    lookup(&tree, &[&b"subnet"[..], subnet_id, &b"public_key"[..]])
+        .ok_or_else(|| "delegation does not contain the subnet key".to_string())
+        .and_then(raw_bls_key)
+}
+
+fn verify_signature(tree: &HashTree, signature: &[u8], public_key: &[u8]) -> Result<(), String> {
+    let mut message = vec![IC_STATE_ROOT_DOMAIN_SEPARATOR.len() as u8];
+    message.extend(IC_STATE_ROOT_DOMAIN_SEPARATOR);
+    message.extend(tree.reconstruct());
+    ic_verify_bls_signature::verify_bls_signature(signature, &message, public_key)
+        .map_err(|_| "invalid certificate signature".to_string())
+}
+
+/// Returns the raw key of the configured IC root key.
+fn root_key() -> Result<Vec<u8>, String> {
+    let der = state::persistent_state(|persistent_state| persistent_state.ic_root_key_der.clone())
+        .map(|key| key.into_vec())
+        .unwrap_or_else(|| hex::decode(IC_ROOT_KEY_DER).expect("invalid root key"));
+    raw_bls_key(&der)
+}
+
+fn raw_bls_key(der: &[u8]) -> Result<Vec<u8>, String> {
+    let prefix = hex::decode(BLS_KEY_DER_PREFIX).expect("invalid DER prefix");
+    match der.strip_prefix(prefix.as_slice()) {
+        Some(key) if key.len() == 96 => Ok(key.to_vec()),
+        _ => Err("malformed BLS public key".to_string()),
+    }
+}
+
+fn decode_leb128(bytes: &[u8]) -> Result<u64, String> {
+    let mut result: u64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        if i >= 10 {
+            break;
+        }
+        result |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err("malformed LEB128 value".to_string())
+}
+
+/// Parses a CBOR encoded hash tree (see https://internetcomputer.org/docs/current/references/ic-interface-spec#certificate).
+fn parse_tree(value: &Value) -> Result<HashTree, String> {
+    let items = match value {
+        Value::Tag(_, value) => return parse_tree(value),
+        Value::Array(items) => items,
+        _ => return Err("malformed hash tree".to_string()),
+    };
+    match items.as_slice() {
+        [Value::Integer(0)] => Ok(HashTree::Empty),
+        [Value::Integer(1), left, right] => Ok(fork(parse_tree(left)?, parse_tree(right)?)),
+        [Value::Integer(2), Value::Bytes(label), subtree] => {
+            Ok(labeled(label.as_slice(), parse_tree(subtree)?))
+        }
+        [Value::Integer(3), Value::Bytes(leaf)] => {
+            Ok(HashTree::Leaf(Cow::Borrowed(leaf.as_slice())))
+        }
+        [Value::Integer(4), Value::Bytes(hash)] => {
+            let hash: Hash = hash
+                .as_slice()
+                .try_into()
+                .map_err(|_| "malformed pruned hash".to_string())?;
+            Ok(HashTree::Pruned(hash))
+        }
+        _ => Err("malformed hash tree".to_string()),
+    }
+}
+
+/// Returns the leaf at the given path, if any.
+fn lookup<'a>(tree: &'a HashTree<'a>, path: &[&[u8]]) -> Option<&'a [u8]> {
+    match path.split_first() {
+        None => match tree {
+            HashTree::Leaf(leaf) => Some(leaf.as_ref()),
+            _ => None,
+        },
+        Some((label, rest)) => find_label(tree, label).and_then(|subtree| lookup(subtree, rest)),
+    }
+}
+
+fn find_label<'a>(tree: &'a HashTree<'a>, label: &[u8]) -> Option<&'a HashTree<'a>> {
+    match tree {
+        HashTree::Labeled(l, subtree) if *l == label => Some(subtree.as_ref()),
+        HashTree::Fork(children) => {
+            find_label(&children.0, label).or_else(|| find_label(&children.1, label))
+        }
+        _ => None,
+    }
+}
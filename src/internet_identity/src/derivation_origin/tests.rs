@@ -0,0 +1,66 @@
+use crate::derivation_origin::{canister_id_from_origin, decode_leb128, lookup, parse_tree};
+use candid::Principal;
+use ic_certified_map::{fork, labeled, HashTree};
+use serde::Serialize;
+use serde_cbor::Value;
+use std::borrow::Cow;
+
+fn encode_tree(tree: &HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    tree.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
+
+#[test]
+fn should_get_canister_id_from_origin() {
+    let canister_id = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+    assert_eq!(
+        canister_id_from_origin("https://rdmx6-jaaaa-aaaaa-aaadq-cai.ic0.app"),
+        Ok(canister_id)
+    );
+    assert_eq!(
+        canister_id_from_origin("https://rdmx6-jaaaa-aaaaa-aaadq-cai.icp0.io"),
+        Ok(canister_id)
+    );
+}
+
+#[test]
+fn should_reject_non_canister_origins() {
+    for origin in [
+        "http://rdmx6-jaaaa-aaaaa-aaadq-cai.ic0.app",
+        "https://rdmx6-jaaaa-aaaaa-aaadq-cai.raw.ic0.app",
+        "https://identity.ic0.app",
+        "https://example.com",
+    ] {
+        assert!(canister_id_from_origin(origin).is_err(), "{}", origin);
+    }
+}
+
+#[test]
+fn should_parse_and_lookup_tree() {
+    let tree = fork(
+        labeled(b"time", HashTree::Leaf(Cow::Borrowed(&[0x80, 0x01]))),
+        labeled(
+            b"http_assets",
+            fork(
+                HashTree::Pruned([1; 32]),
+                labeled(b"/index.html", HashTree::Leaf(Cow::Borrowed(b"hash"))),
+            ),
+        ),
+    );
+    let value: Value = serde_cbor::from_slice(&encode_tree(&tree)).unwrap();
+    let parsed = parse_tree(&value).unwrap();
+
+    assert_eq!(parsed.reconstruct(), tree.reconstruct());
+    assert_eq!(
+        lookup(&parsed, &[&b"http_assets"[..], &b"/index.html"[..]]),
+        Some(&b"hash"[..])
+    );
+    assert_eq!(
+        lookup(&parsed, &[&b"time"[..]]).map(decode_leb128),
+        Some(Ok(128))
+    );
+    assert_eq!(lookup(&parsed, &[&b"http_assets"[..], &b"/"[..]]), None);
+    assert_eq!(lookup(&parsed, &[&b"http_assets"[..]]), None);
+}
@@ -1,15 +1,19 @@
+use crate::anchor_management::registration::{captcha, rate_limit};
 use crate::archive::ArchiveState;
 use crate::assets::ContentType;
-use crate::{assets, state, LABEL_ASSETS, LABEL_SIG};
+use crate::storage::{anchor_statistics, Storage};
+use crate::{archive, assets, state, LABEL_ASSETS, LABEL_DELEGATIONS_NOT_BEFORE, LABEL_SIG};
 use ic_cdk::api::stable::stable64_size;
 use ic_cdk::api::{data_certificate, time};
 use ic_cdk::trap;
 use ic_certified_map::HashTree;
+use ic_stable_structures::DefaultMemoryImpl;
 use internet_identity_interface::{HeaderField, HttpRequest, HttpResponse};
 use metrics_encoder::MetricsEncoder;
 use serde::Serialize;
 use serde_bytes::{ByteBuf, Bytes};
 use std::borrow::Cow;
+use std::time::Duration;
 
 impl ContentType {
     pub fn to_mime_type_string(&self) -> String {
@@ -101,7 +105,13 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             "internet_identity_max_user_number",
             (hi - 1) as f64,
             "The highest Identity Anchor that can be served by this canister.",
-        )
+        )?;
+        w.encode_gauge(
+            "internet_identity_device_index_unindexed_anchors",
+            storage.unindexed_records() as f64,
+            "The number of anchors whose devices have not been added to the device index yet.",
+        )?;
+        encode_anchor_statistics(w, storage)
     })?;
     state::signature_map(|sigs| {
         w.encode_gauge(
@@ -120,30 +130,63 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
         state::last_upgrade_timestamp() as f64,
         "The most recent IC time (in nanos) when this canister was successfully upgraded.",
     )?;
-    state::inflight_challenges(|inflight_challenges| {
+    state::storage(|storage| {
         w.encode_gauge(
             "internet_identity_inflight_challenges",
-            inflight_challenges.len() as f64,
+            storage.inflight_challenges_len() as f64,
             "The number of inflight CAPTCHA challenges",
-        )
-    })?;
-    state::tentative_device_registrations(|tentative_device_registrations| {
+        )?;
         w.encode_gauge(
             "internet_identity_users_in_registration_mode",
-            tentative_device_registrations.len() as f64,
+            storage.tentative_device_registrations_len() as f64,
             "The number of users in registration mode",
         )
     })?;
     state::usage_metrics(|usage_metrics| {
-        w.encode_gauge(
+        w.encode_counter(
             "internet_identity_delegation_counter",
             usage_metrics.delegation_counter as f64,
-            "The number of delegations created since last upgrade",
+            "The number of delegations created.",
         )?;
-        w.encode_gauge(
+        w.encode_counter(
             "internet_identity_anchor_operations_counter",
             usage_metrics.anchor_operation_counter as f64,
-            "The number of anchor operations since last upgrade",
+            "The number of anchor operations.",
+        )?;
+        w.encode_counter(
+            "internet_identity_register_counter",
+            usage_metrics.register_counter as f64,
+            "The number of anchors registered.",
+        )?;
+        w.encode_counter(
+            "internet_identity_add_device_counter",
+            usage_metrics.add_device_counter as f64,
+            "The number of devices added (including verified tentative devices).",
+        )?;
+        w.encode_counter(
+            "internet_identity_update_device_counter",
+            usage_metrics.update_device_counter as f64,
+            "The number of devices updated.",
+        )?;
+        w.encode_counter(
+            "internet_identity_remove_device_counter",
+            usage_metrics.remove_device_counter as f64,
+            "The number of devices removed.",
+        )?;
+        w.encode_counter(
+            "internet_identity_delete_anchor_counter",
+            usage_metrics.delete_anchor_counter as f64,
+            "The number of anchors deleted.",
+        )?;
+        w.encode_counter(
+            "internet_identity_add_tentative_device_counter",
+            usage_metrics.add_tentative_device_counter as f64,
+            "The number of devices added tentatively.",
+        )?;
+        w.encode_counter(
+            "internet_identity_verify_tentative_device_counter",
+            usage_metrics.verify_tentative_device_counter as f64,
+            "The number of tentative devices verified successfully.",
         )
     })?;
     state::persistent_state(|persistent_state| {
@@ -157,9 +200,119 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             Ok(())
         }
     })?;
+    let archive_buffer = archive::archive_buffer_info();
+    w.encode_gauge(
+        "internet_identity_archive_buffered_entries",
+        archive_buffer.buffered_entries as f64,
+        "The number of entries waiting to be acknowledged by the archive.",
+    )?;
+    w.encode_counter(
+        "internet_identity_archive_dropped_entries_counter",
+        archive_buffer.dropped_entries as f64,
+        "The number of archive entries dropped because the buffer was full.",
+    )?;
+    w.encode_counter(
+        "internet_identity_archive_failed_pushes_counter",
+        archive_buffer.failed_pushes as f64,
+        "The number of failed attempts to push entries to the archive since last upgrade.",
+    )?;
+    let rate_limit_config = rate_limit::rate_limit_config();
+    w.encode_gauge(
+        "internet_identity_register_rate_limit_max_tokens",
+        rate_limit_config.max_tokens as f64,
+        "The maximum number of captcha challenges and registrations (each) in a burst.",
+    )?;
+    w.encode_gauge(
+        "internet_identity_register_rate_limit_time_per_token_seconds",
+        Duration::from_nanos(rate_limit_config.time_per_token_ns).as_secs_f64(),
+        "The time it takes for a new captcha challenge and registration (each) to be allowed.",
+    )?;
+    state::registration_rate_limits(|limits| {
+        w.encode_gauge(
+            "internet_identity_challenge_rate_limit_current_tokens",
+            limits
+                .challenges
+                .available_tokens(&rate_limit_config, time()) as f64,
+            "The number of captcha challenges that can currently be created.",
+        )?;
+        w.encode_gauge(
+            "internet_identity_register_rate_limit_current_tokens",
+            limits
+                .registrations
+                .available_tokens(&rate_limit_config, time()) as f64,
+            "The number of registrations that are currently allowed.",
+        )?;
+        w.encode_counter(
+            "internet_identity_rate_limited_registrations_counter",
+            limits.rate_limited_registrations as f64,
+            "The number of registrations rejected due to the rate limit since last upgrade.",
+        )
+    })?;
+    w.encode_gauge(
+        "internet_identity_recent_registrations",
+        state::registration_load(|load| load.recent_registrations(time())) as f64,
+        "The estimated number of registrations within the last hour.",
+    )?;
+    w.encode_gauge(
+        "internet_identity_pow_difficulty",
+        captcha::current_pow_difficulty() as f64,
+        "The number of leading zero bits required for new proof of work challenges.",
+    )?;
     Ok(())
 }
 
+fn encode_anchor_statistics(
+    w: &mut MetricsEncoder<Vec<u8>>,
+    storage: &Storage<DefaultMemoryImpl>,
+) -> std::io::Result<()> {
+    let statistics = storage.anchor_statistics();
+    let last_device_bucket = anchor_statistics::DEVICE_BUCKETS - 1;
+    w.encode_histogram(
+        "internet_identity_devices_per_anchor",
+        statistics
+            .devices_per_anchor
+            .iter()
+            .enumerate()
+            .map(|(devices, count)| {
+                if devices == last_device_bucket {
+                    (f64::INFINITY, *count as f64)
+                } else {
+                    (devices as f64, *count as f64)
+                }
+            }),
+        statistics.key_types.iter().sum::<u64>() as f64,
+        "Number of anchors by number of devices.",
+    )?;
+    w.encode_histogram(
+        "internet_identity_anchor_record_size_ratio",
+        statistics
+            .record_sizes
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| {
+                (
+                    (bucket + 1) as f64 / anchor_statistics::RECORD_SIZE_BUCKETS as f64,
+                    *count as f64,
+                )
+            }),
+        statistics.record_size_sum as f64 / storage.value_size_limit() as f64,
+        "Number of anchors by size of the anchor record relative to the maximum record size.",
+    )?;
+    w.encode_labeled_gauge(
+        "internet_identity_devices_by_key_type",
+        "key_type",
+        ["unknown", "platform", "cross_platform", "seed_phrase"]
+            .into_iter()
+            .zip(statistics.key_types.iter().map(|count| *count as f64)),
+        "Number of devices by key type.",
+    )?;
+    w.encode_gauge(
+        "internet_identity_anchor_statistics_unmeasured_anchors",
+        storage.unmeasured_records() as f64,
+        "The number of anchors that have not been added to the anchor statistics yet.",
+    )
+}
+
 /// List of recommended security headers as per https://owasp.org/www-project-secure-headers/
 /// These headers enable browser security features (like limit access to platform apis and set
 /// iFrame policies, etc.).
@@ -285,11 +438,19 @@ fn make_asset_certificate_header(asset_name: &str) -> (String, String) {
     let certificate = data_certificate().unwrap_or_else(|| {
         trap("data certificate is only available in query calls");
     });
+    let revocations_root_hash =
+        state::delegation_revocations(|revocations| revocations.root_hash());
     state::asset_hashes_and_sigs(|asset_hashes, sigs| {
         let witness = asset_hashes.witness(asset_name.as_bytes());
         let tree = ic_certified_map::fork(
-            ic_certified_map::labeled(LABEL_ASSETS, witness),
-            HashTree::Pruned(ic_certified_map::labeled_hash(LABEL_SIG, &sigs.root_hash())),
+            HashTree::Pruned(ic_certified_map::labeled_hash(
+                LABEL_DELEGATIONS_NOT_BEFORE,
+                &revocations_root_hash,
+            )),
+            ic_certified_map::fork(
+                ic_certified_map::labeled(LABEL_ASSETS, witness),
+                HashTree::Pruned(ic_certified_map::labeled_hash(LABEL_SIG, &sigs.root_hash())),
+            ),
         );
         let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
         serializer.self_describe().unwrap();
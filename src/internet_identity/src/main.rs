@@ -1,12 +1,13 @@
-use crate::anchor_management::tentative_device_registration;
+use crate::anchor_management::{guardian_recovery, recovery_delay, tentative_device_registration};
 use crate::assets::init_assets;
 use crate::state::Anchor;
 use candid::Principal;
-use ic_cdk::api::{caller, set_certified_data, trap};
-use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
+use ic_cdk::api::{caller, set_certified_data, time, trap};
+use ic_cdk_macros::{heartbeat, init, post_upgrade, pre_upgrade, query, update};
 use ic_certified_map::AsHashTree;
 use serde_bytes::ByteBuf;
-use storage::{PersistentStateError, Salt, Storage};
+use std::collections::HashMap;
+use storage::{Salt, Storage};
 
 use crate::archive::ArchiveState;
 use internet_identity_interface::*;
@@ -14,16 +15,20 @@ use internet_identity_interface::*;
 mod anchor_management;
 mod archive;
 mod assets;
+mod attestation;
 mod delegation;
+mod derivation_origin;
 mod hash;
 mod http;
 mod state;
 mod storage;
+mod webauthn;
 
 const fn secs_to_nanos(secs: u64) -> u64 {
     secs * 1_000_000_000
 }
 
+const LABEL_DELEGATIONS_NOT_BEFORE: &[u8] = b"delegations_not_before";
 const LABEL_ASSETS: &[u8] = b"http_assets";
 const LABEL_SIG: &[u8] = b"sig";
 
@@ -59,6 +64,41 @@ async fn verify_tentative_device(
         .await
 }
 
+#[update] // this is an update call because queries are not (yet) certified
+fn get_guardians(user_number: UserNumber) -> Option<Guardians> {
+    guardian_recovery::get_guardians(user_number)
+}
+
+#[update]
+fn set_guardians(user_number: UserNumber, guardians: Option<Guardians>) {
+    guardian_recovery::set_guardians(user_number, guardians)
+}
+
+#[update]
+fn start_guardian_recovery(
+    user_number: UserNumber,
+    device_data: DeviceData,
+) -> StartGuardianRecoveryResponse {
+    guardian_recovery::start_guardian_recovery(user_number, device_data)
+}
+
+#[update] // this is an update call because queries are not (yet) certified
+fn get_guardian_recovery(
+    guardian_number: UserNumber,
+    user_number: UserNumber,
+) -> Option<GuardianRecoveryInfo> {
+    guardian_recovery::get_guardian_recovery(guardian_number, user_number)
+}
+
+#[update]
+fn approve_guardian_recovery(
+    guardian_number: UserNumber,
+    user_number: UserNumber,
+    device_key: DeviceKey,
+) -> ApproveGuardianRecoveryResponse {
+    guardian_recovery::approve_guardian_recovery(guardian_number, user_number, device_key)
+}
+
 #[update]
 async fn create_challenge() -> Challenge {
     anchor_management::registration::create_challenge().await
@@ -84,7 +124,26 @@ async fn remove(user_number: UserNumber, device_key: DeviceKey) {
     anchor_management::remove(user_number, device_key).await
 }
 
+#[update]
+fn delete_anchor(user_number: UserNumber) {
+    anchor_management::delete_anchor(user_number)
+}
+
+#[update]
+async fn create_webauthn_challenge(user_number: UserNumber) -> WebAuthnChallenge {
+    webauthn::create_challenge(user_number).await
+}
+
+#[update]
+fn verify_webauthn_assertion(
+    user_number: UserNumber,
+    assertion: WebAuthnAssertion,
+) -> VerifyWebAuthnAssertionResponse {
+    webauthn::verify_assertion(user_number, assertion)
+}
+
 /// Returns all devices of the user (authentication and recovery) but no information about device registrations.
+/// The device usage is not returned because this call is not authenticated (see get_anchor_info).
 /// Note: Will be changed in the future to be more consistent with get_anchor_info.
 #[query]
 fn lookup(user_number: UserNumber) -> Vec<DeviceData> {
@@ -94,19 +153,81 @@ fn lookup(user_number: UserNumber) -> Vec<DeviceData> {
             .unwrap_or_default()
             .devices
             .into_iter()
-            .map(DeviceData::from)
+            .map(|device| DeviceData {
+                created_at: None,
+                last_used: None,
+                ..DeviceData::from(device)
+            })
             .collect()
     })
 }
 
+/// Returns the anchors that have a device with the given credential id or public key, so that
+/// users can log in with a discoverable credential without entering their anchor.
+/// Note: anchors created before the device index was introduced are only returned once they have
+/// been indexed.
+#[query]
+fn lookup_anchors(device: DeviceIdentifier) -> Vec<UserNumber> {
+    state::storage(|storage| storage.lookup_anchors(&device))
+}
+
 #[update] // this is an update call because queries are not (yet) certified
 fn get_anchor_info(user_number: UserNumber) -> IdentityAnchorInfo {
     anchor_management::get_anchor_info(user_number)
 }
 
+#[update] // this is an update call because queries are not (yet) certified
+fn get_anchor_metadata(user_number: UserNumber) -> HashMap<String, MetadataEntry> {
+    anchor_management::get_anchor_metadata(user_number)
+}
+
+#[update]
+fn set_anchor_metadata(user_number: UserNumber, metadata: HashMap<String, MetadataEntry>) {
+    anchor_management::set_anchor_metadata(user_number, metadata)
+}
+
+#[update] // this is an update call because queries are not (yet) certified
+fn get_delegation_lifetime_limits(user_number: UserNumber) -> DelegationLifetimeLimits {
+    anchor_management::get_delegation_lifetime_limits(user_number)
+}
+
+#[update]
+fn set_delegation_lifetime_limits(user_number: UserNumber, limits: DelegationLifetimeLimits) {
+    anchor_management::set_delegation_lifetime_limits(user_number, limits)
+}
+
+#[update] // this is an update call because queries are not (yet) certified
+fn get_recovery_delay(user_number: UserNumber) -> Option<u64> {
+    recovery_delay::get_recovery_delay(user_number)
+}
+
+#[update]
+fn set_recovery_delay(user_number: UserNumber, recovery_delay: Option<u64>) {
+    recovery_delay::set_recovery_delay(user_number, recovery_delay)
+}
+
+#[update] // this is an update call because queries are not (yet) certified
+fn get_pending_recovery_operations(user_number: UserNumber) -> Vec<PendingRecoveryOperation> {
+    recovery_delay::get_pending_recovery_operations(user_number)
+}
+
+#[update]
+fn cancel_pending_recovery_operations(user_number: UserNumber) {
+    recovery_delay::cancel_pending_recovery_operations(user_number)
+}
+
+#[update] // this is an update call because queries are not (yet) certified
+fn get_sessions(user_number: UserNumber) -> Vec<Session> {
+    delegation::get_sessions(user_number)
+}
+
 #[query]
-fn get_principal(user_number: UserNumber, frontend: FrontendHostname) -> Principal {
-    delegation::get_principal(user_number, frontend)
+fn get_principal(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    derivation_origin: Option<DerivationOrigin>,
+) -> Principal {
+    delegation::get_principal(user_number, frontend, derivation_origin)
 }
 
 /// This makes this Candid service self-describing, so that for example Candid UI, but also other
@@ -123,8 +244,18 @@ async fn prepare_delegation(
     frontend: FrontendHostname,
     session_key: SessionKey,
     max_time_to_live: Option<u64>,
+    targets: Option<Vec<Principal>>,
+    derivation_origin: Option<DerivationOrigin>,
 ) -> (UserKey, Timestamp) {
-    delegation::prepare_delegation(user_number, frontend, session_key, max_time_to_live).await
+    delegation::prepare_delegation(
+        user_number,
+        frontend,
+        session_key,
+        max_time_to_live,
+        targets,
+        derivation_origin,
+    )
+    .await
 }
 
 #[query]
@@ -133,8 +264,48 @@ fn get_delegation(
     frontend: FrontendHostname,
     session_key: SessionKey,
     expiration: Timestamp,
+    targets: Option<Vec<Principal>>,
+    derivation_origin: Option<FrontendHostname>,
 ) -> GetDelegationResponse {
-    delegation::get_delegation(user_number, frontend, session_key, expiration)
+    delegation::get_delegation(
+        user_number,
+        frontend,
+        session_key,
+        expiration,
+        targets,
+        derivation_origin,
+    )
+}
+
+#[update]
+async fn revoke_delegations(user_number: UserNumber, frontend: FrontendHostname) -> Timestamp {
+    delegation::revoke_delegations(user_number, frontend).await
+}
+
+#[query]
+fn get_delegations_not_before(principal: Principal) -> DelegationsNotBefore {
+    delegation::get_delegations_not_before(principal)
+}
+
+#[update]
+async fn prepare_attestation(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    claim: AttestationClaim,
+    derivation_origin: Option<DerivationOrigin>,
+) -> PrepareAttestationResponse {
+    attestation::prepare_attestation(user_number, frontend, claim, derivation_origin).await
+}
+
+#[query]
+fn get_attestation(
+    user_number: UserNumber,
+    frontend: FrontendHostname,
+    claim: AttestationClaim,
+    issued_at: Timestamp,
+    derivation_origin: Option<FrontendHostname>,
+) -> GetAttestationResponse {
+    attestation::get_attestation(user_number, frontend, claim, issued_at, derivation_origin)
 }
 
 #[query]
@@ -167,6 +338,8 @@ fn stats() -> InternetIdentityStats {
         archive_info,
         canister_creation_cycles_cost,
         storage_layout_version: storage.version(),
+        layout_migration_state: storage.migration_state(),
+        archive_buffer: archive::archive_buffer_info(),
     })
 }
 
@@ -178,6 +351,13 @@ async fn deploy_archive(wasm: ByteBuf) -> DeployArchiveResult {
 #[init]
 fn init(maybe_arg: Option<InternetIdentityInit>) {
     init_assets();
+    // there are no anchor records to migrate, so the latest layout is used right away if the
+    // layout migration is requested
+    state::init_new(
+        maybe_arg
+            .as_ref()
+            .map_or(false, |arg| arg.layout_migration_batch_size.is_some()),
+    );
 
     if let Some(arg) = maybe_arg {
         if let Some(range) = arg.assigned_user_number_range {
@@ -195,23 +375,51 @@ fn init(maybe_arg: Option<InternetIdentityInit>) {
                 persistent_state.canister_creation_cycles_cost = cost;
             })
         }
+        if let Some(batch_size) = arg.layout_migration_batch_size {
+            state::storage_mut(|storage| storage.set_migration_batch_size(batch_size));
+        }
+        if let Some(root_key) = arg.ic_root_key_der {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.ic_root_key_der = Some(root_key);
+            })
+        }
+        if let Some(config) = arg.webauthn_verification {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.webauthn_verification = Some(config);
+            })
+        }
+        if let Some(config) = arg.register_rate_limit {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.register_rate_limit = Some(config);
+            })
+        }
+        if let Some(captcha_type) = arg.captcha_type {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.captcha_type = Some(captcha_type);
+            })
+        }
     }
+    // anchors registered from now on record their registration time
+    state::persistent_state_mut(|persistent_state| {
+        persistent_state
+            .anchor_creation_recorded_since
+            .get_or_insert(time());
+    });
 
     // make sure the fully initialized storage configuration is written to stable memory
     state::storage_mut(|storage| storage.flush());
+    state::save_persistent_state();
     update_root_hash();
 }
 
 #[post_upgrade]
 fn post_upgrade(maybe_arg: Option<InternetIdentityInit>) {
     init_assets();
-    state::initialize_from_stable_memory();
+    state::init_from_stable_memory();
 
     // We drop all the signatures on upgrade, users will
     // re-request them if needed.
     update_root_hash();
-    // load the persistent state after initializing storage, otherwise the memory address to load it from cannot be calculated
-    state::load_persistent_state();
 
     if let Some(arg) = maybe_arg {
         if let Some(range) = arg.assigned_user_number_range {
@@ -233,7 +441,41 @@ fn post_upgrade(maybe_arg: Option<InternetIdentityInit>) {
                 persistent_state.canister_creation_cycles_cost = cost;
             })
         }
+        if let Some(batch_size) = arg.layout_migration_batch_size {
+            state::storage_mut(|storage| storage.set_migration_batch_size(batch_size));
+        }
+        if let Some(root_key) = arg.ic_root_key_der {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.ic_root_key_der = Some(root_key);
+            })
+        }
+        if let Some(config) = arg.webauthn_verification {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.webauthn_verification = Some(config);
+            })
+        }
+        if let Some(config) = arg.register_rate_limit {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.register_rate_limit = Some(config);
+            })
+        }
+        if let Some(captcha_type) = arg.captcha_type {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.captcha_type = Some(captcha_type);
+            })
+        }
     }
+    // anchors registered from now on record their registration time
+    state::persistent_state_mut(|persistent_state| {
+        persistent_state
+            .anchor_creation_recorded_since
+            .get_or_insert(time());
+    });
+
+    // the anchor records are migrated and backfilled on upgrade only, so that no instructions are
+    // spent on them once they are done
+    state::migrate_storage_layout();
+    state::backfill_anchor_records();
 }
 
 #[pre_upgrade]
@@ -241,13 +483,27 @@ fn save_persistent_state() {
     state::save_persistent_state();
 }
 
+/// On every heartbeat:
+/// * pending recovery operations are executed in batches once they become effective
+/// * buffered archive entries are pushed to the archive (if due)
+#[heartbeat]
+fn heartbeat() {
+    recovery_delay::execute_due_operations();
+    archive::push_entries_if_due();
+}
+
 fn update_root_hash() {
     use ic_certified_map::{fork_hash, labeled_hash};
+    let revocations_root_hash =
+        state::delegation_revocations(|revocations| revocations.root_hash());
     state::asset_hashes_and_sigs(|asset_hashes, sigs| {
         let prefixed_root_hash = fork_hash(
             // NB: Labels added in lexicographic order
-            &labeled_hash(LABEL_ASSETS, &asset_hashes.root_hash()),
-            &labeled_hash(LABEL_SIG, &sigs.root_hash()),
+            &labeled_hash(LABEL_DELEGATIONS_NOT_BEFORE, &revocations_root_hash),
+            &fork_hash(
+                &labeled_hash(LABEL_ASSETS, &asset_hashes.root_hash()),
+                &labeled_hash(LABEL_SIG, &sigs.root_hash()),
+            ),
         );
         set_certified_data(&prefixed_root_hash[..]);
     })
@@ -71,6 +71,16 @@ impl SignatureMap {
         }
     }
 
+    /// Deletes all signatures for the given seed.
+    pub fn delete_seed(&mut self, seed: Hash) {
+        self.certified_map.delete(&seed[..]);
+        self.expiration_queue = self
+            .expiration_queue
+            .drain()
+            .filter(|expiration| expiration.seed_hash != seed)
+            .collect();
+    }
+
     pub fn prune_expired(&mut self, now: u64, max_to_prune: usize) -> usize {
         let mut num_pruned = 0;
 
@@ -108,3 +108,22 @@ fn test_random_modifications() {
         }
     }
 }
+
+#[test]
+fn test_delete_seed() {
+    let mut map = SignatureMap::default();
+    map.put(seed(1), message(1), 10);
+    map.put(seed(1), message(2), 20);
+    map.put(seed(2), message(1), 15);
+
+    map.delete_seed(seed(1));
+    assert!(map.witness(seed(1), message(1)).is_none());
+    assert!(map.witness(seed(1), message(2)).is_none());
+    assert_eq!(
+        map.witness(seed(2), message(1))
+            .expect("failed to get a witness")
+            .reconstruct(),
+        map.root_hash()
+    );
+    assert_eq!(map.len(), 1);
+}
@@ -1,20 +1,28 @@
-use crate::archive::{ArchiveData, ArchiveInfo, ArchiveState, ArchiveStatusCache};
+use crate::anchor_management::registration::captcha::RegistrationLoad;
+use crate::anchor_management::registration::rate_limit::RegistrationRateLimits;
+use crate::archive::{
+    ArchiveData, ArchiveInfo, ArchivePushStatus, ArchiveState, ArchiveStatusCache,
+};
 use crate::storage::DEFAULT_RANGE_SIZE;
-use crate::{PersistentStateError, Salt, Storage};
+use crate::{Salt, Storage};
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::management_canister::main::CanisterStatusResponse;
-use ic_cdk::api::time;
+use ic_cdk::api::{performance_counter, time};
 use ic_cdk::{call, trap};
 use ic_certified_map::{Hash, RbTree};
 use ic_stable_structures::DefaultMemoryImpl;
 use internet_identity::signature_map::SignatureMap;
 use internet_identity_interface::*;
+use serde_bytes::ByteBuf;
 use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::time::Duration;
 
 pub type Assets = HashMap<&'static str, (Vec<HeaderField>, &'static [u8])>;
 pub type AssetHashes = RbTree<&'static str, Hash>;
+/// Certified copy of the delegation revocations in stable memory: the (big endian) timestamp from
+/// which on delegations are valid, keyed by principal.
+pub type DelegationRevocations = RbTree<Vec<u8>, Vec<u8>>;
 
 thread_local! {
     static STATE: State = State::default();
@@ -22,10 +30,25 @@ thread_local! {
 }
 
 /// Internal representation of the anchor.
-/// After the upcoming stable memory migration, new fields can be added to this struct.
+/// The anchor is stored candid encoded, so fields added to this struct must be optional: records
+/// written before the field existed decode it as None, and previous releases ignore it (so that
+/// rolling back is still possible).
 #[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
 pub struct Anchor {
     pub devices: Vec<DeviceDataInternal>,
+    // time of the registration (None for anchors registered before it was recorded, see
+    // PersistentState::anchor_creation_recorded_since)
+    pub created_at: Option<Timestamp>,
+    // limits for the lifetime of delegations set by the user (None if never set)
+    pub delegation_lifetime_limits: Option<DelegationLifetimeLimits>,
+    // key/value metadata set by the frontend (None if never set)
+    pub metadata: Option<HashMap<String, MetadataEntry>>,
+    // delay of device changes made with recovery devices (None if the recovery delay is disabled,
+    // see anchor_management::recovery_delay)
+    pub recovery_delay: Option<u64>,
+    // anchors that can approve adding a device if the user is locked out (None if never set, see
+    // anchor_management::guardian_recovery)
+    pub guardians: Option<Guardians>,
 }
 
 /// This is an internal version of `DeviceData` primarily useful to provide a
@@ -39,6 +62,27 @@ pub struct DeviceDataInternal {
     pub purpose: Option<Purpose>,
     pub key_type: Option<KeyType>,
     pub protection: Option<DeviceProtection>,
+    // time the device was added (None for devices added before it was recorded)
+    pub created_at: Option<Timestamp>,
+    // time the device was last used to authenticate an update call (recorded coarsely, see
+    // anchor_management::record_device_usage)
+    pub last_used: Option<Timestamp>,
+    pub protection_policy: Option<DeviceProtectionPolicy>,
+}
+
+impl DeviceDataInternal {
+    /// Returns the policy that applies to the device, taking into account devices that are
+    /// protected with `DeviceProtection::Protected` (which implies all restrictions).
+    pub fn effective_protection_policy(&self) -> DeviceProtectionPolicy {
+        if self.protection == Some(DeviceProtection::Protected) {
+            return DeviceProtectionPolicy {
+                protected_from_removal: true,
+                protected_from_purpose_change: true,
+                requires_self_authentication: true,
+            };
+        }
+        self.protection_policy.clone().unwrap_or_default()
+    }
 }
 
 impl From<DeviceData> for DeviceDataInternal {
@@ -50,6 +94,10 @@ impl From<DeviceData> for DeviceDataInternal {
             purpose: Some(device_data.purpose),
             key_type: Some(device_data.key_type),
             protection: Some(device_data.protection),
+            // the usage is tracked by the canister, values provided by the client are ignored
+            created_at: None,
+            last_used: None,
+            protection_policy: device_data.protection_policy,
         }
     }
 }
@@ -67,16 +115,21 @@ impl From<DeviceDataInternal> for DeviceData {
             protection: device_data_internal
                 .protection
                 .unwrap_or(DeviceProtection::Unprotected),
+            created_at: device_data_internal.created_at,
+            last_used: device_data_internal.last_used,
+            protection_policy: device_data_internal.protection_policy,
         }
     }
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct TentativeDeviceRegistration {
     pub expiration: Timestamp,
     pub state: RegistrationState,
 }
 
 /// Registration state of new devices added using the two step device add flow
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub enum RegistrationState {
     DeviceRegistrationModeActive,
     DeviceTentativelyAdded {
@@ -84,20 +137,48 @@ pub enum RegistrationState {
         verification_code: DeviceVerificationCode,
         failed_attempts: FailedAttemptsCounter,
     },
+    // a device is added once enough guardians approved it (instead of verifying a code)
+    GuardianRecoveryRequested {
+        tentative_device: DeviceData,
+        approvals: Vec<UserNumber>,
+    },
 }
 
-#[derive(Default)]
+/// Counters of the canister usage. The counters are kept in [State] and saved as part of the
+/// [PersistentState] so that they survive upgrades.
+/// Note: new fields must be optional in order to read the counters saved by previous releases.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
 pub struct UsageMetrics {
-    // number of prepare_delegation calls since last upgrade
+    // number of prepare_delegation calls
     pub delegation_counter: u64,
-    // number of anchor operations (register, add, remove, update) since last upgrade
+    // number of anchor operations (register, add, remove, update, delete)
     pub anchor_operation_counter: u64,
+    // number of registered anchors
+    pub register_counter: u64,
+    // number of devices added (including verified tentative devices)
+    pub add_device_counter: u64,
+    // number of devices updated
+    pub update_device_counter: u64,
+    // number of devices removed
+    pub remove_device_counter: u64,
+    // number of anchors deleted
+    pub delete_anchor_counter: u64,
+    // number of devices added tentatively
+    pub add_tentative_device_counter: u64,
+    // number of tentative devices verified successfully
+    pub verify_tentative_device_counter: u64,
 }
 
 // The challenges we store and check against
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
 pub struct ChallengeInfo {
-    pub created: Timestamp,
+    pub expiration: Timestamp,
+    // the expected solution (image and text captchas) or the nonce (proof of work)
     pub chars: String,
+    // None for challenges created before the captcha type was configurable (image captchas)
+    pub captcha_type: Option<CaptchaType>,
+    // number of leading zero bits required for proof of work solutions
+    pub pow_difficulty: Option<u8>,
 }
 
 pub type ChallengeKey = String;
@@ -116,26 +197,71 @@ pub struct Challenge {
     pub challenge_key: ChallengeKey,
 }
 
+// The WebAuthn challenges we store and check against
+pub struct WebAuthnChallengeInfo {
+    pub expiration: Timestamp,
+    pub challenge: Vec<u8>,
+}
+
+// WebAuthn assertion verified by the canister, allows a single high-risk operation on the anchor
+// by the same caller
+pub struct VerifiedAssertion {
+    pub caller: Principal,
+    pub device: DeviceKey,
+    pub expiration: Timestamp,
+}
+
 #[derive(Clone, Default, CandidType, Deserialize, Eq, PartialEq, Debug)]
 pub struct PersistentState {
     // Information related to the archive
     pub archive_info: ArchiveInfo,
     // Amount of cycles that need to be attached when II creates a canister
     pub canister_creation_cycles_cost: u64,
+    // DER encoded root key of the IC used to verify certificates of derivation origins
+    // (None means the root key of the IC mainnet)
+    pub ic_root_key_der: Option<ByteBuf>,
+    // Configuration of the canister-side verification of WebAuthn assertions (None means disabled)
+    pub webauthn_verification: Option<WebAuthnVerificationConfig>,
+    // Usage metrics as of the last time the persistent state was saved. At runtime, the counters
+    // are kept (and updated) in the State.
+    pub usage_metrics: Option<UsageMetrics>,
+    // Rate limit of captcha challenges and registrations (None means the default rate limit)
+    pub register_rate_limit: Option<RateLimitConfig>,
+    // Type of the captchas issued by create_challenge (None means image captchas)
+    pub captcha_type: Option<CaptchaType>,
+    // Time since which the registration time of new anchors is recorded, i.e. anchors without
+    // registration time were created before (None until set by init / post_upgrade)
+    pub anchor_creation_recorded_since: Option<Timestamp>,
+    // Id of the last queued recovery operation, so that ids are never reused (None if no
+    // operation was queued yet, see anchor_management::recovery_delay)
+    pub last_recovery_operation_id: Option<u64>,
+    // Number of archive entries dropped because the buffer was full as of the last time the
+    // persistent state was saved. At runtime, the counter is kept in the ArchivePushStatus.
+    pub archive_dropped_entries: Option<u64>,
+}
+
+enum StorageState {
+    Uninitialized,
+    Initialized(Storage<DefaultMemoryImpl>),
 }
 
 struct State {
-    storage: RefCell<Storage<DefaultMemoryImpl>>,
+    // The storage is only initialized in init / post_upgrade (rather than on first access) to
+    // make sure no stable memory is touched before the layout has been read.
+    storage_state: RefCell<StorageState>,
     sigs: RefCell<SignatureMap>,
+    // seed hashes (with the latest signature expiration) of the signatures of each anchor,
+    // required to invalidate the signatures of deleted anchors
+    signature_seeds: RefCell<HashMap<UserNumber, HashMap<Hash, Timestamp>>>,
     asset_hashes: RefCell<AssetHashes>,
+    // rebuilt from stable memory in post_upgrade
+    delegation_revocations: RefCell<DelegationRevocations>,
     last_upgrade_timestamp: Cell<Timestamp>,
-    // note: we COULD persist this through upgrades, although this is currently NOT persisted
-    // through upgrades
-    inflight_challenges: RefCell<HashMap<ChallengeKey, ChallengeInfo>>,
-    // tentative device registrations, not persisted across updates
-    // if a user number is present in this map then registration mode is active until expiration
-    tentative_device_registrations: RefCell<HashMap<UserNumber, TentativeDeviceRegistration>>,
-    // additional usage metrics, NOT persisted across updates (but probably should be in the future)
+    // WebAuthn challenges (at most one per anchor), NOT persisted across upgrades
+    webauthn_challenges: RefCell<HashMap<UserNumber, WebAuthnChallengeInfo>>,
+    // WebAuthn assertions verified by the canister, NOT persisted across upgrades
+    verified_assertions: RefCell<HashMap<UserNumber, VerifiedAssertion>>,
+    // additional usage metrics, persisted across upgrades as part of the persistent state
     usage_metrics: RefCell<UsageMetrics>,
     // State that is temporarily persisted in stable memory during upgrades using
     // pre- and post-upgrade hooks.
@@ -144,48 +270,53 @@ struct State {
     persistent_state: RefCell<PersistentState>,
     // Cache of the archive status (to make unwanted calls to deploy_archive cheap to dismiss).
     archive_status_cache: RefCell<Option<ArchiveStatusCache>>,
+    // Status of the delivery of buffered entries to the archive, NOT persisted across upgrades
+    // (except for the number of dropped entries, which is part of the persistent state)
+    archive_push_status: RefCell<ArchivePushStatus>,
+    // Token buckets limiting captcha challenges and registrations, NOT persisted across upgrades
+    registration_rate_limits: RefCell<RegistrationRateLimits>,
+    // Recent registrations determining the proof of work difficulty, NOT persisted across upgrades
+    registration_load: RefCell<RegistrationLoad>,
 }
 
 impl Default for State {
     fn default() -> Self {
-        const FIRST_USER_ID: UserNumber = 10_000;
         Self {
-            storage: RefCell::new(Storage::new(
-                (
-                    FIRST_USER_ID,
-                    FIRST_USER_ID.saturating_add(DEFAULT_RANGE_SIZE),
-                ),
-                DefaultMemoryImpl::default(),
-            )),
+            storage_state: RefCell::new(StorageState::Uninitialized),
             sigs: RefCell::new(SignatureMap::default()),
+            signature_seeds: RefCell::new(HashMap::new()),
             asset_hashes: RefCell::new(AssetHashes::default()),
+            delegation_revocations: RefCell::new(DelegationRevocations::default()),
             last_upgrade_timestamp: Cell::new(0),
-            inflight_challenges: RefCell::new(HashMap::new()),
-            tentative_device_registrations: RefCell::new(HashMap::new()),
+            webauthn_challenges: RefCell::new(HashMap::new()),
+            verified_assertions: RefCell::new(HashMap::new()),
             usage_metrics: RefCell::new(UsageMetrics::default()),
             persistent_state: RefCell::new(PersistentState::default()),
             archive_status_cache: RefCell::new(None),
+            archive_push_status: RefCell::new(ArchivePushStatus::default()),
+            registration_rate_limits: RefCell::new(RegistrationRateLimits::default()),
+            registration_load: RefCell::new(RegistrationLoad::default()),
         }
     }
 }
 
 // Checks if salt is empty and calls `init_salt` to set it.
 pub async fn ensure_salt_set() {
-    let salt = STATE.with(|s| s.storage.borrow().salt().cloned());
+    let salt = storage(|storage| storage.salt().cloned());
     if salt.is_none() {
         init_salt().await;
     }
 
-    STATE.with(|s| {
-        if s.storage.borrow().salt().is_none() {
+    storage(|storage| {
+        if storage.salt().is_none() {
             trap("Salt is not set. Try calling init_salt() to set it");
         }
     });
 }
 
 pub async fn init_salt() {
-    STATE.with(|s| {
-        if s.storage.borrow().salt().is_some() {
+    storage(|storage| {
+        if storage.salt().is_some() {
             trap("Salt already set");
         }
     });
@@ -201,19 +332,38 @@ pub async fn init_salt() {
         ));
     });
 
-    STATE.with(|s| {
-        let mut store = s.storage.borrow_mut();
-        store.update_salt(salt); // update_salt() traps if salt has already been set
+    storage_mut(|storage| {
+        storage.update_salt(salt); // update_salt() traps if salt has already been set
     });
 }
 
 pub fn salt() -> [u8; 32] {
-    STATE
-        .with(|s| s.storage.borrow().salt().cloned())
+    storage(|storage| storage.salt().cloned())
         .unwrap_or_else(|| trap("Salt is not set. Try calling init_salt() to set it"))
 }
 
-pub fn initialize_from_stable_memory() {
+/// Initializes a new, empty storage using the genesis stable memory layout (so that rolling back
+/// is possible) or, if `layout_version_3` is set, the latest stable memory layout.
+pub fn init_new(layout_version_3: bool) {
+    const FIRST_USER_ID: UserNumber = 10_000;
+    STATE.with(|s| {
+        let id_range = (
+            FIRST_USER_ID,
+            FIRST_USER_ID.saturating_add(DEFAULT_RANGE_SIZE),
+        );
+        let mut storage = if layout_version_3 {
+            Storage::new(id_range, DefaultMemoryImpl::default())
+        } else {
+            Storage::new_with_genesis_layout(id_range, DefaultMemoryImpl::default())
+        };
+        storage.flush();
+        s.storage_state.replace(StorageState::Initialized(storage));
+    });
+}
+
+/// Initializes the storage and the persistent state from stable memory.
+/// If the stable memory is empty, a new storage is initialized instead.
+pub fn init_from_stable_memory() {
     STATE.with(|s| {
         s.last_upgrade_timestamp.set(time() as u64);
         match Storage::from_memory(DefaultMemoryImpl::default()) {
@@ -234,38 +384,58 @@ pub fn initialize_from_stable_memory() {
                     //    upper limit on upgrade.
                     storage.set_user_number_range((lo, lo.saturating_add(max_entries)));
                 }
-                s.storage.replace(storage);
-            }
-            None => {
-                s.storage.borrow_mut().flush();
+                let persistent_state = storage.read_persistent_state().unwrap_or_else(|err| {
+                    trap(&format!(
+                        "failed to recover persistent state! Err: {:?}",
+                        err
+                    ))
+                });
+                let mut delegation_revocations = DelegationRevocations::default();
+                for (principal, not_before) in storage.delegation_revocations() {
+                    delegation_revocations.insert(principal, not_before.to_be_bytes().to_vec());
+                }
+                s.delegation_revocations.replace(delegation_revocations);
+                s.storage_state.replace(StorageState::Initialized(storage));
+                s.usage_metrics
+                    .replace(persistent_state.usage_metrics.clone().unwrap_or_default());
+                s.archive_push_status.borrow_mut().dropped_entries =
+                    persistent_state.archive_dropped_entries.unwrap_or_default();
+                s.persistent_state.replace(persistent_state);
             }
+            None => init_new(false),
         }
     });
 }
 
 pub fn save_persistent_state() {
     STATE.with(|s| {
-        s.storage
-            .borrow_mut()
-            .write_persistent_state(&s.persistent_state.borrow());
+        let mut persistent_state = s.persistent_state.borrow_mut();
+        persistent_state.usage_metrics = Some(s.usage_metrics.borrow().clone());
+        persistent_state.archive_dropped_entries =
+            Some(s.archive_push_status.borrow().dropped_entries);
+        storage_mut(|storage| storage.write_persistent_state(&persistent_state));
     })
 }
 
-pub fn load_persistent_state() {
-    STATE.with(|s| {
-        let storage = s.storage.borrow();
-        match storage.read_persistent_state() {
-            Ok(loaded_state) => *s.persistent_state.borrow_mut() = loaded_state,
-            Err(PersistentStateError::NotFound) => {
-                if storage.version() >= 2 {
-                    trap("unable to load persistent state: not found")
-                }
-                *s.persistent_state.borrow_mut() = PersistentState::default()
-            }
-            Err(err) => trap(&format!(
-                "failed to recover persistent state! Err: {:?}",
-                err
-            )),
+/// Migrates the next batch of anchor records to the current stable memory layout (if any are left).
+pub fn migrate_storage_layout() {
+    storage_mut(|storage| storage.migrate_records());
+}
+
+/// Number of instructions after which the backfill of the device index and the anchor statistics
+/// is stopped, leaving enough room for the rest of the upgrade.
+const BACKFILL_INSTRUCTION_LIMIT: u64 = 50_000_000_000;
+
+/// Adds batches of anchors to the device index and the anchor statistics until all anchors are
+/// added or the current call has used [BACKFILL_INSTRUCTION_LIMIT] instructions.
+/// The remaining anchors are added on the next upgrade.
+pub fn backfill_anchor_records() {
+    storage_mut(|storage| {
+        while (storage.unindexed_records() > 0 || storage.unmeasured_records() > 0)
+            && performance_counter(0) < BACKFILL_INSTRUCTION_LIMIT
+        {
+            storage.index_devices();
+            storage.measure_records();
         }
     })
 }
@@ -273,8 +443,8 @@ pub fn load_persistent_state() {
 // helper methods to access / modify the state in a convenient way
 
 pub fn anchor(anchor: UserNumber) -> Anchor {
-    STATE.with(|s| {
-        s.storage.borrow().read(anchor).unwrap_or_else(|err| {
+    storage(|storage| {
+        storage.read(anchor).unwrap_or_else(|err| {
             trap(&format!(
                 "failed to read device data of user {}: {}",
                 anchor, err
@@ -308,10 +478,8 @@ pub fn archive_data() -> Option<ArchiveData> {
 }
 
 pub fn increment_archive_seq_nr() {
-    STATE.with(|s| {
-        if let ArchiveState::Created(ref mut data) =
-            s.persistent_state.borrow_mut().archive_info.state
-        {
+    persistent_state_mut(|persistent_state| {
+        if let ArchiveState::Created(ref mut data) = persistent_state.archive_info.state {
             data.sequence_number += 1;
         } else {
             trap("no archive deployed")
@@ -319,18 +487,6 @@ pub fn increment_archive_seq_nr() {
     })
 }
 
-pub fn tentative_device_registrations<R>(
-    f: impl FnOnce(&HashMap<UserNumber, TentativeDeviceRegistration>) -> R,
-) -> R {
-    STATE.with(|s| f(&*s.tentative_device_registrations.borrow()))
-}
-
-pub fn tentative_device_registrations_mut<R>(
-    f: impl FnOnce(&mut HashMap<UserNumber, TentativeDeviceRegistration>) -> R,
-) -> R {
-    STATE.with(|s| f(&mut *s.tentative_device_registrations.borrow_mut()))
-}
-
 pub fn assets<R>(f: impl FnOnce(&Assets) -> R) -> R {
     ASSETS.with(|assets| f(&*assets.borrow()))
 }
@@ -345,6 +501,14 @@ pub fn asset_hashes_and_sigs<R>(f: impl FnOnce(&AssetHashes, &SignatureMap) -> R
     STATE.with(|s| f(&*s.asset_hashes.borrow(), &*s.sigs.borrow()))
 }
 
+pub fn delegation_revocations<R>(f: impl FnOnce(&DelegationRevocations) -> R) -> R {
+    STATE.with(|s| f(&*s.delegation_revocations.borrow()))
+}
+
+pub fn delegation_revocations_mut<R>(f: impl FnOnce(&mut DelegationRevocations) -> R) -> R {
+    STATE.with(|s| f(&mut *s.delegation_revocations.borrow_mut()))
+}
+
 pub fn signature_map<R>(f: impl FnOnce(&SignatureMap) -> R) -> R {
     STATE.with(|s| f(&*s.sigs.borrow()))
 }
@@ -353,12 +517,24 @@ pub fn signature_map_mut<R>(f: impl FnOnce(&mut SignatureMap) -> R) -> R {
     STATE.with(|s| f(&mut *s.sigs.borrow_mut()))
 }
 
+pub fn signature_seeds_mut<R>(
+    f: impl FnOnce(&mut HashMap<UserNumber, HashMap<Hash, Timestamp>>) -> R,
+) -> R {
+    STATE.with(|s| f(&mut *s.signature_seeds.borrow_mut()))
+}
+
 pub fn storage<R>(f: impl FnOnce(&Storage<DefaultMemoryImpl>) -> R) -> R {
-    STATE.with(|s| f(&*s.storage.borrow()))
+    STATE.with(|s| match *s.storage_state.borrow() {
+        StorageState::Uninitialized => trap("storage is not initialized"),
+        StorageState::Initialized(ref storage) => f(storage),
+    })
 }
 
 pub fn storage_mut<R>(f: impl FnOnce(&mut Storage<DefaultMemoryImpl>) -> R) -> R {
-    STATE.with(|s| f(&mut *s.storage.borrow_mut()))
+    STATE.with(|s| match *s.storage_state.borrow_mut() {
+        StorageState::Uninitialized => trap("storage is not initialized"),
+        StorageState::Initialized(ref mut storage) => f(storage),
+    })
 }
 
 pub fn usage_metrics<R>(f: impl FnOnce(&UsageMetrics) -> R) -> R {
@@ -369,14 +545,16 @@ pub fn usage_metrics_mut<R>(f: impl FnOnce(&mut UsageMetrics) -> R) -> R {
     STATE.with(|s| f(&mut *s.usage_metrics.borrow_mut()))
 }
 
-pub fn inflight_challenges<R>(f: impl FnOnce(&HashMap<ChallengeKey, ChallengeInfo>) -> R) -> R {
-    STATE.with(|s| f(&*s.inflight_challenges.borrow()))
+pub fn webauthn_challenges_mut<R>(
+    f: impl FnOnce(&mut HashMap<UserNumber, WebAuthnChallengeInfo>) -> R,
+) -> R {
+    STATE.with(|s| f(&mut *s.webauthn_challenges.borrow_mut()))
 }
 
-pub fn inflight_challenges_mut<R>(
-    f: impl FnOnce(&mut HashMap<ChallengeKey, ChallengeInfo>) -> R,
+pub fn verified_assertions_mut<R>(
+    f: impl FnOnce(&mut HashMap<UserNumber, VerifiedAssertion>) -> R,
 ) -> R {
-    STATE.with(|s| f(&mut *s.inflight_challenges.borrow_mut()))
+    STATE.with(|s| f(&mut *s.verified_assertions.borrow_mut()))
 }
 
 pub fn last_upgrade_timestamp() -> Timestamp {
@@ -387,8 +565,36 @@ pub fn persistent_state<R>(f: impl FnOnce(&PersistentState) -> R) -> R {
     STATE.with(|s| f(&*s.persistent_state.borrow()))
 }
 
+/// Modifies the persistent state and writes it to stable memory right away, so that the changes are
+/// durable even if the canister is never upgraded.
 pub fn persistent_state_mut<R>(f: impl FnOnce(&mut PersistentState) -> R) -> R {
-    STATE.with(|s| f(&mut *s.persistent_state.borrow_mut()))
+    let result = STATE.with(|s| f(&mut *s.persistent_state.borrow_mut()));
+    save_persistent_state();
+    result
+}
+
+pub fn archive_push_status<R>(f: impl FnOnce(&ArchivePushStatus) -> R) -> R {
+    STATE.with(|s| f(&*s.archive_push_status.borrow()))
+}
+
+pub fn archive_push_status_mut<R>(f: impl FnOnce(&mut ArchivePushStatus) -> R) -> R {
+    STATE.with(|s| f(&mut *s.archive_push_status.borrow_mut()))
+}
+
+pub fn registration_rate_limits<R>(f: impl FnOnce(&RegistrationRateLimits) -> R) -> R {
+    STATE.with(|s| f(&*s.registration_rate_limits.borrow()))
+}
+
+pub fn registration_rate_limits_mut<R>(f: impl FnOnce(&mut RegistrationRateLimits) -> R) -> R {
+    STATE.with(|s| f(&mut *s.registration_rate_limits.borrow_mut()))
+}
+
+pub fn registration_load<R>(f: impl FnOnce(&RegistrationLoad) -> R) -> R {
+    STATE.with(|s| f(&*s.registration_load.borrow()))
+}
+
+pub fn registration_load_mut<R>(f: impl FnOnce(&mut RegistrationLoad) -> R) -> R {
+    STATE.with(|s| f(&mut *s.registration_load.borrow_mut()))
 }
 
 pub fn cached_archive_status() -> Option<CanisterStatusResponse> {
@@ -1,7 +1,7 @@
 //! This module implements all the stable memory interactions of Internet Identity.
 //! It uses the [Reader] and [Writer] implementations of the `stable_structures` crate.
 //!
-//! ## Stable Memory Layout
+//! ## Stable Memory Layout (version 3)
 //! ```text
 //! ------------------------------------------- <- Address 0
 //! Magic "IIC"                 ↕ 3 bytes
@@ -17,8 +17,49 @@
 //! max_entry_size (SIZE_MAX)   ↕ 2 bytes
 //! -------------------------------------------
 //! Salt                        ↕ 32 bytes
-//! ------------------------------------------- <- Address 58 (header size)
-//! Reserved space              ↕ 454 bytes
+//! -------------------------------------------
+//! Number of genesis records   ↕ 4 bytes
+//! -------------------------------------------
+//! Number of migrated records  ↕ 4 bytes
+//! -------------------------------------------
+//! Migration batch size        ↕ 4 bytes
+//! -------------------------------------------
+//! Number of indexed records   ↕ 4 bytes
+//! -------------------------------------------
+//! Number of measured records  ↕ 4 bytes
+//! -------------------------------------------
+//! Managed memory start page M ↕ 4 bytes
+//! ------------------------------------------- <- Address 82 (header size)
+//! Reserved space              ↕ 430 bytes
+//! ------------------------------------------- <- Address 512 = RESERVED_HEADER_BYTES
+//! Genesis records             ↕ (number of genesis records * SIZE_MAX) bytes
+//! ------------------------------------------- <- Page M
+//! Memory managed by the memory manager:
+//!   - Anchor record index
+//!   - Anchor record data
+//!   - Persistent state
+//!   - Archive buffer
+//!   - Device index
+//!   - Tentative device registrations
+//!   - Inflight captcha challenges
+//!   - Expiration queue
+//!   - Anchor statistics (see [anchor_statistics])
+//!   - Delegation revocations
+//!   - Sessions
+//!   - Pending recovery operations
+//!   - Genesis anchor extras
+//! -------------------------------------------
+//! Unallocated space
+//! ```
+//!
+//! The anchor records are stored as candid encoded [Anchor] values of variable size in a
+//! [RecordStore] (see [record_store] for its memory layout). Canisters installed with layout
+//! version 3 have no genesis records and the managed memory starts at page 1.
+//!
+//! ## Genesis Layout (versions 1 and 2)
+//! ```text
+//! ------------------------------------------- <- Address 0
+//! Header (see above)
 //! ------------------------------------------- <- Address 512 = A_0_offset = RESERVED_HEADER_BYTES
 //! A_0_size                    ↕ 2 bytes
 //! -------------------------------------------
@@ -43,50 +84,235 @@
 //! Unallocated space           ↕ STABLE_MEMORY_RESERVE bytes
 //! -------------------------------------------
 //! ```
+//! In the genesis layout, the candid encoded entries only contain the list of devices. The other
+//! fields of an [Anchor] are kept in the genesis anchor extras, a [StableBTreeMap] in the managed
+//! memory keyed by the anchor number (anchors without any of these fields have no entry).
+//!
+//! The header fields added in version 3 (see above) are also used with the genesis layout, as
+//! releases that only support the genesis layout leave them untouched. The managed memory is placed
+//! after the anchor records of the whole anchor range (and the space reserved for the persistent
+//! state following them, see below), so that such releases never write to it. This also limits
+//! the size of the anchor range to the anchor records that fit in front of the managed memory.
+//!
+//! ## Layout Migration
+//!
+//! A storage using the genesis layout keeps using it until the switch to layout version 3 is
+//! requested by setting the migration batch size (see [Storage::set_migration_batch_size]). Until
+//! then, rolling back to a release that only supports the genesis layout is possible.
+//!
+//! When switching, the header is updated and the existing anchor records become genesis records,
+//! which are moved to the [RecordStore] in batches (see [Storage::migrate_records]). Until an anchor
+//! record has been migrated it is read from its genesis location, and writes always go to the
+//! [RecordStore]. Once a storage has been switched to layout version 3, rolling back to a release
+//! that only supports the genesis layout is no longer possible.
 //!
 //! ## Persistent State
 //!
-//! In order to keep state across upgrades that is not related to specific anchors (such as archive
-//! information) Internet Identity will serialize the [PersistentState] into the first unused memory
-//! location (after the anchor record of the highest allocated anchor number). The [PersistentState]
-//! will be read in `post_upgrade` after which the data can be safely overwritten by the next anchor
-//! to be registered.
+//! In order to keep state that is not related to specific anchors (such as archive information)
+//! Internet Identity serializes the [PersistentState] into a dedicated memory managed by the memory
+//! manager. The [PersistentState] is written whenever it changes and read in `post_upgrade`.
+//! ```text
+//! ------------------------------------------- <- Address 0
+//! Magic "IIPS"                ↕ 4 bytes
+//! -------------------------------------------
+//! Persistent state version    ↕ 1 byte
+//! -------------------------------------------
+//! Candid encoded state size   ↕ 8 bytes
+//! -------------------------------------------
+//! Candid encoded state        ↕ size bytes
+//! -------------------------------------------
+//! ```
+//!
+//! The genesis layout serializes the [PersistentState] (without the version byte) into the first
+//! unused memory location (after the anchor record of the highest allocated anchor number). As long
+//! as the genesis layout is used, the [PersistentState] is written to this location as well, and
+//! the fields known to previous releases are read from there if present (see
+//! [Storage::read_persistent_state]).
+//!
+//! ## Archive Buffer
+//!
+//! Entries for the archive are buffered in a [StableBTreeMap] keyed by their sequence number until
+//! the archive acknowledges them. The values are candid encoded [BufferedEntry] values.
+//!
+//! ## Device Index
 //!
-//! The [PersistentState] is serialized at the end of stable memory to allow for variable sized data
-//! without the risk of running out of space (which might easily happen if the RESERVED_HEADER_BYTES
-//! were used instead).
+//! In order to find the anchor of a device without knowing the anchor number (e.g. for
+//! discoverable credentials), the devices are indexed by the hashes of their credential ids and
+//! public keys in a [StableBTreeMap] with keys of the form
+//! ```text
+//! identifier kind (credential id or public key)  ↕ 1 byte
+//! SHA-256 hash of the identifier                  ↕ 32 bytes
+//! anchor number (big endian)                      ↕ 8 bytes
+//! ```
+//! The same device might be registered on multiple anchors, so a lookup returns all anchors
+//! with the given identifier prefix.
+//!
+//! [Storage::write] keeps the index up to date for all anchor records before the
+//! "number of indexed records" in the header. Records created before the index existed are added
+//! in batches (see [Storage::index_devices]).
+//! Note: writes made by a release without the device index (i.e. after a rollback) are not
+//! reflected in the index.
+//!
+//! ## Tentative Device Registrations and Captcha Challenges
+//!
+//! Tentative device registrations (keyed by anchor number) and inflight captcha challenges (keyed
+//! by challenge key) are kept in [StableBTreeMap]s so that flows in progress survive upgrades.
+//! The values are candid encoded [TentativeDeviceRegistration] and [ChallengeInfo] values.
+//!
+//! Both kinds of entries expire. In order to prune expired entries without scanning the maps, the
+//! expirations are additionally kept in an expiration queue, a [StableBTreeMap] with keys of the
+//! form
+//! ```text
+//! expiration (big endian)                         ↕ 8 bytes
+//! entry kind (registration or challenge)          ↕ 1 byte
+//! key of the entry (anchor number or challenge)   ↕ variable
+//! ```
+//!
+//! ## Delegation Revocations
+//!
+//! The time from which on the delegations of a principal (i.e. of an anchor for a frontend) are
+//! valid again after a revocation is kept in a [StableBTreeMap] keyed by the principal. The values
+//! are this timestamp followed by the expiration of the revocation (both big endian). A revocation
+//! is only relevant as long as delegations issued before it might still be valid, so the
+//! revocations also have an entry in the expiration queue. They
+//! are not removed by [Storage::prune_expired] but by [Storage::prune_expired_revocations], so that
+//! the caller can update the certified copy of the revocations kept on the heap.
+//!
+//! ## Sessions
+//!
+//! The sessions of an anchor (i.e. the delegations issued by `prepare_delegation`) are kept in a
+//! [StableBTreeMap] with keys of the form
+//! ```text
+//! anchor number (big endian)                      ↕ 8 bytes
+//! SHA-256 hash of the session key                 ↕ 32 bytes
+//! ```
+//! so that the sessions of an anchor can be listed with a range query. The values are candid
+//! encoded [Session] values. Every anchor keeps at most [MAX_SESSIONS_PER_ANCHOR] sessions: when
+//! this limit is reached, the least recently issued session is evicted. Sessions are removed from
+//! the expiration queue once they expire.
+//!
+//! ## Pending Recovery Operations
+//!
+//! Device changes delayed by the recovery delay of an anchor are kept in a [StableBTreeMap] with
+//! keys of the form
+//! ```text
+//! anchor number (big endian)                      ↕ 8 bytes
+//! id of the operation (big endian)                ↕ 8 bytes
+//! ```
+//! The values are candid encoded [PendingRecoveryOperation] values. The time at which an operation
+//! becomes effective is kept in the expiration queue. Like revocations, these entries are not
+//! removed by [Storage::prune_expired]: due operations are removed by
+//! [Storage::pop_due_recovery_operations] so that the caller can apply them.
+//!
+//! ## Deleted Anchors
+//!
+//! Deleting an anchor (see [Storage::delete]) replaces its record with a tombstone in the
+//! [RecordStore] and removes its devices from the device index. Anchor numbers are allocated
+//! sequentially, so the number of a deleted anchor is never assigned again.
 
-use crate::state::{Anchor, DeviceDataInternal, PersistentState};
+use crate::hash;
+use crate::state::RegistrationState::GuardianRecoveryRequested;
+use crate::state::{
+    Anchor, ChallengeInfo, ChallengeKey, DeviceDataInternal, PersistentState,
+    TentativeDeviceRegistration,
+};
+use crate::storage::anchor_statistics::AnchorStatistics;
+use crate::storage::record_store::{RecordStore, MAX_RECORD_SIZE};
 use candid;
 use ic_cdk::api::trap;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::reader::{BufferedReader, OutOfBounds, Reader};
 use ic_stable_structures::writer::{BufferedWriter, Writer};
-use ic_stable_structures::Memory;
-use internet_identity_interface::UserNumber;
+use ic_stable_structures::{Memory, RestrictedMemory, StableBTreeMap, Storable};
+use internet_identity_interface::{
+    BufferedEntry, DeviceIdentifier, MigrationState, PendingRecoveryOperation, Session, Timestamp,
+    UserNumber,
+};
+use std::borrow::Cow;
 use std::convert::TryInto;
 use std::fmt;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::ops::RangeInclusive;
 
+pub mod anchor_statistics;
+mod record_store;
 #[cfg(test)]
 mod tests;
 
 // version 0: invalid
 // version 1: genesis layout, might have persistent state
 // version 2: genesis layout, must have persistent state
-// version 3+: invalid
-const SUPPORTED_LAYOUT_VERSIONS: RangeInclusive<u8> = 1..=2;
+// version 3: managed memory layout with variable size anchor records
+// version 4+: invalid
+const SUPPORTED_LAYOUT_VERSIONS: RangeInclusive<u8> = 1..=3;
+const GENESIS_LAYOUT_VERSION: u8 = 1;
+const LAYOUT_VERSION: u8 = 3;
 
 /// Reserved space for the header before the anchor records start.
 const RESERVED_HEADER_BYTES: u64 = 512;
 const DEFAULT_ENTRY_SIZE: u16 = 2048;
 const EMPTY_SALT: [u8; 32] = [0; 32];
 const GB: u64 = 1 << 30;
+const WASM_PAGE_SIZE: u64 = 65536;
 const STABLE_MEMORY_SIZE: u64 = 8 * GB;
+const MAX_WASM_PAGES: u64 = STABLE_MEMORY_SIZE / WASM_PAGE_SIZE;
 /// We reserve last ~10% of the stable memory for later new features.
 const STABLE_MEMORY_RESERVE: u64 = STABLE_MEMORY_SIZE / 10;
+/// Number of genesis records migrated per batch unless configured otherwise.
+const DEFAULT_MIGRATION_BATCH_SIZE: u32 = 100;
+/// Space reserved after the anchor records of the genesis layout for the persistent state.
+const GENESIS_PERSISTENT_STATE_RESERVE: u64 = 16 * WASM_PAGE_SIZE;
 
 const PERSISTENT_STATE_MAGIC: [u8; 4] = *b"IIPS"; // II Persistent State
+/// Version of the persistent state serialization in its dedicated memory.
+/// The genesis layout did not have a version byte.
+const PERSISTENT_STATE_VERSION: u8 = 1;
+
+/// Memory ids of memory managed by the memory manager.
+const ANCHOR_INDEX_MEMORY_ID: MemoryId = MemoryId::new(0);
+const ANCHOR_DATA_MEMORY_ID: MemoryId = MemoryId::new(1);
+const PERSISTENT_STATE_MEMORY_ID: MemoryId = MemoryId::new(2);
+const ARCHIVE_BUFFER_MEMORY_ID: MemoryId = MemoryId::new(3);
+const DEVICE_INDEX_MEMORY_ID: MemoryId = MemoryId::new(4);
+const TENTATIVE_DEVICE_REGISTRATIONS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const INFLIGHT_CHALLENGES_MEMORY_ID: MemoryId = MemoryId::new(6);
+const EXPIRATION_QUEUE_MEMORY_ID: MemoryId = MemoryId::new(7);
+const ANCHOR_STATISTICS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const DELEGATION_REVOCATIONS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const SESSIONS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const PENDING_RECOVERY_OPERATIONS_MEMORY_ID: MemoryId = MemoryId::new(11);
+const GENESIS_ANCHOR_EXTRAS_MEMORY_ID: MemoryId = MemoryId::new(12);
+
+/// Maximum size of a candid encoded [BufferedEntry] in the archive buffer.
+const MAX_BUFFERED_ENTRY_SIZE: u32 = 4096;
+
+/// Number of anchor records added to the device index per batch while building the index.
+const DEVICE_INDEX_BATCH_SIZE: u32 = 500;
+const CREDENTIAL_ID_TAG: u8 = 0;
+const PUBKEY_TAG: u8 = 1;
+
+/// Number of anchor records added to the anchor statistics per batch while backfilling them.
+const ANCHOR_STATISTICS_BATCH_SIZE: u32 = 500;
+
+/// Maximum size of a candid encoded [TentativeDeviceRegistration].
+const MAX_TENTATIVE_REGISTRATION_SIZE: u32 = 1024;
+/// Maximum size of a captcha challenge key.
+const MAX_CHALLENGE_KEY_SIZE: u32 = 32;
+/// Maximum size of a candid encoded [ChallengeInfo].
+const MAX_CHALLENGE_INFO_SIZE: u32 = 128;
+const REGISTRATION_EXPIRATION_TAG: u8 = 0;
+const CHALLENGE_EXPIRATION_TAG: u8 = 1;
+const REVOCATION_EXPIRATION_TAG: u8 = 2;
+const SESSION_EXPIRATION_TAG: u8 = 3;
+const RECOVERY_OPERATION_EXPIRATION_TAG: u8 = 4;
+/// Maximum size of a principal (the key of the delegation revocations).
+const MAX_PRINCIPAL_SIZE: u32 = 29;
+/// Maximum size of a candid encoded [Session].
+const MAX_SESSION_SIZE: u32 = 1024;
+/// Maximum number of sessions kept per anchor.
+pub const MAX_SESSIONS_PER_ANCHOR: usize = 20;
+/// Maximum size of a candid encoded [PendingRecoveryOperation].
+const MAX_PENDING_RECOVERY_OPERATION_SIZE: u32 = 2048;
 
 /// The maximum number of users this canister can store.
 pub const DEFAULT_RANGE_SIZE: u64 =
@@ -95,10 +321,219 @@ pub const DEFAULT_RANGE_SIZE: u64 =
 
 pub type Salt = [u8; 32];
 
+type ManagedMemory<M> = VirtualMemory<RestrictedMemory<M>>;
+
 /// Data type responsible for managing user data in stable memory.
-pub struct Storage<M> {
+pub struct Storage<M: Memory> {
     header: Header,
-    memory: M,
+    header_memory: M,
+    memory_manager: MemoryManager<RestrictedMemory<M>>,
+    anchor_records: RecordStore<ManagedMemory<M>>,
+    archive_buffer: StableBTreeMap<ManagedMemory<M>, SequenceNumber, Vec<u8>>,
+    device_index: StableBTreeMap<ManagedMemory<M>, DeviceIndexKey, ()>,
+    tentative_device_registrations: StableBTreeMap<ManagedMemory<M>, AnchorNumber, Vec<u8>>,
+    // number of the tentative device registrations that are guardian recoveries (not persisted,
+    // counted when the storage is initialized)
+    guardian_recoveries: u64,
+    inflight_challenges: StableBTreeMap<ManagedMemory<M>, Vec<u8>, Vec<u8>>,
+    expiration_queue: StableBTreeMap<ManagedMemory<M>, ExpirationKey, ()>,
+    anchor_statistics: AnchorStatistics,
+    delegation_revocations: StableBTreeMap<ManagedMemory<M>, Vec<u8>, Vec<u8>>,
+    sessions: StableBTreeMap<ManagedMemory<M>, SessionKey, Vec<u8>>,
+    pending_recovery_operations:
+        StableBTreeMap<ManagedMemory<M>, PendingRecoveryOperationKey, Vec<u8>>,
+    genesis_anchor_extras: StableBTreeMap<ManagedMemory<M>, AnchorNumber, Vec<u8>>,
+}
+
+/// Key of the archive buffer.
+/// Big endian is used so that the buffered entries are ordered by sequence number.
+struct SequenceNumber(u64);
+
+impl Storable for SequenceNumber {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        SequenceNumber(u64::from_be_bytes(
+            bytes.try_into().expect("bug: invalid sequence number"),
+        ))
+    }
+}
+
+/// Key of the tentative device registrations and the genesis anchor extras.
+struct AnchorNumber(UserNumber);
+
+impl Storable for AnchorNumber {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        AnchorNumber(u64::from_be_bytes(
+            bytes.try_into().expect("bug: invalid anchor number"),
+        ))
+    }
+}
+
+/// Key of the sessions.
+/// Big endian is used so that the sessions of an anchor can be found with a range query.
+struct SessionKey {
+    anchor: UserNumber,
+    session_key_hash: [u8; 32],
+}
+
+impl SessionKey {
+    const SIZE: usize = 8 + 32;
+}
+
+impl Storable for SessionKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.extend(&self.anchor.to_be_bytes());
+        buf.extend(&self.session_key_hash);
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        SessionKey {
+            anchor: u64::from_be_bytes(bytes[..8].try_into().expect("bug: invalid session anchor")),
+            session_key_hash: bytes[8..]
+                .try_into()
+                .expect("bug: invalid session key hash"),
+        }
+    }
+}
+
+/// Key of the pending recovery operations.
+/// Big endian is used so that the operations of an anchor can be found with a range query.
+struct PendingRecoveryOperationKey {
+    anchor: UserNumber,
+    id: u64,
+}
+
+impl PendingRecoveryOperationKey {
+    const SIZE: usize = 8 + 8;
+}
+
+impl Storable for PendingRecoveryOperationKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.extend(&self.anchor.to_be_bytes());
+        buf.extend(&self.id.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        PendingRecoveryOperationKey {
+            anchor: u64::from_be_bytes(
+                bytes[..8]
+                    .try_into()
+                    .expect("bug: invalid pending recovery operation anchor"),
+            ),
+            id: u64::from_be_bytes(
+                bytes[8..]
+                    .try_into()
+                    .expect("bug: invalid pending recovery operation id"),
+            ),
+        }
+    }
+}
+
+/// Key of the expiration queue.
+/// Big endian is used so that the keys are ordered by expiration.
+struct ExpirationKey {
+    expiration: Timestamp,
+    tag: u8,
+    key: Vec<u8>,
+}
+
+impl ExpirationKey {
+    /// Size of the largest key of the entries in the queue (see the `*_EXPIRATION_TAG`s).
+    const MAX_KEY_SIZE: u32 = max_size(&[
+        std::mem::size_of::<UserNumber>() as u32,
+        MAX_CHALLENGE_KEY_SIZE,
+        MAX_PRINCIPAL_SIZE,
+        SessionKey::SIZE as u32,
+        PendingRecoveryOperationKey::SIZE as u32,
+    ]);
+    const MAX_SIZE: u32 = 8 + 1 + Self::MAX_KEY_SIZE;
+}
+
+const fn max_size(sizes: &[u32]) -> u32 {
+    let mut max = 0;
+    let mut i = 0;
+    while i < sizes.len() {
+        if sizes[i] > max {
+            max = sizes[i];
+        }
+        i += 1;
+    }
+    max
+}
+
+impl Storable for ExpirationKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(9 + self.key.len());
+        buf.extend(&self.expiration.to_be_bytes());
+        buf.push(self.tag);
+        buf.extend(&self.key);
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        ExpirationKey {
+            expiration: u64::from_be_bytes(
+                bytes[..8]
+                    .try_into()
+                    .expect("bug: invalid expiration key timestamp"),
+            ),
+            tag: bytes[8],
+            key: bytes[9..].to_vec(),
+        }
+    }
+}
+
+/// Key of the device index, see [DeviceIndexKey::prefix] for the prefix shared by all anchors of
+/// a device.
+struct DeviceIndexKey {
+    prefix: [u8; DeviceIndexKey::PREFIX_SIZE],
+    anchor: UserNumber,
+}
+
+impl DeviceIndexKey {
+    const PREFIX_SIZE: usize = 1 + 32;
+    const SIZE: usize = Self::PREFIX_SIZE + 8;
+
+    /// Identifier kind tag followed by the hash of the identifier.
+    fn prefix(tag: u8, identifier: &[u8]) -> [u8; Self::PREFIX_SIZE] {
+        let mut prefix = [0; Self::PREFIX_SIZE];
+        prefix[0] = tag;
+        prefix[1..].copy_from_slice(&hash::hash_bytes(identifier));
+        prefix
+    }
+}
+
+impl Storable for DeviceIndexKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(Self::SIZE);
+        buf.extend(&self.prefix);
+        buf.extend(&self.anchor.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        DeviceIndexKey {
+            prefix: bytes[..Self::PREFIX_SIZE]
+                .try_into()
+                .expect("bug: invalid device index prefix"),
+            anchor: u64::from_be_bytes(
+                bytes[Self::PREFIX_SIZE..]
+                    .try_into()
+                    .expect("bug: invalid device index anchor"),
+            ),
+        }
+    }
 }
 
 #[repr(packed)]
@@ -107,44 +542,125 @@ struct Header {
     // version 0: invalid
     // version 1: genesis layout, might have persistent state
     // version 2: genesis layout, must have persistent state
-    // version 3+: invalid
+    // version 3: managed memory layout with variable size anchor records
+    // version 4+: invalid
     version: u8,
     num_users: u32,
     id_range_lo: u64,
     id_range_hi: u64,
     entry_size: u16,
     salt: [u8; 32],
+    // The fields below are only used from version 3 onwards (and are zero in previous versions).
+    // number of anchor records stored in the genesis layout when switching to version 3
+    genesis_records: u32,
+    // number of genesis records already moved to the record store
+    migrated_records: u32,
+    migration_batch_size: u32,
+    // number of anchor records (from the start of the range) whose devices are in the device index
+    indexed_records: u32,
+    // number of anchor records (from the start of the range) included in the anchor statistics
+    measured_records: u32,
+    // first page of the memory managed by the memory manager (zero if not placed yet, i.e. in
+    // storages written by releases that only support the genesis layout)
+    managed_memory_start_page: u32,
 }
 
-impl<M: Memory> Storage<M> {
-    /// Creates a new empty storage that manages the data of users in
+impl<M: Memory + Clone> Storage<M> {
+    /// Creates a new empty storage using layout version 3 that manages the data of users in
     /// the specified range.
-    pub fn new((id_range_lo, id_range_hi): (UserNumber, UserNumber), memory: M) -> Self {
-        if id_range_hi < id_range_lo {
-            trap(&format!(
-                "improper Identity Anchor range: [{}, {})",
-                id_range_lo, id_range_hi,
-            ));
-        }
+    pub fn new(id_range: (UserNumber, UserNumber), memory: M) -> Self {
+        let mut header = new_header(id_range);
+        header.version = LAYOUT_VERSION;
+        header.managed_memory_start_page = 1;
+        Self::init_managed_memory(header, memory)
+    }
 
-        if (id_range_hi - id_range_lo) > DEFAULT_RANGE_SIZE {
-            trap(&format!(
-                "id range [{}, {}) is too large for a single canister (max {} entries)",
-                id_range_lo, id_range_hi, DEFAULT_RANGE_SIZE,
-            ));
-        }
+    /// Creates a new empty storage using the genesis layout that manages the data of users in
+    /// the specified range. The storage is switched to layout version 3 when requested (see
+    /// [Storage::set_migration_batch_size]).
+    pub fn new_with_genesis_layout(id_range: (UserNumber, UserNumber), memory: M) -> Self {
+        let mut header = new_header(id_range);
+        header.version = GENESIS_LAYOUT_VERSION;
+        header.managed_memory_start_page = genesis_managed_memory_start_page(&header);
+        Self::init_managed_memory(header, memory)
+    }
 
+    fn init_managed_memory(header: Header, memory: M) -> Self {
+        let memory_manager = MemoryManager::init(RestrictedMemory::new(
+            memory.clone(),
+            header.managed_memory_start_page as u64..MAX_WASM_PAGES,
+        ));
+        let anchor_records = RecordStore::init(
+            memory_manager.get(ANCHOR_INDEX_MEMORY_ID),
+            memory_manager.get(ANCHOR_DATA_MEMORY_ID),
+        );
+        let archive_buffer = StableBTreeMap::init(
+            memory_manager.get(ARCHIVE_BUFFER_MEMORY_ID),
+            std::mem::size_of::<u64>() as u32,
+            MAX_BUFFERED_ENTRY_SIZE,
+        );
+        let device_index = StableBTreeMap::init(
+            memory_manager.get(DEVICE_INDEX_MEMORY_ID),
+            DeviceIndexKey::SIZE as u32,
+            0,
+        );
+        let tentative_device_registrations = StableBTreeMap::init(
+            memory_manager.get(TENTATIVE_DEVICE_REGISTRATIONS_MEMORY_ID),
+            std::mem::size_of::<u64>() as u32,
+            MAX_TENTATIVE_REGISTRATION_SIZE,
+        );
+        let inflight_challenges = StableBTreeMap::init(
+            memory_manager.get(INFLIGHT_CHALLENGES_MEMORY_ID),
+            MAX_CHALLENGE_KEY_SIZE,
+            MAX_CHALLENGE_INFO_SIZE,
+        );
+        let expiration_queue = StableBTreeMap::init(
+            memory_manager.get(EXPIRATION_QUEUE_MEMORY_ID),
+            ExpirationKey::MAX_SIZE,
+            0,
+        );
+        let anchor_statistics =
+            read_anchor_statistics(&memory_manager.get(ANCHOR_STATISTICS_MEMORY_ID));
+        let delegation_revocations = StableBTreeMap::init(
+            memory_manager.get(DELEGATION_REVOCATIONS_MEMORY_ID),
+            MAX_PRINCIPAL_SIZE,
+            2 * std::mem::size_of::<u64>() as u32,
+        );
+        let sessions = StableBTreeMap::init(
+            memory_manager.get(SESSIONS_MEMORY_ID),
+            SessionKey::SIZE as u32,
+            MAX_SESSION_SIZE,
+        );
+        let pending_recovery_operations = StableBTreeMap::init(
+            memory_manager.get(PENDING_RECOVERY_OPERATIONS_MEMORY_ID),
+            PendingRecoveryOperationKey::SIZE as u32,
+            MAX_PENDING_RECOVERY_OPERATION_SIZE,
+        );
+        let genesis_anchor_extras = StableBTreeMap::init(
+            memory_manager.get(GENESIS_ANCHOR_EXTRAS_MEMORY_ID),
+            std::mem::size_of::<u64>() as u32,
+            MAX_RECORD_SIZE as u32,
+        );
+        let guardian_recoveries = tentative_device_registrations
+            .iter()
+            .filter(|(_, buf)| is_guardian_recovery(buf))
+            .count() as u64;
         Self {
-            header: Header {
-                magic: *b"IIC",
-                version: 1,
-                num_users: 0,
-                id_range_lo,
-                id_range_hi,
-                entry_size: DEFAULT_ENTRY_SIZE,
-                salt: EMPTY_SALT,
-            },
-            memory,
+            header,
+            header_memory: memory,
+            memory_manager,
+            anchor_records,
+            archive_buffer,
+            device_index,
+            tentative_device_registrations,
+            guardian_recoveries,
+            inflight_challenges,
+            expiration_queue,
+            anchor_statistics,
+            delegation_revocations,
+            sessions,
+            pending_recovery_operations,
+            genesis_anchor_extras,
         }
     }
 
@@ -195,7 +711,14 @@ impl<M: Memory> Storage<M> {
             trap(&format!("unsupported header version: {}", header.version));
         }
 
-        Some(Self { header, memory })
+        if header.managed_memory_start_page == 0 {
+            // first load of a storage written by a release that only supports the genesis layout
+            header.managed_memory_start_page = genesis_managed_memory_start_page(&header);
+            let mut storage = Self::init_managed_memory(header, memory);
+            storage.flush();
+            return Some(storage);
+        }
+        Some(Self::init_managed_memory(header, memory))
     }
 
     /// Allocates a fresh Identity Anchor.
@@ -212,34 +735,191 @@ impl<M: Memory> Storage<M> {
         Some(user_number)
     }
 
-    /// Writes the data of the specified user to stable memory.
+    /// Writes the data of the specified user to stable memory and updates the device index and the
+    /// anchor statistics.
     pub fn write(&mut self, user_number: UserNumber, data: Anchor) -> Result<(), StorageError> {
         let record_number = self.user_number_to_record(user_number)?;
-
-        let stable_offset =
-            RESERVED_HEADER_BYTES + record_number as u64 * self.header.entry_size as u64;
-        let buf = candid::encode_one(data.devices).map_err(StorageError::SerializationError)?;
+        if self.anchor_records.is_deleted(record_number) {
+            return Err(StorageError::AnchorDeleted(user_number));
+        }
+        let buf = candid::encode_one(&data).map_err(StorageError::SerializationError)?;
 
         if buf.len() > self.value_size_limit() {
             return Err(StorageError::EntrySizeLimitExceeded(buf.len()));
         }
+        let genesis_record = if self.header.version < LAYOUT_VERSION {
+            Some(self.encode_genesis_record(&data)?)
+        } else {
+            None
+        };
+
+        let previous = if record_number < self.header.indexed_records
+            || record_number < self.header.measured_records
+        {
+            self.read(user_number).ok()
+        } else {
+            None
+        };
+
+        if record_number < self.header.indexed_records {
+            if let Some(previous) = &previous {
+                self.remove_from_device_index(user_number, &previous.devices);
+            }
+            self.add_to_device_index(user_number, &data.devices);
+        } else if record_number == self.header.indexed_records {
+            // the index is complete up to this record, so it can be extended right away
+            self.add_to_device_index(user_number, &data.devices);
+            self.header.indexed_records += 1;
+            self.flush();
+        }
+
+        if record_number < self.header.measured_records {
+            if let Some(previous) = &previous {
+                self.anchor_statistics.remove(
+                    previous,
+                    encoded_record_size(previous),
+                    self.value_size_limit(),
+                );
+            }
+            self.anchor_statistics
+                .add(&data, buf.len(), self.value_size_limit());
+            self.write_anchor_statistics();
+        } else if record_number == self.header.measured_records {
+            // the statistics are complete up to this record, so they can be extended right away
+            self.anchor_statistics
+                .add(&data, buf.len(), self.value_size_limit());
+            self.write_anchor_statistics();
+            self.header.measured_records += 1;
+            self.flush();
+        }
+
+        match genesis_record {
+            Some((devices_buf, extras_buf)) => {
+                self.write_genesis_record(user_number, record_number, &devices_buf, extras_buf)
+            }
+            None => {
+                self.anchor_records.write(record_number, &buf);
+                if record_number < self.header.genesis_records {
+                    // the fields of the genesis record are superseded by the record store
+                    self.genesis_anchor_extras
+                        .remove(&AnchorNumber(user_number));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the data of the specified user from stable memory.
+    pub fn read(&self, user_number: UserNumber) -> Result<Anchor, StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        if self.anchor_records.is_deleted(record_number) {
+            return Err(StorageError::AnchorDeleted(user_number));
+        }
+
+        match self.anchor_records.read(record_number) {
+            Some(buf) => candid::decode_one(&buf).map_err(StorageError::DeserializationError),
+            None if self.header.version < LAYOUT_VERSION
+                || record_number < self.header.genesis_records =>
+            {
+                self.read_genesis_record(record_number)
+            }
+            None => Err(StorageError::BadUserNumber(user_number)),
+        }
+    }
+
+    /// Deletes the record of the specified user and removes it from the device index and the
+    /// anchor statistics.
+    ///
+    /// The record is replaced with a tombstone, so the anchor number stays allocated and is never
+    /// assigned again. Subsequent reads and writes fail with [StorageError::AnchorDeleted].
+    pub fn delete(&mut self, user_number: UserNumber) -> Result<(), StorageError> {
+        let record_number = self.user_number_to_record(user_number)?;
+        let anchor = self.read(user_number)?;
+
+        if record_number < self.header.indexed_records {
+            self.remove_from_device_index(user_number, &anchor.devices);
+        }
+        if record_number < self.header.measured_records {
+            self.anchor_statistics.remove(
+                &anchor,
+                encoded_record_size(&anchor),
+                self.value_size_limit(),
+            );
+            self.write_anchor_statistics();
+        }
+        self.anchor_records.delete(record_number);
+        if self.header.version < LAYOUT_VERSION {
+            // previous releases do not know the tombstone, so the devices are removed as well
+            let (devices_buf, _) = self.encode_genesis_record(&Anchor::default())?;
+            self.write_genesis_record(user_number, record_number, &devices_buf, None);
+        }
+        self.genesis_anchor_extras
+            .remove(&AnchorNumber(user_number));
+        Ok(())
+    }
+
+    /// Encodes the devices of an anchor record for its genesis location and its other fields for
+    /// the genesis anchor extras (None if none of them are set).
+    fn encode_genesis_record(
+        &self,
+        anchor: &Anchor,
+    ) -> Result<(Vec<u8>, Option<Vec<u8>>), StorageError> {
+        let devices_buf =
+            candid::encode_one(&anchor.devices).map_err(StorageError::SerializationError)?;
+        if devices_buf.len() > self.genesis_value_size_limit() {
+            return Err(StorageError::EntrySizeLimitExceeded(devices_buf.len()));
+        }
+
+        let extras = Anchor {
+            devices: vec![],
+            ..anchor.clone()
+        };
+        if extras == Anchor::default() {
+            return Ok((devices_buf, None));
+        }
+        let extras_buf = candid::encode_one(extras).map_err(StorageError::SerializationError)?;
+        Ok((devices_buf, Some(extras_buf)))
+    }
+
+    /// Writes an anchor record using the genesis layout (see [Storage::encode_genesis_record]).
+    fn write_genesis_record(
+        &mut self,
+        user_number: UserNumber,
+        record_number: u32,
+        devices_buf: &[u8],
+        extras_buf: Option<Vec<u8>>,
+    ) {
+        let stable_offset =
+            RESERVED_HEADER_BYTES + record_number as u64 * self.header.entry_size as u64;
 
         // use buffered writer to minimize expensive stable memory operations
         let mut writer = BufferedWriter::new(
             self.header.entry_size as usize,
-            Writer::new(&mut self.memory, stable_offset),
+            Writer::new(&mut self.header_memory, stable_offset),
         );
         writer
-            .write(&(buf.len() as u16).to_le_bytes())
+            .write(&(devices_buf.len() as u16).to_le_bytes())
             .expect("memory write failed");
-        writer.write(&buf).expect("memory write failed");
+        writer.write(devices_buf).expect("memory write failed");
         writer.flush().expect("memory write failed");
-        Ok(())
+
+        match extras_buf {
+            Some(extras_buf) => {
+                self.genesis_anchor_extras
+                    .insert(AnchorNumber(user_number), extras_buf)
+                    .expect("bug: failed to insert genesis anchor extras");
+            }
+            None => {
+                self.genesis_anchor_extras
+                    .remove(&AnchorNumber(user_number));
+            }
+        }
     }
 
-    /// Reads the data of the specified user from stable memory.
-    pub fn read(&self, user_number: UserNumber) -> Result<Anchor, StorageError> {
-        let record_number = self.user_number_to_record(user_number)?;
+    /// Reads an anchor record using the genesis layout, i.e. a record of a storage that has not
+    /// been switched to layout version 3 or a genesis record that has not been migrated yet.
+    fn read_genesis_record(&self, record_number: u32) -> Result<Anchor, StorageError> {
+        let user_number = self.header.id_range_lo + record_number as u64;
         let stable_offset =
             RESERVED_HEADER_BYTES + record_number as u64 * self.header.entry_size as u64;
 
@@ -247,7 +927,7 @@ impl<M: Memory> Storage<M> {
         // use buffered reader to minimize expensive stable memory operations
         let mut reader = BufferedReader::new(
             self.header.entry_size as usize,
-            Reader::new(&self.memory, stable_offset),
+            Reader::new(&self.header_memory, stable_offset),
         );
 
         let mut len_buf = vec![0; 2];
@@ -255,13 +935,17 @@ impl<M: Memory> Storage<M> {
             .read(&mut len_buf.as_mut_slice())
             .expect("failed to read memory");
         let len = u16::from_le_bytes(len_buf.try_into().unwrap()) as usize;
+        if len == 0 {
+            // the anchor number has been allocated, but the record has not been written yet
+            return Err(StorageError::BadUserNumber(user_number));
+        }
 
         // This error most likely indicates stable memory corruption.
-        if len > self.value_size_limit() {
+        if len > self.genesis_value_size_limit() {
             trap(&format!(
                 "persisted value size {} exeeds maximum size {}",
                 len,
-                self.value_size_limit()
+                self.genesis_value_size_limit()
             ))
         }
 
@@ -272,7 +956,192 @@ impl<M: Memory> Storage<M> {
         let devices: Vec<DeviceDataInternal> =
             candid::decode_one(&data_buf).map_err(StorageError::DeserializationError)?;
 
-        Ok(Anchor { devices })
+        let extras = match self.genesis_anchor_extras.get(&AnchorNumber(user_number)) {
+            Some(buf) => candid::decode_one(&buf).map_err(StorageError::DeserializationError)?,
+            None => Anchor::default(),
+        };
+        Ok(Anchor { devices, ..extras })
+    }
+
+    /// Moves the next batch of genesis records to the record store.
+    ///
+    /// Genesis records that have been written since the switch to layout version 3 are already in
+    /// the record store and are skipped.
+    pub fn migrate_records(&mut self) {
+        let start = self.header.migrated_records;
+        let end = self
+            .header
+            .genesis_records
+            .min(start.saturating_add(self.header.migration_batch_size));
+        if start >= end {
+            return;
+        }
+
+        for record_number in start..end {
+            if self.anchor_records.contains(record_number) {
+                continue;
+            }
+            let anchor = match self.read_genesis_record(record_number) {
+                Ok(anchor) => anchor,
+                // the record has never been written, so there is nothing to migrate
+                Err(StorageError::BadUserNumber(_)) => continue,
+                Err(err) => trap(&format!(
+                    "failed to migrate anchor record {}: {}",
+                    record_number, err
+                )),
+            };
+            let buf = candid::encode_one(anchor).unwrap_or_else(|err| {
+                trap(&format!(
+                    "failed to migrate anchor record {}: {}",
+                    record_number, err
+                ))
+            });
+            self.anchor_records.write(record_number, &buf);
+            self.genesis_anchor_extras.remove(&AnchorNumber(
+                self.header.id_range_lo + record_number as u64,
+            ));
+        }
+
+        self.header.migrated_records = end;
+        self.flush();
+    }
+
+    /// Adds the devices of the next batch of anchor records to the device index (if any are left).
+    pub fn index_devices(&mut self) {
+        let start = self.header.indexed_records;
+        let end = self
+            .header
+            .num_users
+            .min(start.saturating_add(DEVICE_INDEX_BATCH_SIZE));
+        if start >= end {
+            return;
+        }
+
+        for record_number in start..end {
+            let user_number = self.header.id_range_lo + record_number as u64;
+            // undecodable records cannot be indexed, but must not block the remaining records
+            if let Ok(anchor) = self.read(user_number) {
+                self.add_to_device_index(user_number, &anchor.devices);
+            }
+        }
+
+        self.header.indexed_records = end;
+        self.flush();
+    }
+
+    /// Returns the number of anchor records that have not been added to the device index yet.
+    pub fn unindexed_records(&self) -> u32 {
+        self.header.num_users - self.header.indexed_records
+    }
+
+    /// Adds the next batch of anchor records to the anchor statistics (if any are left).
+    pub fn measure_records(&mut self) {
+        let start = self.header.measured_records;
+        let end = self
+            .header
+            .num_users
+            .min(start.saturating_add(ANCHOR_STATISTICS_BATCH_SIZE));
+        if start >= end {
+            return;
+        }
+
+        for record_number in start..end {
+            let user_number = self.header.id_range_lo + record_number as u64;
+            // deleted and undecodable records are not part of the statistics
+            if let Ok(anchor) = self.read(user_number) {
+                self.anchor_statistics.add(
+                    &anchor,
+                    encoded_record_size(&anchor),
+                    self.value_size_limit(),
+                );
+            }
+        }
+
+        self.write_anchor_statistics();
+        self.header.measured_records = end;
+        self.flush();
+    }
+
+    /// Returns the number of anchor records that have not been added to the anchor statistics yet.
+    pub fn unmeasured_records(&self) -> u32 {
+        self.header.num_users - self.header.measured_records
+    }
+
+    /// Returns the statistics of the anchor records added so far (see [Storage::measure_records]).
+    pub fn anchor_statistics(&self) -> &AnchorStatistics {
+        &self.anchor_statistics
+    }
+
+    fn write_anchor_statistics(&mut self) {
+        let mut memory = self.memory_manager.get(ANCHOR_STATISTICS_MEMORY_ID);
+        let mut writer = Writer::new(&mut memory, 0);
+        writer
+            .write(&self.anchor_statistics.to_bytes())
+            .expect("bug: failed to write anchor statistics");
+    }
+
+    /// Returns the anchors (in ascending order) that have a device with the given identifier.
+    pub fn lookup_anchors(&self, identifier: &DeviceIdentifier) -> Vec<UserNumber> {
+        let prefix = match identifier {
+            DeviceIdentifier::CredentialId(credential_id) => {
+                DeviceIndexKey::prefix(CREDENTIAL_ID_TAG, credential_id)
+            }
+            DeviceIdentifier::PublicKey(pubkey) => DeviceIndexKey::prefix(PUBKEY_TAG, pubkey),
+        };
+        self.device_index
+            .range(prefix.to_vec(), None)
+            .map(|(key, _)| key.anchor)
+            .collect()
+    }
+
+    fn add_to_device_index(&mut self, user_number: UserNumber, devices: &[DeviceDataInternal]) {
+        for key in device_index_keys(user_number, devices) {
+            self.device_index
+                .insert(key, ())
+                .expect("bug: failed to insert device index entry");
+        }
+    }
+
+    fn remove_from_device_index(
+        &mut self,
+        user_number: UserNumber,
+        devices: &[DeviceDataInternal],
+    ) {
+        for key in device_index_keys(user_number, devices) {
+            self.device_index.remove(&key);
+        }
+    }
+
+    /// Sets the number of genesis records migrated per batch (0 pauses the migration).
+    ///
+    /// A storage still using the genesis layout is switched to layout version 3 first, after which
+    /// it can no longer be read by releases that only support the genesis layout.
+    pub fn set_migration_batch_size(&mut self, batch_size: u32) {
+        if self.header.version < LAYOUT_VERSION {
+            self.header.version = LAYOUT_VERSION;
+            self.header.genesis_records = self.header.num_users;
+            self.header.migrated_records = 0;
+        }
+        self.header.migration_batch_size = batch_size;
+        self.flush();
+    }
+
+    pub fn migration_state(&self) -> MigrationState {
+        if self.header.version < LAYOUT_VERSION {
+            return MigrationState::NotStarted;
+        }
+        let anchors_left = (self.header.genesis_records - self.header.migrated_records) as u64;
+        let batch_size = self.header.migration_batch_size as u64;
+        if anchors_left == 0 {
+            MigrationState::Finished
+        } else if batch_size == 0 {
+            MigrationState::Paused { anchors_left }
+        } else {
+            MigrationState::InProgress {
+                anchors_left,
+                batch_size,
+            }
+        }
     }
 
     /// Make sure all the required metadata is recorded to stable memory.
@@ -283,7 +1152,7 @@ impl<M: Memory> Storage<M> {
                 std::mem::size_of::<Header>(),
             )
         };
-        let mut writer = Writer::new(&mut self.memory, 0);
+        let mut writer = Writer::new(&mut self.header_memory, 0);
 
         // this should never fail as this write only requires a memory of size 1
         writer.write(slice).expect("bug: failed to grow memory");
@@ -295,8 +1164,16 @@ impl<M: Memory> Storage<M> {
 
     /// Returns the maximum number of entries that this storage can fit.
     pub fn max_entries(&self) -> usize {
-        ((STABLE_MEMORY_SIZE - RESERVED_HEADER_BYTES - STABLE_MEMORY_RESERVE)
-            / self.header.entry_size as u64) as usize
+        let max_entries = (STABLE_MEMORY_SIZE - RESERVED_HEADER_BYTES - STABLE_MEMORY_RESERVE)
+            / self.header.entry_size as u64;
+        if self.header.version < LAYOUT_VERSION {
+            // the anchor records of the genesis layout must not reach the managed memory
+            let genesis_space = self.header.managed_memory_start_page as u64 * WASM_PAGE_SIZE
+                - RESERVED_HEADER_BYTES
+                - GENESIS_PERSISTENT_STATE_RESERVE;
+            return max_entries.min(genesis_space / self.header.entry_size as u64) as usize;
+        }
+        max_entries as usize
     }
 
     pub fn assigned_user_number_range(&self) -> (UserNumber, UserNumber) {
@@ -323,7 +1200,12 @@ impl<M: Memory> Storage<M> {
         self.flush();
     }
 
-    fn value_size_limit(&self) -> usize {
+    /// Returns the maximum size of a candid encoded anchor record.
+    pub fn value_size_limit(&self) -> usize {
+        MAX_RECORD_SIZE
+    }
+
+    fn genesis_value_size_limit(&self) -> usize {
         self.header.entry_size as usize - std::mem::size_of::<u16>()
     }
 
@@ -342,95 +1224,745 @@ impl<M: Memory> Storage<M> {
         Ok(record_number)
     }
 
-    /// Returns the address of the first byte not yet allocated to a user.
-    /// This address exists even if the max user number has been reached, because there is a memory
-    /// reserve at the end of stable memory.
-    fn unused_memory_start(&self) -> u64 {
-        let record_number = self.header.num_users as u64;
-        RESERVED_HEADER_BYTES + record_number * self.header.entry_size as u64
-    }
-
-    /// Writes the persistent state to stable memory just outside of the space allocated to the highest user number.
-    /// This is only used to _temporarily_ save state during upgrades. It will be overwritten on next user registration.
+    /// Writes the persistent state to its dedicated managed memory.
+    /// The state is written as a whole, so it can be written at any time (not only in `pre_upgrade`).
+    ///
+    /// While the genesis layout is used, the state is also written to its genesis location, where
+    /// previous releases read it after a rollback.
     pub fn write_persistent_state(&mut self, state: &PersistentState) {
-        let address = self.unused_memory_start();
-
         // In practice, candid encoding is infallible. The Result is an artifact of the serde API.
         let encoded_state = candid::encode_one(state).unwrap();
 
         // In practice, for all reasonably sized persistent states (<800MB) the writes are
-        // infallible because we have a stable memory reserve (i.e. growing the memory will succeed).
-        let mut writer = Writer::new(&mut self.memory, address);
+        // infallible because the managed memory can still grow into the stable memory reserve.
+        let mut memory = self.memory_manager.get(PERSISTENT_STATE_MEMORY_ID);
+        let mut writer = Writer::new(&mut memory, 0);
         writer.write(&PERSISTENT_STATE_MAGIC).unwrap();
+        writer.write(&[PERSISTENT_STATE_VERSION]).unwrap();
         writer
             .write(&(encoded_state.len() as u64).to_le_bytes())
             .unwrap();
         writer.write(&encoded_state).unwrap();
+
+        if self.header.version < LAYOUT_VERSION {
+            self.write_genesis_persistent_state(&encoded_state);
+        }
     }
 
-    /// Reads the persistent state from stable memory just outside of the space allocated to the highest user number.
-    /// This is only used to restore state in `post_upgrade`.
+    /// Writes the encoded persistent state to the first unused memory location of the genesis
+    /// layout. It is overwritten by the next anchor record, but written again in `pre_upgrade`.
+    fn write_genesis_persistent_state(&mut self, encoded_state: &[u8]) {
+        let address = self.genesis_unused_memory_start();
+        let end = address + (PERSISTENT_STATE_MAGIC.len() + 8 + encoded_state.len()) as u64;
+        if end > self.header.managed_memory_start_page as u64 * WASM_PAGE_SIZE {
+            // The state does not fit in front of the managed memory (which only happens if the
+            // anchor range is exhausted), so previous releases will not find it.
+            return;
+        }
+
+        let mut writer = Writer::new(&mut self.header_memory, address);
+        writer.write(&PERSISTENT_STATE_MAGIC).unwrap();
+        writer
+            .write(&(encoded_state.len() as u64).to_le_bytes())
+            .unwrap();
+        writer.write(encoded_state).unwrap();
+    }
+
+    /// Reads the persistent state from its dedicated managed memory.
+    ///
+    /// While the genesis layout is used, the fields known to previous releases are taken from the
+    /// state at the genesis location if present, because a previous release only updated that one
+    /// after a rollback.
     pub fn read_persistent_state(&self) -> Result<PersistentState, PersistentStateError> {
-        const WASM_PAGE_SIZE: u64 = 65536;
-        let address = self.unused_memory_start();
+        let state = read_persistent_state(
+            &self.memory_manager.get(PERSISTENT_STATE_MEMORY_ID),
+            0,
+            Some(PERSISTENT_STATE_VERSION),
+        );
+        if self.header.version == LAYOUT_VERSION {
+            return state;
+        }
 
-        if address > self.memory.size() * WASM_PAGE_SIZE {
-            // the address where the persistent state would be is not allocated yet
-            return Err(PersistentStateError::NotFound);
+        let genesis_state = read_persistent_state(
+            &self.header_memory,
+            self.genesis_unused_memory_start(),
+            None,
+        );
+        match (state, genesis_state) {
+            (Ok(state), Ok(genesis_state)) => Ok(PersistentState {
+                archive_info: genesis_state.archive_info,
+                canister_creation_cycles_cost: genesis_state.canister_creation_cycles_cost,
+                ..state
+            }),
+            // version 1 of the genesis layout might not have a persistent state
+            (Err(PersistentStateError::NotFound), Err(PersistentStateError::NotFound))
+                if self.header.version < 2 =>
+            {
+                Ok(PersistentState::default())
+            }
+            (Err(PersistentStateError::NotFound), genesis_state) => genesis_state,
+            (state, Err(PersistentStateError::NotFound)) => state,
+            (Err(err), _) | (_, Err(err)) => Err(err),
         }
+    }
 
-        let mut reader = Reader::new(&self.memory, address);
-        let mut magic_buf: [u8; 4] = [0; 4];
-        let bytes_read = reader
-            .read(&mut magic_buf)
-            // if we hit out of bounds here, this means that the persistent state has not been
-            // written at the expected location and thus cannot be found
-            .map_err(|_| PersistentStateError::NotFound)?;
+    /// Returns the address of the first byte not allocated to an anchor record of the genesis
+    /// layout.
+    fn genesis_unused_memory_start(&self) -> u64 {
+        RESERVED_HEADER_BYTES + self.header.num_users as u64 * self.header.entry_size as u64
+    }
 
-        if bytes_read != 4 || magic_buf != PERSISTENT_STATE_MAGIC {
-            // less than the expected number of bytes were read or the magic does not match
-            // --> this is not the persistent state
-            return Err(PersistentStateError::NotFound);
+    pub fn version(&self) -> u8 {
+        self.header.version
+    }
+
+    /// Adds an entry to the archive buffer.
+    pub fn buffer_archive_entry(&mut self, entry: BufferedEntry) {
+        let sequence_number = entry.sequence_number;
+        let buf = candid::encode_one(entry).expect("failed to encode buffered archive entry");
+        if buf.len() > MAX_BUFFERED_ENTRY_SIZE as usize {
+            trap(&format!(
+                "buffered archive entry of size {} exceeds the maximum size {}",
+                buf.len(),
+                MAX_BUFFERED_ENTRY_SIZE
+            ));
         }
+        self.archive_buffer
+            .insert(SequenceNumber(sequence_number), buf)
+            .expect("bug: failed to insert buffered archive entry");
+    }
+
+    /// Returns the number of entries in the archive buffer.
+    pub fn archive_buffer_len(&self) -> u64 {
+        self.archive_buffer.len()
+    }
 
-        let mut size_buf: [u8; 8] = [0; 8];
-        let bytes_read = reader
-            .read(&mut size_buf)
-            .map_err(|err| PersistentStateError::ReadError(err))? as u64;
+    /// Returns up to `limit` buffered entries with the lowest sequence numbers.
+    pub fn archive_buffer_entries(&self, limit: usize) -> Vec<BufferedEntry> {
+        self.archive_buffer
+            .iter()
+            .take(limit)
+            .map(|(_, buf)| {
+                candid::decode_one(&buf).expect("failed to decode buffered archive entry")
+            })
+            .collect()
+    }
 
-        // check if we actually read the required amount of data
-        // note: this will only happen if we hit the memory bounds during read
-        if bytes_read != 8 {
-            let max_address = address + 4 + bytes_read;
-            return Err(PersistentStateError::ReadError(OutOfBounds {
-                max_address,
-                attempted_read_address: max_address + 1,
-            }));
+    /// Removes all buffered entries with a sequence number up to (and including) the given one.
+    pub fn acknowledge_archive_entries(&mut self, sequence_number: u64) {
+        let acknowledged: Vec<SequenceNumber> = self
+            .archive_buffer
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(|key| key.0 <= sequence_number)
+            .collect();
+        for key in acknowledged {
+            self.archive_buffer.remove(&key);
         }
+    }
+
+    /// Returns the tentative device registration of the given anchor (if any).
+    pub fn tentative_device_registration(
+        &self,
+        user_number: UserNumber,
+    ) -> Option<TentativeDeviceRegistration> {
+        self.tentative_device_registrations
+            .get(&AnchorNumber(user_number))
+            .map(|buf| {
+                candid::decode_one(&buf).expect("failed to decode tentative device registration")
+            })
+    }
+
+    /// Inserts (or replaces) the tentative device registration of the given anchor.
+    pub fn insert_tentative_device_registration(
+        &mut self,
+        user_number: UserNumber,
+        registration: TentativeDeviceRegistration,
+    ) {
+        self.remove_tentative_device_registration(user_number);
 
-        let size = u64::from_le_bytes(size_buf);
-        let mut data_buf = Vec::new();
-        data_buf.resize(size as usize, 0);
-        let bytes_read = reader
-            .read(data_buf.as_mut_slice())
-            .map_err(|err| PersistentStateError::ReadError(err))? as u64;
+        let buf = candid::encode_one(&registration)
+            .expect("failed to encode tentative device registration");
+        if buf.len() > MAX_TENTATIVE_REGISTRATION_SIZE as usize {
+            trap(&format!(
+                "tentative device registration of size {} exceeds the maximum size {}",
+                buf.len(),
+                MAX_TENTATIVE_REGISTRATION_SIZE
+            ));
+        }
+        if is_guardian_recovery(&buf) {
+            self.guardian_recoveries += 1;
+        }
+        self.tentative_device_registrations
+            .insert(AnchorNumber(user_number), buf)
+            .expect("bug: failed to insert tentative device registration");
+        self.expiration_queue
+            .insert(
+                ExpirationKey {
+                    expiration: registration.expiration,
+                    tag: REGISTRATION_EXPIRATION_TAG,
+                    key: user_number.to_be_bytes().to_vec(),
+                },
+                (),
+            )
+            .expect("bug: failed to insert expiration");
+    }
 
-        // check if we actually read the required amount of data
-        // note: this will only happen if we hit the memory bounds during read
-        if bytes_read != size {
-            let max_address = address + 4 + 8 + bytes_read;
-            return Err(PersistentStateError::ReadError(OutOfBounds {
-                max_address,
-                attempted_read_address: max_address + 1,
-            }));
+    /// Removes the tentative device registration of the given anchor and returns it (if any).
+    pub fn remove_tentative_device_registration(
+        &mut self,
+        user_number: UserNumber,
+    ) -> Option<TentativeDeviceRegistration> {
+        let registration = self.tentative_device_registration(user_number)?;
+        self.tentative_device_registrations
+            .remove(&AnchorNumber(user_number));
+        if matches!(registration.state, GuardianRecoveryRequested { .. }) {
+            self.guardian_recoveries -= 1;
         }
+        self.expiration_queue.remove(&ExpirationKey {
+            expiration: registration.expiration,
+            tag: REGISTRATION_EXPIRATION_TAG,
+            key: user_number.to_be_bytes().to_vec(),
+        });
+        Some(registration)
+    }
 
-        candid::decode_one(&data_buf).map_err(|err| PersistentStateError::CandidError(err))
+    /// Returns the number of tentative device registrations (including expired ones that have not
+    /// been pruned yet).
+    pub fn tentative_device_registrations_len(&self) -> u64 {
+        self.tentative_device_registrations.len()
     }
 
-    pub fn version(&self) -> u8 {
-        self.header.version
+    /// Returns the number of tentative device registrations that are guardian recoveries
+    /// (including expired ones that have not been pruned yet).
+    pub fn guardian_recoveries_len(&self) -> u64 {
+        self.guardian_recoveries
+    }
+
+    /// Returns true if there is an inflight captcha challenge with the given key.
+    pub fn contains_inflight_challenge(&self, challenge_key: &str) -> bool {
+        challenge_key.len() <= MAX_CHALLENGE_KEY_SIZE as usize
+            && self
+                .inflight_challenges
+                .get(&challenge_key.as_bytes().to_vec())
+                .is_some()
+    }
+
+    /// Inserts an inflight captcha challenge.
+    pub fn insert_inflight_challenge(&mut self, challenge_key: ChallengeKey, info: ChallengeInfo) {
+        if challenge_key.len() > MAX_CHALLENGE_KEY_SIZE as usize {
+            trap(&format!(
+                "challenge key of size {} exceeds the maximum size {}",
+                challenge_key.len(),
+                MAX_CHALLENGE_KEY_SIZE
+            ));
+        }
+        self.remove_inflight_challenge(&challenge_key);
+
+        let buf = candid::encode_one(&info).expect("failed to encode challenge info");
+        self.inflight_challenges
+            .insert(challenge_key.as_bytes().to_vec(), buf)
+            .expect("bug: failed to insert inflight challenge");
+        self.expiration_queue
+            .insert(
+                ExpirationKey {
+                    expiration: info.expiration,
+                    tag: CHALLENGE_EXPIRATION_TAG,
+                    key: challenge_key.into_bytes(),
+                },
+                (),
+            )
+            .expect("bug: failed to insert expiration");
     }
+
+    /// Removes the inflight captcha challenge with the given key and returns it (if any).
+    pub fn remove_inflight_challenge(&mut self, challenge_key: &str) -> Option<ChallengeInfo> {
+        if challenge_key.len() > MAX_CHALLENGE_KEY_SIZE as usize {
+            return None;
+        }
+        let key = challenge_key.as_bytes().to_vec();
+        let buf = self.inflight_challenges.remove(&key)?;
+        let info: ChallengeInfo =
+            candid::decode_one(&buf).expect("failed to decode challenge info");
+        self.expiration_queue.remove(&ExpirationKey {
+            expiration: info.expiration,
+            tag: CHALLENGE_EXPIRATION_TAG,
+            key,
+        });
+        Some(info)
+    }
+
+    /// Returns the number of inflight captcha challenges (including expired ones that have not
+    /// been pruned yet).
+    pub fn inflight_challenges_len(&self) -> u64 {
+        self.inflight_challenges.len()
+    }
+
+    /// Returns the time from which on delegations of the given principal are valid (if its
+    /// delegations have been revoked).
+    pub fn delegations_not_before(&self, principal: &[u8]) -> Option<Timestamp> {
+        self.delegation_revocations
+            .get(&principal.to_vec())
+            .map(|buf| decode_revocation(buf).0)
+    }
+
+    /// Records that delegations of the given principal issued before `not_before` are revoked.
+    /// The revocation expires at `expiration`.
+    pub fn insert_delegation_revocation(
+        &mut self,
+        principal: &[u8],
+        not_before: Timestamp,
+        expiration: Timestamp,
+    ) {
+        if principal.len() > MAX_PRINCIPAL_SIZE as usize {
+            trap(&format!(
+                "principal of size {} exceeds the maximum size {}",
+                principal.len(),
+                MAX_PRINCIPAL_SIZE
+            ));
+        }
+        let mut buf = not_before.to_be_bytes().to_vec();
+        buf.extend(&expiration.to_be_bytes());
+        let previous = self
+            .delegation_revocations
+            .insert(principal.to_vec(), buf)
+            .expect("bug: failed to insert delegation revocation");
+        if let Some(previous) = previous {
+            self.expiration_queue.remove(&ExpirationKey {
+                expiration: decode_revocation(previous).1,
+                tag: REVOCATION_EXPIRATION_TAG,
+                key: principal.to_vec(),
+            });
+        }
+        self.expiration_queue
+            .insert(
+                ExpirationKey {
+                    expiration,
+                    tag: REVOCATION_EXPIRATION_TAG,
+                    key: principal.to_vec(),
+                },
+                (),
+            )
+            .expect("bug: failed to insert expiration");
+    }
+
+    /// Returns all delegation revocations (principal and not before timestamp).
+    pub fn delegation_revocations(&self) -> Vec<(Vec<u8>, Timestamp)> {
+        self.delegation_revocations
+            .iter()
+            .map(|(principal, buf)| (principal, decode_revocation(buf).0))
+            .collect()
+    }
+
+    /// Removes the delegation revocations that expired at or before `now` and returns their
+    /// principals.
+    pub fn prune_expired_revocations(&mut self, now: Timestamp) -> Vec<Vec<u8>> {
+        let expired: Vec<ExpirationKey> = self
+            .expiration_queue
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(|key| key.expiration <= now)
+            .filter(|key| key.tag == REVOCATION_EXPIRATION_TAG)
+            .collect();
+        let mut principals = vec![];
+        for key in expired {
+            self.expiration_queue.remove(&key);
+            self.delegation_revocations.remove(&key.key);
+            principals.push(key.key);
+        }
+        principals
+    }
+
+    /// Returns the sessions of the given anchor (including expired ones that have not been pruned
+    /// yet).
+    pub fn sessions(&self, user_number: UserNumber) -> Vec<Session> {
+        self.sessions
+            .range(user_number.to_be_bytes().to_vec(), None)
+            .map(|(_, buf)| candid::decode_one(&buf).expect("failed to decode session"))
+            .collect()
+    }
+
+    /// Records a session of the given anchor, replacing the session with the same session key
+    /// (if any). If the anchor already has [MAX_SESSIONS_PER_ANCHOR] sessions, the least recently
+    /// issued one is evicted.
+    pub fn record_session(&mut self, user_number: UserNumber, session: Session) {
+        let key = SessionKey {
+            anchor: user_number,
+            session_key_hash: session
+                .session_key_hash
+                .as_slice()
+                .try_into()
+                .unwrap_or_else(|_| trap("session key hash must be 32 bytes")),
+        };
+        let buf = candid::encode_one(&session).expect("failed to encode session");
+        if buf.len() > MAX_SESSION_SIZE as usize {
+            trap(&format!(
+                "session of size {} exceeds the maximum size {}",
+                buf.len(),
+                MAX_SESSION_SIZE
+            ));
+        }
+
+        self.remove_session(&key);
+        let sessions = self.sessions(user_number);
+        if sessions.len() >= MAX_SESSIONS_PER_ANCHOR {
+            let least_recent = sessions
+                .into_iter()
+                .min_by_key(|session| session.issued_at)
+                .expect("bug: no sessions to evict");
+            self.remove_session(&SessionKey {
+                anchor: user_number,
+                session_key_hash: least_recent
+                    .session_key_hash
+                    .as_slice()
+                    .try_into()
+                    .expect("bug: invalid session key hash"),
+            });
+        }
+
+        self.expiration_queue
+            .insert(
+                ExpirationKey {
+                    expiration: session.expiration,
+                    tag: SESSION_EXPIRATION_TAG,
+                    key: key.to_bytes().to_vec(),
+                },
+                (),
+            )
+            .expect("bug: failed to insert expiration");
+        self.sessions
+            .insert(key, buf)
+            .expect("bug: failed to insert session");
+    }
+
+    /// Removes the sessions of the given anchor for which `predicate` returns true.
+    pub fn remove_sessions<F: Fn(&Session) -> bool>(
+        &mut self,
+        user_number: UserNumber,
+        predicate: F,
+    ) {
+        let keys: Vec<SessionKey> = self
+            .sessions
+            .range(user_number.to_be_bytes().to_vec(), None)
+            .filter(|(_, buf)| {
+                predicate(&candid::decode_one(buf).expect("failed to decode session"))
+            })
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            self.remove_session(&key);
+        }
+    }
+
+    fn remove_session(&mut self, key: &SessionKey) {
+        if let Some(buf) = self.sessions.remove(key) {
+            let session: Session = candid::decode_one(&buf).expect("failed to decode session");
+            self.expiration_queue.remove(&ExpirationKey {
+                expiration: session.expiration,
+                tag: SESSION_EXPIRATION_TAG,
+                key: key.to_bytes().to_vec(),
+            });
+        }
+    }
+
+    /// Returns the pending recovery operations of the given anchor, ordered by id.
+    pub fn pending_recovery_operations(
+        &self,
+        user_number: UserNumber,
+    ) -> Vec<PendingRecoveryOperation> {
+        self.pending_recovery_operations
+            .range(user_number.to_be_bytes().to_vec(), None)
+            .map(|(_, buf)| {
+                candid::decode_one(&buf).expect("failed to decode pending recovery operation")
+            })
+            .collect()
+    }
+
+    /// Inserts a pending recovery operation of the given anchor, which becomes due at its
+    /// `effective_at` time.
+    pub fn insert_pending_recovery_operation(
+        &mut self,
+        user_number: UserNumber,
+        operation: PendingRecoveryOperation,
+    ) {
+        let key = PendingRecoveryOperationKey {
+            anchor: user_number,
+            id: operation.id,
+        };
+        let buf =
+            candid::encode_one(&operation).expect("failed to encode pending recovery operation");
+        if buf.len() > MAX_PENDING_RECOVERY_OPERATION_SIZE as usize {
+            trap(&format!(
+                "pending recovery operation of size {} exceeds the maximum size {}",
+                buf.len(),
+                MAX_PENDING_RECOVERY_OPERATION_SIZE
+            ));
+        }
+        self.remove_pending_recovery_operation(user_number, operation.id);
+
+        self.expiration_queue
+            .insert(
+                ExpirationKey {
+                    expiration: operation.effective_at,
+                    tag: RECOVERY_OPERATION_EXPIRATION_TAG,
+                    key: key.to_bytes().to_vec(),
+                },
+                (),
+            )
+            .expect("bug: failed to insert expiration");
+        self.pending_recovery_operations
+            .insert(key, buf)
+            .expect("bug: failed to insert pending recovery operation");
+    }
+
+    /// Removes the pending recovery operation with the given id of the given anchor and returns it
+    /// (if any).
+    pub fn remove_pending_recovery_operation(
+        &mut self,
+        user_number: UserNumber,
+        id: u64,
+    ) -> Option<PendingRecoveryOperation> {
+        let key = PendingRecoveryOperationKey {
+            anchor: user_number,
+            id,
+        };
+        let buf = self.pending_recovery_operations.remove(&key)?;
+        let operation: PendingRecoveryOperation =
+            candid::decode_one(&buf).expect("failed to decode pending recovery operation");
+        self.expiration_queue.remove(&ExpirationKey {
+            expiration: operation.effective_at,
+            tag: RECOVERY_OPERATION_EXPIRATION_TAG,
+            key: key.to_bytes().to_vec(),
+        });
+        Some(operation)
+    }
+
+    /// Removes at most `limit` pending recovery operations that became effective at or before
+    /// `now` and returns them (together with their anchor), in the order they became effective.
+    pub fn pop_due_recovery_operations(
+        &mut self,
+        now: Timestamp,
+        limit: usize,
+    ) -> Vec<(UserNumber, PendingRecoveryOperation)> {
+        let due: Vec<PendingRecoveryOperationKey> = self
+            .expiration_queue
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(|key| key.expiration <= now)
+            .filter(|key| key.tag == RECOVERY_OPERATION_EXPIRATION_TAG)
+            .take(limit)
+            .map(|key| PendingRecoveryOperationKey::from_bytes(key.key))
+            .collect();
+        due.into_iter()
+            .filter_map(|key| {
+                self.remove_pending_recovery_operation(key.anchor, key.id)
+                    .map(|operation| (key.anchor, operation))
+            })
+            .collect()
+    }
+
+    /// Removes all tentative device registrations, inflight captcha challenges and sessions that
+    /// expired at or before `now`.
+    pub fn prune_expired(&mut self, now: Timestamp) {
+        let expired: Vec<ExpirationKey> = self
+            .expiration_queue
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(|key| key.expiration <= now)
+            .filter(|key| {
+                key.tag != REVOCATION_EXPIRATION_TAG && key.tag != RECOVERY_OPERATION_EXPIRATION_TAG
+            })
+            .collect();
+        for key in expired {
+            self.expiration_queue.remove(&key);
+            match key.tag {
+                REGISTRATION_EXPIRATION_TAG => {
+                    let removed = self
+                        .tentative_device_registrations
+                        .remove(&AnchorNumber::from_bytes(key.key));
+                    if matches!(removed, Some(buf) if is_guardian_recovery(&buf)) {
+                        self.guardian_recoveries -= 1;
+                    }
+                }
+                SESSION_EXPIRATION_TAG => {
+                    self.sessions.remove(&SessionKey::from_bytes(key.key));
+                }
+                _ => {
+                    self.inflight_challenges.remove(&key.key);
+                }
+            }
+        }
+    }
+}
+
+fn is_guardian_recovery(buf: &[u8]) -> bool {
+    let registration: TentativeDeviceRegistration =
+        candid::decode_one(buf).expect("failed to decode tentative device registration");
+    matches!(registration.state, GuardianRecoveryRequested { .. })
+}
+
+/// Decodes the value of a delegation revocation into the not before timestamp and the expiration.
+fn decode_revocation(buf: Vec<u8>) -> (Timestamp, Timestamp) {
+    let (not_before, expiration) = buf.split_at(8);
+    (
+        u64::from_be_bytes(not_before.try_into().expect("bug: invalid revocation")),
+        u64::from_be_bytes(expiration.try_into().expect("bug: invalid revocation")),
+    )
+}
+
+/// Returns the device index keys of the credential ids and public keys of the given devices.
+fn device_index_keys(
+    user_number: UserNumber,
+    devices: &[DeviceDataInternal],
+) -> Vec<DeviceIndexKey> {
+    let mut keys = vec![];
+    for device in devices {
+        if let Some(ref credential_id) = device.credential_id {
+            keys.push(DeviceIndexKey {
+                prefix: DeviceIndexKey::prefix(CREDENTIAL_ID_TAG, credential_id),
+                anchor: user_number,
+            });
+        }
+        keys.push(DeviceIndexKey {
+            prefix: DeviceIndexKey::prefix(PUBKEY_TAG, &device.pubkey),
+            anchor: user_number,
+        });
+    }
+    keys
+}
+
+fn encoded_record_size(anchor: &Anchor) -> usize {
+    // In practice, candid encoding is infallible. The Result is an artifact of the serde API.
+    candid::encode_one(anchor).map_or(0, |buf| buf.len())
+}
+
+fn read_anchor_statistics<M: Memory>(memory: &M) -> AnchorStatistics {
+    if memory.size() == 0 {
+        // the statistics have not been written yet
+        return AnchorStatistics::default();
+    }
+    let mut buf = vec![0; anchor_statistics::SERIALIZED_SIZE];
+    memory.read(0, &mut buf);
+    AnchorStatistics::from_bytes(&buf)
+}
+
+/// Returns the header of a new empty storage for the given anchor range (without layout version
+/// and managed memory start page).
+fn new_header((id_range_lo, id_range_hi): (UserNumber, UserNumber)) -> Header {
+    if id_range_hi < id_range_lo {
+        trap(&format!(
+            "improper Identity Anchor range: [{}, {})",
+            id_range_lo, id_range_hi,
+        ));
+    }
+
+    if (id_range_hi - id_range_lo) > DEFAULT_RANGE_SIZE {
+        trap(&format!(
+            "id range [{}, {}) is too large for a single canister (max {} entries)",
+            id_range_lo, id_range_hi, DEFAULT_RANGE_SIZE,
+        ));
+    }
+
+    Header {
+        magic: *b"IIC",
+        version: 0,
+        num_users: 0,
+        id_range_lo,
+        id_range_hi,
+        entry_size: DEFAULT_ENTRY_SIZE,
+        salt: EMPTY_SALT,
+        genesis_records: 0,
+        migrated_records: 0,
+        migration_batch_size: DEFAULT_MIGRATION_BATCH_SIZE,
+        indexed_records: 0,
+        measured_records: 0,
+        managed_memory_start_page: 0,
+    }
+}
+
+/// Returns the first page of the memory managed by the memory manager for a storage using the
+/// genesis layout, which is the first page after the anchor records of the whole anchor range and
+/// the space reserved for the persistent state.
+fn genesis_managed_memory_start_page(header: &Header) -> u32 {
+    let genesis_end = RESERVED_HEADER_BYTES
+        + (header.id_range_hi - header.id_range_lo) * header.entry_size as u64
+        + GENESIS_PERSISTENT_STATE_RESERVE;
+    ((genesis_end + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE) as u32
+}
+
+/// Reads a persistent state serialized at the given address.
+///
+/// The genesis layout serialized the persistent state without a version byte, in which case
+/// `expected_version` is `None`.
+fn read_persistent_state<M: Memory>(
+    memory: &M,
+    address: u64,
+    expected_version: Option<u8>,
+) -> Result<PersistentState, PersistentStateError> {
+    if address > memory.size() * WASM_PAGE_SIZE {
+        // the address where the persistent state would be is not allocated yet
+        return Err(PersistentStateError::NotFound);
+    }
+
+    let mut reader = Reader::new(memory, address);
+    let mut magic_buf: [u8; 4] = [0; 4];
+    let bytes_read = reader
+        .read(&mut magic_buf)
+        // if we hit out of bounds here, this means that the persistent state has not been
+        // written at the expected location and thus cannot be found
+        .map_err(|_| PersistentStateError::NotFound)?;
+
+    if bytes_read != 4 || magic_buf != PERSISTENT_STATE_MAGIC {
+        // less than the expected number of bytes were read or the magic does not match
+        // --> this is not the persistent state
+        return Err(PersistentStateError::NotFound);
+    }
+
+    let mut header_len = 4;
+    if let Some(expected_version) = expected_version {
+        let mut version_buf: [u8; 1] = [0; 1];
+        reader
+            .read(&mut version_buf)
+            .map_err(|err| PersistentStateError::ReadError(err))?;
+        if version_buf[0] != expected_version {
+            return Err(PersistentStateError::UnsupportedVersion(version_buf[0]));
+        }
+        header_len += 1;
+    }
+
+    let mut size_buf: [u8; 8] = [0; 8];
+    let bytes_read = reader
+        .read(&mut size_buf)
+        .map_err(|err| PersistentStateError::ReadError(err))? as u64;
+
+    // check if we actually read the required amount of data
+    // note: this will only happen if we hit the memory bounds during read
+    if bytes_read != 8 {
+        let max_address = address + header_len + bytes_read;
+        return Err(PersistentStateError::ReadError(OutOfBounds {
+            max_address,
+            attempted_read_address: max_address + 1,
+        }));
+    }
+
+    let size = u64::from_le_bytes(size_buf);
+    let mut data_buf = Vec::new();
+    data_buf.resize(size as usize, 0);
+    let bytes_read = reader
+        .read(data_buf.as_mut_slice())
+        .map_err(|err| PersistentStateError::ReadError(err))? as u64;
+
+    // check if we actually read the required amount of data
+    // note: this will only happen if we hit the memory bounds during read
+    if bytes_read != size {
+        let max_address = address + header_len + 8 + bytes_read;
+        return Err(PersistentStateError::ReadError(OutOfBounds {
+            max_address,
+            attempted_read_address: max_address + 1,
+        }));
+    }
+
+    candid::decode_one(&data_buf).map_err(|err| PersistentStateError::CandidError(err))
 }
 
 #[derive(Debug)]
@@ -438,6 +1970,7 @@ pub enum PersistentStateError {
     CandidError(candid::error::Error),
     NotFound,
     ReadError(OutOfBounds),
+    UnsupportedVersion(u8),
 }
 
 #[derive(Debug)]
@@ -450,6 +1983,7 @@ pub enum StorageError {
     DeserializationError(candid::error::Error),
     SerializationError(candid::error::Error),
     EntrySizeLimitExceeded(usize),
+    AnchorDeleted(UserNumber),
 }
 
 impl fmt::Display for StorageError {
@@ -473,6 +2007,7 @@ impl fmt::Display for StorageError {
                  which is larger then the max allowed entry size",
                 n
             ),
+            Self::AnchorDeleted(n) => write!(f, "Identity Anchor {} has been deleted", n),
         }
     }
 }
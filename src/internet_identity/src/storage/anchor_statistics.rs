@@ -0,0 +1,118 @@
+//! Statistics about the anchor records (number of devices, record sizes and key types).
+//!
+//! The statistics are updated incrementally whenever an anchor record is written, so that they
+//! are available without scanning all anchor records. They are stored in their own memory using
+//! the following layout (all values are little endian `u64`s):
+//! ```text
+//! ------------------------------------------- <- Address 0
+//! Anchors by number of devices    ↕ DEVICE_BUCKETS * 8 bytes
+//! -------------------------------------------
+//! Anchors by record size          ↕ RECORD_SIZE_BUCKETS * 8 bytes
+//! -------------------------------------------
+//! Sum of all record sizes         ↕ 8 bytes
+//! -------------------------------------------
+//! Devices by key type             ↕ KEY_TYPES * 8 bytes
+//! -------------------------------------------
+//! ```
+use crate::state::Anchor;
+use internet_identity_interface::KeyType;
+use std::convert::TryInto;
+
+/// Anchors with 0 to `DEVICE_BUCKETS - 2` devices are counted individually, the last bucket
+/// counts all anchors with more devices.
+pub const DEVICE_BUCKETS: usize = 12;
+/// Record sizes are counted in steps of 10% of the maximum record size.
+pub const RECORD_SIZE_BUCKETS: usize = 10;
+const KEY_TYPES: usize = 4;
+
+pub const SERIALIZED_SIZE: usize = (DEVICE_BUCKETS + RECORD_SIZE_BUCKETS + 1 + KEY_TYPES) * 8;
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AnchorStatistics {
+    /// Number of anchors by number of devices.
+    pub devices_per_anchor: [u64; DEVICE_BUCKETS],
+    /// Number of anchors by size of the encoded anchor record relative to the maximum size.
+    pub record_sizes: [u64; RECORD_SIZE_BUCKETS],
+    /// Sum of the sizes of all encoded anchor records (in bytes).
+    pub record_size_sum: u64,
+    /// Number of devices by key type, in the order unknown, platform, cross-platform and seed
+    /// phrase.
+    pub key_types: [u64; KEY_TYPES],
+}
+
+impl AnchorStatistics {
+    /// Adds an anchor record of the given encoded size to the statistics.
+    pub fn add(&mut self, anchor: &Anchor, record_size: usize, max_record_size: usize) {
+        self.update(anchor, record_size, max_record_size, |value| *value += 1);
+        self.record_size_sum += record_size as u64;
+    }
+
+    /// Removes an anchor record of the given encoded size from the statistics.
+    pub fn remove(&mut self, anchor: &Anchor, record_size: usize, max_record_size: usize) {
+        self.update(anchor, record_size, max_record_size, |value| {
+            *value = value.saturating_sub(1)
+        });
+        self.record_size_sum = self.record_size_sum.saturating_sub(record_size as u64);
+    }
+
+    fn update(
+        &mut self,
+        anchor: &Anchor,
+        record_size: usize,
+        max_record_size: usize,
+        f: impl Fn(&mut u64),
+    ) {
+        f(&mut self.devices_per_anchor[anchor.devices.len().min(DEVICE_BUCKETS - 1)]);
+        f(&mut self.record_sizes[record_size_bucket(record_size, max_record_size)]);
+        for device in &anchor.devices {
+            f(&mut self.key_types[key_type_index(device.key_type.as_ref())]);
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(SERIALIZED_SIZE);
+        for value in self
+            .devices_per_anchor
+            .iter()
+            .chain(self.record_sizes.iter())
+            .chain(std::iter::once(&self.record_size_sum))
+            .chain(self.key_types.iter())
+        {
+            buf.extend(&value.to_le_bytes());
+        }
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut values = bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+        let mut statistics = AnchorStatistics::default();
+        for value in statistics
+            .devices_per_anchor
+            .iter_mut()
+            .chain(statistics.record_sizes.iter_mut())
+            .chain(std::iter::once(&mut statistics.record_size_sum))
+            .chain(statistics.key_types.iter_mut())
+        {
+            *value = values.next().unwrap_or_default();
+        }
+        statistics
+    }
+}
+
+/// Returns the index of the record size bucket, i.e. the smallest multiple of 10% of the maximum
+/// record size that fits the record.
+pub fn record_size_bucket(record_size: usize, max_record_size: usize) -> usize {
+    let bucket = (record_size * RECORD_SIZE_BUCKETS + max_record_size - 1) / max_record_size;
+    bucket.clamp(1, RECORD_SIZE_BUCKETS) - 1
+}
+
+fn key_type_index(key_type: Option<&KeyType>) -> usize {
+    match key_type {
+        None | Some(KeyType::Unknown) => 0,
+        Some(KeyType::Platform) => 1,
+        Some(KeyType::CrossPlatform) => 2,
+        Some(KeyType::SeedPhrase) => 3,
+    }
+}
@@ -0,0 +1,251 @@
+//! Variable size record storage used by stable memory layout version 3.
+//!
+//! Records are addressed by their record number (i.e. the offset of the anchor within the range of
+//! anchors assigned to the canister). The store is backed by two memories.
+//!
+//! ## Index Memory
+//! ```text
+//! ------------------------------------------- <- Address 0
+//! Record 0: data address      ↕ 8 bytes
+//! -------------------------------------------
+//! Record 0: length            ↕ 4 bytes
+//! -------------------------------------------
+//! Record 0: chunk capacity    ↕ 4 bytes
+//! ------------------------------------------- <- Address 16
+//! Record 1: data address      ↕ 8 bytes
+//! -------------------------------------------
+//! ...
+//! ```
+//! A data address of 0 means that no data has been written for that record (yet). A data address
+//! of `u64::MAX` marks a deleted record (tombstone) which does not occupy a chunk.
+//!
+//! ## Data Memory
+//! ```text
+//! ------------------------------------------- <- Address 0
+//! Next unallocated address    ↕ 8 bytes
+//! -------------------------------------------
+//! Free list heads             ↕ NUM_SIZE_CLASSES * 8 bytes
+//! ------------------------------------------- <- Address 128 = DATA_HEADER_SIZE
+//! Chunks
+//! -------------------------------------------
+//! ```
+//! Every record is stored in a chunk of which the capacity is the smallest power of two (but at
+//! least [MIN_CHUNK_SIZE]) that fits the record. Records are updated in place as long as they fit
+//! the capacity of their chunk. Otherwise, the record is moved to a new chunk and the old chunk is
+//! put on the free list of its size class for later reuse. The first 8 bytes of a free chunk hold
+//! the address of the next free chunk of the same size class (0 terminates the list).
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::Memory;
+use std::convert::TryInto;
+
+#[cfg(test)]
+mod tests;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+const INDEX_ENTRY_SIZE: u64 = 16;
+const DATA_HEADER_SIZE: u64 = 128;
+const MIN_CHUNK_SIZE: u32 = 64;
+const NUM_SIZE_CLASSES: usize = 8;
+const TOMBSTONE_ADDRESS: u64 = u64::MAX;
+
+/// The maximum size of a single record (8 KiB).
+pub const MAX_RECORD_SIZE: usize = (MIN_CHUNK_SIZE as usize) << (NUM_SIZE_CLASSES - 1);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct IndexEntry {
+    address: u64,
+    len: u32,
+    capacity: u32,
+}
+
+pub struct RecordStore<M: Memory> {
+    index_memory: M,
+    data_memory: M,
+    next_free: u64,
+    free_lists: [u64; NUM_SIZE_CLASSES],
+}
+
+impl<M: Memory> RecordStore<M> {
+    /// Loads the record store from the given memories.
+    /// An empty record store is created if the data memory is empty.
+    pub fn init(index_memory: M, data_memory: M) -> Self {
+        let mut store = Self {
+            index_memory,
+            data_memory,
+            next_free: DATA_HEADER_SIZE,
+            free_lists: [0; NUM_SIZE_CLASSES],
+        };
+
+        if store.data_memory.size() == 0 {
+            store.flush_header();
+            return store;
+        }
+
+        let mut buf = [0u8; 8 * (NUM_SIZE_CLASSES + 1)];
+        store.data_memory.read(0, &mut buf);
+        store.next_free = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+        for (class, head) in store.free_lists.iter_mut().enumerate() {
+            let start = 8 * (class + 1);
+            *head = u64::from_le_bytes(buf[start..start + 8].try_into().unwrap());
+        }
+        store
+    }
+
+    /// Returns true if data has been written for the given record (including deleted records).
+    pub fn contains(&self, record_number: u32) -> bool {
+        self.index_entry(record_number).is_some()
+    }
+
+    /// Returns true if the given record has been deleted.
+    pub fn is_deleted(&self, record_number: u32) -> bool {
+        matches!(self.index_entry(record_number), Some(entry) if entry.address == TOMBSTONE_ADDRESS)
+    }
+
+    /// Reads the data of the given record, if any. Deleted records read as empty data.
+    pub fn read(&self, record_number: u32) -> Option<Vec<u8>> {
+        let entry = self.index_entry(record_number)?;
+        if entry.address == TOMBSTONE_ADDRESS {
+            return Some(vec![]);
+        }
+        let mut buf = vec![0; entry.len as usize];
+        self.data_memory.read(entry.address, &mut buf);
+        Some(buf)
+    }
+
+    /// Writes the data of the given record, replacing any previous data.
+    ///
+    /// Panics if the data is larger than [MAX_RECORD_SIZE].
+    pub fn write(&mut self, record_number: u32, data: &[u8]) {
+        assert!(
+            data.len() <= MAX_RECORD_SIZE,
+            "bug: record size {} exceeds the maximum record size {}",
+            data.len(),
+            MAX_RECORD_SIZE
+        );
+        let len = data.len() as u32;
+
+        let mut entry = match self.index_entry(record_number) {
+            Some(entry) if entry.address == TOMBSTONE_ADDRESS => self.allocate_chunk(len),
+            Some(entry) if len <= entry.capacity => entry,
+            Some(entry) => {
+                let new_entry = self.allocate_chunk(len);
+                self.free_chunk(entry.address, entry.capacity);
+                new_entry
+            }
+            None => self.allocate_chunk(len),
+        };
+        entry.len = len;
+
+        write_bytes(&mut self.data_memory, entry.address, data);
+        self.write_index_entry(record_number, entry);
+    }
+
+    /// Deletes the data of the given record and replaces it with a tombstone. The chunk of the
+    /// record is put on the free list.
+    pub fn delete(&mut self, record_number: u32) {
+        match self.index_entry(record_number) {
+            Some(entry) if entry.address == TOMBSTONE_ADDRESS => return,
+            Some(entry) => self.free_chunk(entry.address, entry.capacity),
+            None => {}
+        }
+        self.write_index_entry(
+            record_number,
+            IndexEntry {
+                address: TOMBSTONE_ADDRESS,
+                len: 0,
+                capacity: 0,
+            },
+        );
+    }
+
+    fn index_entry(&self, record_number: u32) -> Option<IndexEntry> {
+        let address = record_number as u64 * INDEX_ENTRY_SIZE;
+        if address + INDEX_ENTRY_SIZE > self.index_memory.size() * WASM_PAGE_SIZE {
+            // the index has not been grown to this record yet
+            return None;
+        }
+
+        let mut buf = [0u8; INDEX_ENTRY_SIZE as usize];
+        self.index_memory.read(address, &mut buf);
+        let entry = IndexEntry {
+            address: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
+            len: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+            capacity: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+        };
+        if entry.address == 0 {
+            return None;
+        }
+        Some(entry)
+    }
+
+    fn write_index_entry(&mut self, record_number: u32, entry: IndexEntry) {
+        let mut buf = Vec::with_capacity(INDEX_ENTRY_SIZE as usize);
+        buf.extend(&entry.address.to_le_bytes());
+        buf.extend(&entry.len.to_le_bytes());
+        buf.extend(&entry.capacity.to_le_bytes());
+        write_bytes(
+            &mut self.index_memory,
+            record_number as u64 * INDEX_ENTRY_SIZE,
+            &buf,
+        );
+    }
+
+    /// Returns an empty chunk that can hold `len` bytes, reusing a free chunk if possible.
+    fn allocate_chunk(&mut self, len: u32) -> IndexEntry {
+        let class = size_class(len);
+        let capacity = MIN_CHUNK_SIZE << class;
+
+        let address = match self.free_lists[class] {
+            0 => {
+                let address = self.next_free;
+                self.next_free += capacity as u64;
+                address
+            }
+            head => {
+                let mut next = [0u8; 8];
+                self.data_memory.read(head, &mut next);
+                self.free_lists[class] = u64::from_le_bytes(next);
+                head
+            }
+        };
+        self.flush_header();
+
+        IndexEntry {
+            address,
+            len: 0,
+            capacity,
+        }
+    }
+
+    fn free_chunk(&mut self, address: u64, capacity: u32) {
+        let class = size_class(capacity);
+        write_bytes(
+            &mut self.data_memory,
+            address,
+            &self.free_lists[class].to_le_bytes(),
+        );
+        self.free_lists[class] = address;
+        self.flush_header();
+    }
+
+    fn flush_header(&mut self) {
+        let mut buf = Vec::with_capacity(8 * (NUM_SIZE_CLASSES + 1));
+        buf.extend(&self.next_free.to_le_bytes());
+        for head in self.free_lists {
+            buf.extend(&head.to_le_bytes());
+        }
+        write_bytes(&mut self.data_memory, 0, &buf);
+    }
+}
+
+/// Returns the index of the smallest size class with chunks that can hold `len` bytes.
+fn size_class(len: u32) -> usize {
+    let capacity = len.max(MIN_CHUNK_SIZE).next_power_of_two();
+    (capacity.trailing_zeros() - MIN_CHUNK_SIZE.trailing_zeros()) as usize
+}
+
+fn write_bytes<M: Memory>(memory: &mut M, address: u64, bytes: &[u8]) {
+    // the writer grows the memory as required
+    let mut writer = Writer::new(memory, address);
+    writer.write(bytes).expect("bug: failed to grow memory");
+}
@@ -0,0 +1,110 @@
+use crate::storage::record_store::{
+    size_class, RecordStore, DATA_HEADER_SIZE, MAX_RECORD_SIZE, MIN_CHUNK_SIZE,
+};
+use ic_stable_structures::VectorMemory;
+
+#[test]
+fn should_compute_size_class() {
+    assert_eq!(size_class(0), 0);
+    assert_eq!(size_class(MIN_CHUNK_SIZE), 0);
+    assert_eq!(size_class(MIN_CHUNK_SIZE + 1), 1);
+    assert_eq!(size_class(MAX_RECORD_SIZE as u32), 7);
+}
+
+#[test]
+fn should_not_contain_unwritten_records() {
+    let store = RecordStore::init(VectorMemory::default(), VectorMemory::default());
+
+    assert!(!store.contains(0));
+    assert!(!store.contains(1000));
+    assert_eq!(store.read(0), None);
+}
+
+#[test]
+fn should_write_and_read_records() {
+    let mut store = RecordStore::init(VectorMemory::default(), VectorMemory::default());
+
+    store.write(0, &[1; 10]);
+    store.write(7, &[2; 100]);
+    store.write(3, &[]);
+
+    assert_eq!(store.read(0).unwrap(), vec![1; 10]);
+    assert_eq!(store.read(7).unwrap(), vec![2; 100]);
+    assert_eq!(store.read(3).unwrap(), Vec::<u8>::new());
+    assert!(!store.contains(1));
+}
+
+#[test]
+fn should_update_record_in_place_if_it_fits() {
+    let mut store = RecordStore::init(VectorMemory::default(), VectorMemory::default());
+
+    store.write(0, &[1; 10]);
+    let entry = store.index_entry(0).unwrap();
+    store.write(0, &[2; 60]);
+
+    let updated_entry = store.index_entry(0).unwrap();
+    assert_eq!(updated_entry.address, entry.address);
+    assert_eq!(updated_entry.len, 60);
+    assert_eq!(store.read(0).unwrap(), vec![2; 60]);
+}
+
+#[test]
+fn should_move_record_and_reuse_freed_chunk() {
+    let mut store = RecordStore::init(VectorMemory::default(), VectorMemory::default());
+
+    store.write(0, &[1; 10]);
+    let freed_address = store.index_entry(0).unwrap().address;
+    store.write(0, &[2; 1000]);
+    assert_ne!(store.index_entry(0).unwrap().address, freed_address);
+
+    store.write(1, &[3; 20]);
+    assert_eq!(store.index_entry(1).unwrap().address, freed_address);
+
+    assert_eq!(store.read(0).unwrap(), vec![2; 1000]);
+    assert_eq!(store.read(1).unwrap(), vec![3; 20]);
+}
+
+#[test]
+fn should_restore_record_store_from_memory() {
+    let index_memory = VectorMemory::default();
+    let data_memory = VectorMemory::default();
+    let mut store = RecordStore::init(index_memory.clone(), data_memory.clone());
+    store.write(0, &[1; 10]);
+    store.write(0, &[2; 1000]);
+    store.write(1, &[3; 300]);
+
+    let mut store = RecordStore::init(index_memory, data_memory);
+    assert_eq!(store.read(0).unwrap(), vec![2; 1000]);
+    assert_eq!(store.read(1).unwrap(), vec![3; 300]);
+
+    // the free list has been restored as well
+    store.write(2, &[4; 10]);
+    assert_eq!(store.index_entry(2).unwrap().address, DATA_HEADER_SIZE);
+}
+
+#[test]
+fn should_delete_records_and_reuse_their_chunks() {
+    let mut store = RecordStore::init(VectorMemory::default(), VectorMemory::default());
+
+    store.write(0, &[1; 10]);
+    let freed_address = store.index_entry(0).unwrap().address;
+    store.delete(0);
+    store.delete(5);
+
+    assert!(store.contains(0));
+    assert!(store.is_deleted(0));
+    assert!(store.is_deleted(5));
+    assert_eq!(store.read(0).unwrap(), Vec::<u8>::new());
+
+    store.write(1, &[2; 20]);
+    assert_eq!(store.index_entry(1).unwrap().address, freed_address);
+    assert!(!store.is_deleted(1));
+}
+
+#[test]
+#[should_panic(expected = "exceeds the maximum record size")]
+fn should_not_write_records_exceeding_max_size() {
+    let mut store = RecordStore::init(VectorMemory::default(), VectorMemory::default());
+
+    store.write(0, &vec![0; MAX_RECORD_SIZE + 1]);
+}
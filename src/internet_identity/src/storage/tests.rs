@@ -1,14 +1,26 @@
 use crate::archive::{ArchiveData, ArchiveInfo, ArchiveState};
-use crate::state::{Anchor, DeviceDataInternal, PersistentState};
-use crate::storage::{Header, PersistentStateError, StorageError};
+use crate::state::{
+    Anchor, ChallengeInfo, DeviceDataInternal, PersistentState, RegistrationState,
+    TentativeDeviceRegistration, UsageMetrics,
+};
+use crate::storage::anchor_statistics::{record_size_bucket, AnchorStatistics};
+use crate::storage::{
+    Header, PersistentStateError, StorageError, MAX_SESSIONS_PER_ANCHOR, PERSISTENT_STATE_MEMORY_ID,
+};
 use crate::Storage;
-use candid::Principal;
+use candid::{CandidType, Deserialize, Principal};
 use ic_stable_structures::{Memory, VectorMemory};
-use internet_identity_interface::{DeviceProtection, KeyType, Purpose};
+use internet_identity_interface::{
+    BufferedEntry, CaptchaType, DeviceData, DeviceIdentifier, DeviceProtection, KeyType,
+    MetadataEntry, MigrationState, PendingRecoveryOperation, Purpose, RateLimitConfig,
+    RecoveryDeviceChange, Session, WebAuthnVerificationConfig,
+};
 use serde_bytes::ByteBuf;
+use std::collections::HashMap;
 
-const HEADER_SIZE: usize = 58;
+const HEADER_SIZE: usize = 82;
 const RESERVED_HEADER_BYTES: u64 = 512;
+const WASM_PAGE_SIZE: u64 = 65536;
 const PERSISTENT_STATE_MAGIC: [u8; 4] = *b"IIPS";
 
 #[test]
@@ -33,7 +45,7 @@ fn should_serialize_header() {
 
     let mut buf = vec![0; HEADER_SIZE];
     memory.read(0, &mut buf);
-    assert_eq!(buf, hex::decode("49494301000000000100000000000000020000000000000000080505050505050505050505050505050505050505050505050505050505050505").unwrap());
+    assert_eq!(buf, hex::decode("49494303000000000100000000000000020000000000000000080505050505050505050505050505050505050505050505050505050505050505000000000000000064000000000000000000000001000000").unwrap());
 }
 
 #[test]
@@ -46,6 +58,7 @@ fn should_recover_header_from_memory() {
     assert_eq!(storage.assigned_user_number_range(), (123456, 654321));
     assert_eq!(storage.salt().unwrap(), &[67u8; 32]);
     assert_eq!(storage.user_count(), 5);
+    assert_eq!(storage.version(), 1);
 }
 
 #[test]
@@ -55,49 +68,129 @@ fn should_update_header() {
     memory.write(0, &hex::decode("494943010500000040e2010000000000f1fb09000000000000084343434343434343434343434343434343434343434343434343434343434343").unwrap());
 
     let mut storage = Storage::from_memory(memory.clone()).unwrap();
-    storage.set_user_number_range((1234567, 5_000_000));
+    storage.set_user_number_range((1234567, 1_500_000));
     storage.allocate_user_number();
     storage.flush();
 
     let mut buf = vec![0; HEADER_SIZE];
     memory.read(0, &mut buf);
-    assert_eq!(buf, hex::decode("494943010600000087d6120000000000404b4c000000000000084343434343434343434343434343434343434343434343434343434343434343").unwrap());
+    assert_eq!(buf, hex::decode("494943010600000087d612000000000060e3160000000000000843434343434343434343434343434343434343434343434343434343434343430000000000000000000000000000000000000000de400000").unwrap());
 }
 
 #[test]
-fn should_serialize_first_record() {
+fn should_write_and_read_record() {
     let memory = VectorMemory::default();
-    let mut storage = Storage::new((123, 456), memory.clone());
+    let mut storage = Storage::new((123, 456), memory);
     let user_number = storage.allocate_user_number().unwrap();
     assert_eq!(user_number, 123u64);
 
     let anchor = sample_anchor_record();
     storage.write(user_number, anchor.clone()).unwrap();
 
-    let mut buf = [0u8; 192];
-    memory.read(512, &mut buf);
-    let decoded_from_memory: Vec<DeviceDataInternal> = candid::decode_one(&buf[2..]).unwrap();
-    assert_eq!(decoded_from_memory, anchor.devices);
+    assert_eq!(storage.read(user_number).unwrap(), anchor);
 }
 
 #[test]
-fn should_serialize_subsequent_record_to_expected_memory_location() {
-    const EXPECTED_RECORD_OFFSET: u64 = 204_800; // 100 * max anchor size
+fn should_read_records_without_creation_time() {
     let memory = VectorMemory::default();
-    let mut storage = Storage::new((123, 456), memory.clone());
-    for _ in 0..100 {
-        storage.allocate_user_number().unwrap();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    let user_number = storage.allocate_user_number().unwrap();
+    storage.write(user_number, sample_anchor_record()).unwrap();
+
+    // overwrite the record with one written before the creation time was recorded
+    #[derive(CandidType)]
+    struct PreviousAnchor {
+        devices: Vec<DeviceDataInternal>,
     }
+    let previous = PreviousAnchor {
+        devices: sample_anchor_record().devices,
+    };
+    storage
+        .anchor_records
+        .write(0, &candid::encode_one(previous).unwrap());
+
+    assert_eq!(
+        storage.read(user_number).unwrap(),
+        Anchor {
+            created_at: None,
+            ..sample_anchor_record()
+        }
+    );
+}
+
+#[test]
+fn should_keep_records_with_metadata_readable_by_previous_releases() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
     let user_number = storage.allocate_user_number().unwrap();
-    assert_eq!(user_number, 223u64);
+    let anchor = Anchor {
+        metadata: Some(HashMap::from([(
+            "language".to_string(),
+            MetadataEntry::String("de".to_string()),
+        )])),
+        ..sample_anchor_record()
+    };
+    storage.write(user_number, anchor.clone()).unwrap();
+    assert_eq!(storage.read(user_number).unwrap(), anchor);
 
-    let anchor = sample_anchor_record();
+    // a release without metadata ignores the additional field
+    #[derive(Debug, Deserialize, Eq, PartialEq)]
+    struct PreviousAnchor {
+        devices: Vec<DeviceDataInternal>,
+        created_at: Option<u64>,
+    }
+    let buf = storage.anchor_records.read(0).unwrap();
+    assert_eq!(
+        candid::decode_one::<PreviousAnchor>(&buf).unwrap(),
+        PreviousAnchor {
+            devices: anchor.devices,
+            created_at: anchor.created_at,
+        }
+    );
+}
+
+#[test]
+fn should_write_records_larger_than_genesis_entry_size() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), memory);
+    let user_number = storage.allocate_user_number().unwrap();
+
+    let anchor = large_anchor_record(10);
+    assert!(candid::encode_one(&anchor).unwrap().len() > 2048);
     storage.write(user_number, anchor.clone()).unwrap();
 
-    let mut buf = [0u8; 192];
-    memory.read(512 + EXPECTED_RECORD_OFFSET, &mut buf);
-    let decoded_from_memory: Vec<DeviceDataInternal> = candid::decode_one(&buf[2..]).unwrap();
-    assert_eq!(decoded_from_memory, anchor.devices);
+    assert_eq!(storage.read(user_number).unwrap(), anchor);
+}
+
+#[test]
+fn should_overwrite_record_with_record_of_different_size() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), memory);
+    let first = storage.allocate_user_number().unwrap();
+    let second = storage.allocate_user_number().unwrap();
+    storage.write(first, sample_anchor_record()).unwrap();
+    storage.write(second, sample_anchor_record()).unwrap();
+
+    storage.write(first, large_anchor_record(10)).unwrap();
+    assert_eq!(storage.read(first).unwrap(), large_anchor_record(10));
+    assert_eq!(storage.read(second).unwrap(), sample_anchor_record());
+
+    storage.write(first, Anchor::default()).unwrap();
+    assert_eq!(storage.read(first).unwrap(), Anchor::default());
+    assert_eq!(storage.read(second).unwrap(), sample_anchor_record());
+}
+
+#[test]
+fn should_not_write_record_exceeding_size_limit() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), memory);
+    let user_number = storage.allocate_user_number().unwrap();
+
+    let result = storage.write(user_number, large_anchor_record(100));
+    assert!(matches!(
+        result,
+        Err(StorageError::EntrySizeLimitExceeded(_))
+    ));
 }
 
 #[test]
@@ -111,54 +204,280 @@ fn should_not_write_using_anchor_number_outside_allocated_range() {
 }
 
 #[test]
-fn should_deserialize_first_record() {
+fn should_not_read_using_anchor_number_outside_allocated_range() {
     let memory = VectorMemory::default();
-    memory.grow(1);
     let mut storage = Storage::new((123, 456), memory.clone());
+    storage.allocate_user_number().unwrap();
+
+    let result = storage.read(222);
+    assert!(matches!(result, Err(StorageError::BadUserNumber(_))))
+}
+
+#[test]
+fn should_not_read_allocated_but_unwritten_record() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), memory);
     let user_number = storage.allocate_user_number().unwrap();
-    assert_eq!(user_number, 123u64);
 
-    let anchor = sample_anchor_record();
-    let buf = candid::encode_one(&anchor.devices).unwrap();
-    memory.write(512, &(buf.len() as u16).to_le_bytes());
-    memory.write(514, &buf);
+    let result = storage.read(user_number);
+    assert!(matches!(result, Err(StorageError::BadUserNumber(_))))
+}
+
+#[test]
+fn should_keep_genesis_layout_until_switch_is_requested() {
+    let memory = genesis_memory(5);
+    let mut genesis_bytes = vec![0; 5 * 2048];
+    memory.read(RESERVED_HEADER_BYTES, &mut genesis_bytes);
+
+    let storage = Storage::from_memory(memory.clone()).unwrap();
+    assert_eq!(storage.version(), 1);
+    assert_eq!(storage.migration_state(), MigrationState::NotStarted);
+
+    let mut storage = Storage::from_memory(memory.clone()).unwrap();
+    storage.migrate_records();
+    assert_eq!(storage.migration_state(), MigrationState::NotStarted);
 
-    let read_from_storage = storage.read(123).unwrap();
-    assert_eq!(read_from_storage, anchor);
+    // the header fields known to previous releases and the anchor records are unchanged
+    let mut buf = vec![0; 58];
+    memory.read(0, &mut buf);
+    assert_eq!(buf, hex::decode("49494301050000001027000000000000f82a00000000000000084343434343434343434343434343434343434343434343434343434343434343").unwrap());
+    let mut buf = vec![0; 5 * 2048];
+    memory.read(RESERVED_HEADER_BYTES, &mut buf);
+    assert_eq!(buf, genesis_bytes);
 }
 
 #[test]
-fn should_deserialize_subsequent_record_at_expected_memory_location() {
-    const EXPECTED_RECORD_OFFSET: u64 = 204_800; // 100 * max anchor size
+fn should_write_records_readable_by_previous_releases_before_switch() {
     let memory = VectorMemory::default();
-    memory.grow(4); // grow memory to accommodate a write to EXPECTED_RECORD_OFFSET
-    let mut storage = Storage::new((123, 456), memory.clone());
-    for _ in 0..100 {
-        storage.allocate_user_number().unwrap();
-    }
+    let mut storage = Storage::new_with_genesis_layout((10_000, 11_000), memory.clone());
     let user_number = storage.allocate_user_number().unwrap();
-    assert_eq!(user_number, 223u64);
+    let anchor = Anchor {
+        metadata: Some(HashMap::from([(
+            "language".to_string(),
+            MetadataEntry::String("de".to_string()),
+        )])),
+        ..sample_anchor_record()
+    };
+    storage.write(user_number, anchor.clone()).unwrap();
+    assert_eq!(storage.read(user_number).unwrap(), anchor);
 
-    let anchor = sample_anchor_record();
-    let buf = candid::encode_one(&anchor.devices).unwrap();
+    // previous releases read the devices from the genesis location
+    let mut buf = [0u8; 2048];
+    memory.read(RESERVED_HEADER_BYTES, &mut buf);
+    let len = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+    let devices: Vec<DeviceDataInternal> = candid::decode_one(&buf[2..2 + len]).unwrap();
+    assert_eq!(devices, anchor.devices);
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.version(), 1);
+    assert_eq!(storage.read(user_number).unwrap(), anchor);
+}
+
+#[test]
+fn should_not_write_devices_exceeding_genesis_entry_size_before_switch() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new_with_genesis_layout((10_000, 11_000), memory);
+    let user_number = storage.allocate_user_number().unwrap();
+
+    let result = storage.write(user_number, large_anchor_record(10));
+    assert!(matches!(
+        result,
+        Err(StorageError::EntrySizeLimitExceeded(_))
+    ));
+
+    // the switch lifts the limit
+    storage.set_migration_batch_size(100);
+    storage.write(user_number, large_anchor_record(10)).unwrap();
+    assert_eq!(storage.read(user_number).unwrap(), large_anchor_record(10));
+}
+
+#[test]
+fn should_remove_devices_of_deleted_anchor_before_switch() {
+    let memory = genesis_memory(3);
+    let mut storage = Storage::from_memory(memory.clone()).unwrap();
+
+    storage.delete(10_001).unwrap();
+    assert!(matches!(
+        storage.read(10_001),
+        Err(StorageError::AnchorDeleted(_))
+    ));
+
+    // previous releases read an anchor without devices
+    let mut buf = [0u8; 2048];
+    memory.read(RESERVED_HEADER_BYTES + 2048, &mut buf);
+    let len = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+    let devices: Vec<DeviceDataInternal> = candid::decode_one(&buf[2..2 + len]).unwrap();
+    assert!(devices.is_empty());
+}
+
+#[test]
+fn should_merge_persistent_state_written_by_previous_release() {
+    let memory = genesis_memory(3);
+    let mut storage = Storage::from_memory(memory.clone()).unwrap();
+    storage.write_persistent_state(&sample_persistent_state());
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        sample_persistent_state()
+    );
+
+    // a previous release only writes the state at the genesis location (and ignores new fields)
+    let previous_state = PersistentState {
+        canister_creation_cycles_cost: 42,
+        ..PersistentState::default()
+    };
     memory.write(
-        512 + EXPECTED_RECORD_OFFSET,
-        &(buf.len() as u16).to_le_bytes(),
+        RESERVED_HEADER_BYTES + 3 * 2048,
+        &persistent_state_bytes(&previous_state),
     );
-    memory.write(514 + EXPECTED_RECORD_OFFSET, &buf);
 
-    let read_from_storage = storage.read(223).unwrap();
-    assert_eq!(read_from_storage, anchor);
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        PersistentState {
+            archive_info: ArchiveInfo::default(),
+            canister_creation_cycles_cost: 42,
+            ..sample_persistent_state()
+        }
+    );
 }
 
 #[test]
-fn should_not_read_using_anchor_number_outside_allocated_range() {
-    let memory = VectorMemory::default();
-    let mut storage = Storage::new((123, 456), memory.clone());
-    storage.allocate_user_number().unwrap();
+fn should_switch_genesis_layout_to_v3() {
+    let memory = genesis_memory(5);
 
-    let result = storage.read(222);
-    assert!(matches!(result, Err(StorageError::BadUserNumber(_))))
+    let mut storage = Storage::from_memory(memory.clone()).unwrap();
+    storage.set_migration_batch_size(100);
+    assert_eq!(storage.version(), 3);
+    assert_eq!(storage.user_count(), 5);
+    assert_eq!(
+        storage.migration_state(),
+        MigrationState::InProgress {
+            anchors_left: 5,
+            batch_size: 100
+        }
+    );
+
+    let mut buf = vec![0; HEADER_SIZE];
+    memory.read(0, &mut buf);
+    assert_eq!(buf, hex::decode("49494303050000001027000000000000f82a00000000000000084343434343434343434343434343434343434343434343434343434343434343050000000000000064000000000000000000000030000000").unwrap());
+}
+
+#[test]
+fn should_read_genesis_records_before_migration() {
+    let memory = genesis_memory(5);
+
+    let mut storage = Storage::from_memory(memory).unwrap();
+    storage.set_migration_batch_size(100);
+    for i in 0..5 {
+        assert_eq!(
+            storage.read(10_000 + i).unwrap(),
+            genesis_anchor_record(i as usize)
+        );
+    }
+}
+
+#[test]
+fn should_migrate_genesis_records_in_batches() {
+    let memory = genesis_memory(5);
+
+    let mut storage = Storage::from_memory(memory.clone()).unwrap();
+    storage.set_migration_batch_size(2);
+
+    storage.migrate_records();
+    assert_eq!(
+        storage.migration_state(),
+        MigrationState::InProgress {
+            anchors_left: 3,
+            batch_size: 2
+        }
+    );
+    storage.migrate_records();
+    storage.migrate_records();
+    assert_eq!(storage.migration_state(), MigrationState::Finished);
+
+    // clear the genesis records to make sure they are no longer used
+    memory.write(RESERVED_HEADER_BYTES, &vec![0; 5 * 2048]);
+    for i in 0..5 {
+        assert_eq!(
+            storage.read(10_000 + i).unwrap(),
+            genesis_anchor_record(i as usize)
+        );
+    }
+}
+
+#[test]
+fn should_pause_migration_with_batch_size_zero() {
+    let memory = genesis_memory(5);
+
+    let mut storage = Storage::from_memory(memory).unwrap();
+    storage.set_migration_batch_size(0);
+
+    storage.migrate_records();
+    assert_eq!(
+        storage.migration_state(),
+        MigrationState::Paused { anchors_left: 5 }
+    );
+}
+
+#[test]
+fn should_not_overwrite_genesis_records_written_during_migration() {
+    let memory = genesis_memory(5);
+
+    let mut storage = Storage::from_memory(memory).unwrap();
+    storage.set_migration_batch_size(100);
+    storage.write(10_002, sample_anchor_record()).unwrap();
+    assert_eq!(storage.read(10_002).unwrap(), sample_anchor_record());
+
+    storage.migrate_records();
+    assert_eq!(storage.migration_state(), MigrationState::Finished);
+    assert_eq!(storage.read(10_001).unwrap(), genesis_anchor_record(1));
+    assert_eq!(storage.read(10_002).unwrap(), sample_anchor_record());
+}
+
+#[test]
+fn should_keep_migration_progress_across_reloads() {
+    let memory = genesis_memory(5);
+
+    let mut storage = Storage::from_memory(memory.clone()).unwrap();
+    storage.set_migration_batch_size(3);
+    storage.migrate_records();
+    let user_number = storage.allocate_user_number().unwrap();
+    storage.write(user_number, sample_anchor_record()).unwrap();
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.migration_state(),
+        MigrationState::InProgress {
+            anchors_left: 2,
+            batch_size: 3
+        }
+    );
+    for i in 0..5 {
+        assert_eq!(
+            storage.read(10_000 + i).unwrap(),
+            genesis_anchor_record(i as usize)
+        );
+    }
+    assert_eq!(storage.read(user_number).unwrap(), sample_anchor_record());
+}
+
+#[test]
+fn should_not_touch_genesis_records_when_writing_new_anchors() {
+    let memory = genesis_memory(31);
+    let mut genesis_bytes = vec![0; 31 * 2048];
+    memory.read(RESERVED_HEADER_BYTES, &mut genesis_bytes);
+
+    let mut storage = Storage::from_memory(memory.clone()).unwrap();
+    storage.set_migration_batch_size(100);
+    for _ in 0..10 {
+        let user_number = storage.allocate_user_number().unwrap();
+        storage.write(user_number, large_anchor_record(5)).unwrap();
+    }
+    storage.write_persistent_state(&sample_persistent_state());
+
+    let mut buf = vec![0; 31 * 2048];
+    memory.read(RESERVED_HEADER_BYTES, &mut buf);
+    assert_eq!(buf, genesis_bytes);
 }
 
 #[test]
@@ -175,22 +494,23 @@ fn should_save_and_restore_persistent_state() {
 }
 
 #[test]
-fn should_save_persistent_state_at_expected_memory_address() {
+fn should_restore_persistent_state_after_reload() {
     let memory = VectorMemory::default();
-    let mut storage = Storage::new((10_000, 3_784_873), memory.clone());
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
     storage.flush();
-
     storage.write_persistent_state(&sample_persistent_state());
 
-    let mut buf = vec![0u8; 4];
-    memory.read(RESERVED_HEADER_BYTES, &mut buf);
-    assert_eq!(buf, PERSISTENT_STATE_MAGIC);
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        sample_persistent_state()
+    );
 }
 
 #[test]
 fn should_not_find_persistent_state() {
     let memory = VectorMemory::default();
-    let mut storage = Storage::new((10_000, 3_784_873), memory.clone());
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
     storage.flush();
 
     let result = storage.read_persistent_state();
@@ -201,119 +521,648 @@ fn should_not_find_persistent_state() {
 fn should_not_find_persistent_state_on_magic_bytes_mismatch() {
     let memory = VectorMemory::default();
 
-    let mut storage = Storage::new((10_000, 3_784_873), memory.clone());
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
     storage.flush();
 
-    memory.write(RESERVED_HEADER_BYTES, b"IIPX"); // correct magic bytes are IIPS
+    let mut persistent_state_memory = storage.memory_manager.get(PERSISTENT_STATE_MEMORY_ID);
+    persistent_state_memory.grow(1);
+    persistent_state_memory.write(0, b"IIPX"); // correct magic bytes are IIPS
 
     let result = storage.read_persistent_state();
     assert!(matches!(result, Err(PersistentStateError::NotFound)))
 }
 
 #[test]
-fn should_save_persistent_state_at_expected_memory_address_with_anchors() {
-    const EXPECTED_ADDRESS: u64 = RESERVED_HEADER_BYTES + 100 * 2048; // number of anchors is 100
+fn should_serialize_persistent_state_with_version() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    storage.write_persistent_state(&sample_persistent_state());
+
+    let mut buf = vec![0u8; 5];
+    storage
+        .memory_manager
+        .get(PERSISTENT_STATE_MEMORY_ID)
+        .read(0, &mut buf);
+    assert_eq!(buf, b"IIPS\x01");
+}
+
+#[test]
+fn should_not_read_persistent_state_with_unsupported_version() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    storage.write_persistent_state(&sample_persistent_state());
+
+    storage
+        .memory_manager
+        .get(PERSISTENT_STATE_MEMORY_ID)
+        .write(4, &[2]);
 
+    let result = storage.read_persistent_state();
+    assert!(matches!(
+        result,
+        Err(PersistentStateError::UnsupportedVersion(2))
+    ))
+}
+
+#[test]
+fn should_overwrite_persistent_state_with_smaller_state() {
     let memory = VectorMemory::default();
-    let mut storage = Storage::new((10_000, 3_784_873), memory.clone());
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    storage.write_persistent_state(&sample_persistent_state());
+    storage.write_persistent_state(&PersistentState::default());
+
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        PersistentState::default()
+    );
+}
+
+#[test]
+fn should_keep_persistent_state_when_writing_anchors() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
     storage.flush();
 
+    storage.allocate_user_number().unwrap();
+    storage.write_persistent_state(&sample_persistent_state());
+
     for _ in 0..100 {
-        storage.allocate_user_number().unwrap();
+        let anchor = storage.allocate_user_number().unwrap();
+        storage.write(anchor, large_anchor_record(5)).unwrap();
     }
 
-    storage.write_persistent_state(&sample_persistent_state());
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        sample_persistent_state()
+    );
+}
+
+#[test]
+fn should_use_default_persistent_state_for_v1_without_persistent_state() {
+    let memory = genesis_memory(32);
+
+    let storage = Storage::from_memory(memory).unwrap();
 
-    let mut buf = vec![0u8; 4];
-    memory.read(EXPECTED_ADDRESS, &mut buf);
-    assert_eq!(buf, PERSISTENT_STATE_MAGIC);
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        PersistentState::default()
+    );
 }
 
 /// This tests verifies that address calculation is correct for 64bit addresses.
 /// Note: this test takes about 8GB of memory.
 #[test]
-fn should_save_persistent_state_at_expected_memory_address_with_many_anchors() {
+fn should_read_persistent_state_from_genesis_layout_with_many_anchors() {
     let memory = VectorMemory::default();
     memory.grow(1);
     memory.write(0, &hex::decode("49494301C0C62D001027000000000000a9c039000000000000084343434343434343434343434343434343434343434343434343434343434343").unwrap());
-    const EXPECTED_ADDRESS: u64 = RESERVED_HEADER_BYTES + 3_000_000 * 2048; // number of anchors is 100
+    const GENESIS_ADDRESS: u64 = RESERVED_HEADER_BYTES + 3_000_000 * 2048; // number of anchors is 3'000'000
 
-    let mut storage = Storage::from_memory(memory.clone()).unwrap();
-    storage.write_persistent_state(&sample_persistent_state());
+    let persistent_state_bytes = persistent_state_bytes(&sample_persistent_state());
+    memory.grow((GENESIS_ADDRESS + persistent_state_bytes.len() as u64) / WASM_PAGE_SIZE + 1);
+    memory.write(GENESIS_ADDRESS, &persistent_state_bytes);
 
-    let mut buf = vec![0u8; 4];
-    memory.read(EXPECTED_ADDRESS, &mut buf);
-    assert_eq!(buf, PERSISTENT_STATE_MAGIC);
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        sample_persistent_state()
+    );
 }
 
-/// This test verifies that storage correctly reports `NotFound` if the persistent state address
-/// lies outside of the allocated stable memory range. This can happen on upgrade from a version
-/// that did not serialize a persistent state into stable memory.
 #[test]
-fn should_not_panic_on_unallocated_persistent_state_mem_address() {
+fn should_read_previously_stored_persistent_state() {
+    const EXPECTED_ADDRESS: u64 = RESERVED_HEADER_BYTES + 3 * 2048; // 3 anchors
+    const PERSISTENT_STATE_BYTES: &'static str = "4949505388000000000000004449444c066c02cbc282b70501f7f5cbfb07786c02faafb5ac020291ecada008046e036d7b6b03d1d3dab70b78b5c2d2b70d7fc8bbeff50d056c02c7e8ccee037884fbf0820968010001206363636363636363636363636363636363636363636363636363636363636363022700000000000000010a00000000006000b001018002e1df02000000";
+
     let memory = VectorMemory::default();
-    let mut storage = Storage::new((10_000, 3_784_873), memory.clone());
-    storage.flush();
-    for _ in 0..32 {
-        storage.allocate_user_number();
+    // allocate space for the writes
+    memory.grow(1);
+
+    // write header so the number of users is set
+    memory.write(0, &hex::decode("494943010300000040e2010000000000f1fb09000000000000084343434343434343434343434343434343434343434343434343434343434343").unwrap());
+    memory.write(
+        EXPECTED_ADDRESS,
+        &hex::decode(PERSISTENT_STATE_BYTES).unwrap(),
+    );
+
+    let storage = Storage::from_memory(memory).unwrap();
+
+    // the fields added after the state was stored are not set
+    assert_eq!(
+        storage.read_persistent_state().unwrap(),
+        PersistentState {
+            webauthn_verification: None,
+            usage_metrics: None,
+            register_rate_limit: None,
+            captcha_type: None,
+            anchor_creation_recorded_since: None,
+            last_recovery_operation_id: None,
+            archive_dropped_entries: None,
+            ..sample_persistent_state()
+        }
+    );
+}
+
+#[test]
+fn should_buffer_and_acknowledge_archive_entries() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    for sequence_number in 0..5 {
+        storage.buffer_archive_entry(sample_buffered_entry(sequence_number));
     }
+    assert_eq!(storage.archive_buffer_len(), 5);
+    assert_eq!(
+        storage.archive_buffer_entries(2),
+        vec![sample_buffered_entry(0), sample_buffered_entry(1)]
+    );
+
+    storage.acknowledge_archive_entries(2);
+    assert_eq!(storage.archive_buffer_len(), 2);
+    assert_eq!(
+        storage.archive_buffer_entries(10),
+        vec![sample_buffered_entry(3), sample_buffered_entry(4)]
+    );
+}
+
+#[test]
+fn should_keep_archive_buffer_after_reload() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    storage.flush();
+    storage.buffer_archive_entry(sample_buffered_entry(7));
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.archive_buffer_entries(10),
+        vec![sample_buffered_entry(7)]
+    );
+}
+
+#[test]
+fn should_lookup_anchors_by_credential_id_and_pubkey() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    let user_number = storage.allocate_user_number().unwrap();
+    let anchor = sample_anchor_record();
+    storage.write(user_number, anchor.clone()).unwrap();
+
+    let device = &anchor.devices[0];
+    assert_eq!(
+        storage.lookup_anchors(&DeviceIdentifier::CredentialId(
+            device.credential_id.clone().unwrap()
+        )),
+        vec![user_number]
+    );
+    assert_eq!(
+        storage.lookup_anchors(&DeviceIdentifier::PublicKey(device.pubkey.clone())),
+        vec![user_number]
+    );
+    // credential ids and public keys are indexed separately
+    assert!(storage
+        .lookup_anchors(&DeviceIdentifier::PublicKey(
+            device.credential_id.clone().unwrap()
+        ))
+        .is_empty());
+}
+
+#[test]
+fn should_update_device_index_when_writing_anchors() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    let user_number = storage.allocate_user_number().unwrap();
+    storage.write(user_number, large_anchor_record(2)).unwrap();
+
+    // drop the second device
+    storage.write(user_number, large_anchor_record(1)).unwrap();
+
+    let credential_id = |i: u8| DeviceIdentifier::CredentialId(ByteBuf::from(vec![i; 64]));
+    assert_eq!(storage.lookup_anchors(&credential_id(0)), vec![user_number]);
+    assert!(storage.lookup_anchors(&credential_id(1)).is_empty());
+    assert!(storage
+        .lookup_anchors(&DeviceIdentifier::PublicKey(ByteBuf::from(vec![1; 150])))
+        .is_empty());
+}
+
+#[test]
+fn should_return_all_anchors_of_device() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    let first = storage.allocate_user_number().unwrap();
+    let second = storage.allocate_user_number().unwrap();
+    storage.write(first, sample_anchor_record()).unwrap();
+    storage.write(second, sample_anchor_record()).unwrap();
+
+    let credential_id = sample_anchor_record().devices[0]
+        .credential_id
+        .clone()
+        .unwrap();
+    assert_eq!(
+        storage.lookup_anchors(&DeviceIdentifier::CredentialId(credential_id.clone())),
+        vec![first, second]
+    );
+
+    storage.write(first, Anchor::default()).unwrap();
+    assert_eq!(
+        storage.lookup_anchors(&DeviceIdentifier::CredentialId(credential_id)),
+        vec![second]
+    );
+}
+
+#[test]
+fn should_index_genesis_records_in_batches() {
+    let memory = genesis_memory(5);
+    let mut storage = Storage::from_memory(memory.clone()).unwrap();
+    let credential_id = DeviceIdentifier::CredentialId(
+        sample_anchor_record().devices[0]
+            .credential_id
+            .clone()
+            .unwrap(),
+    );
+    assert_eq!(storage.unindexed_records(), 5);
+    assert!(storage.lookup_anchors(&credential_id).is_empty());
+
+    // anchors written before being indexed are only indexed once the index reaches them
+    storage.write(10_004, Anchor::default()).unwrap();
+
+    storage.index_devices();
+    assert_eq!(storage.unindexed_records(), 0);
+    assert_eq!(
+        storage.lookup_anchors(&credential_id),
+        vec![10_000, 10_001, 10_002, 10_003]
+    );
+
+    // the indexing progress is persisted
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.unindexed_records(), 0);
+}
+
+#[test]
+fn should_update_anchor_statistics_when_writing_and_deleting_anchors() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    let first = storage.allocate_user_number().unwrap();
+    let second = storage.allocate_user_number().unwrap();
+    storage.write(first, sample_anchor_record()).unwrap();
+    storage.write(second, large_anchor_record(2)).unwrap();
+    storage.write(second, large_anchor_record(3)).unwrap();
+
+    let statistics = storage.anchor_statistics();
+    assert_eq!(statistics.devices_per_anchor[1], 1);
+    assert_eq!(statistics.devices_per_anchor[2], 0);
+    assert_eq!(statistics.devices_per_anchor[3], 1);
+    assert_eq!(statistics.record_sizes.iter().sum::<u64>(), 2);
+    assert_eq!(
+        statistics.record_size_sum as usize,
+        candid::encode_one(sample_anchor_record()).unwrap().len()
+            + candid::encode_one(large_anchor_record(3)).unwrap().len()
+    );
+    assert_eq!(statistics.key_types, [1, 0, 3, 0]);
+
+    storage.delete(first).unwrap();
+    assert_eq!(storage.anchor_statistics().devices_per_anchor[1], 0);
+    assert_eq!(storage.anchor_statistics().key_types, [0, 0, 3, 0]);
+
+    // the statistics are persisted
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.anchor_statistics().devices_per_anchor[3], 1);
+    assert_eq!(storage.anchor_statistics().key_types, [0, 0, 3, 0]);
+}
+
+#[test]
+fn should_measure_genesis_records_in_batches() {
+    let memory = genesis_memory(5);
+    let mut storage = Storage::from_memory(memory.clone()).unwrap();
+    assert_eq!(storage.unmeasured_records(), 5);
+
+    // anchors written before being measured are only measured once the backfill reaches them
+    storage.write(10_004, Anchor::default()).unwrap();
+    assert_eq!(storage.anchor_statistics(), &AnchorStatistics::default());
+
+    storage.measure_records();
+    assert_eq!(storage.unmeasured_records(), 0);
+    assert_eq!(storage.anchor_statistics().devices_per_anchor[0], 1);
+    assert_eq!(storage.anchor_statistics().devices_per_anchor[1], 4);
+    assert_eq!(storage.anchor_statistics().key_types, [4, 0, 0, 0]);
+
+    // the backfill progress is persisted
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.unmeasured_records(), 0);
+    assert_eq!(storage.anchor_statistics().key_types, [4, 0, 0, 0]);
+}
+
+#[test]
+fn should_assign_record_sizes_to_buckets() {
+    assert_eq!(record_size_bucket(0, 100), 0);
+    assert_eq!(record_size_bucket(10, 100), 0);
+    assert_eq!(record_size_bucket(11, 100), 1);
+    assert_eq!(record_size_bucket(95, 100), 9);
+    assert_eq!(record_size_bucket(100, 100), 9);
+}
+
+#[test]
+fn should_delete_anchor() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    let user_number = storage.allocate_user_number().unwrap();
+    let anchor = sample_anchor_record();
+    storage.write(user_number, anchor.clone()).unwrap();
+
+    storage.delete(user_number).unwrap();
 
     assert!(matches!(
-        storage.read_persistent_state(),
-        Err(PersistentStateError::NotFound)
+        storage.read(user_number),
+        Err(StorageError::AnchorDeleted(n)) if n == user_number
+    ));
+    assert!(matches!(
+        storage.write(user_number, anchor.clone()),
+        Err(StorageError::AnchorDeleted(n)) if n == user_number
+    ));
+    assert!(storage
+        .lookup_anchors(&DeviceIdentifier::PublicKey(
+            anchor.devices[0].pubkey.clone()
+        ))
+        .is_empty());
+
+    // the anchor number is not assigned again and the deletion is persisted
+    assert_eq!(storage.allocate_user_number(), Some(user_number + 1));
+    let storage = Storage::from_memory(memory).unwrap();
+    assert!(matches!(
+        storage.read(user_number),
+        Err(StorageError::AnchorDeleted(_))
     ));
 }
 
 #[test]
-fn should_overwrite_persistent_state_with_next_anchor() {
-    const EXPECTED_ADDRESS: u64 = RESERVED_HEADER_BYTES + 2048; // only one anchor exists
+fn should_not_migrate_deleted_genesis_records() {
+    let memory = genesis_memory(3);
+    let mut storage = Storage::from_memory(memory).unwrap();
+    storage.set_migration_batch_size(100);
+
+    storage.delete(10_001).unwrap();
+    storage.migrate_records();
 
+    assert_eq!(storage.read(10_000).unwrap(), genesis_anchor_record(0));
+    assert!(matches!(
+        storage.read(10_001),
+        Err(StorageError::AnchorDeleted(_))
+    ));
+}
+
+#[test]
+fn should_keep_tentative_device_registrations_and_challenges_after_reload() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    let registration = sample_tentative_device_registration(100);
+    // proof of work challenges are the largest challenges
+    let challenge = ChallengeInfo {
+        expiration: 200,
+        chars: "abcdefghijklmnop".to_string(),
+        captcha_type: Some(CaptchaType::ProofOfWork),
+        pow_difficulty: Some(16),
+    };
+    storage.insert_tentative_device_registration(10_000, registration.clone());
+    storage.insert_inflight_challenge("challenge".to_string(), challenge.clone());
+
+    let mut storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.tentative_device_registration(10_000),
+        Some(registration)
+    );
+    assert!(storage.contains_inflight_challenge("challenge"));
+    assert_eq!(
+        storage.remove_inflight_challenge("challenge"),
+        Some(challenge)
+    );
+    assert!(!storage.contains_inflight_challenge("challenge"));
+}
+
+#[test]
+fn should_prune_expired_tentative_device_registrations_and_challenges() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    storage.insert_tentative_device_registration(10_000, sample_tentative_device_registration(10));
+    storage.insert_tentative_device_registration(10_001, sample_tentative_device_registration(30));
+    storage.insert_inflight_challenge(
+        "challenge".to_string(),
+        ChallengeInfo {
+            expiration: 20,
+            chars: "abcde".to_string(),
+            captcha_type: Some(CaptchaType::Image),
+            pow_difficulty: None,
+        },
+    );
+    // replacing a registration also replaces its expiration
+    storage.insert_tentative_device_registration(10_000, sample_tentative_device_registration(40));
+
+    storage.prune_expired(20);
+
+    assert_eq!(storage.inflight_challenges_len(), 0);
+    assert_eq!(storage.tentative_device_registrations_len(), 2);
+
+    storage.prune_expired(30);
+
+    assert_eq!(storage.tentative_device_registrations_len(), 1);
+    assert!(storage.tentative_device_registration(10_000).is_some());
+    assert!(storage.tentative_device_registration(10_001).is_none());
+}
+
+#[test]
+fn should_count_guardian_recoveries() {
     let memory = VectorMemory::default();
     let mut storage = Storage::new((10_000, 3_784_873), memory.clone());
     storage.flush();
+    storage.insert_tentative_device_registration(10_000, sample_guardian_recovery(10));
+    storage.insert_tentative_device_registration(10_001, sample_guardian_recovery(30));
+    storage.insert_tentative_device_registration(10_002, sample_tentative_device_registration(30));
+    // replacing a guardian recovery does not count it twice
+    storage.insert_tentative_device_registration(10_001, sample_guardian_recovery(30));
+    assert_eq!(storage.guardian_recoveries_len(), 2);
 
-    storage.allocate_user_number().unwrap();
-    storage.write_persistent_state(&sample_persistent_state());
+    let mut storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.guardian_recoveries_len(), 2);
 
-    let mut buf = vec![0u8; 4];
-    memory.read(EXPECTED_ADDRESS, &mut buf);
-    assert_eq!(buf, PERSISTENT_STATE_MAGIC);
+    storage.prune_expired(20);
+    assert_eq!(storage.guardian_recoveries_len(), 1);
 
-    let anchor = storage.allocate_user_number().unwrap();
-    storage.write(anchor, sample_anchor_record()).unwrap();
+    storage.insert_tentative_device_registration(10_001, sample_tentative_device_registration(30));
+    assert_eq!(storage.guardian_recoveries_len(), 0);
+    assert_eq!(storage.tentative_device_registrations_len(), 2);
+}
 
-    let mut buf = vec![0u8; 4];
-    memory.read(EXPECTED_ADDRESS, &mut buf);
-    assert_ne!(buf, PERSISTENT_STATE_MAGIC);
+#[test]
+fn should_keep_delegation_revocations_after_reload() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory.clone());
+    storage.flush();
+    storage.insert_delegation_revocation(&[1; 29], 10, 100);
+    storage.insert_delegation_revocation(&[2; 10], 20, 200);
+    // a new revocation replaces the previous one of the same principal
+    storage.insert_delegation_revocation(&[1; 29], 30, 300);
 
-    assert!(matches!(
-        storage.read_persistent_state(),
-        Err(PersistentStateError::NotFound)
-    ));
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.delegations_not_before(&[1; 29]), Some(30));
+    assert_eq!(storage.delegations_not_before(&[2; 10]), Some(20));
+    assert_eq!(storage.delegations_not_before(&[3; 29]), None);
+    assert_eq!(
+        storage.delegation_revocations(),
+        vec![(vec![1; 29], 30), (vec![2; 10], 20)]
+    );
 }
 
 #[test]
-fn should_read_previously_stored_persistent_state() {
-    const EXPECTED_ADDRESS: u64 = RESERVED_HEADER_BYTES + 3 * 2048; // 3 anchors
-    const PERSISTENT_STATE_BYTES: &'static str = "4949505388000000000000004449444c066c02cbc282b70501f7f5cbfb07786c02faafb5ac020291ecada008046e036d7b6b03d1d3dab70b78b5c2d2b70d7fc8bbeff50d056c02c7e8ccee037884fbf0820968010001206363636363636363636363636363636363636363636363636363636363636363022700000000000000010a00000000006000b001018002e1df02000000";
+fn should_prune_expired_delegation_revocations_separately() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    storage.insert_tentative_device_registration(10_000, sample_tentative_device_registration(10));
+    storage.insert_delegation_revocation(&[1; 29], 10, 100);
+    storage.insert_delegation_revocation(&[2; 29], 20, 200);
+    storage.insert_delegation_revocation(&[1; 29], 30, 300);
+
+    // revocations are not pruned together with the registrations and challenges
+    storage.prune_expired(1_000);
+    assert_eq!(storage.tentative_device_registrations_len(), 0);
+    assert_eq!(storage.delegation_revocations().len(), 2);
+
+    // the replaced revocation does not expire the new one
+    assert_eq!(
+        storage.prune_expired_revocations(100),
+        Vec::<Vec<u8>>::new()
+    );
+    assert_eq!(storage.prune_expired_revocations(200), vec![vec![2; 29]]);
+    assert_eq!(storage.delegation_revocations(), vec![(vec![1; 29], 30)]);
+}
 
+#[test]
+fn should_keep_sessions_after_reload() {
     let memory = VectorMemory::default();
-    // allocate space for the writes
-    memory.grow(1);
+    let mut storage = Storage::new((10_000, 3_784_873), memory.clone());
+    storage.flush();
+    storage.record_session(10_000, sample_session(1, 10, 100));
+    storage.record_session(10_001, sample_session(2, 20, 200));
+    // a session with the same session key replaces the previous one
+    storage.record_session(10_000, sample_session(1, 30, 300));
 
-    // write header so the number of users is set
-    memory.write(0, &hex::decode("494943010300000040e2010000000000f1fb09000000000000084343434343434343434343434343434343434343434343434343434343434343").unwrap());
-    memory.write(
-        EXPECTED_ADDRESS,
-        &hex::decode(PERSISTENT_STATE_BYTES).unwrap(),
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.sessions(10_000), vec![sample_session(1, 30, 300)]);
+    assert_eq!(storage.sessions(10_001), vec![sample_session(2, 20, 200)]);
+    assert_eq!(storage.sessions(10_002), vec![]);
+}
+
+#[test]
+fn should_evict_least_recently_issued_session() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    for i in 0..MAX_SESSIONS_PER_ANCHOR {
+        storage.record_session(10_000, sample_session(i as u8, i as u64 + 1, 1_000));
+    }
+    // session 0 is used again, so session 1 is now the least recently issued one
+    storage.record_session(10_000, sample_session(0, 200, 1_000));
+    storage.record_session(10_000, sample_session(99, 300, 1_000));
+
+    let sessions = storage.sessions(10_000);
+    assert_eq!(sessions.len(), MAX_SESSIONS_PER_ANCHOR);
+    assert!(sessions.contains(&sample_session(0, 200, 1_000)));
+    assert!(sessions.contains(&sample_session(99, 300, 1_000)));
+    assert!(!sessions.contains(&sample_session(1, 2, 1_000)));
+}
+
+#[test]
+fn should_prune_and_remove_sessions() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    storage.record_session(10_000, sample_session(1, 10, 100));
+    storage.record_session(10_000, sample_session(2, 20, 200));
+    storage.record_session(10_001, sample_session(3, 30, 300));
+
+    storage.prune_expired(100);
+    assert_eq!(storage.sessions(10_000), vec![sample_session(2, 20, 200)]);
+
+    storage.remove_sessions(10_000, |_| true);
+    assert_eq!(storage.sessions(10_000), vec![]);
+    assert_eq!(storage.sessions(10_001), vec![sample_session(3, 30, 300)]);
+}
+
+#[test]
+fn should_record_and_prune_session() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    storage.record_session(u64::MAX, sample_session(u8::MAX, 10, 100));
+    assert_eq!(
+        storage.sessions(u64::MAX),
+        vec![sample_session(u8::MAX, 10, 100)]
     );
 
+    storage.prune_expired(100);
+    assert_eq!(storage.sessions(u64::MAX), vec![]);
+    assert_eq!(storage.expiration_queue.len(), 0);
+}
+
+#[test]
+fn should_keep_pending_recovery_operations_after_reload() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory.clone());
+    storage.flush();
+    storage.insert_pending_recovery_operation(10_000, sample_pending_recovery_operation(1, 100));
+    storage.insert_pending_recovery_operation(10_000, sample_pending_recovery_operation(2, 200));
+    storage.insert_pending_recovery_operation(10_001, sample_pending_recovery_operation(1, 300));
+
     let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(
+        storage.pending_recovery_operations(10_000),
+        vec![
+            sample_pending_recovery_operation(1, 100),
+            sample_pending_recovery_operation(2, 200)
+        ]
+    );
+    assert_eq!(
+        storage.pending_recovery_operations(10_001),
+        vec![sample_pending_recovery_operation(1, 300)]
+    );
+    assert_eq!(storage.pending_recovery_operations(10_002), vec![]);
+}
 
+#[test]
+fn should_pop_due_recovery_operations_separately() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((10_000, 3_784_873), memory);
+    storage.insert_pending_recovery_operation(10_000, sample_pending_recovery_operation(1, 100));
+    storage.insert_pending_recovery_operation(10_001, sample_pending_recovery_operation(1, 200));
+    storage.insert_pending_recovery_operation(10_001, sample_pending_recovery_operation(2, 300));
+    storage.insert_pending_recovery_operation(10_002, sample_pending_recovery_operation(1, 400));
+
+    // pending operations are not pruned together with the other expiring entries
+    storage.prune_expired(1_000);
+    assert_eq!(storage.pending_recovery_operations(10_000).len(), 1);
+
+    // a removed operation does not become due
     assert_eq!(
-        storage.read_persistent_state().unwrap(),
-        sample_persistent_state()
+        storage.remove_pending_recovery_operation(10_001, 2),
+        Some(sample_pending_recovery_operation(2, 300))
+    );
+    assert_eq!(storage.remove_pending_recovery_operation(10_001, 2), None);
+
+    assert_eq!(
+        storage.pop_due_recovery_operations(300, 1),
+        vec![(10_000, sample_pending_recovery_operation(1, 100))]
     );
+    assert_eq!(
+        storage.pop_due_recovery_operations(300, 10),
+        vec![(10_001, sample_pending_recovery_operation(1, 200))]
+    );
+    assert_eq!(storage.pop_due_recovery_operations(300, 10), vec![]);
+    assert_eq!(
+        storage.pending_recovery_operations(10_002),
+        vec![sample_pending_recovery_operation(1, 400)]
+    );
+}
+
+fn sample_buffered_entry(sequence_number: u64) -> BufferedEntry {
+    BufferedEntry {
+        anchor: 10_000,
+        timestamp: 1_234,
+        sequence_number,
+        entry: ByteBuf::from(vec![sequence_number as u8; 100]),
+    }
 }
 
 fn sample_anchor_record() -> Anchor {
@@ -325,7 +1174,58 @@ fn sample_anchor_record() -> Anchor {
             purpose: Some(Purpose::Authentication),
             key_type: Some(KeyType::Unknown),
             protection: Some(DeviceProtection::Protected),
+            created_at: Some(1_620_328_630_000_000_000),
+            last_used: Some(1_620_328_640_000_000_000),
+            protection_policy: None,
         }],
+        created_at: Some(1_620_328_630_000_000_000),
+        delegation_lifetime_limits: None,
+        metadata: None,
+        recovery_delay: None,
+        guardians: None,
+    }
+}
+
+fn sample_tentative_device_registration(expiration: u64) -> TentativeDeviceRegistration {
+    TentativeDeviceRegistration {
+        expiration,
+        state: RegistrationState::DeviceTentativelyAdded {
+            tentative_device: DeviceData::from(sample_anchor_record().devices[0].clone()),
+            verification_code: "123456".to_string(),
+            failed_attempts: 1,
+        },
+    }
+}
+
+fn sample_guardian_recovery(expiration: u64) -> TentativeDeviceRegistration {
+    TentativeDeviceRegistration {
+        expiration,
+        state: RegistrationState::GuardianRecoveryRequested {
+            tentative_device: DeviceData::from(sample_anchor_record().devices[0].clone()),
+            approvals: vec![10_003],
+        },
+    }
+}
+
+fn sample_session(session_key: u8, issued_at: u64, expiration: u64) -> Session {
+    Session {
+        frontend: "https://some-dapp.com".to_string(),
+        derivation_origin: None,
+        session_key_hash: ByteBuf::from(vec![session_key; 32]),
+        issued_at,
+        expiration,
+    }
+}
+
+fn sample_pending_recovery_operation(id: u64, effective_at: u64) -> PendingRecoveryOperation {
+    PendingRecoveryOperation {
+        id,
+        change: RecoveryDeviceChange::Add(DeviceData::from(
+            sample_anchor_record().devices[0].clone(),
+        )),
+        queued_by: Principal::from_slice(&[1; 29]),
+        queued_at: 10,
+        effective_at,
     }
 }
 
@@ -339,6 +1239,85 @@ fn sample_persistent_state() -> PersistentState {
             }),
         },
         canister_creation_cycles_cost: 12_346_000_000,
+        ic_root_key_der: None,
+        webauthn_verification: Some(WebAuthnVerificationConfig {
+            allowed_origins: vec!["https://identity.ic0.app".to_string()],
+            require_user_verification: true,
+            required_for_recovery_devices: false,
+        }),
+        usage_metrics: Some(UsageMetrics {
+            delegation_counter: 1_234,
+            anchor_operation_counter: 567,
+            register_counter: 89,
+            ..UsageMetrics::default()
+        }),
+        register_rate_limit: Some(RateLimitConfig {
+            time_per_token_ns: 1_000_000_000,
+            max_tokens: 42,
+        }),
+        captcha_type: Some(CaptchaType::Text),
+        anchor_creation_recorded_since: Some(1_620_328_630_000_000_000),
+        last_recovery_operation_id: Some(7),
+        archive_dropped_entries: Some(3),
     };
     persistent_state
 }
+
+/// Anchor with the given number of devices, each of which has a large public key and credential id.
+fn large_anchor_record(num_devices: usize) -> Anchor {
+    Anchor {
+        devices: (0..num_devices)
+            .map(|i| DeviceDataInternal {
+                pubkey: ByteBuf::from(vec![i as u8; 150]),
+                alias: "a device with a rather long alias".to_string(),
+                credential_id: Some(ByteBuf::from(vec![i as u8; 64])),
+                purpose: Some(Purpose::Authentication),
+                key_type: Some(KeyType::CrossPlatform),
+                protection: Some(DeviceProtection::Unprotected),
+                created_at: None,
+                last_used: None,
+                protection_policy: None,
+            })
+            .collect(),
+        created_at: Some(1_620_328_630_000_000_000),
+        delegation_lifetime_limits: None,
+        metadata: None,
+        recovery_delay: None,
+        guardians: None,
+    }
+}
+
+fn genesis_anchor_record(index: usize) -> Anchor {
+    let mut anchor = sample_anchor_record();
+    anchor.devices[0].alias = format!("genesis device {}", index);
+    // the genesis layout only stores the devices
+    anchor.created_at = None;
+    anchor
+}
+
+/// Creates a memory using the genesis layout (version 1) with the given number of anchors in the
+/// range [10'000, 11'000).
+fn genesis_memory(num_anchors: u32) -> VectorMemory {
+    let memory = VectorMemory::default();
+    let genesis_end = RESERVED_HEADER_BYTES + num_anchors as u64 * 2048;
+    memory.grow(genesis_end / WASM_PAGE_SIZE + 1);
+
+    memory.write(0, &hex::decode("49494301000000001027000000000000f82a00000000000000084343434343434343434343434343434343434343434343434343434343434343").unwrap());
+    memory.write(4, &num_anchors.to_le_bytes());
+
+    for i in 0..num_anchors {
+        let buf = candid::encode_one(&genesis_anchor_record(i as usize).devices).unwrap();
+        let address = RESERVED_HEADER_BYTES + i as u64 * 2048;
+        memory.write(address, &(buf.len() as u16).to_le_bytes());
+        memory.write(address + 2, &buf);
+    }
+    memory
+}
+
+fn persistent_state_bytes(persistent_state: &PersistentState) -> Vec<u8> {
+    let encoded_state = candid::encode_one(persistent_state).unwrap();
+    let mut buf = PERSISTENT_STATE_MAGIC.to_vec();
+    buf.extend_from_slice(&(encoded_state.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&encoded_state);
+    buf
+}
@@ -0,0 +1,392 @@
+//! Canister-side verification of WebAuthn assertions.
+//!
+//! Usually, a device is authenticated by the IC itself: the caller is the self-authenticating
+//! principal of the device public key, which requires the agent to have signed the request
+//! envelope with a WebAuthn assertion. For high-risk operations (e.g. removing a recovery device)
+//! II can additionally require an assertion that the canister verifies itself:
+//! 1. the client requests a fresh challenge using `create_webauthn_challenge`
+//! 2. the client signs the challenge using `navigator.credentials.get` with one of the anchor's
+//!    WebAuthn devices and submits the assertion using `verify_webauthn_assertion`
+//! 3. the verified assertion allows a single high-risk operation on the anchor within a short
+//!    period of time
+//!
+//! The public keys of WebAuthn devices are DER-wrapped COSE keys. Supported are ES256 (EC2 /
+//! P-256), RS256 (RSA PKCS#1 v1.5 / SHA-256) and Ed25519 (OKP) keys.
+use crate::anchor_management::registration::make_rng;
+use crate::state::{DeviceDataInternal, VerifiedAssertion, WebAuthnChallengeInfo};
+use crate::{hash, secs_to_nanos, state, trap_if_not_authenticated};
+use ic_cdk::api::time;
+use ic_cdk::{caller, trap};
+use internet_identity_interface::*;
+use p256::ecdsa::signature::Verifier;
+use rand_core::RngCore;
+use rsa::{BigUint, PaddingScheme, PublicKey, RsaPublicKey};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use sha2::Sha256;
+
+#[cfg(test)]
+mod tests;
+
+// 5 mins
+const WEBAUTHN_CHALLENGE_LIFETIME: u64 = secs_to_nanos(300);
+// How long a verified assertion can be used for a high-risk operation
+const VERIFIED_ASSERTION_LIFETIME: u64 = secs_to_nanos(300);
+// How many WebAuthn challenges we keep in memory (at most)
+const MAX_INFLIGHT_WEBAUTHN_CHALLENGES: usize = 500;
+const CHALLENGE_LENGTH: usize = 32;
+
+// rp id hash (32 bytes), flags (1 byte), signature counter (4 bytes)
+const AUTHENTICATOR_DATA_MIN_LENGTH: usize = 37;
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+
+/// OID 1.3.6.1.4.1.56387.1.1 (DER-wrapped COSE key) as used by the IC.
+const COSE_KEY_OID: [u8; 10] = [0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb8, 0x43, 0x01, 0x01];
+const DER_SEQUENCE: u8 = 0x30;
+const DER_OID: u8 = 0x06;
+const DER_BIT_STRING: u8 = 0x03;
+
+// COSE key parameters and values, see https://www.iana.org/assignments/cose/cose.xhtml
+const COSE_KTY: i128 = 1;
+const COSE_ALG: i128 = 3;
+const COSE_CRV: i128 = -1;
+const COSE_X: i128 = -2;
+const COSE_Y: i128 = -3;
+const COSE_RSA_N: i128 = -1;
+const COSE_RSA_E: i128 = -2;
+const COSE_KTY_OKP: i128 = 1;
+const COSE_KTY_EC2: i128 = 2;
+const COSE_KTY_RSA: i128 = 3;
+const COSE_ALG_ES256: i128 = -7;
+const COSE_ALG_EDDSA: i128 = -8;
+const COSE_ALG_RS256: i128 = -257;
+const COSE_CRV_P256: i128 = 1;
+const COSE_CRV_ED25519: i128 = 6;
+
+#[derive(Debug, Eq, PartialEq)]
+enum CoseKey {
+    Es256 { x: Vec<u8>, y: Vec<u8> },
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+    Ed25519 { x: Vec<u8> },
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+}
+
+pub async fn create_challenge(user_number: UserNumber) -> WebAuthnChallenge {
+    let anchor = state::anchor(user_number);
+    // must be called before the first await because it requires caller()
+    trap_if_not_authenticated(&anchor);
+    // traps if the verification is disabled
+    verification_config();
+
+    let mut rng = make_rng().await;
+    let mut challenge = vec![0; CHALLENGE_LENGTH];
+    rng.fill_bytes(&mut challenge);
+
+    state::webauthn_challenges_mut(|webauthn_challenges| {
+        let now = time();
+        webauthn_challenges.retain(|_, info| info.expiration > now);
+
+        if webauthn_challenges.len() >= MAX_INFLIGHT_WEBAUTHN_CHALLENGES
+            && !webauthn_challenges.contains_key(&user_number)
+        {
+            trap("too many inflight WebAuthn challenges");
+        }
+
+        let expiration = now + WEBAUTHN_CHALLENGE_LIFETIME;
+        // a new challenge replaces the previous challenge of the anchor
+        webauthn_challenges.insert(
+            user_number,
+            WebAuthnChallengeInfo {
+                challenge: challenge.clone(),
+                expiration,
+            },
+        );
+
+        WebAuthnChallenge {
+            challenge: ByteBuf::from(challenge),
+            expiration,
+        }
+    })
+}
+
+/// Verifies the assertion over the challenge of the anchor.
+/// Once the challenge has been consumed, failures are returned rather than trapping: a trap would
+/// roll back the removal of the challenge, which could then be used again.
+pub fn verify_assertion(
+    user_number: UserNumber,
+    assertion: WebAuthnAssertion,
+) -> VerifyWebAuthnAssertionResponse {
+    let config = verification_config();
+    let anchor = state::anchor(user_number);
+    trap_if_not_authenticated(&anchor);
+
+    // challenges can only be used once
+    let now = time();
+    let challenge = state::webauthn_challenges_mut(|webauthn_challenges| {
+        webauthn_challenges.remove(&user_number)
+    })
+    .filter(|info| info.expiration > now)
+    .unwrap_or_else(|| {
+        trap(&format!(
+            "no valid WebAuthn challenge for anchor {}",
+            user_number
+        ))
+    });
+
+    let Some(device) = anchor
+        .devices
+        .into_iter()
+        .find(|device| device.credential_id.as_ref() == Some(&assertion.credential_id))
+    else {
+        return VerifyWebAuthnAssertionResponse::UnknownCredential;
+    };
+
+    if let Err(err) =
+        verify_assertion_signature(&device.pubkey, &assertion, &challenge.challenge, &config)
+    {
+        return VerifyWebAuthnAssertionResponse::VerificationFailed(err);
+    }
+
+    state::verified_assertions_mut(|verified_assertions| {
+        verified_assertions.retain(|_, verified| verified.expiration > now);
+        verified_assertions.insert(
+            user_number,
+            VerifiedAssertion {
+                caller: caller(),
+                device: device.pubkey,
+                expiration: now + VERIFIED_ASSERTION_LIFETIME,
+            },
+        );
+    });
+    VerifyWebAuthnAssertionResponse::Verified
+}
+
+/// Traps if a canister-verified assertion is required to modify a recovery device but the anchor
+/// does not have a fresh one (made by one of its current devices and submitted by the caller).
+/// The assertion is consumed, i.e. it can only be used for a single operation.
+pub fn trap_if_no_verified_assertion(user_number: UserNumber, devices: &[DeviceDataInternal]) {
+    let required = state::persistent_state(|persistent_state| {
+        persistent_state
+            .webauthn_verification
+            .as_ref()
+            .map(|config| config.required_for_recovery_devices)
+            .unwrap_or(false)
+    });
+    if !required {
+        return;
+    }
+
+    let verified_assertion = state::verified_assertions_mut(|verified_assertions| {
+        verified_assertions.remove(&user_number)
+    });
+    match verified_assertion {
+        Some(VerifiedAssertion {
+            caller: verified_by,
+            device,
+            expiration,
+        }) if verified_by == caller()
+            && expiration > time()
+            && devices.iter().any(|d| d.pubkey == device) => {}
+        _ => trap(
+            "Modifying a recovery device requires a fresh WebAuthn assertion verified by the canister",
+        ),
+    }
+}
+
+fn verification_config() -> WebAuthnVerificationConfig {
+    state::persistent_state(|persistent_state| persistent_state.webauthn_verification.clone())
+        .unwrap_or_else(|| trap("WebAuthn verification is not enabled"))
+}
+
+/// Verifies the assertion against the public key of the device and the expected challenge.
+fn verify_assertion_signature(
+    pubkey: &[u8],
+    assertion: &WebAuthnAssertion,
+    challenge: &[u8],
+    config: &WebAuthnVerificationConfig,
+) -> Result<(), String> {
+    let client_data: ClientData = serde_json::from_slice(&assertion.client_data_json)
+        .map_err(|err| format!("failed to parse client data: {}", err))?;
+    if client_data.type_ != "webauthn.get" {
+        return Err(format!("unexpected client data type {}", client_data.type_));
+    }
+    let client_challenge = base64::decode_config(&client_data.challenge, base64::URL_SAFE_NO_PAD)
+        .map_err(|err| format!("failed to decode challenge: {}", err))?;
+    if client_challenge != challenge {
+        return Err("challenge mismatch".to_string());
+    }
+    if !config.allowed_origins.contains(&client_data.origin) {
+        return Err(format!("origin {} is not allowed", client_data.origin));
+    }
+
+    let authenticator_data = &assertion.authenticator_data;
+    if authenticator_data.len() < AUTHENTICATOR_DATA_MIN_LENGTH {
+        return Err("authenticator data is too short".to_string());
+    }
+    if authenticator_data[..32] != hash::hash_string(rp_id(&client_data.origin)) {
+        return Err("relying party id mismatch".to_string());
+    }
+    let flags = authenticator_data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err("user presence flag is not set".to_string());
+    }
+    if config.require_user_verification && flags & FLAG_USER_VERIFIED == 0 {
+        return Err("user verification flag is not set".to_string());
+    }
+
+    let key = parse_cose_key(cose_key_from_der(pubkey)?)?;
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(&hash::hash_bytes(&assertion.client_data_json));
+    key.verify(&message, &assertion.signature)
+}
+
+/// The relying party id is the host of the origin (the II frontend does not set it explicitly).
+fn rp_id(origin: &str) -> &str {
+    let host = origin
+        .split_once("://")
+        .map(|(_, host)| host)
+        .unwrap_or(origin);
+    host.split_once(':').map(|(host, _)| host).unwrap_or(host)
+}
+
+/// Unwraps the COSE key from its DER encoding, i.e.
+/// `SEQUENCE { SEQUENCE { OID 1.3.6.1.4.1.56387.1.1 }, BIT STRING <COSE key> }`.
+fn cose_key_from_der(der: &[u8]) -> Result<&[u8], String> {
+    let (key_info, rest) = der_element(der, DER_SEQUENCE)?;
+    if !rest.is_empty() {
+        return Err("trailing bytes after DER encoded key".to_string());
+    }
+    let (algorithm, bit_string) = der_element(key_info, DER_SEQUENCE)?;
+    let (oid, _) = der_element(algorithm, DER_OID)?;
+    if oid != COSE_KEY_OID {
+        return Err("not a DER encoded COSE key".to_string());
+    }
+    let (bit_string, _) = der_element(bit_string, DER_BIT_STRING)?;
+    match bit_string.split_first() {
+        // the first byte is the number of unused bits
+        Some((0, cose_key)) => Ok(cose_key),
+        _ => Err("invalid bit string".to_string()),
+    }
+}
+
+/// Splits off the DER element with the given tag, returning its content and the remaining bytes.
+fn der_element(bytes: &[u8], tag: u8) -> Result<(&[u8], &[u8]), String> {
+    let rest = match bytes.split_first() {
+        Some((first, rest)) if *first == tag => rest,
+        _ => return Err(format!("expected DER element with tag {:#04x}", tag)),
+    };
+    let (len, rest) = match rest.split_first() {
+        Some((len, rest)) if *len < 0x80 => (*len as usize, rest),
+        // long form with 1 or 2 length bytes (keys are smaller than 64KiB)
+        Some((len_bytes @ (0x81 | 0x82), rest)) => {
+            let n = (len_bytes & 0x7f) as usize;
+            if rest.len() < n {
+                return Err("truncated DER length".to_string());
+            }
+            let len = rest[..n]
+                .iter()
+                .fold(0usize, |len, byte| len << 8 | *byte as usize);
+            (len, &rest[n..])
+        }
+        _ => return Err("unsupported DER length".to_string()),
+    };
+    if rest.len() < len {
+        return Err("truncated DER element".to_string());
+    }
+    Ok(rest.split_at(len))
+}
+
+fn parse_cose_key(bytes: &[u8]) -> Result<CoseKey, String> {
+    let value: Value = serde_cbor::from_slice(bytes)
+        .map_err(|err| format!("failed to decode COSE key: {}", err))?;
+    let Value::Map(map) = value else {
+        return Err("COSE key is not a map".to_string());
+    };
+    let int_param = |label: i128| match map.get(&Value::Integer(label)) {
+        Some(Value::Integer(value)) => Ok(*value),
+        _ => Err(format!("missing COSE key parameter {}", label)),
+    };
+    let bytes_param =
+        |label: i128, expected_len: Option<usize>| match map.get(&Value::Integer(label)) {
+            Some(Value::Bytes(bytes)) if expected_len.map_or(true, |len| bytes.len() == len) => {
+                Ok(bytes.clone())
+            }
+            _ => Err(format!("missing or invalid COSE key parameter {}", label)),
+        };
+
+    match (int_param(COSE_KTY)?, int_param(COSE_ALG)?) {
+        (COSE_KTY_EC2, COSE_ALG_ES256) => {
+            if int_param(COSE_CRV)? != COSE_CRV_P256 {
+                return Err("unsupported elliptic curve".to_string());
+            }
+            Ok(CoseKey::Es256 {
+                x: bytes_param(COSE_X, Some(32))?,
+                y: bytes_param(COSE_Y, Some(32))?,
+            })
+        }
+        (COSE_KTY_RSA, COSE_ALG_RS256) => Ok(CoseKey::Rs256 {
+            n: bytes_param(COSE_RSA_N, None)?,
+            e: bytes_param(COSE_RSA_E, None)?,
+        }),
+        (COSE_KTY_OKP, COSE_ALG_EDDSA) => {
+            if int_param(COSE_CRV)? != COSE_CRV_ED25519 {
+                return Err("unsupported elliptic curve".to_string());
+            }
+            Ok(CoseKey::Ed25519 {
+                x: bytes_param(COSE_X, Some(32))?,
+            })
+        }
+        (kty, alg) => Err(format!(
+            "unsupported COSE key type {} with algorithm {}",
+            kty, alg
+        )),
+    }
+}
+
+impl CoseKey {
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), String> {
+        match self {
+            CoseKey::Es256 { x, y } => {
+                let point = p256::EncodedPoint::from_affine_coordinates(
+                    p256::FieldBytes::from_slice(x),
+                    p256::FieldBytes::from_slice(y),
+                    false,
+                );
+                let key = p256::ecdsa::VerifyingKey::from_encoded_point(&point)
+                    .map_err(|_| "invalid ES256 public key".to_string())?;
+                // WebAuthn ECDSA signatures are DER encoded
+                let signature = p256::ecdsa::Signature::from_der(signature)
+                    .map_err(|_| "malformed ES256 signature".to_string())?;
+                key.verify(message, &signature)
+                    .map_err(|_| "invalid ES256 signature".to_string())
+            }
+            CoseKey::Rs256 { n, e } => {
+                let key = RsaPublicKey::new(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e))
+                    .map_err(|err| format!("invalid RS256 public key: {}", err))?;
+                key.verify(
+                    PaddingScheme::new_pkcs1v15_sign::<Sha256>(),
+                    &hash::hash_bytes(message),
+                    signature,
+                )
+                .map_err(|_| "invalid RS256 signature".to_string())
+            }
+            CoseKey::Ed25519 { x } => {
+                let key = ed25519_compact::PublicKey::from_slice(x)
+                    .map_err(|_| "invalid Ed25519 public key".to_string())?;
+                let signature = ed25519_compact::Signature::from_slice(signature)
+                    .map_err(|_| "malformed Ed25519 signature".to_string())?;
+                key.verify(message, &signature)
+                    .map_err(|_| "invalid Ed25519 signature".to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use crate::hash;
+use crate::webauthn::{
+    cose_key_from_der, parse_cose_key, rp_id, verify_assertion_signature, CoseKey,
+    FLAG_USER_PRESENT, FLAG_USER_VERIFIED,
+};
+use internet_identity_interface::{WebAuthnAssertion, WebAuthnVerificationConfig};
+use p256::ecdsa::signature::Signer;
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use std::collections::BTreeMap;
+
+const ORIGIN: &str = "https://identity.ic0.app";
+const CHALLENGE: [u8; 32] = [7; 32];
+
+fn config(require_user_verification: bool) -> WebAuthnVerificationConfig {
+    WebAuthnVerificationConfig {
+        allowed_origins: vec![ORIGIN.to_string()],
+        require_user_verification,
+        required_for_recovery_devices: true,
+    }
+}
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    match content.len() {
+        len if len < 0x80 => der.push(len as u8),
+        len if len <= 0xff => der.extend_from_slice(&[0x81, len as u8]),
+        len => der.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+    }
+    der.extend_from_slice(content);
+    der
+}
+
+fn der_encode_cose_key(params: Vec<(i128, Value)>) -> Vec<u8> {
+    let map: BTreeMap<Value, Value> = params
+        .into_iter()
+        .map(|(label, value)| (Value::Integer(label), value))
+        .collect();
+    let cose_key = serde_cbor::to_vec(&Value::Map(map)).unwrap();
+    let algorithm = der(
+        0x30,
+        &der(
+            0x06,
+            &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb8, 0x43, 0x01, 0x01],
+        ),
+    );
+    let bit_string = der(0x03, &[&[0u8][..], &cose_key].concat());
+    der(0x30, &[algorithm, bit_string].concat())
+}
+
+fn es256_key() -> (p256::ecdsa::SigningKey, Vec<u8>) {
+    let signing_key = p256::ecdsa::SigningKey::from_bytes(&[42; 32]).unwrap();
+    let point = signing_key.verifying_key().to_encoded_point(false);
+    let pubkey = der_encode_cose_key(vec![
+        (1, Value::Integer(2)),
+        (3, Value::Integer(-7)),
+        (-1, Value::Integer(1)),
+        (-2, Value::Bytes(point.x().unwrap().to_vec())),
+        (-3, Value::Bytes(point.y().unwrap().to_vec())),
+    ]);
+    (signing_key, pubkey)
+}
+
+fn ed25519_key() -> (ed25519_compact::KeyPair, Vec<u8>) {
+    let key_pair = ed25519_compact::KeyPair::from_seed(ed25519_compact::Seed::new([42; 32]));
+    let pubkey = der_encode_cose_key(vec![
+        (1, Value::Integer(1)),
+        (3, Value::Integer(-8)),
+        (-1, Value::Integer(6)),
+        (-2, Value::Bytes(key_pair.pk.to_vec())),
+    ]);
+    (key_pair, pubkey)
+}
+
+fn client_data_json(type_: &str, challenge: &[u8], origin: &str) -> Vec<u8> {
+    format!(
+        r#"{{"type":"{}","challenge":"{}","origin":"{}","crossOrigin":false}}"#,
+        type_,
+        base64::encode_config(challenge, base64::URL_SAFE_NO_PAD),
+        origin
+    )
+    .into_bytes()
+}
+
+fn authenticator_data(rp_id: &str, flags: u8) -> Vec<u8> {
+    let mut authenticator_data = hash::hash_string(rp_id).to_vec();
+    authenticator_data.push(flags);
+    authenticator_data.extend_from_slice(&1u32.to_be_bytes());
+    authenticator_data
+}
+
+fn assertion(
+    authenticator_data: Vec<u8>,
+    client_data_json: Vec<u8>,
+    sign: impl FnOnce(&[u8]) -> Vec<u8>,
+) -> WebAuthnAssertion {
+    let mut message = authenticator_data.clone();
+    message.extend_from_slice(&hash::hash_bytes(&client_data_json));
+    WebAuthnAssertion {
+        credential_id: ByteBuf::from(vec![1, 2, 3]),
+        signature: ByteBuf::from(sign(&message)),
+        authenticator_data: ByteBuf::from(authenticator_data),
+        client_data_json: ByteBuf::from(client_data_json),
+    }
+}
+
+fn es256_assertion(
+    signing_key: &p256::ecdsa::SigningKey,
+    flags: u8,
+    challenge: &[u8],
+    origin: &str,
+) -> WebAuthnAssertion {
+    assertion(
+        authenticator_data(rp_id(origin), flags),
+        client_data_json("webauthn.get", challenge, origin),
+        |message| {
+            let signature: p256::ecdsa::Signature = signing_key.sign(message);
+            signature.to_der().as_bytes().to_vec()
+        },
+    )
+}
+
+#[test]
+fn should_parse_es256_key() {
+    let (signing_key, pubkey) = es256_key();
+    let point = signing_key.verifying_key().to_encoded_point(false);
+    assert_eq!(
+        parse_cose_key(cose_key_from_der(&pubkey).unwrap()),
+        Ok(CoseKey::Es256 {
+            x: point.x().unwrap().to_vec(),
+            y: point.y().unwrap().to_vec(),
+        })
+    );
+}
+
+#[test]
+fn should_parse_rs256_key() {
+    let n = vec![0xab; 256];
+    let pubkey = der_encode_cose_key(vec![
+        (1, Value::Integer(3)),
+        (3, Value::Integer(-257)),
+        (-1, Value::Bytes(n.clone())),
+        (-2, Value::Bytes(vec![1, 0, 1])),
+    ]);
+    assert_eq!(
+        parse_cose_key(cose_key_from_der(&pubkey).unwrap()),
+        Ok(CoseKey::Rs256 {
+            n,
+            e: vec![1, 0, 1]
+        })
+    );
+}
+
+#[test]
+fn should_reject_unsupported_keys() {
+    // ES384
+    let pubkey = der_encode_cose_key(vec![
+        (1, Value::Integer(2)),
+        (3, Value::Integer(-35)),
+        (-1, Value::Integer(2)),
+        (-2, Value::Bytes(vec![1; 48])),
+        (-3, Value::Bytes(vec![1; 48])),
+    ]);
+    assert!(parse_cose_key(cose_key_from_der(&pubkey).unwrap()).is_err());
+
+    // not DER wrapped
+    assert!(cose_key_from_der(&[1, 2, 3]).is_err());
+}
+
+#[test]
+fn should_get_rp_id_from_origin() {
+    assert_eq!(rp_id("https://identity.ic0.app"), "identity.ic0.app");
+    assert_eq!(rp_id("http://localhost:8080"), "localhost");
+}
+
+#[test]
+fn should_verify_es256_assertion() {
+    let (signing_key, pubkey) = es256_key();
+    let assertion = es256_assertion(
+        &signing_key,
+        FLAG_USER_PRESENT | FLAG_USER_VERIFIED,
+        &CHALLENGE,
+        ORIGIN,
+    );
+    assert_eq!(
+        verify_assertion_signature(&pubkey, &assertion, &CHALLENGE, &config(true)),
+        Ok(())
+    );
+}
+
+#[test]
+fn should_verify_ed25519_assertion() {
+    let (key_pair, pubkey) = ed25519_key();
+    let assertion = assertion(
+        authenticator_data("identity.ic0.app", FLAG_USER_PRESENT),
+        client_data_json("webauthn.get", &CHALLENGE, ORIGIN),
+        |message| key_pair.sk.sign(message, None).to_vec(),
+    );
+    assert_eq!(
+        verify_assertion_signature(&pubkey, &assertion, &CHALLENGE, &config(false)),
+        Ok(())
+    );
+}
+
+#[test]
+fn should_reject_wrong_challenge() {
+    let (signing_key, pubkey) = es256_key();
+    let assertion = es256_assertion(&signing_key, FLAG_USER_PRESENT, &[8; 32], ORIGIN);
+    assert_eq!(
+        verify_assertion_signature(&pubkey, &assertion, &CHALLENGE, &config(false)),
+        Err("challenge mismatch".to_string())
+    );
+}
+
+#[test]
+fn should_reject_unknown_origin() {
+    let (signing_key, pubkey) = es256_key();
+    let assertion = es256_assertion(
+        &signing_key,
+        FLAG_USER_PRESENT,
+        &CHALLENGE,
+        "https://evil.com",
+    );
+    assert_eq!(
+        verify_assertion_signature(&pubkey, &assertion, &CHALLENGE, &config(false)),
+        Err("origin https://evil.com is not allowed".to_string())
+    );
+}
+
+#[test]
+fn should_reject_rp_id_mismatch() {
+    let (signing_key, pubkey) = es256_key();
+    let assertion = assertion(
+        authenticator_data("evil.com", FLAG_USER_PRESENT),
+        client_data_json("webauthn.get", &CHALLENGE, ORIGIN),
+        |message| {
+            let signature: p256::ecdsa::Signature = signing_key.sign(message);
+            signature.to_der().as_bytes().to_vec()
+        },
+    );
+    assert_eq!(
+        verify_assertion_signature(&pubkey, &assertion, &CHALLENGE, &config(false)),
+        Err("relying party id mismatch".to_string())
+    );
+}
+
+#[test]
+fn should_enforce_user_flags() {
+    let (signing_key, pubkey) = es256_key();
+
+    let assertion = es256_assertion(&signing_key, 0, &CHALLENGE, ORIGIN);
+    assert_eq!(
+        verify_assertion_signature(&pubkey, &assertion, &CHALLENGE, &config(false)),
+        Err("user presence flag is not set".to_string())
+    );
+
+    let assertion = es256_assertion(&signing_key, FLAG_USER_PRESENT, &CHALLENGE, ORIGIN);
+    assert_eq!(
+        verify_assertion_signature(&pubkey, &assertion, &CHALLENGE, &config(true)),
+        Err("user verification flag is not set".to_string())
+    );
+}
+
+#[test]
+fn should_reject_invalid_signature() {
+    let (signing_key, pubkey) = es256_key();
+    let mut assertion = es256_assertion(&signing_key, FLAG_USER_PRESENT, &CHALLENGE, ORIGIN);
+    // the signature does not cover the changed signature counter
+    assertion.authenticator_data[36] += 1;
+    assert_eq!(
+        verify_assertion_signature(&pubkey, &assertion, &CHALLENGE, &config(false)),
+        Err("invalid ES256 signature".to_string())
+    );
+
+    // the device key does not match
+    let (_, other_pubkey) = ed25519_key();
+    let assertion = es256_assertion(&signing_key, FLAG_USER_PRESENT, &CHALLENGE, ORIGIN);
+    assert_eq!(
+        verify_assertion_signature(&other_pubkey, &assertion, &CHALLENGE, &config(false)),
+        Err("malformed Ed25519 signature".to_string())
+    );
+}
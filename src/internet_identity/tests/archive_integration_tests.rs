@@ -5,11 +5,11 @@ use canister_tests::flows;
 use canister_tests::framework::*;
 use ic_state_machine_tests::StateMachine;
 use internet_identity_interface::{
-    DeployArchiveResult, DeviceDataUpdate, DeviceDataWithoutAlias, DeviceProtection, Entry,
-    InternetIdentityInit, KeyType, Operation, Purpose,
+    ArchiveBufferInfo, DeployArchiveResult, DeviceDataUpdate, DeviceDataWithoutAlias,
+    DeviceProtection, Entry, InternetIdentityInit, KeyType, Operation, Purpose,
 };
 use serde_bytes::ByteBuf;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 /// Test to verify that II can spawn an archive canister.
 #[test]
@@ -37,6 +37,11 @@ fn should_deploy_archive_with_cycles() -> Result<(), CallError> {
             assigned_user_number_range: None,
             archive_module_hash: Some(archive_wasm_hash(&ARCHIVE_WASM)),
             canister_creation_cycles_cost: Some(100_000_000_000), // current cost in application subnets
+            layout_migration_batch_size: None,
+            ic_root_key_der: None,
+            webauthn_verification: None,
+            register_rate_limit: None,
+            captcha_type: None,
         }),
     );
     env.add_cycles(ii_canister, 150_000_000_000);
@@ -155,6 +160,7 @@ fn should_keep_archive_across_upgrades() -> Result<(), CallError> {
     upgrade_ii_canister(&env, ii_canister, II_WASM.clone());
 
     let anchor = flows::register_anchor(&env, ii_canister);
+    push_entries_to_archive(&env);
 
     let entries = archive_api::get_anchor_entries(&env, archive_canister, anchor, None, None)?;
     assert_eq!(entries.entries.len(), 1);
@@ -192,9 +198,7 @@ fn should_record_anchor_operations() -> Result<(), CallError> {
 
     ii_api::remove(&env, ii_canister, principal_1(), anchor, pubkey.clone())?;
 
-    let entries = archive_api::get_entries(&env, archive_canister, None, None)?;
-
-    assert_eq!(entries.entries.len(), 4);
+    ii_api::delete_anchor(&env, ii_canister, principal_1(), anchor)?;
 
     let timestamp = env
         .time()
@@ -202,6 +206,11 @@ fn should_record_anchor_operations() -> Result<(), CallError> {
         .unwrap()
         .as_nanos() as u64;
 
+    push_entries_to_archive(&env);
+    let entries = archive_api::get_entries(&env, archive_canister, None, None)?;
+
+    assert_eq!(entries.entries.len(), 5);
+
     let register_entry = Entry {
         anchor,
         operation: Operation::RegisterAnchor {
@@ -211,6 +220,7 @@ fn should_record_anchor_operations() -> Result<(), CallError> {
                 purpose: Purpose::Authentication,
                 key_type: KeyType::Unknown,
                 protection: DeviceProtection::Unprotected,
+                protection_policy: None,
             },
         },
         timestamp,
@@ -246,6 +256,7 @@ fn should_record_anchor_operations() -> Result<(), CallError> {
                 purpose: Some(Purpose::Recovery),
                 key_type: None,
                 protection: None,
+                protection_policy: None,
             },
         },
         timestamp,
@@ -270,5 +281,99 @@ fn should_record_anchor_operations() -> Result<(), CallError> {
         entries.entries.get(3).unwrap().as_ref().unwrap(),
         &delete_entry
     );
+
+    let delete_anchor_entry = Entry {
+        anchor,
+        operation: Operation::DeleteAnchor,
+        timestamp,
+        caller: Principal::from(principal_1()),
+        sequence_number: 4,
+    };
+    assert_eq!(
+        entries.entries.get(4).unwrap().as_ref().unwrap(),
+        &delete_anchor_entry
+    );
+    Ok(())
+}
+
+/// Test to verify that II buffers entries while the archive is unavailable and delivers them once
+/// the archive is available again.
+#[test]
+fn should_retry_pushing_entries_to_unavailable_archive() -> Result<(), CallError> {
+    let env = StateMachine::new();
+    let ii_canister = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_wasm_hash(ARCHIVE_WASM.clone()),
+    );
+    let archive_canister = deploy_archive_via_ii(&env, ii_canister);
+
+    env.stop_canister(archive_canister).unwrap();
+    let anchor = flows::register_anchor(&env, ii_canister);
+    ii_api::add(&env, ii_canister, principal_1(), anchor, device_data_2())?;
+    push_entries_to_archive(&env);
+
+    let stats = ii_api::stats(&env, ii_canister)?;
+    assert_eq!(stats.archive_buffer.buffered_entries, 2);
+    assert!(stats.archive_buffer.failed_pushes > 0);
+    let metrics = flows::get_metrics(&env, ii_canister);
+    assert_metric(&metrics, "internet_identity_archive_buffered_entries", 2);
+
+    env.start_canister(archive_canister).unwrap();
+    // entries are not pushed again until the retry delay has passed
+    push_entries_to_archive(&env);
+    let stats = ii_api::stats(&env, ii_canister)?;
+    assert_eq!(stats.archive_buffer.buffered_entries, 2);
+
+    env.advance_time(Duration::from_secs(60));
+    push_entries_to_archive(&env);
+
+    let stats = ii_api::stats(&env, ii_canister)?;
+    assert_eq!(stats.archive_buffer.buffered_entries, 0);
+    let entries = archive_api::get_anchor_entries(&env, archive_canister, anchor, None, None)?;
+    assert_eq!(entries.entries.len(), 2);
+    let sequence_numbers: Vec<u64> = entries
+        .entries
+        .iter()
+        .map(|entry| entry.as_ref().unwrap().sequence_number)
+        .collect();
+    assert_eq!(sequence_numbers, vec![0, 1]);
+    Ok(())
+}
+
+/// Test to verify that acknowledged entries are removed from the buffer.
+#[test]
+fn should_empty_buffer_after_push() -> Result<(), CallError> {
+    let env = StateMachine::new();
+    let ii_canister = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_wasm_hash(ARCHIVE_WASM.clone()),
+    );
+    let archive_canister = deploy_archive_via_ii(&env, ii_canister);
+
+    for _ in 0..3 {
+        flows::register_anchor(&env, ii_canister);
+    }
+    push_entries_to_archive(&env);
+
+    let stats = ii_api::stats(&env, ii_canister)?;
+    assert_eq!(
+        stats.archive_buffer,
+        ArchiveBufferInfo {
+            buffered_entries: 0,
+            dropped_entries: 0,
+            failed_pushes: 0,
+        }
+    );
+    let entries = archive_api::get_entries(&env, archive_canister, None, None)?;
+    assert_eq!(entries.entries.len(), 3);
     Ok(())
 }
+
+/// Executes a few rounds so that II pushes the buffered entries to the archive on heartbeat.
+fn push_entries_to_archive(env: &StateMachine) {
+    for _ in 0..3 {
+        env.tick();
+    }
+}
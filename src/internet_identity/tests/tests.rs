@@ -1,12 +1,13 @@
 use candid::Principal;
 use canister_tests::api::internet_identity as api;
-use canister_tests::certificate_validation::validate_certification;
+use canister_tests::certificate_validation::{validate_certification, validate_certified_lookup};
 use canister_tests::flows;
 use canister_tests::framework::*;
 use ic_state_machine_tests::{ErrorCode::CanisterCalledTrap, PrincipalId, StateMachine};
 use internet_identity_interface::*;
 use regex::Regex;
 use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
 use std::ops::Add;
 use std::path::PathBuf;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -61,6 +62,11 @@ mod upgrade_tests {
                 assigned_user_number_range: Some((2000, 4000)),
                 archive_module_hash: None,
                 canister_creation_cycles_cost: None,
+                layout_migration_batch_size: None,
+                ic_root_key_der: None,
+                webauthn_verification: None,
+                register_rate_limit: None,
+                captcha_type: None,
             }),
         );
 
@@ -88,6 +94,11 @@ mod upgrade_tests {
                 assigned_user_number_range: Some(stats.assigned_user_number_range),
                 archive_module_hash: None,
                 canister_creation_cycles_cost: None,
+                layout_migration_batch_size: None,
+                ic_root_key_der: None,
+                webauthn_verification: None,
+                register_rate_limit: None,
+                captcha_type: None,
             }),
         );
 
@@ -198,13 +209,22 @@ mod registration_tests {
         let devices = api::lookup(&env, canister_id, user_number)?;
         assert_eq!(devices, vec![device_data_1()]);
         let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
-        assert_eq!(anchor_info.devices, vec![device_data_1()]);
+        let now = env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        assert_eq!(
+            anchor_info.devices,
+            vec![DeviceData {
+                created_at: Some(now),
+                last_used: Some(now),
+                ..device_data_1()
+            }]
+        );
         let principal = api::get_principal(
             &env,
             canister_id,
             principal_1(),
             user_number,
             "https://some-frontend.com".to_string(),
+            None,
         )?;
         assert_ne!(principal, Principal::anonymous());
         Ok(())
@@ -235,6 +255,11 @@ mod registration_tests {
                 assigned_user_number_range: Some((127, 129)),
                 archive_module_hash: None,
                 canister_creation_cycles_cost: None,
+                layout_migration_batch_size: None,
+                ic_root_key_der: None,
+                webauthn_verification: None,
+                register_rate_limit: None,
+                captcha_type: None,
             }),
         );
 
@@ -285,31 +310,18 @@ mod registration_tests {
         Ok(())
     }
 
-    /// Verifies that non-recovery devices cannot be registered as protected.
+    /// Verifies that devices other than recovery phrases can be registered as protected.
     #[test]
-    fn should_not_register_non_recovery_device_as_protected() -> Result<(), CallError> {
+    fn should_register_non_recovery_device_as_protected() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = install_ii_canister(&env, II_WASM.clone());
         let mut device1 = device_data_1();
         device1.protection = DeviceProtection::Protected;
 
-        let challenge = api::create_challenge(&&env, canister_id)?;
-        let result = api::register(
-            &env,
-            canister_id,
-            principal_1(),
-            &device1,
-            ChallengeAttempt {
-                chars: "a".to_string(),
-                key: challenge.challenge_key,
-            },
-        );
+        let user_number = flows::register_anchor_with(&env, canister_id, principal_1(), &device1);
 
-        expect_user_error_with_message(
-            result,
-            CanisterCalledTrap,
-            Regex::new("Only recovery phrases can be protected but key type is Unknown").unwrap(),
-        );
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices, vec![device1]);
         Ok(())
     }
 
@@ -336,7 +348,6 @@ mod registration_tests {
     }
 
     /// Tests that there is a time limit for captchas.
-    /// Currently only checked by create_challenge, see L2-766.
     #[test]
     fn should_not_allow_expired_captcha() -> Result<(), CallError> {
         let env = StateMachine::new();
@@ -345,8 +356,6 @@ mod registration_tests {
         let challenge = api::create_challenge(&env, canister_id)?;
         env.advance_time(Duration::from_secs(301)); // one second longer than captcha validity
 
-        // required because register does not check captcha expiry
-        api::create_challenge(&env, canister_id)?;
         let result = api::register(
             &env,
             canister_id,
@@ -362,11 +371,43 @@ mod registration_tests {
         Ok(())
     }
 
+    /// Tests that captchas created before an upgrade can be used after the upgrade.
+    #[test]
+    fn should_keep_captcha_across_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+        let challenge = api::create_challenge(&env, canister_id)?;
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+        let result = api::register(
+            &env,
+            canister_id,
+            principal_1(),
+            &device_data_1(),
+            ChallengeAttempt {
+                chars: "a".to_string(),
+                key: challenge.challenge_key,
+            },
+        )?;
+
+        assert!(matches!(result, RegisterResponse::Registered { .. }));
+        Ok(())
+    }
+
     /// Tests that there is a maximum number of captchas that can be created in a given timeframe.
     #[test]
     fn should_limit_captcha_creation() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        // the default rate limit would kick in first
+        let canister_id = install_ii_canister_with_arg(
+            &env,
+            II_WASM.clone(),
+            arg_with_rate_limit(RateLimitConfig {
+                time_per_token_ns: Duration::from_secs(10).as_nanos() as u64,
+                max_tokens: 1_000,
+            }),
+        );
 
         for _ in 0..500 {
             api::create_challenge(&env, canister_id)?;
@@ -380,6 +421,250 @@ mod registration_tests {
         );
         Ok(())
     }
+
+    /// Tests that image captchas are issued by default.
+    #[test]
+    fn should_issue_image_captcha_by_default() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+        let challenge = api::create_challenge(&env, canister_id)?;
+        assert_eq!(challenge.captcha, CaptchaChallenge::Image);
+        assert!(!challenge.png_base64.is_empty());
+        Ok(())
+    }
+
+    /// Tests registration using a text captcha (arithmetic question).
+    #[test]
+    fn should_register_with_text_captcha() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister_with_arg(
+            &env,
+            II_WASM.clone(),
+            arg_with_captcha_type(CaptchaType::Text),
+        );
+
+        let challenge = api::create_challenge(&env, canister_id)?;
+        assert!(challenge.png_base64.is_empty());
+        let question = match challenge.captcha {
+            CaptchaChallenge::Text { question } => question,
+            captcha => panic!("expected text captcha, got {:?}", captcha),
+        };
+        let words: Vec<&str> = question.trim_end_matches('?').split(' ').collect();
+        let (a, b): (u32, u32) = (words[2].parse().unwrap(), words[4].parse().unwrap());
+        let answer = match words[3] {
+            "plus" => a + b,
+            "minus" => a - b,
+            "times" => a * b,
+            operator => panic!("unexpected operator {}", operator),
+        };
+
+        let result = api::register(
+            &env,
+            canister_id,
+            principal_1(),
+            &device_data_1(),
+            ChallengeAttempt {
+                chars: answer.to_string(),
+                key: challenge.challenge_key,
+            },
+        )?;
+        assert!(matches!(result, RegisterResponse::Registered { .. }));
+        Ok(())
+    }
+
+    /// Tests registration using a proof of work captcha.
+    #[test]
+    fn should_register_with_proof_of_work_captcha() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister_with_arg(
+            &env,
+            II_WASM.clone(),
+            arg_with_captcha_type(CaptchaType::ProofOfWork),
+        );
+
+        let challenge = api::create_challenge(&env, canister_id)?;
+        let result = api::register(
+            &env,
+            canister_id,
+            principal_1(),
+            &device_data_1(),
+            ChallengeAttempt {
+                chars: "not a solution".to_string(),
+                key: challenge.challenge_key,
+            },
+        )?;
+        assert!(matches!(result, RegisterResponse::BadChallenge));
+
+        let challenge = api::create_challenge(&env, canister_id)?;
+        let result = api::register(
+            &env,
+            canister_id,
+            principal_1(),
+            &device_data_1(),
+            ChallengeAttempt {
+                chars: solve_proof_of_work(&challenge.captcha),
+                key: challenge.challenge_key,
+            },
+        )?;
+        assert!(matches!(result, RegisterResponse::Registered { .. }));
+        Ok(())
+    }
+
+    /// Tests that the proof of work difficulty is derived from the recent registrations.
+    #[test]
+    fn should_derive_proof_of_work_difficulty_from_recent_registrations() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister_with_arg(
+            &env,
+            II_WASM.clone(),
+            arg_with_captcha_type(CaptchaType::ProofOfWork),
+        );
+        for _ in 0..2 {
+            let challenge = api::create_challenge(&env, canister_id)?;
+            let result = api::register(
+                &env,
+                canister_id,
+                principal_1(),
+                &device_data_1(),
+                ChallengeAttempt {
+                    chars: solve_proof_of_work(&challenge.captcha),
+                    key: challenge.challenge_key,
+                },
+            )?;
+            assert!(matches!(result, RegisterResponse::Registered { .. }));
+        }
+
+        let metrics = flows::get_metrics(&env, canister_id);
+        assert_metric(&metrics, "internet_identity_recent_registrations", 2);
+        let (difficulty, _) = parse_metric(&metrics, "internet_identity_pow_difficulty");
+        let challenge = api::create_challenge(&env, canister_id)?;
+        assert!(matches!(
+            challenge.captcha,
+            CaptchaChallenge::ProofOfWork { difficulty: d, .. } if d as u64 == difficulty
+        ));
+
+        env.advance_time(Duration::from_secs(2 * 60 * 60));
+        let metrics = flows::get_metrics(&env, canister_id);
+        assert_metric(&metrics, "internet_identity_recent_registrations", 0);
+        Ok(())
+    }
+
+    fn solve_proof_of_work(captcha: &CaptchaChallenge) -> String {
+        let (nonce, difficulty) = match captcha {
+            CaptchaChallenge::ProofOfWork { nonce, difficulty } => (nonce, *difficulty as u32),
+            captcha => panic!("expected proof of work captcha, got {:?}", captcha),
+        };
+        (0u64..)
+            .map(|i| i.to_string())
+            .find(|candidate| {
+                let hash = Sha256::new()
+                    .chain_update(nonce.as_bytes())
+                    .chain_update(candidate.as_bytes())
+                    .finalize();
+                let leading_zeros = hash
+                    .iter()
+                    .position(|byte| *byte != 0)
+                    .map_or(256, |i| i as u32 * 8 + hash[i].leading_zeros());
+                leading_zeros >= difficulty
+            })
+            .unwrap()
+    }
+
+    /// Tests that the creation of captchas is rate limited and that the configured rate limit is kept
+    /// across upgrades.
+    #[test]
+    fn should_rate_limit_captcha_creation() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister_with_arg(
+            &env,
+            II_WASM.clone(),
+            arg_with_rate_limit(RateLimitConfig {
+                time_per_token_ns: Duration::from_secs(10).as_nanos() as u64,
+                max_tokens: 2,
+            }),
+        );
+
+        api::create_challenge(&env, canister_id)?;
+        api::create_challenge(&env, canister_id)?;
+        let result = api::create_challenge(&env, canister_id);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("rate limit reached, try again later").unwrap(),
+        );
+
+        env.advance_time(Duration::from_secs(10));
+        api::create_challenge(&env, canister_id)?;
+
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+        api::create_challenge(&env, canister_id)?;
+        api::create_challenge(&env, canister_id)?;
+        let result = api::create_challenge(&env, canister_id);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("rate limit reached, try again later").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Tests that registrations are rate limited independently of the creation of captchas and that
+    /// the captcha of a rate limited registration can be used again.
+    #[test]
+    fn should_rate_limit_registrations() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister_with_arg(
+            &env,
+            II_WASM.clone(),
+            arg_with_rate_limit(RateLimitConfig {
+                time_per_token_ns: Duration::from_secs(10).as_nanos() as u64,
+                max_tokens: 2,
+            }),
+        );
+        let mut challenges = vec![
+            api::create_challenge(&env, canister_id)?,
+            api::create_challenge(&env, canister_id)?,
+        ];
+        env.advance_time(Duration::from_secs(10));
+        challenges.push(api::create_challenge(&env, canister_id)?);
+
+        let mut responses = vec![];
+        for challenge in &challenges {
+            responses.push(api::register(
+                &env,
+                canister_id,
+                principal_1(),
+                &device_data_1(),
+                ChallengeAttempt {
+                    chars: "a".to_string(),
+                    key: challenge.challenge_key.clone(),
+                },
+            )?);
+        }
+        assert!(matches!(responses[0], RegisterResponse::Registered { .. }));
+        assert!(matches!(responses[1], RegisterResponse::Registered { .. }));
+        assert!(matches!(responses[2], RegisterResponse::RateLimited));
+        assert_metric(
+            &flows::get_metrics(&env, canister_id),
+            "internet_identity_rate_limited_registrations_counter",
+            1,
+        );
+
+        env.advance_time(Duration::from_secs(10));
+        let response = api::register(
+            &env,
+            canister_id,
+            principal_1(),
+            &device_data_1(),
+            ChallengeAttempt {
+                chars: "a".to_string(),
+                key: challenges[2].challenge_key.clone(),
+            },
+        )?;
+        assert!(matches!(response, RegisterResponse::Registered { .. }));
+        Ok(())
+    }
 }
 
 /// Tests related to stable memory. In particular, the tests in this module make sure that II can be recovered from a stable memory backup.
@@ -408,6 +693,9 @@ mod stable_memory_tests {
             credential_id: Some(ByteBuf::from(hex::decode(CREDENTIAL_ID_1).unwrap())),
             key_type: KeyType::Unknown,
             protection: DeviceProtection::Unprotected,
+            created_at: None,
+            last_used: None,
+            protection_policy: None,
         };
         let device2 = DeviceData {
             pubkey: ByteBuf::from(hex::decode(PUB_KEY_2).unwrap()),
@@ -416,6 +704,9 @@ mod stable_memory_tests {
             credential_id: Some(ByteBuf::from(hex::decode(CREDENTIAL_ID_2).unwrap())),
             key_type: KeyType::Unknown,
             protection: DeviceProtection::Unprotected,
+            created_at: None,
+            last_used: None,
+            protection_policy: None,
         };
         let device3 = DeviceData {
             pubkey: ByteBuf::from(hex::decode(PUB_KEY_3).unwrap()),
@@ -424,6 +715,9 @@ mod stable_memory_tests {
             credential_id: Some(ByteBuf::from(hex::decode(CREDENTIAL_ID_3).unwrap())),
             key_type: KeyType::Unknown,
             protection: DeviceProtection::Unprotected,
+            created_at: None,
+            last_used: None,
+            protection_policy: None,
         };
         let device4 = DeviceData {
             pubkey: ByteBuf::from(hex::decode(PUB_KEY_4).unwrap()),
@@ -432,6 +726,9 @@ mod stable_memory_tests {
             credential_id: Some(ByteBuf::from(hex::decode(CREDENTIAL_ID_4).unwrap())),
             key_type: KeyType::Unknown,
             protection: DeviceProtection::Unprotected,
+            created_at: None,
+            last_used: None,
+            protection_policy: None,
         };
         let device5 = DeviceData {
             pubkey: ByteBuf::from(hex::decode(PUB_KEY_5).unwrap()),
@@ -440,6 +737,9 @@ mod stable_memory_tests {
             credential_id: None,
             key_type: KeyType::Unknown,
             protection: DeviceProtection::Unprotected,
+            created_at: None,
+            last_used: None,
+            protection_policy: None,
         };
         let device6 = DeviceData {
             pubkey: ByteBuf::from(hex::decode(PUB_KEY_6).unwrap()),
@@ -448,6 +748,9 @@ mod stable_memory_tests {
             credential_id: None,
             key_type: KeyType::Unknown,
             protection: DeviceProtection::Unprotected,
+            created_at: None,
+            last_used: None,
+            protection_policy: None,
         };
 
         let env = StateMachine::new();
@@ -500,6 +803,8 @@ mod stable_memory_tests {
             "example.com".to_string(),
             ByteBuf::from("dummykey"),
             None,
+            None,
+            None,
         )?;
 
         // check that we get the same user key; this proves that the salt was recovered from the backup
@@ -514,6 +819,7 @@ mod stable_memory_tests {
             principal,
             10_030,
             "example.com".to_string(),
+            None,
         )?;
         assert_eq!(Principal::self_authenticating(user_key), principal);
         Ok(())
@@ -570,6 +876,8 @@ mod stable_memory_tests {
             frontend_hostname.clone(),
             session_key.clone(),
             None,
+            None,
+            None,
         )?;
         api::prepare_delegation(
             &env,
@@ -579,6 +887,8 @@ mod stable_memory_tests {
             frontend_hostname,
             session_key,
             None,
+            None,
+            None,
         )?;
         Ok(())
     }
@@ -682,57 +992,226 @@ mod stable_memory_tests {
         );
         Ok(())
     }
-}
-
-/// Tests related to local device management (add, remove, lookup, get_anchor_info).
-/// Tests for the 'add remote device flow' are in the module [remote_device_registration_tests].
-#[cfg(test)]
-mod device_management_tests {
-    use super::*;
 
-    /// Verifies that a new device can be added.
+    /// Verifies that changes to the persistent state are written to stable memory immediately and
+    /// not only in `pre_upgrade`.
     #[test]
-    fn should_add_additional_device() -> Result<(), CallError> {
+    fn should_persist_state_without_upgrade() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-
-        api::add(
+        // use the latest layout, which keeps the stable memory small enough to be copied
+        let canister_id = install_ii_canister_with_arg(
             &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            device_data_2(),
-        )?;
-        let mut devices = api::lookup(&env, canister_id, user_number)?;
-        assert_eq!(devices.len(), 2);
-        assert!(devices.iter().any(|device| device == &device_data_1()));
-        assert!(devices.iter().any(|device| device == &device_data_2()));
-
-        let mut anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
-
-        // sort devices to not fail on different orderings
-        devices.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
-        anchor_info.devices.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
-        assert_eq!(devices, anchor_info.devices);
+            II_WASM.clone(),
+            Some(InternetIdentityInit {
+                assigned_user_number_range: None,
+                archive_module_hash: Some([1u8; 32]),
+                canister_creation_cycles_cost: Some(123),
+                layout_migration_batch_size: Some(100),
+                ic_root_key_der: None,
+                webauthn_verification: None,
+                register_rate_limit: None,
+                captcha_type: None,
+            }),
+        );
+        flows::register_anchor(&env, canister_id);
+        flows::register_anchor(&env, canister_id);
+
+        // copy the stable memory to a canister that never ran the pre_upgrade hook of II
+        let restored_canister_id = install_ii_canister(&env, EMPTY_WASM.clone());
+        env.set_stable_memory(restored_canister_id, &env.stable_memory(canister_id));
+        upgrade_ii_canister(&env, restored_canister_id, II_WASM.clone());
+
+        let stats = api::stats(&env, restored_canister_id)?;
+        assert_eq!(stats.canister_creation_cycles_cost, 123);
+        assert_eq!(stats.archive_info.expected_wasm_hash, Some([1u8; 32]));
+        assert_eq!(stats.users_registered, 2);
         Ok(())
     }
 
-    /// Verifies that the same device cannot be added twice.
+    /// Verifies that the genesis layout is kept (so that II can be rolled back) as long as no
+    /// layout migration batch size is set.
     #[test]
-    fn should_not_add_existing_device() -> Result<(), CallError> {
+    fn should_keep_genesis_layout_without_migration_batch_size() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let canister_id = install_ii_canister(&env, EMPTY_WASM.clone());
+
+        let stable_memory_backup =
+            std::fs::read(PathBuf::from("stable_memory/genesis-memory-layout.bin")).unwrap();
+        env.set_stable_memory(canister_id, &stable_memory_backup);
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+        let devices_before = api::lookup(&env, canister_id, 10_030)?;
         let user_number = flows::register_anchor(&env, canister_id);
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
 
-        let result = api::add(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            device_data_1(), // this device was already added during registration
+        let stats = api::stats(&env, canister_id)?;
+        assert!(stats.storage_layout_version < 3);
+        assert_eq!(stats.layout_migration_state, MigrationState::NotStarted);
+        assert_eq!(api::lookup(&env, canister_id, 10_030)?, devices_before);
+        assert_eq!(
+            api::lookup(&env, canister_id, user_number)?,
+            vec![device_data_1()]
         );
-
+        Ok(())
+    }
+
+    /// Verifies that the anchors of a genesis layout backup are migrated to layout version 3 in
+    /// batches (one per upgrade) and stay available throughout the migration.
+    #[test]
+    fn should_migrate_genesis_layout_in_batches() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, EMPTY_WASM.clone());
+
+        let stable_memory_backup =
+            std::fs::read(PathBuf::from("stable_memory/genesis-memory-layout.bin")).unwrap();
+        env.set_stable_memory(canister_id, &stable_memory_backup);
+        upgrade_ii_canister_with_arg(
+            &env,
+            canister_id,
+            II_WASM.clone(),
+            Some(InternetIdentityInit {
+                assigned_user_number_range: None,
+                archive_module_hash: None,
+                canister_creation_cycles_cost: None,
+                layout_migration_batch_size: Some(10),
+                ic_root_key_der: None,
+                webauthn_verification: None,
+                register_rate_limit: None,
+                captcha_type: None,
+            }),
+        )
+        .unwrap();
+
+        let stats = api::stats(&env, canister_id)?;
+        assert_eq!(stats.storage_layout_version, 3);
+        assert_eq!(
+            stats.layout_migration_state,
+            MigrationState::InProgress {
+                anchors_left: 21,
+                batch_size: 10
+            }
+        );
+        let devices_before = api::lookup(&env, canister_id, 10_030)?;
+
+        // no batches are migrated between upgrades
+        env.tick();
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+        let stats = api::stats(&env, canister_id)?;
+        assert_eq!(
+            stats.layout_migration_state,
+            MigrationState::InProgress {
+                anchors_left: 11,
+                batch_size: 10
+            }
+        );
+
+        for _ in 0..2 {
+            upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+        }
+        let stats = api::stats(&env, canister_id)?;
+        assert_eq!(stats.layout_migration_state, MigrationState::Finished);
+        assert_eq!(stats.users_registered, 31);
+        assert_eq!(api::lookup(&env, canister_id, 10_030)?, devices_before);
+
+        // anchors registered after the migration are stored as well
+        let user_number = flows::register_anchor(&env, canister_id);
+        assert_eq!(user_number, 10_031);
+        Ok(())
+    }
+
+    /// Verifies that the layout migration can be paused using the init arguments.
+    #[test]
+    fn should_pause_layout_migration() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, EMPTY_WASM.clone());
+
+        let stable_memory_backup =
+            std::fs::read(PathBuf::from("stable_memory/genesis-memory-layout.bin")).unwrap();
+        env.set_stable_memory(canister_id, &stable_memory_backup);
+        upgrade_ii_canister_with_arg(
+            &env,
+            canister_id,
+            II_WASM.clone(),
+            Some(InternetIdentityInit {
+                assigned_user_number_range: None,
+                archive_module_hash: None,
+                canister_creation_cycles_cost: None,
+                layout_migration_batch_size: Some(0),
+                ic_root_key_der: None,
+                webauthn_verification: None,
+                register_rate_limit: None,
+                captcha_type: None,
+            }),
+        )
+        .unwrap();
+
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+        let stats = api::stats(&env, canister_id)?;
+        assert_eq!(
+            stats.layout_migration_state,
+            MigrationState::Paused { anchors_left: 31 }
+        );
+        Ok(())
+    }
+}
+
+/// Tests related to local device management (add, remove, lookup, get_anchor_info).
+/// Tests for the 'add remote device flow' are in the module [remote_device_registration_tests].
+#[cfg(test)]
+mod device_management_tests {
+    use super::*;
+
+    /// Verifies that a new device can be added.
+    #[test]
+    fn should_add_additional_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        let mut devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices.len(), 2);
+        assert!(devices.iter().any(|device| device == &device_data_1()));
+        assert!(devices.iter().any(|device| device == &device_data_2()));
+
+        let mut anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+
+        // the devices only differ in the usage, which is not returned by lookup
+        let now = env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        for device in devices.iter_mut() {
+            device.created_at = Some(now);
+            if device.pubkey == device_data_1().pubkey {
+                device.last_used = Some(now);
+            }
+        }
+
+        // sort devices to not fail on different orderings
+        devices.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
+        anchor_info.devices.sort_by(|a, b| a.pubkey.cmp(&b.pubkey));
+        assert_eq!(devices, anchor_info.devices);
+        Ok(())
+    }
+
+    /// Verifies that the same device cannot be added twice.
+    #[test]
+    fn should_not_add_existing_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_1(), // this device was already added during registration
+        );
+
         expect_user_error_with_message(
             result,
             CanisterCalledTrap,
@@ -977,30 +1456,27 @@ mod device_management_tests {
             );
         }
 
-        /// Verifies that non-recovery devices cannot be updated to be protected.
+        /// Verifies that devices other than recovery phrases can be updated to be protected.
         #[test]
-        fn should_not_update_non_recovery_device_to_be_protected() {
+        fn should_update_non_recovery_device_to_be_protected() -> Result<(), CallError> {
             let env = StateMachine::new();
             let canister_id = install_ii_canister(&env, II_WASM.clone());
             let user_number = flows::register_anchor(&env, canister_id);
 
             let mut device1 = device_data_1();
             device1.protection = DeviceProtection::Protected;
-            let result = api::update(
+            api::update(
                 &env,
                 canister_id,
                 principal_1(),
                 user_number,
                 device1.pubkey.clone(),
-                device1.clone(), // data here doesnt' actually matter
-            );
+                device1.clone(),
+            )?;
 
-            expect_user_error_with_message(
-                result,
-                CanisterCalledTrap,
-                Regex::new("Only recovery phrases can be protected but key type is Unknown")
-                    .unwrap(),
-            );
+            let devices = api::lookup(&env, canister_id, user_number)?;
+            assert_eq!(devices, vec![device1]);
+            Ok(())
         }
     }
 
@@ -1199,572 +1675,3319 @@ mod device_management_tests {
             Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
         );
     }
-}
 
-/// Tests related to prepare_delegation, get_delegation and get_principal II canister calls.
-#[cfg(test)]
-mod delegation_tests {
-    use super::*;
-
-    /// Verifies that valid delegations are issued.
+    /// Verifies that anchors can be looked up by the credential ids and public keys of their devices.
     #[test]
-    fn should_get_valid_delegation() -> Result<(), CallError> {
+    fn should_lookup_anchors_by_device() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let mut device = device_data_2();
+        device.credential_id = Some(ByteBuf::from("credential id of device 2"));
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
-
-        let (canister_sig_key, expiration) = api::prepare_delegation(
+        let other_user_number = flows::register_anchor(&env, canister_id);
+        api::add(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            None,
+            device.clone(),
         )?;
-        assert_eq!(
-            expiration,
-            env.time()
-                .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64
-        );
 
-        let signed_delegation = match api::get_delegation(
+        let anchors = api::lookup_anchors(
             &env,
             canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(delegation) => delegation,
-            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
-        };
+            DeviceIdentifier::CredentialId(device.credential_id.clone().unwrap()),
+        )?;
+        assert_eq!(anchors, vec![user_number]);
 
-        verify_delegation(&env, canister_sig_key, &signed_delegation);
-        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
-        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        let anchors = api::lookup_anchors(
+            &env,
+            canister_id,
+            DeviceIdentifier::PublicKey(device_data_1().pubkey),
+        )?;
+        assert_eq!(anchors, vec![user_number, other_user_number]);
         Ok(())
     }
 
-    /// Verifies that non-default expirations are respected.
+    /// Verifies that removed devices can no longer be used to look up the anchor.
     #[test]
-    fn should_get_valid_delegation_with_custom_expiration() -> Result<(), CallError> {
+    fn should_not_lookup_anchor_by_removed_device() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let mut device = device_data_2();
+        device.credential_id = Some(ByteBuf::from("credential id of device 2"));
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
-
-        let (canister_sig_key, expiration) = api::prepare_delegation(
+        api::add(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            Some(3_600_000_000_000), // 1 hour
+            device.clone(),
         )?;
-        assert_eq!(
-            expiration,
-            env.time()
-                .add(Duration::from_secs(60 * 60)) // 1 hour
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64
-        );
 
-        let signed_delegation = match api::get_delegation(
+        api::remove(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(delegation) => delegation,
-            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
-        };
+            device.pubkey.clone(),
+        )?;
 
-        verify_delegation(&env, canister_sig_key, &signed_delegation);
-        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
-        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        let anchors = api::lookup_anchors(
+            &env,
+            canister_id,
+            DeviceIdentifier::CredentialId(device.credential_id.unwrap()),
+        )?;
+        assert!(anchors.is_empty());
         Ok(())
     }
 
-    /// Verifies that the delegations are valid at most for 30 days.
+    /// Verifies that the time a device was added and last used is recorded, at most once per day.
     #[test]
-    fn should_shorten_expiration_greater_max_ttl() -> Result<(), CallError> {
+    fn should_record_device_usage() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = install_ii_canister(&env, II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
-
-        let (canister_sig_key, expiration) = api::prepare_delegation(
+        let registered_at = env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        env.advance_time(Duration::from_secs(60));
+        api::add(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            Some(Duration::from_secs(31 * 24 * 60 * 60).as_nanos() as u64), // 31 days
+            device_data_2(),
         )?;
+        let added_at = env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+
+        let devices = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?.devices;
         assert_eq!(
-            expiration,
-            env.time()
-                .add(Duration::from_secs(30 * 24 * 60 * 60)) // 30 days
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64
+            devices,
+            vec![
+                DeviceData {
+                    created_at: Some(registered_at),
+                    last_used: Some(registered_at),
+                    ..device_data_1()
+                },
+                DeviceData {
+                    created_at: Some(added_at),
+                    last_used: None,
+                    ..device_data_2()
+                },
+            ]
         );
 
-        let signed_delegation = match api::get_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(delegation) => delegation,
-            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
-        };
-
-        verify_delegation(&env, canister_sig_key, &signed_delegation);
-        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
-        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        // the usage is only recorded again after a day
+        for (advance, expected_last_used) in [
+            (Duration::from_secs(60 * 60), registered_at),
+            (
+                Duration::from_secs(24 * 60 * 60),
+                registered_at + Duration::from_secs(25 * 60 * 60 + 60).as_nanos() as u64,
+            ),
+        ] {
+            env.advance_time(advance);
+            api::prepare_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                "https://some-dapp.com".to_string(),
+                ByteBuf::from("session public key"),
+                None,
+                None,
+                None,
+            )?;
+            let devices =
+                api::get_anchor_info(&env, canister_id, principal_1(), user_number)?.devices;
+            assert_eq!(devices[0].last_used, Some(expected_last_used));
+            assert_eq!(devices[1].last_used, None);
+        }
         Ok(())
     }
 
-    /// Verifies that delegations can be requested in parallel.
+    /// Verifies that the usage of devices is not returned by lookup and cannot be changed by the client.
     #[test]
-    fn should_get_multiple_valid_delegations() -> Result<(), CallError> {
+    fn should_protect_device_usage() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = install_ii_canister(&env, II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname_1 = "https://dapp1.com";
+        let registered_at = env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+
+        assert_eq!(
+            api::lookup(&env, canister_id, user_number)?,
+            vec![device_data_1()]
+        );
+
+        let mut device = device_data_1();
+        device.alias = "new alias".to_string();
+        device.created_at = Some(0);
+        device.last_used = Some(0);
+        api::update(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+            device.clone(),
+        )?;
+
+        let devices = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?.devices;
+        assert_eq!(
+            devices,
+            vec![DeviceData {
+                created_at: Some(registered_at),
+                last_used: Some(registered_at),
+                ..device
+            }]
+        );
+        Ok(())
+    }
+}
+
+/// Tests for the deletion of anchors.
+#[cfg(test)]
+mod anchor_deletion_tests {
+    use super::*;
+
+    /// Verifies that a deleted anchor can no longer be used.
+    #[test]
+    fn should_delete_anchor() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::delete_anchor(&env, canister_id, principal_1(), user_number)?;
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices.is_empty());
+        let anchors = api::lookup_anchors(
+            &env,
+            canister_id,
+            DeviceIdentifier::PublicKey(device_data_1().pubkey),
+        )?;
+        assert!(anchors.is_empty());
+
+        let result = api::get_anchor_info(&env, canister_id, principal_1(), user_number);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Identity Anchor \\d+ has been deleted").unwrap(),
+        );
+        let result = api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Identity Anchor \\d+ has been deleted").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that the number of a deleted anchor is not assigned again, even after an upgrade.
+    #[test]
+    fn should_not_reassign_deleted_anchor() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::delete_anchor(&env, canister_id, principal_1(), user_number)?;
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+        let new_user_number = flows::register_anchor(&env, canister_id);
+        assert_eq!(new_user_number, user_number + 1);
+        let result = api::get_anchor_info(&env, canister_id, principal_1(), user_number);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Identity Anchor \\d+ has been deleted").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that prepared delegations can no longer be fetched once the anchor is deleted.
+    #[test]
+    fn should_invalidate_prepared_delegations() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (_, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        api::delete_anchor(&env, canister_id, principal_1(), user_number)?;
+
+        let result = api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key,
+            expiration,
+            None,
+            None,
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Identity Anchor \\d+ has been deleted").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that users can only delete their own anchors.
+    #[test]
+    fn should_not_delete_anchor_of_different_user() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+        let user_number_2 =
+            flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+
+        let result = api::delete_anchor(&env, canister_id, principal_1(), user_number_2);
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+
+    /// Verifies that an anchor with a protected device can only be deleted using that device.
+    #[test]
+    fn should_not_delete_anchor_with_protected_device_using_different_device(
+    ) -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let mut device1 = device_data_1();
+        device1.protection = DeviceProtection::Protected;
+        device1.key_type = KeyType::SeedPhrase;
+        let user_number = flows::register_anchor_with(&env, canister_id, principal_1(), &device1);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+
+        let result = api::delete_anchor(&env, canister_id, principal_2(), user_number);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Device is protected. Must be authenticated with this device to mutate")
+                .unwrap(),
+        );
+
+        api::delete_anchor(&env, canister_id, principal_1(), user_number)?;
+        Ok(())
+    }
+}
+
+/// Tests for the recovery delay, which delays device changes made with recovery devices.
+#[cfg(test)]
+mod recovery_delay_tests {
+    use super::*;
+    use ic_state_machine_tests::CanisterId;
+
+    const RECOVERY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// Registers an anchor with an authentication and a recovery device and enables the recovery
+    /// delay.
+    fn setup(env: &StateMachine, canister_id: CanisterId) -> Result<UserNumber, CallError> {
+        let user_number = flows::register_anchor(env, canister_id);
+        api::add(
+            env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        api::set_recovery_delay(
+            env,
+            canister_id,
+            principal_1(),
+            user_number,
+            Some(RECOVERY_DELAY.as_nanos() as u64),
+        )?;
+        Ok(user_number)
+    }
+
+    /// Verifies that devices added with a recovery device are only added once the delay has
+    /// passed, whereas authentication devices are not affected by the delay.
+    #[test]
+    fn should_delay_device_changes_made_with_recovery_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = setup(&env, canister_id)?;
+        assert_eq!(
+            api::get_recovery_delay(&env, canister_id, principal_1(), user_number)?,
+            Some(RECOVERY_DELAY.as_nanos() as u64)
+        );
+
+        api::add(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        let queued_at = env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(!devices.iter().any(|device| device == &device_data_2()));
+        let pending =
+            api::get_pending_recovery_operations(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(
+            pending,
+            vec![PendingRecoveryOperation {
+                id: 1,
+                change: RecoveryDeviceChange::Add(device_data_2()),
+                queued_by: principal_recovery_1().0,
+                queued_at,
+                effective_at: queued_at + RECOVERY_DELAY.as_nanos() as u64,
+            }]
+        );
+
+        env.advance_time(RECOVERY_DELAY);
+        env.tick();
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices.iter().any(|device| device == &device_data_2()));
+        let pending =
+            api::get_pending_recovery_operations(&env, canister_id, principal_1(), user_number)?;
+        assert!(pending.is_empty());
+
+        // changes made with authentication devices are applied immediately
+        api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2().pubkey,
+        )?;
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(!devices.iter().any(|device| device == &device_data_2()));
+        Ok(())
+    }
+
+    /// Verifies that pending recovery operations can be cancelled with an authentication device
+    /// but not with a recovery device.
+    #[test]
+    fn should_cancel_pending_recovery_operations() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = setup(&env, canister_id)?;
+
+        api::remove(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            device_data_1().pubkey,
+        )?;
+
+        let result = api::cancel_pending_recovery_operations(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ is a recovery device, an authentication device is required.")
+                .unwrap(),
+        );
+
+        api::cancel_pending_recovery_operations(&env, canister_id, principal_1(), user_number)?;
+        let pending =
+            api::get_pending_recovery_operations(&env, canister_id, principal_1(), user_number)?;
+        assert!(pending.is_empty());
+
+        env.advance_time(RECOVERY_DELAY);
+        env.tick();
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices.iter().any(|device| device == &device_data_1()));
+        Ok(())
+    }
+
+    /// Verifies that ids of cancelled recovery operations are not handed out again.
+    #[test]
+    fn should_not_reuse_recovery_operation_ids() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = setup(&env, canister_id)?;
+
+        api::add(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        api::cancel_pending_recovery_operations(&env, canister_id, principal_1(), user_number)?;
+
+        api::add(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        let pending =
+            api::get_pending_recovery_operations(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, 2);
+        Ok(())
+    }
+
+    /// Verifies that a recovery device can neither disable the recovery delay nor delete the
+    /// anchor to get around it.
+    #[test]
+    fn should_not_bypass_recovery_delay_with_recovery_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = setup(&env, canister_id)?;
+
+        let result =
+            api::set_recovery_delay(&env, canister_id, principal_recovery_1(), user_number, None);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ is a recovery device, an authentication device is required.")
+                .unwrap(),
+        );
+
+        let result = api::delete_anchor(&env, canister_id, principal_recovery_1(), user_number);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new(
+                "Anchor cannot be deleted with a recovery device while the recovery delay is enabled",
+            )
+            .unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that the recovery delay must be within the allowed range.
+    #[test]
+    fn should_not_set_recovery_delay_outside_of_range() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result =
+            api::set_recovery_delay(&env, canister_id, principal_1(), user_number, Some(1));
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("recovery delay 1 is not within the allowed range").unwrap(),
+        );
+    }
+}
+
+/// Tests for the social recovery, where guardian anchors approve adding a device to an anchor.
+#[cfg(test)]
+mod guardian_recovery_tests {
+    use super::*;
+    use ic_state_machine_tests::CanisterId;
+
+    const NEW_DEVICE_PUBKEY: &str = "new device";
+
+    fn new_device() -> DeviceData {
+        DeviceData {
+            pubkey: ByteBuf::from(NEW_DEVICE_PUBKEY),
+            alias: "New Device".to_string(),
+            ..device_data_1()
+        }
+    }
+
+    fn principal_new_device() -> PrincipalId {
+        PrincipalId(Principal::self_authenticating(NEW_DEVICE_PUBKEY))
+    }
+
+    /// Registers an anchor protected by two guardian anchors (both of which must approve) and
+    /// returns the numbers of the anchor and the guardians.
+    fn setup(
+        env: &StateMachine,
+        canister_id: CanisterId,
+    ) -> Result<(UserNumber, UserNumber, UserNumber), CallError> {
+        let user_number = flows::register_anchor(env, canister_id);
+        let guardian_1 =
+            flows::register_anchor_with(env, canister_id, principal_2(), &device_data_2());
+        let guardian_2 = flows::register_anchor_with(
+            env,
+            canister_id,
+            principal_recovery_1(),
+            &recovery_device_data_1(),
+        );
+        api::set_guardians(
+            env,
+            canister_id,
+            principal_1(),
+            user_number,
+            Some(Guardians {
+                anchors: vec![guardian_1, guardian_2],
+                threshold: 2,
+            }),
+        )?;
+        Ok((user_number, guardian_1, guardian_2))
+    }
+
+    /// Verifies that the device is added once the threshold of guardians approved it.
+    #[test]
+    fn should_add_device_approved_by_guardians() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let (user_number, guardian_1, guardian_2) = setup(&env, canister_id)?;
+
+        let response = api::start_guardian_recovery(
+            &env,
+            canister_id,
+            principal_new_device(),
+            user_number,
+            new_device(),
+        )?;
+        let expiration = env
+            .time()
+            .add(Duration::from_secs(3 * 24 * 60 * 60))
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        assert_eq!(
+            response,
+            StartGuardianRecoveryResponse::Started { expiration }
+        );
+
+        let response = api::approve_guardian_recovery(
+            &env,
+            canister_id,
+            principal_2(),
+            guardian_1,
+            user_number,
+            new_device().pubkey,
+        )?;
+        assert_eq!(
+            response,
+            ApproveGuardianRecoveryResponse::Approved {
+                approvals: 1,
+                threshold: 2
+            }
+        );
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(!devices.iter().any(|device| device == &new_device()));
+
+        let recovery = api::get_guardian_recovery(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            guardian_2,
+            user_number,
+        )?;
+        assert_eq!(
+            recovery,
+            Some(GuardianRecoveryInfo {
+                tentative_device: new_device(),
+                approvals: vec![guardian_1],
+                threshold: 2,
+                expiration,
+            })
+        );
+
+        let response = api::approve_guardian_recovery(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            guardian_2,
+            user_number,
+            new_device().pubkey,
+        )?;
+        assert_eq!(response, ApproveGuardianRecoveryResponse::DeviceAdded);
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices.iter().any(|device| device == &new_device()));
+        Ok(())
+    }
+
+    /// Verifies that a device approved by the guardians is only added once the recovery delay of
+    /// the anchor has passed, and that the user can cancel it until then.
+    #[test]
+    fn should_delay_guardian_recovery() -> Result<(), CallError> {
+        const RECOVERY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let (user_number, guardian_1, guardian_2) = setup(&env, canister_id)?;
+        api::set_recovery_delay(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            Some(RECOVERY_DELAY.as_nanos() as u64),
+        )?;
+        let approve = || -> Result<ApproveGuardianRecoveryResponse, CallError> {
+            api::start_guardian_recovery(
+                &env,
+                canister_id,
+                principal_new_device(),
+                user_number,
+                new_device(),
+            )?;
+            api::approve_guardian_recovery(
+                &env,
+                canister_id,
+                principal_2(),
+                guardian_1,
+                user_number,
+                new_device().pubkey,
+            )?;
+            api::approve_guardian_recovery(
+                &env,
+                canister_id,
+                principal_recovery_1(),
+                guardian_2,
+                user_number,
+                new_device().pubkey,
+            )
+        };
+
+        assert_eq!(approve()?, ApproveGuardianRecoveryResponse::DeviceAdded);
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(!devices.iter().any(|device| device == &new_device()));
+        let pending =
+            api::get_pending_recovery_operations(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].change, RecoveryDeviceChange::Add(new_device()));
+        assert_eq!(pending[0].queued_by, principal_new_device().0);
+
+        // the user cancels the recovery
+        api::cancel_pending_recovery_operations(&env, canister_id, principal_1(), user_number)?;
+        env.advance_time(RECOVERY_DELAY);
+        env.tick();
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(!devices.iter().any(|device| device == &new_device()));
+
+        // the user does not react
+        approve()?;
+        env.advance_time(RECOVERY_DELAY);
+        env.tick();
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices.iter().any(|device| device == &new_device()));
+        Ok(())
+    }
+
+    /// Verifies that the approvals expire together with the recovery.
+    #[test]
+    fn should_expire_guardian_approvals() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let (user_number, guardian_1, guardian_2) = setup(&env, canister_id)?;
+
+        api::start_guardian_recovery(
+            &env,
+            canister_id,
+            principal_new_device(),
+            user_number,
+            new_device(),
+        )?;
+        api::approve_guardian_recovery(
+            &env,
+            canister_id,
+            principal_2(),
+            guardian_1,
+            user_number,
+            new_device().pubkey,
+        )?;
+
+        env.advance_time(Duration::from_secs(3 * 24 * 60 * 60));
+
+        let response = api::approve_guardian_recovery(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            guardian_2,
+            user_number,
+            new_device().pubkey,
+        )?;
+        assert_eq!(
+            response,
+            ApproveGuardianRecoveryResponse::NoRecoveryInProgress
+        );
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(!devices.iter().any(|device| device == &new_device()));
+        Ok(())
+    }
+
+    /// Verifies that only guardians can approve a recovery, authenticated with their anchor.
+    #[test]
+    fn should_only_accept_approvals_of_guardians() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let (user_number, guardian_1, _) = setup(&env, canister_id)?;
+        let other_anchor = flows::register_anchor_with(
+            &env,
+            canister_id,
+            principal_recovery_2(),
+            &recovery_device_data_2(),
+        );
+        api::start_guardian_recovery(
+            &env,
+            canister_id,
+            principal_new_device(),
+            user_number,
+            new_device(),
+        )?;
+
+        let response = api::approve_guardian_recovery(
+            &env,
+            canister_id,
+            principal_recovery_2(),
+            other_anchor,
+            user_number,
+            new_device().pubkey,
+        )?;
+        assert_eq!(response, ApproveGuardianRecoveryResponse::NotAGuardian);
+
+        let result = api::approve_guardian_recovery(
+            &env,
+            canister_id,
+            principal_recovery_2(),
+            guardian_1,
+            user_number,
+            new_device().pubkey,
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that a recovery that no guardian approved yet is replaced by a new one, so that
+    /// requesting a recovery first does not block the recovery of the anchor.
+    #[test]
+    fn should_replace_unapproved_guardian_recovery() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let (user_number, guardian_1, _) = setup(&env, canister_id)?;
+        let other_device = DeviceData {
+            pubkey: ByteBuf::from("other device"),
+            ..new_device()
+        };
+        let principal_other_device = PrincipalId(Principal::self_authenticating("other device"));
+
+        api::start_guardian_recovery(
+            &env,
+            canister_id,
+            principal_other_device,
+            user_number,
+            other_device.clone(),
+        )?;
+        let response = api::start_guardian_recovery(
+            &env,
+            canister_id,
+            principal_new_device(),
+            user_number,
+            new_device(),
+        )?;
+        assert!(matches!(
+            response,
+            StartGuardianRecoveryResponse::Started { .. }
+        ));
+        let recovery =
+            api::get_guardian_recovery(&env, canister_id, principal_2(), guardian_1, user_number)?
+                .expect("no recovery in progress");
+        assert_eq!(recovery.tentative_device, new_device());
+
+        // once approved, the recovery can no longer be replaced
+        api::approve_guardian_recovery(
+            &env,
+            canister_id,
+            principal_2(),
+            guardian_1,
+            user_number,
+            new_device().pubkey,
+        )?;
+        let response = api::start_guardian_recovery(
+            &env,
+            canister_id,
+            principal_other_device,
+            user_number,
+            other_device,
+        )?;
+        assert_eq!(
+            response,
+            StartGuardianRecoveryResponse::RegistrationInProgress
+        );
+        Ok(())
+    }
+
+    /// Verifies that starting guardian recoveries is rate limited.
+    #[test]
+    fn should_rate_limit_guardian_recoveries() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister_with_arg(
+            &env,
+            II_WASM.clone(),
+            arg_with_rate_limit(RateLimitConfig {
+                time_per_token_ns: Duration::from_secs(10).as_nanos() as u64,
+                max_tokens: 3,
+            }),
+        );
+        let (user_number, _, _) = setup(&env, canister_id)?;
+
+        for _ in 0..3 {
+            api::start_guardian_recovery(
+                &env,
+                canister_id,
+                principal_new_device(),
+                user_number,
+                new_device(),
+            )?;
+        }
+        let result = api::start_guardian_recovery(
+            &env,
+            canister_id,
+            principal_new_device(),
+            user_number,
+            new_device(),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("rate limit reached, try again later").unwrap(),
+        );
+
+        env.advance_time(Duration::from_secs(10));
+        api::start_guardian_recovery(
+            &env,
+            canister_id,
+            principal_new_device(),
+            user_number,
+            new_device(),
+        )?;
+        Ok(())
+    }
+
+    /// Verifies that a recovery can only be started for anchors with guardians and only with the
+    /// device to add.
+    #[test]
+    fn should_not_start_guardian_recovery_without_guardians() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let response = api::start_guardian_recovery(
+            &env,
+            canister_id,
+            principal_new_device(),
+            user_number,
+            new_device(),
+        )?;
+        assert_eq!(response, StartGuardianRecoveryResponse::NoGuardians);
+
+        let result = api::start_guardian_recovery(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            new_device(),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated against").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that the threshold must be reachable and that an anchor cannot be its own
+    /// guardian.
+    #[test]
+    fn should_not_set_invalid_guardians() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let guardian =
+            flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+
+        let result = api::set_guardians(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            Some(Guardians {
+                anchors: vec![guardian],
+                threshold: 2,
+            }),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("threshold 2 must be between 1 and the number of guardians").unwrap(),
+        );
+
+        let result = api::set_guardians(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            Some(Guardians {
+                anchors: vec![user_number],
+                threshold: 1,
+            }),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("an anchor cannot be its own guardian").unwrap(),
+        );
+    }
+}
+
+/// Tests for device protection policies, which restrict the changes of a device made with other
+/// devices of the anchor.
+#[cfg(test)]
+mod device_protection_tests {
+    use super::*;
+    use ic_state_machine_tests::CanisterId;
+
+    fn protected_device(policy: DeviceProtectionPolicy) -> DeviceData {
+        DeviceData {
+            key_type: KeyType::CrossPlatform,
+            protection_policy: Some(policy),
+            ..device_data_2()
+        }
+    }
+
+    /// Registers an anchor and adds the second device with the given policy to it.
+    fn setup(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        policy: DeviceProtectionPolicy,
+    ) -> Result<UserNumber, CallError> {
+        let user_number = flows::register_anchor(env, canister_id);
+        api::add(
+            env,
+            canister_id,
+            principal_1(),
+            user_number,
+            protected_device(policy),
+        )?;
+        Ok(user_number)
+    }
+
+    fn expect_protected(result: Result<(), CallError>) {
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Device is protected. Must be authenticated with this device to mutate")
+                .unwrap(),
+        );
+    }
+
+    /// Verifies that a device protected from removal can only be removed with the device itself,
+    /// but can still be updated with other devices.
+    #[test]
+    fn should_only_remove_protected_device_with_device_itself() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let policy = DeviceProtectionPolicy {
+            protected_from_removal: true,
+            ..DeviceProtectionPolicy::default()
+        };
+        let user_number = setup(&env, canister_id, policy.clone())?;
+
+        let result = api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2().pubkey,
+        );
+        expect_protected(result);
+        let result = api::delete_anchor(&env, canister_id, principal_1(), user_number);
+        expect_protected(result);
+
+        let mut device = protected_device(policy);
+        device.alias = "renamed".to_string();
+        api::update(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+            device.clone(),
+        )?;
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices.contains(&device));
+
+        api::remove(&env, canister_id, principal_2(), user_number, device.pubkey)?;
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices, vec![device_data_1()]);
+        Ok(())
+    }
+
+    /// Verifies that the purpose of a device protected from purpose changes can only be changed
+    /// with the device itself.
+    #[test]
+    fn should_only_change_purpose_with_device_itself() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let policy = DeviceProtectionPolicy {
+            protected_from_purpose_change: true,
+            ..DeviceProtectionPolicy::default()
+        };
+        let user_number = setup(&env, canister_id, policy.clone())?;
+
+        let mut device = protected_device(policy);
+        device.purpose = Purpose::Recovery;
+        let result = api::update(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+            device.clone(),
+        );
+        expect_protected(result);
+
+        api::update(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device.pubkey.clone(),
+            device.clone(),
+        )?;
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices.contains(&device));
+
+        // other changes are not restricted
+        api::remove(&env, canister_id, principal_1(), user_number, device.pubkey)?;
+        Ok(())
+    }
+
+    /// Verifies that a device requiring self-authentication can neither be updated nor removed
+    /// with other devices.
+    #[test]
+    fn should_only_mutate_device_requiring_self_authentication_with_device_itself(
+    ) -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let policy = DeviceProtectionPolicy {
+            requires_self_authentication: true,
+            ..DeviceProtectionPolicy::default()
+        };
+        let user_number = setup(&env, canister_id, policy.clone())?;
+
+        let mut device = protected_device(policy);
+        device.alias = "renamed".to_string();
+        let result = api::update(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+            device.clone(),
+        );
+        expect_protected(result);
+        let result = api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device.pubkey.clone(),
+        );
+        expect_protected(result);
+
+        api::update(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device.pubkey.clone(),
+            device.clone(),
+        )?;
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert!(devices.contains(&device));
+        Ok(())
+    }
+
+    /// Verifies that the protection policy cannot be lifted with other devices.
+    #[test]
+    fn should_only_change_protection_policy_with_device_itself() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let policy = DeviceProtectionPolicy {
+            protected_from_removal: true,
+            ..DeviceProtectionPolicy::default()
+        };
+        let user_number = setup(&env, canister_id, policy)?;
+
+        let unprotected_device = DeviceData {
+            protection_policy: None,
+            ..protected_device(DeviceProtectionPolicy::default())
+        };
+        let result = api::update(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            unprotected_device.pubkey.clone(),
+            unprotected_device.clone(),
+        );
+        expect_protected(result);
+
+        api::update(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            unprotected_device.pubkey.clone(),
+            unprotected_device.clone(),
+        )?;
+        api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            unprotected_device.pubkey,
+        )?;
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices, vec![device_data_1()]);
+        Ok(())
+    }
+
+    /// Verifies that removing a protected device with a recovery device is rejected right away
+    /// rather than being queued if the recovery delay is enabled.
+    #[test]
+    fn should_not_queue_removal_of_protected_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let policy = DeviceProtectionPolicy {
+            protected_from_removal: true,
+            ..DeviceProtectionPolicy::default()
+        };
+        let user_number = setup(&env, canister_id, policy)?;
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        api::set_recovery_delay(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            Some(Duration::from_secs(60 * 60).as_nanos() as u64),
+        )?;
+
+        let result = api::remove(
+            &env,
+            canister_id,
+            principal_recovery_1(),
+            user_number,
+            device_data_2().pubkey,
+        );
+        expect_protected(result);
+        let pending =
+            api::get_pending_recovery_operations(&env, canister_id, principal_1(), user_number)?;
+        assert!(pending.is_empty());
+        Ok(())
+    }
+}
+
+/// Tests for the key/value metadata of anchors.
+#[cfg(test)]
+mod anchor_metadata_tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn sample_metadata() -> HashMap<String, MetadataEntry> {
+        HashMap::from([
+            (
+                "language".to_string(),
+                MetadataEntry::String("de".to_string()),
+            ),
+            (
+                "last_used_device".to_string(),
+                MetadataEntry::Bytes(ByteBuf::from(vec![1, 2, 3])),
+            ),
+        ])
+    }
+
+    /// Verifies that the metadata can be set and read back, also after an upgrade.
+    #[test]
+    fn should_set_and_get_metadata() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        assert_eq!(
+            api::get_anchor_metadata(&env, canister_id, principal_1(), user_number)?,
+            HashMap::new()
+        );
+
+        api::set_anchor_metadata(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            sample_metadata(),
+        )?;
+        assert_eq!(
+            api::get_anchor_metadata(&env, canister_id, principal_1(), user_number)?,
+            sample_metadata()
+        );
+
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+        assert_eq!(
+            api::get_anchor_metadata(&env, canister_id, principal_1(), user_number)?,
+            sample_metadata()
+        );
+        Ok(())
+    }
+
+    /// Verifies that setting the metadata does not affect the devices.
+    #[test]
+    fn should_keep_devices_when_setting_metadata() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let devices = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?.devices;
+
+        api::set_anchor_metadata(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            sample_metadata(),
+        )?;
+
+        assert_eq!(
+            api::get_anchor_info(&env, canister_id, principal_1(), user_number)?.devices,
+            devices
+        );
+        Ok(())
+    }
+
+    /// Verifies that the metadata can only be read and written by the anchor itself.
+    #[test]
+    fn should_not_access_metadata_of_different_user() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::set_anchor_metadata(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            sample_metadata(),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+
+        let result = api::get_anchor_metadata(&env, canister_id, principal_2(), user_number);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+
+    /// Verifies that the metadata size is limited.
+    #[test]
+    fn should_not_set_too_large_metadata() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::set_anchor_metadata(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            HashMap::from([(
+                "large".to_string(),
+                MetadataEntry::Bytes(ByteBuf::from(vec![0; 2048])),
+            )]),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("metadata size \\d+ exceeds the limit of 2048 bytes").unwrap(),
+        );
+    }
+
+    /// Verifies that metadata keys are limited to 64 bytes.
+    #[test]
+    fn should_not_set_metadata_with_too_long_key() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::set_anchor_metadata(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            HashMap::from([("a".repeat(65), MetadataEntry::String("value".to_string()))]),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("metadata key length 65 exceeds the limit of 64 bytes").unwrap(),
+        );
+    }
+}
+
+/// Tests related to prepare_delegation, get_delegation and get_principal II canister calls.
+#[cfg(test)]
+mod delegation_tests {
+    use super::*;
+
+    /// Verifies that valid delegations are issued.
+    #[test]
+    fn should_get_valid_delegation() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+        assert_eq!(
+            expiration,
+            env.time()
+                .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        );
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        Ok(())
+    }
+
+    /// Verifies that non-default expirations are respected.
+    #[test]
+    fn should_get_valid_delegation_with_custom_expiration() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            Some(3_600_000_000_000), // 1 hour
+            None,
+            None,
+        )?;
+        assert_eq!(
+            expiration,
+            env.time()
+                .add(Duration::from_secs(60 * 60)) // 1 hour
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        );
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        Ok(())
+    }
+
+    /// Verifies that delegations can be restricted to specific target canisters.
+    #[test]
+    fn should_get_valid_delegation_with_targets() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+        let targets = vec![
+            Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap(),
+            Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+        ];
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            Some(targets.clone()),
+            None,
+        )?;
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            Some(targets.clone()),
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.targets, Some(targets));
+        Ok(())
+    }
+
+    /// Verifies that a delegation prepared for specific targets cannot be retrieved with different targets.
+    #[test]
+    fn should_not_get_delegation_with_different_targets() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+        let target = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+
+        let (_, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            Some(vec![target]),
+            None,
+        )?;
+
+        for targets in [None, Some(vec![]), Some(vec![target, target])] {
+            match api::get_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend_hostname.to_string(),
+                pub_session_key.clone(),
+                expiration,
+                targets,
+                None,
+            )? {
+                GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+                GetDelegationResponse::NoSuchDelegation => {}
+            };
+        }
+        Ok(())
+    }
+
+    /// Verifies that the number of delegation targets is limited.
+    #[test]
+    fn should_not_prepare_delegation_with_too_many_targets() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session public key"),
+            None,
+            Some(vec![Principal::anonymous(); 1001]),
+            None,
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("delegation targets 1001 exceeds the limit of 1000 targets").unwrap(),
+        );
+    }
+
+    /// Verifies that the delegations are valid at most for 30 days.
+    #[test]
+    fn should_shorten_expiration_greater_max_ttl() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            Some(Duration::from_secs(31 * 24 * 60 * 60).as_nanos() as u64), // 31 days
+            None,
+            None,
+        )?;
+        assert_eq!(
+            expiration,
+            env.time()
+                .add(Duration::from_secs(30 * 24 * 60 * 60)) // 30 days
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        );
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        Ok(())
+    }
+
+    /// Verifies that delegations can be requested in parallel.
+    #[test]
+    fn should_get_multiple_valid_delegations() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname_1 = "https://dapp1.com";
+        let frontend_hostname_2 = "https://dapp2.com";
+        let pub_session_key_1 = ByteBuf::from("session public key 1");
+        let pub_session_key_2 = ByteBuf::from("session public key 2");
+        let delegation_params = vec![
+            (
+                &pub_session_key_1,
+                frontend_hostname_1,
+                Duration::from_secs(0),
+            ),
+            (
+                &pub_session_key_1,
+                frontend_hostname_2,
+                Duration::from_secs(0),
+            ),
+            (
+                &pub_session_key_2,
+                frontend_hostname_1,
+                Duration::from_secs(0),
+            ),
+            (
+                &pub_session_key_1,
+                frontend_hostname_1,
+                Duration::from_secs(30),
+            ),
+        ];
+
+        // prepare multiple delegations in parallel before calling get_delegation
+        let prepare_delegation_results =
+            delegation_params
+                .into_iter()
+                .map(|(session_key, frontend_hostname, time_shift)| {
+                    env.advance_time(time_shift);
+                    let (canister_sig_key, expiration) = api::prepare_delegation(
+                        &env,
+                        canister_id,
+                        principal_1(),
+                        user_number,
+                        frontend_hostname.to_string(),
+                        session_key.clone(),
+                        None,
+                        None,
+                        None,
+                    )
+                    .expect("prepare_delegation failed");
+
+                    assert_eq!(
+                        expiration,
+                        env.time()
+                            .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_nanos() as u64
+                    );
+                    (session_key, frontend_hostname, canister_sig_key, expiration)
+                });
+
+        for (session_key, frontend_hostname, canister_sig_key, expiration) in
+            prepare_delegation_results
+        {
+            let signed_delegation = match api::get_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend_hostname.to_string(),
+                session_key.clone(),
+                expiration,
+                None,
+                None,
+            )? {
+                GetDelegationResponse::SignedDelegation(delegation) => delegation,
+                GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+            };
+
+            verify_delegation(&env, canister_sig_key, &signed_delegation);
+            assert_eq!(signed_delegation.delegation.pubkey, session_key.clone());
+            assert_eq!(signed_delegation.delegation.expiration, expiration);
+        }
+        Ok(())
+    }
+
+    /// Verifies that an anchor that was registered using II_WASM_PREVIOUS gets valid delegations after upgrading to the current version.
+    #[test]
+    fn should_get_valid_delegation_for_old_anchor_after_ii_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM_PREVIOUS.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+        let (canister_sig_key, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+        assert_eq!(
+            expiration,
+            env.time()
+                .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        );
+
+        let signed_delegation = match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
+        };
+
+        verify_delegation(&env, canister_sig_key, &signed_delegation);
+        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
+        assert_eq!(signed_delegation.delegation.expiration, expiration);
+        Ok(())
+    }
+
+    /// Verifies that different front-ends yield different principals.
+    #[test]
+    fn should_issue_different_principals() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname_1 = "https://dapp1.com";
         let frontend_hostname_2 = "https://dapp2.com";
-        let pub_session_key_1 = ByteBuf::from("session public key 1");
-        let pub_session_key_2 = ByteBuf::from("session public key 2");
-        let delegation_params = vec![
-            (
-                &pub_session_key_1,
-                frontend_hostname_1,
-                Duration::from_secs(0),
-            ),
-            (
-                &pub_session_key_1,
-                frontend_hostname_2,
-                Duration::from_secs(0),
-            ),
-            (
-                &pub_session_key_2,
-                frontend_hostname_1,
-                Duration::from_secs(0),
-            ),
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key_1, _) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname_1.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+        let (canister_sig_key_2, _) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname_2.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        assert_ne!(canister_sig_key_1, canister_sig_key_2);
+        Ok(())
+    }
+
+    /// Verifies that there is a graceful failure if II gets upgraded between prepare_delegation and get_delegation.
+    #[test]
+    fn should_not_get_prepared_delegation_after_ii_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (_, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        // upgrade, even with the same WASM clears non-stable memory
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+        match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+            GetDelegationResponse::NoSuchDelegation => {}
+        };
+        Ok(())
+    }
+
+    /// Verifies that there is a graceful failure if get_delegation is called after the expiration of the delegation.
+    #[test]
+    fn should_not_get_delegation_after_expiration() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (_, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        env.advance_time(Duration::from_secs(30 * 60 + 1)); // one second more than delegation validity of 30 min
+
+        // we have to call prepare again, because expired signatures can only be pruned in update calls
+        api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        match api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+            None,
+        )? {
+            GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
+            GetDelegationResponse::NoSuchDelegation => {}
+        };
+        Ok(())
+    }
+
+    /// Verifies that delegations can only be prepared by the matching user.
+    #[test]
+    fn can_not_prepare_delegation_for_different_user() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number, // belongs to principal_1
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            None,
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+
+    /// Verifies that get_delegation can only be called by the matching user.
+    #[test]
+    fn can_not_get_delegation_for_different_user() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (_, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+        let result = api::get_delegation(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            expiration,
+            None,
+            None,
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that get_principal and prepare_delegation return the same principal.
+    #[test]
+    fn get_principal_should_match_prepare_delegation() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+
+        let (canister_sig_key, _) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+
+        let principal = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            None,
+        )?;
+        assert_eq!(Principal::self_authenticating(canister_sig_key), principal);
+        Ok(())
+    }
+
+    /// Verifies that get_principal returns different principals for different front end host names.
+    #[test]
+    fn should_return_different_principals_for_different_frontends() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname_1 = "https://dapp-1.com";
+        let frontend_hostname_2 = "https://dapp-2.com";
+
+        let dapp_principal_1 = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname_1.to_string(),
+            None,
+        )?;
+
+        let dapp_principal_2 = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname_2.to_string(),
+            None,
+        )?;
+
+        assert_ne!(dapp_principal_1, dapp_principal_2);
+        Ok(())
+    }
+
+    /// Verifies that get_principal returns different principals for different anchors.
+    #[test]
+    fn should_return_different_principals_for_different_users() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number_1 =
+            flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+        let user_number_2 =
+            flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+        let frontend_hostname = "https://dapp-1.com";
+
+        let dapp_principal_1 = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number_1,
+            frontend_hostname.to_string(),
+            None,
+        )?;
+
+        let dapp_principal_2 = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number_2,
+            frontend_hostname.to_string(),
+            None,
+        )?;
+
+        assert_ne!(dapp_principal_1, dapp_principal_2);
+        Ok(())
+    }
+
+    /// Verifies that get_principal requires authentication.
+    #[test]
+    fn should_not_allow_get_principal_for_other_user() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+        let user_number_2 =
+            flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+        let frontend_hostname_1 = "https://dapp-1.com";
+
+        let result = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number_2,
+            frontend_hostname_1.to_string(),
+            None,
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated\\.").unwrap(),
+        );
+    }
+
+    /// Verifies that revoking the delegations drops the pending signatures and records the time
+    /// of the revocation for the principal of the frontend.
+    #[test]
+    fn should_revoke_delegations() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let pub_session_key = ByteBuf::from("session public key");
+        let principal = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            None,
+        )?;
+        assert_eq!(
+            api::get_delegations_not_before(&env, canister_id, principal)?.not_before,
+            None
+        );
+
+        let (_, expiration) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key.clone(),
+            None,
+            None,
+            None,
+        )?;
+        let not_before = api::revoke_delegations(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+        )?;
+        assert_eq!(
+            not_before,
+            env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+        );
+
+        let response = api::get_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            pub_session_key,
+            expiration,
+            None,
+            None,
+        )?;
+        assert!(matches!(response, GetDelegationResponse::NoSuchDelegation));
+
+        let delegations_not_before = api::get_delegations_not_before(&env, canister_id, principal)?;
+        assert_eq!(delegations_not_before.not_before, Some(not_before));
+        let certified_value = validate_certified_lookup(
+            &delegations_not_before.certified_lookup.unwrap(),
+            canister_id,
+            &[b"delegations_not_before", principal.as_slice()],
+            env.root_key(),
+        )
+        .expect("certified lookup invalid");
+        assert_eq!(certified_value, Some(not_before.to_be_bytes().to_vec()));
+        Ok(())
+    }
+
+    /// Verifies that a revocation only affects the given frontend and that the certified lookup
+    /// proves the absence of revocations for other principals.
+    #[test]
+    fn should_only_revoke_delegations_of_given_frontend() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::revoke_delegations(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+        )?;
+
+        let other_principal = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://other-dapp.com".to_string(),
+            None,
+        )?;
+        let delegations_not_before =
+            api::get_delegations_not_before(&env, canister_id, other_principal)?;
+        assert_eq!(delegations_not_before.not_before, None);
+        let certified_value = validate_certified_lookup(
+            &delegations_not_before.certified_lookup.unwrap(),
+            canister_id,
+            &[b"delegations_not_before", other_principal.as_slice()],
+            env.root_key(),
+        )
+        .expect("certified lookup invalid");
+        assert_eq!(certified_value, None);
+        Ok(())
+    }
+
+    /// Verifies that revocations are kept across upgrades.
+    #[test]
+    fn should_keep_revocations_across_upgrades() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let not_before = api::revoke_delegations(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+        )?;
+
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+        let principal = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            None,
+        )?;
+        let delegations_not_before = api::get_delegations_not_before(&env, canister_id, principal)?;
+        assert_eq!(delegations_not_before.not_before, Some(not_before));
+        let certified_value = validate_certified_lookup(
+            &delegations_not_before.certified_lookup.unwrap(),
+            canister_id,
+            &[b"delegations_not_before", principal.as_slice()],
+            env.root_key(),
+        )
+        .expect("certified lookup invalid");
+        assert_eq!(certified_value, Some(not_before.to_be_bytes().to_vec()));
+        Ok(())
+    }
+
+    /// Verifies that delegations can only be revoked by the anchor itself.
+    #[test]
+    fn should_not_revoke_delegations_of_different_user() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::revoke_delegations(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+
+    /// Verifies that the delegation lifetime limits of the anchor shorten the expiration, with
+    /// frontend overrides taking precedence over the limit for all frontends.
+    #[test]
+    fn should_enforce_delegation_lifetime_limits() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let limits = DelegationLifetimeLimits {
+            max_time_to_live: Some(Duration::from_secs(8 * 60 * 60).as_nanos() as u64), // 8 hours
+            frontend_overrides: vec![(
+                "https://trusted-dapp.com".to_string(),
+                Duration::from_secs(7 * 24 * 60 * 60).as_nanos() as u64, // 7 days
+            )],
+        };
+        api::set_delegation_lifetime_limits(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            limits.clone(),
+        )?;
+        assert_eq!(
+            api::get_delegation_lifetime_limits(&env, canister_id, principal_1(), user_number)?,
+            limits
+        );
+
+        for (frontend, expected_time_to_live) in [
+            ("https://some-dapp.com", Duration::from_secs(8 * 60 * 60)),
             (
-                &pub_session_key_1,
-                frontend_hostname_1,
-                Duration::from_secs(30),
+                "https://trusted-dapp.com",
+                Duration::from_secs(7 * 24 * 60 * 60),
             ),
-        ];
+        ] {
+            let (_, expiration) = api::prepare_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend.to_string(),
+                ByteBuf::from("session public key"),
+                Some(Duration::from_secs(30 * 24 * 60 * 60).as_nanos() as u64), // 30 days
+                None,
+                None,
+            )?;
+            assert_eq!(
+                expiration,
+                env.time()
+                    .add(expected_time_to_live)
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos() as u64
+            );
+        }
+        Ok(())
+    }
+
+    /// Verifies that the delegation lifetime limits can only be set by the anchor itself.
+    #[test]
+    fn should_not_set_delegation_lifetime_limits_of_different_user() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::set_delegation_lifetime_limits(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            DelegationLifetimeLimits::default(),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
 
-        // prepare multiple delegations in parallel before calling get_delegation
-        let prepare_delegation_results =
-            delegation_params
-                .into_iter()
-                .map(|(session_key, frontend_hostname, time_shift)| {
-                    env.advance_time(time_shift);
-                    let (canister_sig_key, expiration) = api::prepare_delegation(
-                        &env,
-                        canister_id,
-                        principal_1(),
-                        user_number,
-                        frontend_hostname.to_string(),
-                        session_key.clone(),
-                        None,
-                    )
-                    .expect("prepare_delegation failed");
+    /// Verifies that a frontend can only have one override.
+    #[test]
+    fn should_not_set_duplicate_frontend_overrides() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
 
-                    assert_eq!(
-                        expiration,
-                        env.time()
-                            .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_nanos() as u64
-                    );
-                    (session_key, frontend_hostname, canister_sig_key, expiration)
-                });
+        let result = api::set_delegation_lifetime_limits(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            DelegationLifetimeLimits {
+                max_time_to_live: None,
+                frontend_overrides: vec![
+                    ("https://some-dapp.com".to_string(), 1_000),
+                    ("https://some-dapp.com".to_string(), 2_000),
+                ],
+            },
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("duplicate frontend override for https://some-dapp.com").unwrap(),
+        );
+    }
+
+    /// Verifies that the sessions created by prepare_delegation are listed, most recent first.
+    #[test]
+    fn should_list_sessions() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        assert_eq!(
+            api::get_sessions(&env, canister_id, principal_1(), user_number)?,
+            vec![]
+        );
+
+        let (_, expiration_1) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session key 1"),
+            None,
+            None,
+            None,
+        )?;
+        let issued_at_1 = env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        env.advance_time(Duration::from_secs(10));
+        let (_, expiration_2) = api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://other-dapp.com".to_string(),
+            ByteBuf::from("session key 2"),
+            None,
+            None,
+            None,
+        )?;
+        let issued_at_2 = env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+
+        let sessions = api::get_sessions(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(
+            sessions,
+            vec![
+                Session {
+                    frontend: "https://other-dapp.com".to_string(),
+                    derivation_origin: None,
+                    session_key_hash: ByteBuf::from(
+                        Sha256::digest(b"session key 2").as_slice().to_vec()
+                    ),
+                    issued_at: issued_at_2,
+                    expiration: expiration_2,
+                },
+                Session {
+                    frontend: "https://some-dapp.com".to_string(),
+                    derivation_origin: None,
+                    session_key_hash: ByteBuf::from(
+                        Sha256::digest(b"session key 1").as_slice().to_vec()
+                    ),
+                    issued_at: issued_at_1,
+                    expiration: expiration_1,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    /// Verifies that expired and revoked sessions are no longer listed.
+    #[test]
+    fn should_not_list_expired_or_revoked_sessions() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        for (frontend, time_to_live) in [
+            ("https://some-dapp.com", 60),
+            ("https://other-dapp.com", 3600),
+            ("https://third-dapp.com", 3600),
+        ] {
+            api::prepare_delegation(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                frontend.to_string(),
+                ByteBuf::from(frontend),
+                Some(Duration::from_secs(time_to_live).as_nanos() as u64),
+                None,
+                None,
+            )?;
+        }
+        api::revoke_delegations(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://third-dapp.com".to_string(),
+        )?;
+        env.advance_time(Duration::from_secs(120));
+
+        let sessions = api::get_sessions(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].frontend, "https://other-dapp.com");
+        Ok(())
+    }
+
+    /// Verifies that sessions are kept across upgrades.
+    #[test]
+    fn should_keep_sessions_across_upgrades() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            None,
+        )?;
+        let sessions = api::get_sessions(&env, canister_id, principal_1(), user_number)?;
+
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+        assert_eq!(
+            api::get_sessions(&env, canister_id, principal_1(), user_number)?,
+            sessions
+        );
+        Ok(())
+    }
+
+    /// Verifies that the sessions can only be listed by the anchor itself.
+    #[test]
+    fn should_not_list_sessions_of_different_user() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::get_sessions(&env, canister_id, principal_2(), user_number);
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
+}
+
+/// Tests for canister-signed attestations of anchor claims.
+#[cfg(test)]
+mod attestation_tests {
+    use super::*;
+
+    /// Verifies that the creation time of an anchor is attested with a valid signature of the
+    /// principal for the relying party.
+    #[test]
+    fn should_attest_anchor_creation_time() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let before_registration = time_nanos(&env);
+        env.advance_time(Duration::from_secs(1));
+        let user_number = flows::register_anchor(&env, canister_id);
+        env.advance_time(Duration::from_secs(1));
+        let frontend_hostname = "https://some-dapp.com";
+
+        let claim = AttestationClaim::CreatedBefore(time_nanos(&env));
+        let (user_key, issued_at) = match api::prepare_attestation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            claim.clone(),
+            None,
+        )? {
+            PrepareAttestationResponse::Prepared {
+                user_key,
+                issued_at,
+            } => (user_key, issued_at),
+            response => panic!("failed to prepare attestation: {:?}", response),
+        };
+        assert_eq!(issued_at, time_nanos(&env));
+
+        let signed_attestation = match api::get_attestation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            claim.clone(),
+            issued_at,
+            None,
+        )? {
+            GetAttestationResponse::SignedAttestation(attestation) => attestation,
+            GetAttestationResponse::NoSuchAttestation => panic!("failed to get attestation"),
+        };
+        verify_attestation(&env, user_key.clone(), &signed_attestation);
+        assert_eq!(signed_attestation.attestation.claim, claim);
+
+        // the attestation is signed by the principal the relying party knows
+        let principal = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            None,
+        )?;
+        assert_eq!(Principal::self_authenticating(&user_key), principal);
+
+        let response = api::prepare_attestation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            AttestationClaim::CreatedBefore(before_registration),
+            None,
+        )?;
+        assert!(matches!(
+            response,
+            PrepareAttestationResponse::ClaimNotSatisfied
+        ));
+        Ok(())
+    }
+
+    /// Verifies that anchors registered before the creation time was recorded are attested to be
+    /// created before the upgrade.
+    #[test]
+    fn should_attest_creation_time_of_anchors_registered_before_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM_PREVIOUS.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        env.advance_time(Duration::from_secs(1));
+        let before_upgrade = time_nanos(&env);
+        env.advance_time(Duration::from_secs(1));
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+        env.advance_time(Duration::from_secs(1));
+
+        let response = api::prepare_attestation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            AttestationClaim::CreatedBefore(before_upgrade),
+            None,
+        )?;
+        assert!(matches!(
+            response,
+            PrepareAttestationResponse::ClaimNotSatisfied
+        ));
+
+        let response = api::prepare_attestation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            AttestationClaim::CreatedBefore(time_nanos(&env)),
+            None,
+        )?;
+        assert!(matches!(
+            response,
+            PrepareAttestationResponse::Prepared { .. }
+        ));
+        Ok(())
+    }
+
+    /// Verifies that recovery devices are attested only if the anchor has one.
+    #[test]
+    fn should_attest_recovery_device() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+
+        let response = api::prepare_attestation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            AttestationClaim::HasRecoveryDevice,
+            None,
+        )?;
+        assert!(matches!(
+            response,
+            PrepareAttestationResponse::ClaimNotSatisfied
+        ));
+
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+        let (user_key, issued_at) = match api::prepare_attestation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            AttestationClaim::HasRecoveryDevice,
+            None,
+        )? {
+            PrepareAttestationResponse::Prepared {
+                user_key,
+                issued_at,
+            } => (user_key, issued_at),
+            response => panic!("failed to prepare attestation: {:?}", response),
+        };
+
+        match api::get_attestation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            AttestationClaim::HasRecoveryDevice,
+            issued_at,
+            None,
+        )? {
+            GetAttestationResponse::SignedAttestation(attestation) => {
+                verify_attestation(&env, user_key, &attestation)
+            }
+            GetAttestationResponse::NoSuchAttestation => panic!("failed to get attestation"),
+        };
+        Ok(())
+    }
 
-        for (session_key, frontend_hostname, canister_sig_key, expiration) in
-            prepare_delegation_results
-        {
-            let signed_delegation = match api::get_delegation(
+    /// Verifies that only the prepared attestation can be retrieved.
+    #[test]
+    fn should_not_get_attestation_that_was_not_prepared() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        let frontend_hostname = "https://some-dapp.com";
+        let claim = AttestationClaim::CreatedBefore(time_nanos(&env) + 1);
+
+        let issued_at = match api::prepare_attestation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            frontend_hostname.to_string(),
+            claim.clone(),
+            None,
+        )? {
+            PrepareAttestationResponse::Prepared { issued_at, .. } => issued_at,
+            response => panic!("failed to prepare attestation: {:?}", response),
+        };
+
+        for (frontend_hostname, claim) in [
+            (
+                frontend_hostname,
+                AttestationClaim::CreatedBefore(issued_at + 2),
+            ),
+            ("https://other-dapp.com", claim),
+        ] {
+            let response = api::get_attestation(
                 &env,
                 canister_id,
                 principal_1(),
                 user_number,
                 frontend_hostname.to_string(),
-                session_key.clone(),
-                expiration,
-            )? {
-                GetDelegationResponse::SignedDelegation(delegation) => delegation,
-                GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
-            };
-
-            verify_delegation(&env, canister_sig_key, &signed_delegation);
-            assert_eq!(signed_delegation.delegation.pubkey, session_key.clone());
-            assert_eq!(signed_delegation.delegation.expiration, expiration);
+                claim,
+                issued_at,
+                None,
+            )?;
+            assert!(matches!(
+                response,
+                GetAttestationResponse::NoSuchAttestation
+            ));
         }
         Ok(())
     }
 
-    /// Verifies that an anchor that was registered using II_WASM_PREVIOUS gets valid delegations after upgrading to the current version.
+    /// Verifies that attestations can only be requested by the anchor itself.
     #[test]
-    fn should_get_valid_delegation_for_old_anchor_after_ii_upgrade() -> Result<(), CallError> {
+    fn should_not_prepare_attestation_for_different_user() {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM_PREVIOUS.clone());
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
-
-        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
 
-        let (canister_sig_key, expiration) = api::prepare_delegation(
+        let result = api::prepare_attestation(
             &env,
             canister_id,
-            principal_1(),
+            principal_2(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
+            "https://some-dapp.com".to_string(),
+            AttestationClaim::HasRecoveryDevice,
             None,
-        )?;
-        assert_eq!(
-            expiration,
-            env.time()
-                .add(Duration::from_secs(30 * 60)) // default expiration: 30 minutes
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_nanos() as u64
         );
 
-        let signed_delegation = match api::get_delegation(
-            &env,
-            canister_id,
-            principal_1(),
-            user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(delegation) => delegation,
-            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
-        };
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+        );
+    }
 
-        verify_delegation(&env, canister_sig_key, &signed_delegation);
-        assert_eq!(signed_delegation.delegation.pubkey, pub_session_key);
-        assert_eq!(signed_delegation.delegation.expiration, expiration);
-        Ok(())
+    fn time_nanos(env: &StateMachine) -> u64 {
+        env.time().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+    }
+}
+
+/// Tests related to principals derived from an alternative origin.
+#[cfg(test)]
+mod derivation_origin_tests {
+    use super::*;
+    use canister_tests::api::test_app;
+    use canister_tests::api::test_app::AlternativeOriginsMode;
+    use ic_state_machine_tests::CanisterId;
+
+    const FRONTEND: &str = "https://some-dapp.com";
+
+    fn setup(env: &StateMachine, alternative_origins: &str) -> (CanisterId, CanisterId) {
+        let canister_id =
+            install_ii_canister_with_arg(env, II_WASM.clone(), arg_with_root_key(env));
+        let test_app_id = install_test_app_canister(env);
+        test_app::update_alternative_origins(
+            env,
+            test_app_id,
+            alternative_origins,
+            AlternativeOriginsMode::CertifiedContent,
+        )
+        .expect("failed to update alternative origins");
+        (canister_id, test_app_id)
     }
 
-    /// Verifies that different front-ends yield different principals.
+    /// Verifies that the principal is derived from the derivation origin if the frontend is listed as alternative origin.
     #[test]
-    fn should_issue_different_principals() -> Result<(), CallError> {
+    fn should_derive_principal_from_derivation_origin() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let (canister_id, test_app_id) = setup(
+            &env,
+            &format!("{{\"alternativeOrigins\":[\"{}\"]}}", FRONTEND),
+        );
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname_1 = "https://dapp1.com";
-        let frontend_hostname_2 = "https://dapp2.com";
-        let pub_session_key = ByteBuf::from("session public key");
+        let derivation_origin = flows::derivation_origin(&env, test_app_id);
 
-        let (canister_sig_key_1, _) = api::prepare_delegation(
+        let principal = api::get_principal(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname_1.to_string(),
-            pub_session_key.clone(),
+            FRONTEND.to_string(),
+            Some(derivation_origin.clone()),
+        )?;
+
+        let derivation_origin_principal = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            derivation_origin.origin.clone(),
             None,
         )?;
-        let (canister_sig_key_2, _) = api::prepare_delegation(
+        let frontend_principal = api::get_principal(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname_2.to_string(),
-            pub_session_key.clone(),
+            FRONTEND.to_string(),
             None,
         )?;
-
-        assert_ne!(canister_sig_key_1, canister_sig_key_2);
+        assert_eq!(principal, derivation_origin_principal);
+        assert_ne!(principal, frontend_principal);
         Ok(())
     }
 
-    /// Verifies that there is a graceful failure if II gets upgraded between prepare_delegation and get_delegation.
+    /// Verifies that a valid delegation for the principal of the derivation origin can be retrieved.
     #[test]
-    fn should_not_get_prepared_delegation_after_ii_upgrade() -> Result<(), CallError> {
+    fn should_get_delegation_for_derivation_origin() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let (canister_id, test_app_id) = setup(
+            &env,
+            &format!("{{\"alternativeOrigins\":[\"{}\"]}}", FRONTEND),
+        );
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
+        let derivation_origin = flows::derivation_origin(&env, test_app_id);
         let pub_session_key = ByteBuf::from("session public key");
 
-        let (_, expiration) = api::prepare_delegation(
+        let (user_key, expiration) = api::prepare_delegation(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname.to_string(),
+            FRONTEND.to_string(),
             pub_session_key.clone(),
             None,
+            None,
+            Some(derivation_origin.clone()),
         )?;
+        assert_eq!(
+            Principal::self_authenticating(&user_key),
+            api::get_principal(
+                &env,
+                canister_id,
+                principal_1(),
+                user_number,
+                derivation_origin.origin.clone(),
+                None,
+            )?
+        );
 
-        // upgrade, even with the same WASM clears non-stable memory
-        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
-
-        match api::get_delegation(
+        let signed_delegation = match api::get_delegation(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname.to_string(),
+            FRONTEND.to_string(),
             pub_session_key.clone(),
             expiration,
+            None,
+            Some(derivation_origin.origin),
         )? {
-            GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
-            GetDelegationResponse::NoSuchDelegation => {}
+            GetDelegationResponse::SignedDelegation(delegation) => delegation,
+            GetDelegationResponse::NoSuchDelegation => panic!("failed to get delegation"),
         };
+        verify_delegation(&env, user_key, &signed_delegation);
         Ok(())
     }
 
-    /// Verifies that there is a graceful failure if get_delegation is called after the expiration of the delegation.
+    /// Verifies that the derivation origin is rejected if it does not list the frontend.
     #[test]
-    fn should_not_get_delegation_after_expiration() -> Result<(), CallError> {
+    fn should_reject_unlisted_frontend() {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let (canister_id, test_app_id) = setup(
+            &env,
+            "{\"alternativeOrigins\":[\"https://other-dapp.com\"]}",
+        );
         let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
 
-        let (_, expiration) = api::prepare_delegation(
+        let result = api::prepare_delegation(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
+            FRONTEND.to_string(),
+            ByteBuf::from("session public key"),
             None,
-        )?;
+            None,
+            Some(flows::derivation_origin(&env, test_app_id)),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("https://some-dapp.com is not listed as alternative origin").unwrap(),
+        );
+    }
 
-        env.advance_time(Duration::from_secs(30 * 60 + 1)); // one second more than delegation validity of 30 min
+    /// Verifies that the derivation origin is rejected if the alternative origins do not match the certified ones.
+    #[test]
+    fn should_reject_tampered_alternative_origins() {
+        let env = StateMachine::new();
+        let (canister_id, test_app_id) = setup(&env, "{\"alternativeOrigins\":[]}");
+        let user_number = flows::register_anchor(&env, canister_id);
+        let mut derivation_origin = flows::derivation_origin(&env, test_app_id);
+        derivation_origin.alternative_origins.body =
+            ByteBuf::from(format!("{{\"alternativeOrigins\":[\"{}\"]}}", FRONTEND));
 
-        // we have to call prepare again, because expired signatures can only be pruned in update calls
-        api::prepare_delegation(
+        let result = api::get_principal(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            None,
-        )?;
+            FRONTEND.to_string(),
+            Some(derivation_origin),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("alternative origins hash mismatch").unwrap(),
+        );
+    }
 
-        match api::get_delegation(
+    /// Verifies that the certified alternative origins of one canister cannot be used for another derivation origin.
+    #[test]
+    fn should_reject_alternative_origins_of_other_canister() {
+        let env = StateMachine::new();
+        let (canister_id, test_app_id) = setup(
+            &env,
+            &format!("{{\"alternativeOrigins\":[\"{}\"]}}", FRONTEND),
+        );
+        let user_number = flows::register_anchor(&env, canister_id);
+        let mut derivation_origin = flows::derivation_origin(&env, test_app_id);
+        derivation_origin.origin = format!("https://{}.ic0.app", canister_id.get());
+
+        let result = api::get_principal(
             &env,
             canister_id,
             principal_1(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
-        )? {
-            GetDelegationResponse::SignedDelegation(_) => panic!("unexpected delegation"),
-            GetDelegationResponse::NoSuchDelegation => {}
-        };
+            FRONTEND.to_string(),
+            Some(derivation_origin),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("certificate does not contain the certified data").unwrap(),
+        );
+    }
+
+    /// Verifies that certificates are only accepted if they are recent.
+    #[test]
+    fn should_reject_outdated_certificate() {
+        let env = StateMachine::new();
+        let (canister_id, test_app_id) = setup(
+            &env,
+            &format!("{{\"alternativeOrigins\":[\"{}\"]}}", FRONTEND),
+        );
+        let user_number = flows::register_anchor(&env, canister_id);
+        let derivation_origin = flows::derivation_origin(&env, test_app_id);
+        env.advance_time(Duration::from_secs(10 * 60));
+        env.tick();
+
+        let result = api::get_principal(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            FRONTEND.to_string(),
+            Some(derivation_origin),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("certificate time is not recent").unwrap(),
+        );
+    }
+}
+
+/// Tests for the canister-side verification of WebAuthn assertions.
+#[cfg(test)]
+mod webauthn_tests {
+    use super::*;
+    use ic_state_machine_tests::CanisterId;
+
+    fn setup(env: &StateMachine) -> (CanisterId, UserNumber) {
+        let canister_id =
+            install_ii_canister_with_arg(env, II_WASM.clone(), arg_with_webauthn_verification());
+        let user_number = flows::register_anchor_with(
+            env,
+            canister_id,
+            principal_webauthn(),
+            &webauthn_device_data(),
+        );
+        api::add(
+            env,
+            canister_id,
+            principal_webauthn(),
+            user_number,
+            recovery_device_data_1(),
+        )
+        .unwrap();
+        (canister_id, user_number)
+    }
+
+    fn verify_assertion(
+        env: &StateMachine,
+        canister_id: CanisterId,
+        user_number: UserNumber,
+    ) -> Result<(), CallError> {
+        let challenge =
+            api::create_webauthn_challenge(env, canister_id, principal_webauthn(), user_number)?;
+        let response = api::verify_webauthn_assertion(
+            env,
+            canister_id,
+            principal_webauthn(),
+            user_number,
+            webauthn_assertion(&challenge.challenge),
+        )?;
+        assert_eq!(response, VerifyWebAuthnAssertionResponse::Verified);
+        Ok(())
+    }
+
+    /// Verifies that a recovery device can be removed after a verified assertion.
+    #[test]
+    fn should_remove_recovery_device_with_verified_assertion() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let (canister_id, user_number) = setup(&env);
+
+        verify_assertion(&env, canister_id, user_number)?;
+        api::remove(
+            &env,
+            canister_id,
+            principal_webauthn(),
+            user_number,
+            ByteBuf::from(RECOVERY_PUBKEY_1),
+        )?;
+
+        let devices = api::lookup(&env, canister_id, user_number)?;
+        assert_eq!(devices, vec![webauthn_device_data()]);
         Ok(())
     }
 
-    /// Verifies that delegations can only be prepared by the matching user.
+    /// Verifies that a recovery device cannot be removed without a verified assertion.
+    #[test]
+    fn should_not_remove_recovery_device_without_verified_assertion() {
+        let env = StateMachine::new();
+        let (canister_id, user_number) = setup(&env);
+
+        let result = api::remove(
+            &env,
+            canister_id,
+            principal_webauthn(),
+            user_number,
+            ByteBuf::from(RECOVERY_PUBKEY_1),
+        );
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Modifying a recovery device requires a fresh WebAuthn assertion verified by the canister").unwrap(),
+        );
+    }
+
+    /// Verifies that a verified assertion can only be used for a single operation.
     #[test]
-    fn can_not_prepare_delegation_for_different_user() {
+    fn should_consume_verified_assertion() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
+        let (canister_id, user_number) = setup(&env);
+        let mut recovery_device = recovery_device_data_1();
+        recovery_device.alias = "new alias".to_string();
 
-        let result = api::prepare_delegation(
+        verify_assertion(&env, canister_id, user_number)?;
+        api::update(
             &env,
             canister_id,
-            principal_2(),
-            user_number, // belongs to principal_1
-            "https://some-dapp.com".to_string(),
-            ByteBuf::from("session key"),
-            None,
+            principal_webauthn(),
+            user_number,
+            recovery_device.pubkey.clone(),
+            recovery_device.clone(),
+        )?;
+        let result = api::remove(
+            &env,
+            canister_id,
+            principal_webauthn(),
+            user_number,
+            recovery_device.pubkey,
         );
 
         expect_user_error_with_message(
             result,
             CanisterCalledTrap,
-            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+            Regex::new("Modifying a recovery device requires a fresh WebAuthn assertion verified by the canister").unwrap(),
         );
+        Ok(())
     }
 
-    /// Verifies that get_delegation can only be called by the matching user.
+    /// Verifies that a verified assertion can only be used by the caller that submitted it.
     #[test]
-    fn can_not_get_delegation_for_different_user() -> Result<(), CallError> {
+    fn should_not_use_verified_assertion_of_other_caller() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
-
-        let (_, expiration) = api::prepare_delegation(
+        let (canister_id, user_number) = setup(&env);
+        api::add(
             &env,
             canister_id,
-            principal_1(),
+            principal_webauthn(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            None,
+            device_data_2(),
         )?;
-        let result = api::get_delegation(
+
+        verify_assertion(&env, canister_id, user_number)?;
+        let result = api::remove(
             &env,
             canister_id,
             principal_2(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            expiration,
+            ByteBuf::from(RECOVERY_PUBKEY_1),
         );
-
         expect_user_error_with_message(
             result,
             CanisterCalledTrap,
-            Regex::new("[a-z\\d-]+ could not be authenticated.").unwrap(),
+            Regex::new("Modifying a recovery device requires a fresh WebAuthn assertion verified by the canister").unwrap(),
         );
+
+        // the caller that submitted the assertion can still use it
+        api::remove(
+            &env,
+            canister_id,
+            principal_webauthn(),
+            user_number,
+            ByteBuf::from(RECOVERY_PUBKEY_1),
+        )?;
         Ok(())
     }
 
-    /// Verifies that get_principal and prepare_delegation return the same principal.
+    /// Verifies that authentication devices can be modified without a verified assertion.
     #[test]
-    fn get_principal_should_match_prepare_delegation() -> Result<(), CallError> {
+    fn should_not_require_verified_assertion_for_authentication_devices() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname = "https://some-dapp.com";
-        let pub_session_key = ByteBuf::from("session public key");
-
-        let (canister_sig_key, _) = api::prepare_delegation(
+        let (canister_id, user_number) = setup(&env);
+        api::add(
             &env,
             canister_id,
-            principal_1(),
+            principal_webauthn(),
             user_number,
-            frontend_hostname.to_string(),
-            pub_session_key.clone(),
-            None,
+            device_data_2(),
         )?;
 
-        let principal = api::get_principal(
+        api::remove(
             &env,
             canister_id,
-            principal_1(),
+            principal_webauthn(),
             user_number,
-            frontend_hostname.to_string(),
+            device_data_2().pubkey,
         )?;
-        assert_eq!(Principal::self_authenticating(canister_sig_key), principal);
         Ok(())
     }
 
-    /// Verifies that get_principal returns different principals for different front end host names.
+    /// Verifies that assertions over a different challenge are rejected and that the challenge is
+    /// consumed nevertheless.
     #[test]
-    fn should_return_different_principals_for_different_frontends() -> Result<(), CallError> {
+    fn should_reject_assertion_with_wrong_challenge() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
-        let user_number = flows::register_anchor(&env, canister_id);
-        let frontend_hostname_1 = "https://dapp-1.com";
-        let frontend_hostname_2 = "https://dapp-2.com";
+        let (canister_id, user_number) = setup(&env);
 
-        let dapp_principal_1 = api::get_principal(
+        let challenge =
+            api::create_webauthn_challenge(&env, canister_id, principal_webauthn(), user_number)?;
+        let response = api::verify_webauthn_assertion(
             &env,
             canister_id,
-            principal_1(),
+            principal_webauthn(),
             user_number,
-            frontend_hostname_1.to_string(),
+            webauthn_assertion(&[0; 32]),
         )?;
+        assert_eq!(
+            response,
+            VerifyWebAuthnAssertionResponse::VerificationFailed("challenge mismatch".to_string())
+        );
 
-        let dapp_principal_2 = api::get_principal(
+        let result = api::verify_webauthn_assertion(
             &env,
             canister_id,
-            principal_1(),
+            principal_webauthn(),
             user_number,
-            frontend_hostname_2.to_string(),
-        )?;
-
-        assert_ne!(dapp_principal_1, dapp_principal_2);
+            webauthn_assertion(&challenge.challenge),
+        );
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("no valid WebAuthn challenge for anchor \\d+").unwrap(),
+        );
         Ok(())
     }
 
-    /// Verifies that get_principal returns different principals for different anchors.
+    /// Verifies that challenges cannot be reused.
     #[test]
-    fn should_return_different_principals_for_different_users() -> Result<(), CallError> {
+    fn should_not_reuse_challenge() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
-        let user_number_1 =
-            flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
-        let user_number_2 =
-            flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
-        let frontend_hostname = "https://dapp-1.com";
+        let (canister_id, user_number) = setup(&env);
 
-        let dapp_principal_1 = api::get_principal(
+        let challenge =
+            api::create_webauthn_challenge(&env, canister_id, principal_webauthn(), user_number)?;
+        let assertion = webauthn_assertion(&challenge.challenge);
+        api::verify_webauthn_assertion(
             &env,
             canister_id,
-            principal_1(),
-            user_number_1,
-            frontend_hostname.to_string(),
+            principal_webauthn(),
+            user_number,
+            assertion.clone(),
         )?;
-
-        let dapp_principal_2 = api::get_principal(
+        let result = api::verify_webauthn_assertion(
             &env,
             canister_id,
-            principal_1(),
-            user_number_2,
-            frontend_hostname.to_string(),
-        )?;
+            principal_webauthn(),
+            user_number,
+            assertion,
+        );
 
-        assert_ne!(dapp_principal_1, dapp_principal_2);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("no valid WebAuthn challenge for anchor \\d+").unwrap(),
+        );
         Ok(())
     }
 
-    /// Verifies that get_principal requires authentication.
+    /// Verifies that expired challenges are rejected.
     #[test]
-    fn should_not_allow_get_principal_for_other_user() {
+    fn should_reject_expired_challenge() -> Result<(), CallError> {
         let env = StateMachine::new();
-        let canister_id = install_ii_canister(&env, II_WASM.clone());
-        flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
-        let user_number_2 =
-            flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
-        let frontend_hostname_1 = "https://dapp-1.com";
+        let (canister_id, user_number) = setup(&env);
 
-        let result = api::get_principal(
+        let challenge =
+            api::create_webauthn_challenge(&env, canister_id, principal_webauthn(), user_number)?;
+        env.advance_time(Duration::from_secs(301));
+        let result = api::verify_webauthn_assertion(
             &env,
             canister_id,
-            principal_1(),
-            user_number_2,
-            frontend_hostname_1.to_string(),
+            principal_webauthn(),
+            user_number,
+            webauthn_assertion(&challenge.challenge),
         );
 
         expect_user_error_with_message(
             result,
             CanisterCalledTrap,
-            Regex::new("[a-z\\d-]+ could not be authenticated\\.").unwrap(),
+            Regex::new("no valid WebAuthn challenge for anchor \\d+").unwrap(),
+        );
+        Ok(())
+    }
+
+    /// Verifies that challenges are only issued if the verification is enabled.
+    #[test]
+    fn should_not_create_challenge_if_disabled() {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        let result = api::create_webauthn_challenge(&env, canister_id, principal_1(), user_number);
+
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("WebAuthn verification is not enabled").unwrap(),
         );
     }
 }
@@ -1849,6 +5072,28 @@ mod http_tests {
             "internet_identity_last_upgrade_timestamp",
             "internet_identity_inflight_challenges",
             "internet_identity_users_in_registration_mode",
+            "internet_identity_archive_buffered_entries",
+            "internet_identity_archive_dropped_entries_counter",
+            "internet_identity_archive_failed_pushes_counter",
+            "internet_identity_delegation_counter",
+            "internet_identity_anchor_operations_counter",
+            "internet_identity_register_counter",
+            "internet_identity_add_device_counter",
+            "internet_identity_update_device_counter",
+            "internet_identity_remove_device_counter",
+            "internet_identity_delete_anchor_counter",
+            "internet_identity_add_tentative_device_counter",
+            "internet_identity_verify_tentative_device_counter",
+            "internet_identity_devices_per_anchor_count",
+            "internet_identity_anchor_record_size_ratio_count",
+            "internet_identity_anchor_statistics_unmeasured_anchors",
+            "internet_identity_register_rate_limit_max_tokens",
+            "internet_identity_register_rate_limit_time_per_token_seconds",
+            "internet_identity_challenge_rate_limit_current_tokens",
+            "internet_identity_register_rate_limit_current_tokens",
+            "internet_identity_rate_limited_registrations_counter",
+            "internet_identity_recent_registrations",
+            "internet_identity_pow_difficulty",
         ];
         let env = StateMachine::new();
         env.advance_time(Duration::from_secs(300)); // advance time to see it reflected on the metrics endpoint
@@ -1891,6 +5136,11 @@ mod http_tests {
                 assigned_user_number_range: Some((127, 129)),
                 archive_module_hash: None,
                 canister_creation_cycles_cost: None,
+                layout_migration_batch_size: None,
+                ic_root_key_der: None,
+                webauthn_verification: None,
+                register_rate_limit: None,
+                captcha_type: None,
             }),
         );
 
@@ -1956,6 +5206,8 @@ mod http_tests {
                 frontend_hostname.to_string(),
                 ByteBuf::from(format!("session key {}", count)),
                 None,
+                None,
+                None,
             )?;
 
             assert_metric(
@@ -1981,6 +5233,8 @@ mod http_tests {
             frontend_hostname.to_string(),
             ByteBuf::from("last session key"),
             None,
+            None,
+            None,
         )?;
 
         assert_metric(
@@ -1996,27 +5250,133 @@ mod http_tests {
         Ok(())
     }
 
+    /// Verifies that the anchor statistics are updated when anchors change and are kept across upgrades.
+    #[test]
+    fn metrics_anchor_statistics_should_reflect_anchors() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        flows::register_anchor(&env, canister_id);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            recovery_device_data_1(),
+        )?;
+
+        let assert_statistics = |metrics: &str| {
+            assert_metric(
+                metrics,
+                "internet_identity_devices_per_anchor_bucket{le=\"0\"}",
+                0,
+            );
+            assert_metric(
+                metrics,
+                "internet_identity_devices_per_anchor_bucket{le=\"1\"}",
+                1,
+            );
+            assert_metric(
+                metrics,
+                "internet_identity_devices_per_anchor_bucket{le=\"2\"}",
+                2,
+            );
+            assert_metric(metrics, "internet_identity_devices_per_anchor_count", 2);
+            assert_metric(metrics, "internet_identity_devices_per_anchor_sum", 3);
+            assert_metric(
+                metrics,
+                "internet_identity_anchor_record_size_ratio_bucket{le=\"0.1\"}",
+                2,
+            );
+            assert_metric(
+                metrics,
+                "internet_identity_devices_by_key_type{key_type=\"unknown\"}",
+                2,
+            );
+            assert_metric(
+                metrics,
+                "internet_identity_devices_by_key_type{key_type=\"seed_phrase\"}",
+                1,
+            );
+            assert_metric(
+                metrics,
+                "internet_identity_anchor_statistics_unmeasured_anchors",
+                0,
+            );
+        };
+        assert_statistics(&flows::get_metrics(&env, canister_id));
+
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+        assert_statistics(&flows::get_metrics(&env, canister_id));
+        Ok(())
+    }
+
+    /// Verifies that the usage counters are kept across upgrades.
+    #[test]
+    fn metrics_usage_counters_should_survive_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+        api::add(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2(),
+        )?;
+        api::remove(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2().pubkey,
+        )?;
+        api::prepare_delegation(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session key"),
+            None,
+            None,
+            None,
+        )?;
+
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+        flows::register_anchor(&env, canister_id);
+
+        let metrics = flows::get_metrics(&env, canister_id);
+        assert_metric(&metrics, "internet_identity_register_counter", 2);
+        assert_metric(&metrics, "internet_identity_add_device_counter", 1);
+        assert_metric(&metrics, "internet_identity_remove_device_counter", 1);
+        assert_metric(&metrics, "internet_identity_delegation_counter", 1);
+        assert_metric(&metrics, "internet_identity_anchor_operations_counter", 4);
+        Ok(())
+    }
+
     /// Verifies that the stable memory pages count metric is updated correctly.
     #[test]
-    fn metrics_stable_memory_pages_should_increase_with_more_users() -> Result<(), CallError> {
+    fn metrics_stable_memory_pages_should_cover_managed_memory() -> Result<(), CallError> {
         let env = StateMachine::new();
         let canister_id = install_ii_canister(&env, II_WASM.clone());
 
         let metrics = flows::get_metrics(&env, canister_id);
         let (stable_memory_pages, _) =
             parse_metric(&metrics, "internet_identity_stable_memory_pages");
-        // empty II has some metadata in stable memory which requires at least one page
-        assert_eq!(stable_memory_pages, 1);
+        // the genesis layout places the managed memory after the space for 3'774'873 anchor
+        // records of 2kb, which is used right away
+        assert!(stable_memory_pages > 3_774_873 * 2048 / 65536);
 
-        // a wasm page is 64kb and a single user takes up 2kb -> 33rd anchor record starts on the second wasm page
+        // the anchor records are written in front of the managed memory
         for _ in 0..33 {
             flows::register_anchor(&env, canister_id);
         }
 
         let metrics = flows::get_metrics(&env, canister_id);
-        let (stable_memory_pages, _) =
+        let (pages_after_registrations, _) =
             parse_metric(&metrics, "internet_identity_stable_memory_pages");
-        assert_eq!(stable_memory_pages, 2);
+        assert_eq!(pages_after_registrations, stable_memory_pages);
         Ok(())
     }
 
@@ -2295,6 +5655,50 @@ mod remote_device_registration_tests {
         Ok(())
     }
 
+    /// Tests that the device registration flow can be completed across an upgrade.
+    #[test]
+    fn can_register_remote_device_across_upgrade() -> Result<(), CallError> {
+        let env = StateMachine::new();
+        let canister_id = install_ii_canister(&env, II_WASM.clone());
+        let user_number = flows::register_anchor(&env, canister_id);
+
+        api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+        let add_response = api::add_tentative_device(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device_data_2(),
+        )?;
+        let verification_code = match add_response {
+            AddTentativeDeviceResponse::AddedTentatively {
+                verification_code, ..
+            } => verification_code,
+            err => panic!("failed to add tentative device: {:?}", err),
+        };
+
+        upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+        let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+        assert_eq!(
+            anchor_info.device_registration.unwrap().tentative_device,
+            Some(device_data_2())
+        );
+        let verification_response = api::verify_tentative_device(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            verification_code,
+        )?;
+
+        assert!(matches!(
+            verification_response,
+            VerifyTentativeDeviceResponse::Verified
+        ));
+        Ok(())
+    }
+
     /// Tests that the device registration flow can be completed successfully after submitting an invalid code.
     #[test]
     fn can_verify_remote_device_after_failed_attempt() -> Result<(), CallError> {
@@ -25,6 +25,12 @@ pub struct DeviceData {
     pub purpose: Purpose,
     pub key_type: KeyType,
     pub protection: DeviceProtection,
+    // time the device was added and last used (only returned to the anchor itself, ignored when
+    // adding or updating devices)
+    pub created_at: Option<Timestamp>,
+    pub last_used: Option<Timestamp>,
+    // fine grained protection of the device (None if the device only uses `protection`)
+    pub protection_policy: Option<DeviceProtectionPolicy>,
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
@@ -43,6 +49,8 @@ pub enum RegisterResponse {
     CanisterFull,
     #[serde(rename = "bad_challenge")]
     BadChallenge,
+    #[serde(rename = "rate_limited")]
+    RateLimited,
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
@@ -65,10 +73,48 @@ pub enum DeviceProtection {
     Unprotected,
 }
 
+/// Changes of a device that are only allowed if the call is authenticated with the device itself.
+/// `DeviceProtection::Protected` is equivalent to the policy with all flags set.
+#[derive(Eq, PartialEq, Clone, Debug, Default, CandidType, Deserialize)]
+pub struct DeviceProtectionPolicy {
+    // removing the device (this includes deleting the anchor)
+    pub protected_from_removal: bool,
+    // changing the purpose of the device
+    pub protected_from_purpose_change: bool,
+    // any change of the device
+    pub requires_self_authentication: bool,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Challenge {
+    // the captcha image (empty unless the captcha is an image captcha)
     pub png_base64: String,
     pub challenge_key: ChallengeKey,
+    pub captcha: CaptchaChallenge,
+}
+
+/// Type of the captchas issued by `create_challenge`.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum CaptchaType {
+    #[serde(rename = "image")]
+    Image,
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "proof_of_work")]
+    ProofOfWork,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum CaptchaChallenge {
+    // the characters shown on the image in `png_base64`
+    #[serde(rename = "image")]
+    Image,
+    // a question (e.g. "What is 7 plus 12?") to be answered in plain text
+    #[serde(rename = "text")]
+    Text { question: String },
+    // a solution s such that sha256(nonce · s) starts with (at least) `difficulty` zero bits
+    #[serde(rename = "proof_of_work")]
+    ProofOfWork { nonce: String, difficulty: u8 },
 }
 
 pub type ChallengeKey = String;
@@ -87,6 +133,24 @@ pub struct Delegation {
     pub targets: Option<Vec<Principal>>,
 }
 
+/// Origin to derive the principal from instead of the frontend hostname, together with the proof
+/// that the frontend is allowed to use it.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DerivationOrigin {
+    // must be of the form https://<canister id>.ic0.app or https://<canister id>.icp0.io
+    pub origin: FrontendHostname,
+    pub alternative_origins: CertifiedAlternativeOrigins,
+}
+
+/// The /.well-known/ii-alternative-origins document as served by the derivation origin canister.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CertifiedAlternativeOrigins {
+    pub body: ByteBuf,
+    // certificate and tree of the IC-Certificate header (decoded from base64)
+    pub certificate: ByteBuf,
+    pub tree: ByteBuf,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct SignedDelegation {
     pub delegation: Delegation,
@@ -101,6 +165,109 @@ pub enum GetDelegationResponse {
     NoSuchDelegation,
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DelegationsNotBefore {
+    // delegations issued before this time have been revoked (None if they were never revoked)
+    pub not_before: Option<Timestamp>,
+    // CBOR encoded certificate and hash tree proving not_before (only available in query calls)
+    pub certified_lookup: Option<ByteBuf>,
+}
+
+/// Device change made with a recovery device while the recovery delay of the anchor is enabled.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum RecoveryDeviceChange {
+    #[serde(rename = "add")]
+    Add(DeviceData),
+    // the device to update is identified by the public key of the new device data
+    #[serde(rename = "update")]
+    Update(DeviceData),
+    #[serde(rename = "remove")]
+    Remove(DeviceKey),
+}
+
+/// A device change that becomes effective once the recovery delay has passed (unless cancelled).
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct PendingRecoveryOperation {
+    pub id: u64,
+    pub change: RecoveryDeviceChange,
+    // the recovery device that made the change (or the device added by a guardian recovery)
+    pub queued_by: Principal,
+    pub queued_at: Timestamp,
+    pub effective_at: Timestamp,
+}
+
+/// Value of the anchor metadata.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum MetadataEntry {
+    #[serde(rename = "string")]
+    String(String),
+    #[serde(rename = "bytes")]
+    Bytes(ByteBuf),
+}
+
+/// Upper bounds for the lifetime of the delegations issued for an anchor.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
+pub struct DelegationLifetimeLimits {
+    // maximum time to live (in nanoseconds) of delegations for any frontend
+    pub max_time_to_live: Option<u64>,
+    // maximum time to live (in nanoseconds) of delegations for specific frontends (or derivation
+    // origins), takes precedence over max_time_to_live
+    pub frontend_overrides: Vec<(FrontendHostname, u64)>,
+}
+
+/// A delegation issued to a frontend by `prepare_delegation`.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct Session {
+    pub frontend: FrontendHostname,
+    // origin the principal was derived from (if different from the frontend)
+    pub derivation_origin: Option<FrontendHostname>,
+    // SHA-256 hash of the session public key
+    pub session_key_hash: ByteBuf,
+    pub issued_at: Timestamp,
+    pub expiration: Timestamp,
+}
+
+/// Claim about an anchor that II attests to a relying party.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AttestationClaim {
+    // the anchor was created before the given time
+    #[serde(rename = "created_before")]
+    CreatedBefore(Timestamp),
+    #[serde(rename = "has_recovery_device")]
+    HasRecoveryDevice,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Attestation {
+    pub claim: AttestationClaim,
+    pub issued_at: Timestamp,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SignedAttestation {
+    pub attestation: Attestation,
+    pub signature: Signature,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum PrepareAttestationResponse {
+    #[serde(rename = "prepared")]
+    Prepared {
+        user_key: UserKey,
+        issued_at: Timestamp,
+    },
+    #[serde(rename = "claim_not_satisfied")]
+    ClaimNotSatisfied,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum GetAttestationResponse {
+    #[serde(rename = "signed_attestation")]
+    SignedAttestation(SignedAttestation),
+    #[serde(rename = "no_such_attestation")]
+    NoSuchAttestation,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum AddTentativeDeviceResponse {
     #[serde(rename = "added_tentatively")]
@@ -126,6 +293,48 @@ pub enum VerifyTentativeDeviceResponse {
     NoDeviceToVerify,
 }
 
+/// Other anchors that can approve adding a device to the anchor if the user is locked out.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct Guardians {
+    pub anchors: Vec<UserNumber>,
+    // number of guardians that must approve the recovery
+    pub threshold: u16,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum StartGuardianRecoveryResponse {
+    #[serde(rename = "started")]
+    Started { expiration: Timestamp },
+    #[serde(rename = "no_guardians")]
+    NoGuardians,
+    // a device registration or another guardian recovery is in progress
+    #[serde(rename = "registration_in_progress")]
+    RegistrationInProgress,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum ApproveGuardianRecoveryResponse {
+    // more approvals are required to add the device
+    #[serde(rename = "approved")]
+    Approved { approvals: u16, threshold: u16 },
+    #[serde(rename = "device_added")]
+    DeviceAdded,
+    #[serde(rename = "no_recovery_in_progress")]
+    NoRecoveryInProgress,
+    #[serde(rename = "not_a_guardian")]
+    NotAGuardian,
+}
+
+/// A guardian recovery in progress, as seen by the guardians.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct GuardianRecoveryInfo {
+    pub tentative_device: DeviceData,
+    // guardians that approved the recovery so far
+    pub approvals: Vec<UserNumber>,
+    pub threshold: u16,
+    pub expiration: Timestamp,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct DeviceRegistrationInfo {
     pub expiration: Timestamp,
@@ -138,6 +347,63 @@ pub struct IdentityAnchorInfo {
     pub device_registration: Option<DeviceRegistrationInfo>,
 }
 
+/// Identifier of a device used to look up its anchors.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum DeviceIdentifier {
+    #[serde(rename = "credential_id")]
+    CredentialId(CredentialId),
+    #[serde(rename = "pubkey")]
+    PublicKey(DeviceKey),
+}
+
+/// Challenge issued by the canister to be signed by a WebAuthn device of the anchor.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct WebAuthnChallenge {
+    pub challenge: ByteBuf,
+    pub expiration: Timestamp,
+}
+
+/// WebAuthn assertion (as returned by `navigator.credentials.get`) over a canister issued challenge.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct WebAuthnAssertion {
+    pub credential_id: CredentialId,
+    pub authenticator_data: ByteBuf,
+    pub client_data_json: ByteBuf,
+    pub signature: ByteBuf,
+}
+
+/// Result of the canister-side verification of a WebAuthn assertion.
+/// The challenge is consumed in all cases.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum VerifyWebAuthnAssertionResponse {
+    #[serde(rename = "verified")]
+    Verified,
+    #[serde(rename = "unknown_credential")]
+    UnknownCredential,
+    #[serde(rename = "verification_failed")]
+    VerificationFailed(String),
+}
+
+/// Configuration of the canister-side verification of WebAuthn assertions.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct WebAuthnVerificationConfig {
+    // origins (e.g. https://identity.ic0.app) the assertions must have been created on
+    pub allowed_origins: Vec<String>,
+    // whether the authenticator must have verified the user (e.g. using a PIN or biometrics)
+    pub require_user_verification: bool,
+    // whether updating or removing a recovery device requires a fresh verified assertion
+    pub required_for_recovery_devices: bool,
+}
+
+/// Configuration of the token bucket limiting the rate of captcha challenges and registrations.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct RateLimitConfig {
+    // time it takes (in ns) for a new token to be added to the bucket
+    pub time_per_token_ns: u64,
+    // maximum number of tokens the bucket can hold (i.e. the maximum burst)
+    pub max_tokens: u64,
+}
+
 pub type HeaderField = (String, String);
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -175,6 +441,11 @@ pub struct InternetIdentityInit {
     pub assigned_user_number_range: Option<(UserNumber, UserNumber)>,
     pub archive_module_hash: Option<[u8; 32]>,
     pub canister_creation_cycles_cost: Option<u64>,
+    pub layout_migration_batch_size: Option<u32>,
+    pub ic_root_key_der: Option<ByteBuf>,
+    pub webauthn_verification: Option<WebAuthnVerificationConfig>,
+    pub register_rate_limit: Option<RateLimitConfig>,
+    pub captcha_type: Option<CaptchaType>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -184,6 +455,20 @@ pub struct InternetIdentityStats {
     pub archive_info: ArchiveInfo,
     pub canister_creation_cycles_cost: u64,
     pub storage_layout_version: u8,
+    pub layout_migration_state: MigrationState,
+    pub archive_buffer: ArchiveBufferInfo,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum MigrationState {
+    #[serde(rename = "not_started")]
+    NotStarted,
+    #[serde(rename = "in_progress")]
+    InProgress { anchors_left: u64, batch_size: u64 },
+    #[serde(rename = "paused")]
+    Paused { anchors_left: u64 },
+    #[serde(rename = "finished")]
+    Finished,
 }
 
 // Archive specific types
@@ -201,6 +486,69 @@ pub enum Operation {
     },
     #[serde(rename = "remove_device")]
     RemoveDevice { device: PublicKey },
+    #[serde(rename = "delete_anchor")]
+    DeleteAnchor,
+    // a device change made with a recovery device was delayed (see PendingRecoveryOperation)
+    #[serde(rename = "queue_recovery_operation")]
+    QueueRecoveryOperation {
+        id: u64,
+        effective_at: Timestamp,
+        operation: Box<Operation>,
+    },
+    #[serde(rename = "execute_recovery_operation")]
+    ExecuteRecoveryOperation { id: u64, operation: Box<Operation> },
+    #[serde(rename = "cancel_recovery_operation")]
+    CancelRecoveryOperation { id: u64 },
+    #[serde(rename = "set_guardians")]
+    SetGuardians { guardians: Option<Guardians> },
+    // the device is added (as a separate add_device operation) once enough guardians approved
+    #[serde(rename = "approve_guardian_recovery")]
+    ApproveGuardianRecovery {
+        guardian: UserNumber,
+        device: PublicKey,
+    },
+}
+
+/// The variant of an [Operation] without its content, used to filter archive entries.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub enum OperationType {
+    #[serde(rename = "register_anchor")]
+    RegisterAnchor,
+    #[serde(rename = "add_device")]
+    AddDevice,
+    #[serde(rename = "update_device")]
+    UpdateDevice,
+    #[serde(rename = "remove_device")]
+    RemoveDevice,
+    #[serde(rename = "delete_anchor")]
+    DeleteAnchor,
+    #[serde(rename = "queue_recovery_operation")]
+    QueueRecoveryOperation,
+    #[serde(rename = "execute_recovery_operation")]
+    ExecuteRecoveryOperation,
+    #[serde(rename = "cancel_recovery_operation")]
+    CancelRecoveryOperation,
+    #[serde(rename = "set_guardians")]
+    SetGuardians,
+    #[serde(rename = "approve_guardian_recovery")]
+    ApproveGuardianRecovery,
+}
+
+impl From<&Operation> for OperationType {
+    fn from(operation: &Operation) -> Self {
+        match operation {
+            Operation::RegisterAnchor { .. } => OperationType::RegisterAnchor,
+            Operation::AddDevice { .. } => OperationType::AddDevice,
+            Operation::UpdateDevice { .. } => OperationType::UpdateDevice,
+            Operation::RemoveDevice { .. } => OperationType::RemoveDevice,
+            Operation::DeleteAnchor => OperationType::DeleteAnchor,
+            Operation::QueueRecoveryOperation { .. } => OperationType::QueueRecoveryOperation,
+            Operation::ExecuteRecoveryOperation { .. } => OperationType::ExecuteRecoveryOperation,
+            Operation::CancelRecoveryOperation { .. } => OperationType::CancelRecoveryOperation,
+            Operation::SetGuardians { .. } => OperationType::SetGuardians,
+            Operation::ApproveGuardianRecovery { .. } => OperationType::ApproveGuardianRecovery,
+        }
+    }
 }
 
 #[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
@@ -221,6 +569,7 @@ pub struct DeviceDataWithoutAlias {
     pub purpose: Purpose,
     pub key_type: KeyType,
     pub protection: DeviceProtection,
+    pub protection_policy: Option<DeviceProtectionPolicy>,
 }
 
 impl From<DeviceData> for DeviceDataWithoutAlias {
@@ -231,6 +580,7 @@ impl From<DeviceData> for DeviceDataWithoutAlias {
             purpose: device_data.purpose,
             key_type: device_data.key_type,
             protection: device_data.protection,
+            protection_policy: device_data.protection_policy,
         }
     }
 }
@@ -244,6 +594,8 @@ pub struct DeviceDataUpdate {
     pub purpose: Option<Purpose>,
     pub key_type: Option<KeyType>,
     pub protection: Option<DeviceProtection>,
+    // the policy is recorded with all flags unset if it was removed
+    pub protection_policy: Option<DeviceProtectionPolicy>,
 }
 
 // Placeholder for information that has been hidden for privacy reasons.
@@ -277,6 +629,26 @@ pub enum Cursor {
     NextToken { next_token: ByteBuf },
 }
 
+/// Criteria for archive entries. Only entries matching all the given criteria are returned.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct EntryFilter {
+    // inclusive lower bound of the entry timestamp
+    pub from_timestamp: Option<Timestamp>,
+    // exclusive upper bound of the entry timestamp
+    pub to_timestamp: Option<Timestamp>,
+    pub operation: Option<OperationType>,
+    pub caller: Option<Principal>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FilteredEntries {
+    // make this a vec of options to keep Entry extensible
+    pub entries: Vec<Option<Entry>>,
+    // cursor pointing to the next index entry to be inspected, if any
+    // Note: the cursor can be returned even if less than limit many entries are returned
+    pub cursor: Option<Cursor>,
+}
+
 /// Information about the archive.
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct ArchiveInfo {
@@ -284,6 +656,49 @@ pub struct ArchiveInfo {
     pub expected_wasm_hash: Option<[u8; 32]>,
 }
 
+/// Information about the entries buffered by II until they are acknowledged by the archive.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ArchiveBufferInfo {
+    // number of entries currently waiting to be acknowledged by the archive
+    pub buffered_entries: u64,
+    // number of entries dropped because the buffer was full
+    pub dropped_entries: u64,
+    // number of failed attempts to push entries to the archive (since last upgrade)
+    pub failed_pushes: u64,
+}
+
+/// Archive entry together with the metadata required by the archive to store and index it.
+/// II keeps buffered entries until the archive acknowledges them.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub struct BufferedEntry {
+    pub anchor: Anchor,
+    pub timestamp: Timestamp,
+    pub sequence_number: u64,
+    // candid encoded Entry
+    pub entry: ByteBuf,
+}
+
+/// Information about the completeness of the entries pushed to the archive.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ArchiveHealth {
+    pub highest_sequence_number: Option<u64>,
+    // all entries from first_tracked_sequence_number up to this sequence number have been stored
+    pub highest_contiguous_sequence_number: Option<u64>,
+    // entries with lower sequence numbers were written before the archive tracked sequence numbers
+    pub first_tracked_sequence_number: u64,
+    // total number of missing sequence numbers, including those of gaps that are not listed
+    // because too many gaps have been recorded
+    pub missing_entries: u64,
+    pub gaps: Vec<SequenceNumberGap>,
+}
+
+/// Inclusive range of sequence numbers that are missing in the archive.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct SequenceNumberGap {
+    pub start: u64,
+    pub end: u64,
+}
+
 /// Init arguments of the archive canister.
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct ArchiveInit {
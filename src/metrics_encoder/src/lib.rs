@@ -99,6 +99,27 @@ impl<W: io::Write> MetricsEncoder<W> {
     pub fn encode_gauge(&mut self, name: &str, value: f64, help: &str) -> io::Result<()> {
         self.encode_single_value("gauge", name, value, help)
     }
+
+    /// Encodes the metadata and the values of a gauge with a single label.
+    ///
+    /// VALUES is a list of (label value, value) pairs.
+    pub fn encode_labeled_gauge<'a>(
+        &mut self,
+        name: &str,
+        label: &str,
+        values: impl Iterator<Item = (&'a str, f64)>,
+        help: &str,
+    ) -> io::Result<()> {
+        self.encode_header(name, help, "gauge")?;
+        for (label_value, value) in values {
+            writeln!(
+                self.writer,
+                "{}{{{}=\"{}\"}} {} {}",
+                name, label, label_value, value, self.now_millis
+            )?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -60,3 +60,23 @@ http_request_duration_seconds_count 144320 1234567890000
 "#
     )
 }
+
+#[test]
+fn test_labeled_gauge_encoding() {
+    let mut w = new_encoder();
+    w.encode_labeled_gauge(
+        "devices",
+        "key_type",
+        [("platform", 12.0), ("seed_phrase", 3.0)].iter().cloned(),
+        "The number of devices by key type.",
+    )
+    .unwrap();
+    assert_eq!(
+        &as_text(w),
+        r#"# HELP devices The number of devices by key type.
+# TYPE devices gauge
+devices{key_type="platform"} 12 1234567890000
+devices{key_type="seed_phrase"} 3 1234567890000
+"#
+    )
+}